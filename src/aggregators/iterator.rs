@@ -0,0 +1,327 @@
+use super::{AggOp, Aggregator};
+use crate::common::types::{Sample, Timestamp};
+use crate::module::calc_bucket_start;
+use crate::storage::{BucketTimestamp, FillMode};
+
+/// Aggregates samples into fixed-duration buckets, as in `VKM.RANGE ... AGGREGATION`.
+///
+/// Buckets start at multiples of the bucket duration, offset by the alignment timestamp, and are
+/// reported at their start, middle or end according to `bucket_ts`. Only buckets from the first to
+/// the last bucket holding samples are reported. Between those, buckets without samples are
+/// reported if `empty` is set, and skipped otherwise. Their value is given by `fill`, and defaults to
+/// the empty value of the aggregator (NaN, or 0 for counts and sums).
+pub(crate) struct AggrIterator {
+    aggregator: Aggregator,
+    /// Bucket duration, in milliseconds
+    bucket_duration: i64,
+    bucket_ts: BucketTimestamp,
+    timestamp_alignment: Timestamp,
+    count: Option<usize>,
+    empty: bool,
+    fill: Option<FillMode>,
+}
+
+impl AggrIterator {
+    pub fn new(
+        aggregator: Aggregator,
+        bucket_duration: i64,
+        bucket_ts: BucketTimestamp,
+        timestamp_alignment: Timestamp,
+        count: Option<usize>,
+        empty: bool,
+        fill: Option<FillMode>
+    ) -> Self {
+        debug_assert!(bucket_duration > 0);
+        Self {
+            aggregator,
+            bucket_duration,
+            bucket_ts,
+            timestamp_alignment,
+            count,
+            empty: empty || fill.is_some(),
+            fill,
+        }
+    }
+
+    fn bucket_start(&self, ts: Timestamp) -> Timestamp {
+        calc_bucket_start(ts, self.bucket_duration, self.timestamp_alignment).max(0)
+    }
+
+    fn finalize_bucket(&mut self, bucket_start: Timestamp) -> Sample {
+        let value = self.aggregator.finalize();
+        self.aggregator.reset();
+        Sample {
+            timestamp: self.bucket_ts.calculate(bucket_start, self.bucket_duration),
+            value,
+        }
+    }
+
+    /// Aggregates `samples`, which must be ordered by timestamp.
+    pub fn calculate(&mut self, samples: impl Iterator<Item=Sample>) -> Vec<Sample> {
+        self.calculate_with_empty_buckets(samples).0
+    }
+
+    /// As `calculate`, also returning the indexes of the empty buckets, in order. These are the
+    /// buckets without samples, as opposed to those whose aggregate is NaN.
+    pub fn calculate_with_empty_buckets(
+        &mut self,
+        samples: impl Iterator<Item=Sample>
+    ) -> (Vec<Sample>, Vec<usize>) {
+        let max_count = self.count.unwrap_or(usize::MAX);
+        let mut buckets: Vec<Sample> = Vec::new();
+        let mut empty_buckets: Vec<usize> = Vec::new();
+        if max_count == 0 {
+            return (buckets, empty_buckets);
+        }
+
+        let mut current_bucket: Option<Timestamp> = None;
+        for sample in samples {
+            let bucket_start = self.bucket_start(sample.timestamp);
+            match current_bucket {
+                Some(current) if current != bucket_start => {
+                    buckets.push(self.finalize_bucket(current));
+                    if self.empty {
+                        let empty_value = self.aggregator.empty_value();
+                        let mut empty_bucket = current + self.bucket_duration;
+                        while empty_bucket < bucket_start && buckets.len() < max_count {
+                            empty_buckets.push(buckets.len());
+                            buckets.push(Sample {
+                                timestamp: self.bucket_ts.calculate(empty_bucket, self.bucket_duration),
+                                value: empty_value,
+                            });
+                            empty_bucket += self.bucket_duration;
+                        }
+                    }
+                    if buckets.len() >= max_count {
+                        buckets.truncate(max_count);
+                        self.fill_empty_buckets(&mut buckets, &empty_buckets);
+                        return (buckets, empty_buckets);
+                    }
+                    current_bucket = Some(bucket_start);
+                }
+                Some(_) => {}
+                None => current_bucket = Some(bucket_start),
+            }
+            self.aggregator.update_sample(sample.timestamp, sample.value);
+        }
+
+        if let Some(current) = current_bucket {
+            buckets.push(self.finalize_bucket(current));
+        }
+        self.fill_empty_buckets(&mut buckets, &empty_buckets);
+        (buckets, empty_buckets)
+    }
+
+    /// Sets the values of the empty buckets at `indexes` according to the fill mode. An empty
+    /// bucket always follows a bucket with samples, but may not precede one if the output was
+    /// truncated, in which case linear fill falls back to the previous value.
+    fn fill_empty_buckets(&self, buckets: &mut [Sample], indexes: &[usize]) {
+        let Some(fill) = self.fill else {
+            return;
+        };
+        for &index in indexes {
+            let value = match fill {
+                FillMode::Null => f64::NAN,
+                FillMode::Value(value) => value,
+                FillMode::Previous => buckets[index - 1].value,
+                FillMode::Linear => {
+                    // the previous bucket is either filled already or has samples
+                    let (prev_ts, prev_value) = (buckets[index - 1].timestamp, buckets[index - 1].value);
+                    let next = buckets[index + 1..]
+                        .iter()
+                        .zip(index + 1..)
+                        .find(|(_, i)| indexes.binary_search(i).is_err())
+                        .map(|(sample, _)| (sample.timestamp, sample.value));
+                    match next {
+                        Some((next_ts, next_value)) => {
+                            let ratio = (buckets[index].timestamp - prev_ts) as f64 /
+                                (next_ts - prev_ts) as f64;
+                            prev_value + (next_value - prev_value) * ratio
+                        }
+                        None => prev_value,
+                    }
+                }
+            };
+            buckets[index].value = value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::generators::create_rng;
+    use rand::Rng;
+    use std::collections::BTreeMap;
+
+    fn samples(items: &[(Timestamp, f64)]) -> Vec<Sample> {
+        items.iter().map(|(timestamp, value)| Sample::new(*timestamp, *value)).collect()
+    }
+
+    fn aggregate(
+        name: &str,
+        bucket_duration: i64,
+        bucket_ts: BucketTimestamp,
+        alignment: Timestamp,
+        count: Option<usize>,
+        empty: bool,
+        data: &[Sample]
+    ) -> Vec<(Timestamp, f64)> {
+        let aggregator = Aggregator::new(name).unwrap();
+        let mut iter = AggrIterator::new(aggregator, bucket_duration, bucket_ts, alignment, count, empty, None);
+        iter.calculate(data.iter().cloned())
+            .into_iter()
+            .map(|sample| (sample.timestamp, sample.value))
+            .collect()
+    }
+
+    #[test]
+    fn test_buckets() {
+        let data = samples(&[(1000, 1.), (1500, 2.), (2000, 3.), (4500, 4.)]);
+        let res = aggregate("sum", 1000, BucketTimestamp::Start, 0, None, false, &data);
+        // the last bucket is reported
+        assert_eq!(res, vec![(1000, 3.), (2000, 3.), (4000, 4.)]);
+    }
+
+    #[test]
+    fn test_empty_buckets() {
+        let data = samples(&[(1000, 1.), (4500, 4.)]);
+        let res = aggregate("sum", 1000, BucketTimestamp::Start, 0, None, true, &data);
+        assert_eq!(res, vec![(1000, 1.), (2000, 0.), (3000, 0.), (4000, 4.)]);
+
+        let res = aggregate("max", 1000, BucketTimestamp::Start, 0, None, true, &data);
+        assert_eq!(res.len(), 4);
+        assert!(res[1].1.is_nan());
+        assert!(res[2].1.is_nan());
+    }
+
+    #[test]
+    fn test_bucket_timestamp_and_alignment() {
+        let data = samples(&[(1000, 1.), (1200, 2.), (1300, 3.)]);
+        let res = aggregate("count", 1000, BucketTimestamp::Mid, 250, None, false, &data);
+        // buckets are [250, 1250) and [1250, 2250)
+        assert_eq!(res, vec![(750, 2.), (1750, 1.)]);
+
+        let res = aggregate("count", 1000, BucketTimestamp::End, 250, None, false, &data);
+        assert_eq!(res, vec![(1250, 2.), (2250, 1.)]);
+    }
+
+    #[test]
+    fn test_count() {
+        let data = samples(&[(1000, 1.), (2000, 2.), (5000, 3.)]);
+        let res = aggregate("sum", 1000, BucketTimestamp::Start, 0, Some(2), false, &data);
+        assert_eq!(res, vec![(1000, 1.), (2000, 2.)]);
+
+        // empty buckets count towards the limit
+        let res = aggregate("sum", 1000, BucketTimestamp::Start, 0, Some(4), true, &data);
+        assert_eq!(res, vec![(1000, 1.), (2000, 2.), (3000, 0.), (4000, 0.)]);
+    }
+
+    fn aggregate_with_fill(fill: FillMode, count: Option<usize>, data: &[Sample]) -> Vec<(Timestamp, f64)> {
+        let aggregator = Aggregator::new("max").unwrap();
+        let mut iter = AggrIterator::new(aggregator, 1000, BucketTimestamp::Start, 0, count, false, Some(fill));
+        iter.calculate(data.iter().cloned())
+            .into_iter()
+            .map(|sample| (sample.timestamp, sample.value))
+            .collect()
+    }
+
+    #[test]
+    fn test_fill() {
+        let data = samples(&[(1000, 1.), (4000, 7.), (5000, 8.)]);
+
+        let res = aggregate_with_fill(FillMode::Value(-1.), None, &data);
+        assert_eq!(res, vec![(1000, 1.), (2000, -1.), (3000, -1.), (4000, 7.), (5000, 8.)]);
+
+        let res = aggregate_with_fill(FillMode::Previous, None, &data);
+        assert_eq!(res, vec![(1000, 1.), (2000, 1.), (3000, 1.), (4000, 7.), (5000, 8.)]);
+
+        let res = aggregate_with_fill(FillMode::Linear, None, &data);
+        assert_eq!(res, vec![(1000, 1.), (2000, 3.), (3000, 5.), (4000, 7.), (5000, 8.)]);
+
+        let res = aggregate_with_fill(FillMode::Null, None, &data);
+        assert_eq!(res.len(), 5);
+        assert!(res[1].1.is_nan() && res[2].1.is_nan());
+    }
+
+    #[test]
+    fn test_empty_buckets_are_told_apart_from_nan_buckets() {
+        let aggregator = Aggregator::new("last").unwrap();
+        let fill = Some(FillMode::Null);
+        let mut iter = AggrIterator::new(aggregator, 1000, BucketTimestamp::Start, 0, None, false, fill);
+        let data = samples(&[(1000, f64::NAN), (3000, 2.), (6000, 3.)]);
+        let (buckets, empty_buckets) = iter.calculate_with_empty_buckets(data.into_iter());
+        assert_eq!(buckets.len(), 6);
+        // the first bucket holds a NaN sample, so is not empty
+        assert!(buckets[0].value.is_nan());
+        assert_eq!(empty_buckets, vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn test_linear_fill_of_truncated_output() {
+        let data = samples(&[(1000, 1.), (4000, 7.)]);
+        let res = aggregate_with_fill(FillMode::Linear, Some(3), &data);
+        assert_eq!(res, vec![(1000, 1.), (2000, 1.), (3000, 1.)]);
+    }
+
+    /// Buckets samples the straightforward way, for comparison with `AggrIterator`.
+    fn reference_sum(
+        data: &[Sample],
+        bucket_duration: i64,
+        bucket_ts: BucketTimestamp,
+        alignment: Timestamp,
+        count: Option<usize>,
+        empty: bool
+    ) -> Vec<(Timestamp, f64)> {
+        let mut buckets: BTreeMap<Timestamp, f64> = BTreeMap::new();
+        for sample in data {
+            let start = calc_bucket_start(sample.timestamp, bucket_duration, alignment).max(0);
+            *buckets.entry(start).or_default() += sample.value;
+        }
+        if empty {
+            if let (Some(first), Some(last)) = (buckets.keys().next().copied(), buckets.keys().last().copied()) {
+                let mut start = first;
+                while start < last {
+                    buckets.entry(start).or_default();
+                    start += bucket_duration;
+                }
+            }
+        }
+        buckets
+            .into_iter()
+            .map(|(start, value)| (bucket_ts.calculate(start, bucket_duration), value))
+            .take(count.unwrap_or(usize::MAX))
+            .collect()
+    }
+
+    #[test]
+    fn test_matches_reference_on_random_input() {
+        let mut rng = create_rng(Some(1234)).unwrap();
+        let bucket_timestamps = [BucketTimestamp::Start, BucketTimestamp::Mid, BucketTimestamp::End];
+
+        for _ in 0..500 {
+            // late enough that no bucket start is clamped to 0
+            let mut timestamp: Timestamp = rng.gen_range(100_000..110_000);
+            let data: Vec<Sample> = (0..rng.gen_range(0..200))
+                .map(|_| {
+                    timestamp += rng.gen_range(1..5_000);
+                    // integer values keep sums exact
+                    Sample::new(timestamp, rng.gen_range(0..100) as f64)
+                })
+                .collect();
+
+            let bucket_duration = rng.gen_range(1..20_000);
+            let bucket_ts = bucket_timestamps[rng.gen_range(0..bucket_timestamps.len())];
+            let alignment = rng.gen_range(-50_000..50_000);
+            let count = rng.gen_bool(0.3).then(|| rng.gen_range(1..50));
+            let empty = rng.gen_bool(0.5);
+
+            let expected = reference_sum(&data, bucket_duration, bucket_ts, alignment, count, empty);
+            let actual = aggregate("sum", bucket_duration, bucket_ts, alignment, count, empty, &data);
+            assert_eq!(
+                actual, expected,
+                "duration={bucket_duration} alignment={alignment} count={count:?} empty={empty}"
+            );
+        }
+    }
+}
@@ -2,6 +2,16 @@
 // https://github.com/cryptorelay/redis-aggregation/tree/master
 // License: Apache License 2.0
 
+mod iterator;
+mod resample;
+mod sketch;
+
+use crate::common::types::Timestamp;
+use sketch::DDSketch;
+
+pub(crate) use iterator::*;
+pub(crate) use resample::*;
+use std::time::Duration;
 use valkey_module::{ValkeyError, ValkeyString};
 
 type Value = f64;
@@ -10,6 +20,10 @@ pub trait AggOp {
     fn save(&self) -> (&str, String);
     fn load(&mut self, buf: &str);
     fn update(&mut self, value: Value);
+    /// Updates with a sample. Only aggregators which weigh values by time use the timestamp.
+    fn update_sample(&mut self, _timestamp: Timestamp, value: Value) {
+        self.update(value)
+    }
     fn reset(&mut self);
     fn current(&self) -> Option<Value>;
     fn empty_value(&self) -> Value {
@@ -368,6 +382,223 @@ impl AggOp for AggStdS {
 }
 
 
+/// Increase of a counter over a bucket, accounting for counter resets as in Prometheus: a value
+/// lower than the previous one is taken as a reset, and the value before the reset is added to
+/// the increase. The increase of a bucket is measured from the last sample of the previous
+/// bucket, so that the increases of consecutive buckets add up to the total increase.
+#[derive(Clone, Default, Debug)]
+pub struct AggIncrease {
+    /// Value the increase of the current bucket is measured from
+    base: Option<Value>,
+    /// Last value seen, possibly in a previous bucket
+    prev: Option<Value>,
+    /// Sum of the values before resets in the current bucket
+    correction: Value,
+    has_samples: bool,
+}
+impl AggOp for AggIncrease {
+    fn save(&self) -> (&str, String) {
+        (
+            "increase",
+            serde_json::to_string(&(self.base, self.prev, self.correction, self.has_samples)).unwrap(),
+        )
+    }
+    fn load(&mut self, buf: &str) {
+        let t = serde_json::from_str::<(Option<Value>, Option<Value>, Value, bool)>(buf).unwrap();
+        self.base = t.0;
+        self.prev = t.1;
+        self.correction = t.2;
+        self.has_samples = t.3;
+    }
+    fn update(&mut self, value: Value) {
+        match self.prev {
+            Some(prev) if value < prev => self.correction += prev,
+            Some(_) => {}
+            None => self.base = Some(value),
+        }
+        self.prev = Some(value);
+        self.has_samples = true;
+    }
+    fn reset(&mut self) {
+        self.base = self.prev;
+        self.correction = 0.;
+        self.has_samples = false;
+    }
+    fn current(&self) -> Option<Value> {
+        match (self.has_samples, self.base, self.prev) {
+            (true, Some(base), Some(last)) => Some(last + self.correction - base),
+            _ => None,
+        }
+    }
+}
+
+/// Per-second rate of increase of a counter over a bucket. See `AggIncrease`.
+#[derive(Clone, Default, Debug)]
+pub struct AggRate {
+    increase: AggIncrease,
+    /// Bucket duration, in seconds
+    window_secs: Value,
+}
+impl AggRate {
+    pub fn new(bucket_duration: Duration) -> Self {
+        Self {
+            increase: AggIncrease::default(),
+            window_secs: bucket_duration.as_secs_f64(),
+        }
+    }
+}
+impl AggOp for AggRate {
+    fn save(&self) -> (&str, String) {
+        let (_, increase) = self.increase.save();
+        ("rate", serde_json::to_string(&(increase, self.window_secs)).unwrap())
+    }
+    fn load(&mut self, buf: &str) {
+        let t = serde_json::from_str::<(String, Value)>(buf).unwrap();
+        self.increase.load(&t.0);
+        self.window_secs = t.1;
+    }
+    fn update(&mut self, value: Value) {
+        self.increase.update(value)
+    }
+    fn reset(&mut self) {
+        self.increase.reset()
+    }
+    fn current(&self) -> Option<Value> {
+        if self.window_secs <= 0. {
+            return None;
+        }
+        self.increase.current().map(|increase| increase / self.window_secs)
+    }
+}
+
+/// Number of NaN values, e.g. staleness markers.
+#[derive(Clone, Default, Debug)]
+pub struct AggCountNaN(usize);
+impl AggOp for AggCountNaN {
+    fn save(&self) -> (&str, String) {
+        ("countNaN", serde_json::to_string(&self.0).unwrap())
+    }
+    fn load(&mut self, buf: &str) {
+        self.0 = serde_json::from_str(buf).unwrap();
+    }
+    fn update(&mut self, value: Value) {
+        if value.is_nan() {
+            self.0 += 1;
+        }
+    }
+    fn reset(&mut self) {
+        self.0 = 0;
+    }
+    fn current(&self) -> Option<Value> {
+        Some(self.0 as Value)
+    }
+    fn empty_value(&self) -> Value {
+        0.
+    }
+}
+
+/// Estimated quantile of the values, from a DDSketch with a relative error of 1%. NaN values are
+/// ignored.
+#[derive(Clone, Debug)]
+pub struct AggQuantile {
+    quantile: Value,
+    sketch: DDSketch,
+}
+impl AggQuantile {
+    pub fn new(quantile: Value) -> Self {
+        Self {
+            quantile,
+            sketch: DDSketch::default(),
+        }
+    }
+}
+impl AggOp for AggQuantile {
+    fn save(&self) -> (&str, String) {
+        (
+            "quantile",
+            serde_json::to_string(&(self.quantile, &self.sketch)).unwrap(),
+        )
+    }
+    fn load(&mut self, buf: &str) {
+        let t = serde_json::from_str::<(Value, DDSketch)>(buf).unwrap();
+        self.quantile = t.0;
+        self.sketch = t.1;
+    }
+    fn update(&mut self, value: Value) {
+        self.sketch.add(value)
+    }
+    fn reset(&mut self) {
+        self.sketch.clear()
+    }
+    fn current(&self) -> Option<Value> {
+        self.sketch.quantile(self.quantile)
+    }
+}
+
+/// Time-weighted average of the values, interpolating linearly between samples. A single sample
+/// averages to its value.
+#[derive(Clone, Default, Debug)]
+pub struct AggTwa {
+    first_timestamp: Option<Timestamp>,
+    last: Option<(Timestamp, Value)>,
+    /// Area under the interpolated values, in value * milliseconds
+    area: Value,
+}
+impl AggOp for AggTwa {
+    fn save(&self) -> (&str, String) {
+        (
+            "twa",
+            serde_json::to_string(&(self.first_timestamp, self.last, self.area)).unwrap(),
+        )
+    }
+    fn load(&mut self, buf: &str) {
+        let t = serde_json::from_str::<(Option<Timestamp>, Option<(Timestamp, Value)>, Value)>(buf).unwrap();
+        self.first_timestamp = t.0;
+        self.last = t.1;
+        self.area = t.2;
+    }
+    fn update(&mut self, value: Value) {
+        // without timestamps, samples are taken as evenly spaced
+        let timestamp = self.last.map_or(0, |(ts, _)| ts + 1);
+        self.update_sample(timestamp, value)
+    }
+    fn update_sample(&mut self, timestamp: Timestamp, value: Value) {
+        if value.is_nan() {
+            return;
+        }
+        match self.last {
+            Some((last_ts, last_value)) => {
+                self.area += (last_value + value) / 2. * (timestamp - last_ts) as Value;
+            }
+            None => self.first_timestamp = Some(timestamp),
+        }
+        self.last = Some((timestamp, value));
+    }
+    fn reset(&mut self) {
+        self.first_timestamp = None;
+        self.last = None;
+        self.area = 0.;
+    }
+    fn current(&self) -> Option<Value> {
+        let (first_ts, (last_ts, last_value)) = (self.first_timestamp?, self.last?);
+        if last_ts == first_ts {
+            Some(last_value)
+        } else {
+            Some(self.area / (last_ts - first_ts) as Value)
+        }
+    }
+}
+
+/// Names of the quantile aggregations, with their quantile
+const QUANTILE_AGGREGATORS: [(&str, Value); 6] = [
+    ("p50", 0.5),
+    ("p75", 0.75),
+    ("p90", 0.9),
+    ("p95", 0.95),
+    ("p99", 0.99),
+    ("p999", 0.999),
+];
+
 #[derive(Clone, Debug)]
 pub enum Aggregator {
     First(AggFirst),
@@ -382,6 +613,11 @@ pub enum Aggregator {
     StdP(AggStdP),
     VarS(AggVarS),
     VarP(AggVarP),
+    Increase(AggIncrease),
+    Rate(AggRate),
+    CountNaN(AggCountNaN),
+    Quantile(AggQuantile),
+    Twa(AggTwa),
 }
 
 impl TryFrom<&ValkeyString> for Aggregator {
@@ -419,7 +655,14 @@ impl Aggregator {
             s if s.eq_ignore_ascii_case("std.p") => Some(Aggregator::StdP(AggStdP::default())),
             s if s.eq_ignore_ascii_case("var.s") => Some(Aggregator::VarS(AggVarS::default())),
             s if s.eq_ignore_ascii_case("var.p") => Some(Aggregator::VarP(AggVarP::default())),
-            _ => None,
+            s if s.eq_ignore_ascii_case("increase") => Some(Aggregator::Increase(AggIncrease::default())),
+            s if s.eq_ignore_ascii_case("rate") => Some(Aggregator::Rate(AggRate::default())),
+            s if s.eq_ignore_ascii_case("countNaN") => Some(Aggregator::CountNaN(AggCountNaN::default())),
+            s if s.eq_ignore_ascii_case("twa") => Some(Aggregator::Twa(AggTwa::default())),
+            s => QUANTILE_AGGREGATORS
+                .iter()
+                .find(|(name, _)| s.eq_ignore_ascii_case(name))
+                .map(|(_, quantile)| Aggregator::Quantile(AggQuantile::new(*quantile))),
         }
     }
 
@@ -436,7 +679,22 @@ impl Aggregator {
             Aggregator::StdP(_) => "std.p",
             Aggregator::VarS(_) => "var.s",
             Aggregator::VarP(_) => "var.p",
-            Aggregator::Range(_) =>"range"
+            Aggregator::Range(_) =>"range",
+            Aggregator::Increase(_) => "increase",
+            Aggregator::Rate(_) => "rate",
+            Aggregator::CountNaN(_) => "countNaN",
+            Aggregator::Twa(_) => "twa",
+            Aggregator::Quantile(agg) => QUANTILE_AGGREGATORS
+                .iter()
+                .find(|(_, quantile)| *quantile == agg.quantile)
+                .map_or("quantile", |(name, _)| name),
+        }
+    }
+
+    /// Sets the bucket duration, for aggregators which depend on it.
+    pub fn set_bucket_duration(&mut self, bucket_duration: Duration) {
+        if let Aggregator::Rate(agg) = self {
+            agg.window_secs = bucket_duration.as_secs_f64();
         }
     }
 
@@ -463,7 +721,12 @@ impl AggOp for Aggregator {
             Aggregator::StdP(agg) => agg.save(),
             Aggregator::VarS(agg) => agg.save(),
             Aggregator::VarP(agg) => agg.save(),
-            Aggregator::Range(agg) => agg.save()
+            Aggregator::Range(agg) => agg.save(),
+            Aggregator::Increase(agg) => agg.save(),
+            Aggregator::Rate(agg) => agg.save(),
+            Aggregator::CountNaN(agg) => agg.save(),
+            Aggregator::Quantile(agg) => agg.save(),
+            Aggregator::Twa(agg) => agg.save(),
         }
     }
 
@@ -480,7 +743,12 @@ impl AggOp for Aggregator {
             Aggregator::StdP(agg) => agg.load(buf),
             Aggregator::VarS(agg) => agg.load(buf),
             Aggregator::VarP(agg) => agg.load(buf),
-            Aggregator::Range(agg) => agg.load(buf)
+            Aggregator::Range(agg) => agg.load(buf),
+            Aggregator::Increase(agg) => agg.load(buf),
+            Aggregator::Rate(agg) => agg.load(buf),
+            Aggregator::CountNaN(agg) => agg.load(buf),
+            Aggregator::Quantile(agg) => agg.load(buf),
+            Aggregator::Twa(agg) => agg.load(buf),
         }
     }
 
@@ -497,7 +765,34 @@ impl AggOp for Aggregator {
             Aggregator::StdP(agg) => agg.update(value),
             Aggregator::VarS(agg) => agg.update(value),
             Aggregator::VarP(agg) => agg.update(value),
-            Aggregator::Range(agg) => agg.update(value)
+            Aggregator::Range(agg) => agg.update(value),
+            Aggregator::Increase(agg) => agg.update(value),
+            Aggregator::Rate(agg) => agg.update(value),
+            Aggregator::CountNaN(agg) => agg.update(value),
+            Aggregator::Quantile(agg) => agg.update(value),
+            Aggregator::Twa(agg) => agg.update(value),
+        }
+    }
+
+    fn update_sample(&mut self, timestamp: Timestamp, value: Value) {
+        match self {
+            Aggregator::First(agg) => agg.update_sample(timestamp, value),
+            Aggregator::Last(agg) => agg.update_sample(timestamp, value),
+            Aggregator::Min(agg) => agg.update_sample(timestamp, value),
+            Aggregator::Max(agg) => agg.update_sample(timestamp, value),
+            Aggregator::Avg(agg) => agg.update_sample(timestamp, value),
+            Aggregator::Sum(agg) => agg.update_sample(timestamp, value),
+            Aggregator::Count(agg) => agg.update_sample(timestamp, value),
+            Aggregator::StdS(agg) => agg.update_sample(timestamp, value),
+            Aggregator::StdP(agg) => agg.update_sample(timestamp, value),
+            Aggregator::VarS(agg) => agg.update_sample(timestamp, value),
+            Aggregator::VarP(agg) => agg.update_sample(timestamp, value),
+            Aggregator::Range(agg) => agg.update_sample(timestamp, value),
+            Aggregator::Increase(agg) => agg.update_sample(timestamp, value),
+            Aggregator::Rate(agg) => agg.update_sample(timestamp, value),
+            Aggregator::CountNaN(agg) => agg.update_sample(timestamp, value),
+            Aggregator::Quantile(agg) => agg.update_sample(timestamp, value),
+            Aggregator::Twa(agg) => agg.update_sample(timestamp, value),
         }
     }
 
@@ -514,7 +809,12 @@ impl AggOp for Aggregator {
             Aggregator::StdP(agg) => agg.reset(),
             Aggregator::VarS(agg) => agg.reset(),
             Aggregator::VarP(agg) => agg.reset(),
-            Aggregator::Range(agg) => agg.reset()
+            Aggregator::Range(agg) => agg.reset(),
+            Aggregator::Increase(agg) => agg.reset(),
+            Aggregator::Rate(agg) => agg.reset(),
+            Aggregator::CountNaN(agg) => agg.reset(),
+            Aggregator::Quantile(agg) => agg.reset(),
+            Aggregator::Twa(agg) => agg.reset(),
         }
     }
 
@@ -531,7 +831,12 @@ impl AggOp for Aggregator {
             Aggregator::StdP(agg) => agg.current(),
             Aggregator::VarS(agg) => agg.current(),
             Aggregator::VarP(agg) => agg.current(),
-            Aggregator::Range(agg) => agg.current()
+            Aggregator::Range(agg) => agg.current(),
+            Aggregator::Increase(agg) => agg.current(),
+            Aggregator::Rate(agg) => agg.current(),
+            Aggregator::CountNaN(agg) => agg.current(),
+            Aggregator::Quantile(agg) => agg.current(),
+            Aggregator::Twa(agg) => agg.current(),
         }
     }
 
@@ -549,6 +854,123 @@ impl AggOp for Aggregator {
             Aggregator::StdP(agg) => agg.empty_value(),
             Aggregator::VarS(agg) => agg.empty_value(),
             Aggregator::VarP(agg) => agg.empty_value(),
+            Aggregator::Increase(agg) => agg.empty_value(),
+            Aggregator::Rate(agg) => agg.empty_value(),
+            Aggregator::CountNaN(agg) => agg.empty_value(),
+            Aggregator::Quantile(agg) => agg.empty_value(),
+            Aggregator::Twa(agg) => agg.empty_value(),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aggregate(agg: &mut impl AggOp, buckets: &[&[Value]]) -> Vec<Option<Value>> {
+        buckets
+            .iter()
+            .map(|values| {
+                for value in values.iter() {
+                    agg.update(*value);
+                }
+                let res = agg.current();
+                agg.reset();
+                res
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_increase() {
+        let mut agg = AggIncrease::default();
+        let res = aggregate(&mut agg, &[&[1., 3., 5.], &[7., 9.]]);
+        // the second bucket counts from the last value of the first
+        assert_eq!(res, vec![Some(4.), Some(4.)]);
+    }
+
+    #[test]
+    fn test_increase_with_resets() {
+        let mut agg = AggIncrease::default();
+        let res = aggregate(&mut agg, &[&[1., 3., 5.], &[7., 2., 4.], &[], &[1.]]);
+        // 5 -> 7, reset, 0 -> 4
+        assert_eq!(res[1], Some(6.));
+        assert_eq!(res[2], None);
+        // reset across buckets: 4 -> 0 -> 1
+        assert_eq!(res[3], Some(1.));
+    }
+
+    #[test]
+    fn test_rate() {
+        let mut agg = AggRate::new(Duration::from_secs(10));
+        let res = aggregate(&mut agg, &[&[0., 10., 20.], &[5., 15.]]);
+        assert_eq!(res, vec![Some(2.), Some(1.5)]);
+    }
+
+    #[test]
+    fn test_count_nan() {
+        let mut agg = AggCountNaN::default();
+        let res = aggregate(&mut agg, &[&[1., f64::NAN, 2., f64::NAN], &[]]);
+        assert_eq!(res, vec![Some(2.), Some(0.)]);
+    }
+
+    #[test]
+    fn test_quantile() {
+        let mut agg = Aggregator::new("p90").unwrap();
+        assert_eq!(agg.name(), "p90");
+        for i in 1..=100 {
+            agg.update(i as Value);
+        }
+        let value = agg.current().unwrap();
+        assert!((value - 90.).abs() <= 0.9, "{value}");
+
+        agg.reset();
+        assert_eq!(agg.current(), None);
+        assert!(agg.finalize().is_nan());
+    }
+
+    #[test]
+    fn test_twa() {
+        let mut agg = AggTwa::default();
+        // 10 for 1s, then rising linearly from 10 to 30 over 1s
+        agg.update_sample(0, 10.);
+        agg.update_sample(1000, 10.);
+        agg.update_sample(2000, 30.);
+        assert_eq!(agg.current(), Some(15.));
+
+        agg.reset();
+        agg.update_sample(5000, 7.);
+        assert_eq!(agg.current(), Some(7.));
+    }
+
+    #[test]
+    fn test_aggregator_dispatches_timestamps() {
+        let mut agg = Aggregator::new("twa").unwrap();
+        agg.update_sample(0, 0.);
+        agg.update_sample(3000, 0.);
+        agg.update_sample(4000, 4.);
+        // area 2000 over 4000ms
+        assert_eq!(agg.current(), Some(0.5));
+    }
+
+    #[test]
+    fn test_save_load() {
+        let mut agg = AggRate::new(Duration::from_secs(10));
+        agg.update(5.);
+        agg.update(1.);
+        let (name, buf) = agg.save();
+        assert_eq!(name, "rate");
+
+        let mut loaded = AggRate::default();
+        loaded.load(&buf);
+        assert_eq!(loaded.current(), Some(0.1));
+
+        let mut agg = Aggregator::new("p50").unwrap();
+        agg.update(10.);
+        let (_, buf) = agg.save();
+        let mut loaded = Aggregator::new("p99").unwrap();
+        loaded.load(&buf);
+        assert_eq!(loaded.name(), "p50");
+        assert_eq!(loaded.current(), agg.current());
+    }
+}
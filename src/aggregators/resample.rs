@@ -0,0 +1,100 @@
+use crate::common::types::{Sample, Timestamp};
+
+/// Resamples `samples`, ordered by timestamp, to at most one point per `step` from `start` to
+/// `end`, as PromQL evaluates an instant vector selector at each step of a range query: the point
+/// at `t` takes the value of the last sample in `(t - lookback, t]`. Steps without such a sample
+/// have no point.
+pub(crate) fn resample(
+    samples: impl Iterator<Item=Sample>,
+    start: Timestamp,
+    end: Timestamp,
+    step: i64,
+    lookback: i64,
+    count: Option<usize>
+) -> Vec<Sample> {
+    debug_assert!(step > 0);
+    let max_count = count.unwrap_or(usize::MAX);
+    let mut samples = samples.peekable();
+    let mut result: Vec<Sample> = Vec::new();
+    let mut last: Option<(Timestamp, f64)> = None;
+
+    let mut t = start;
+    while t <= end && result.len() < max_count {
+        while let Some(sample) = samples.next_if(|sample| sample.timestamp <= t) {
+            last = Some((sample.timestamp, sample.value));
+        }
+        let next = match last {
+            Some((timestamp, value)) if timestamp > t.saturating_sub(lookback) => {
+                result.push(Sample { timestamp: t, value });
+                t.checked_add(step)
+            }
+            _ => {
+                // no point until the next sample, so skip to the first step at or after it
+                let Some(sample) = samples.peek() else {
+                    break;
+                };
+                let gap = sample.timestamp.saturating_sub(t);
+                let steps = gap / step + i64::from(gap % step != 0);
+                steps.checked_mul(step).and_then(|skipped| t.checked_add(skipped))
+            }
+        };
+        let Some(next) = next else {
+            break;
+        };
+        t = next;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resample_points(
+        data: &[(Timestamp, f64)],
+        start: Timestamp,
+        end: Timestamp,
+        step: i64,
+        lookback: i64,
+        count: Option<usize>
+    ) -> Vec<(Timestamp, f64)> {
+        let samples = data.iter().map(|(timestamp, value)| Sample::new(*timestamp, *value));
+        resample(samples, start, end, step, lookback, count)
+            .into_iter()
+            .map(|sample| (sample.timestamp, sample.value))
+            .collect()
+    }
+
+    #[test]
+    fn test_resample_takes_last_sample() {
+        let data = [(900, 1.), (1000, 2.), (1400, 3.), (1600, 4.), (2500, 5.)];
+        let res = resample_points(&data, 1000, 3000, 500, 1000, None);
+        assert_eq!(res, vec![(1000, 2.), (1500, 3.), (2000, 4.), (2500, 5.), (3000, 5.)]);
+    }
+
+    #[test]
+    fn test_resample_lookback() {
+        let data = [(1000, 1.), (5000, 2.)];
+        let res = resample_points(&data, 1000, 5000, 1000, 1500, None);
+        // the sample at 1000 is too old for steps after 2000
+        assert_eq!(res, vec![(1000, 1.), (2000, 1.), (5000, 2.)]);
+    }
+
+    #[test]
+    fn test_resample_count() {
+        let data = [(1000, 1.), (2000, 2.), (3000, 3.)];
+        let res = resample_points(&data, 1000, 3000, 1000, 1000, Some(2));
+        assert_eq!(res, vec![(1000, 1.), (2000, 2.)]);
+    }
+
+    #[test]
+    fn test_resample_skips_gaps() {
+        let data = [(1000, 1.), (1_000_000_001, 2.)];
+        let res = resample_points(&data, 0, i64::MAX - 1, 1000, 1000, None);
+        assert_eq!(res, vec![(1000, 1.), (1_000_001_000, 2.)]);
+
+        // without samples after the start, no step is evaluated
+        let res = resample_points(&[], i64::MIN, i64::MAX, 1, 1000, None);
+        assert!(res.is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+//! A DDSketch (https://arxiv.org/abs/1908.10693) for estimating quantiles in a single pass, with a
+//! bounded relative error on the returned values.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const DEFAULT_RELATIVE_ACCURACY: f64 = 0.01;
+
+/// Values closer to zero than this are counted as zero
+const MIN_INDEXABLE_VALUE: f64 = 1e-9;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DDSketch {
+    gamma: f64,
+    /// Bucket counts of positive values, by bucket key
+    positive: BTreeMap<i32, u64>,
+    /// Bucket counts of negative values, by the bucket key of their absolute value
+    negative: BTreeMap<i32, u64>,
+    zero_count: u64,
+    count: u64,
+}
+
+impl Default for DDSketch {
+    fn default() -> Self {
+        Self::new(DEFAULT_RELATIVE_ACCURACY)
+    }
+}
+
+impl DDSketch {
+    pub fn new(relative_accuracy: f64) -> Self {
+        Self {
+            gamma: (1.0 + relative_accuracy) / (1.0 - relative_accuracy),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zero_count: 0,
+            count: 0,
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Adds a value. NaN values are ignored.
+    pub fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        if value > MIN_INDEXABLE_VALUE {
+            *self.positive.entry(self.key(value)).or_default() += 1;
+        } else if value < -MIN_INDEXABLE_VALUE {
+            *self.negative.entry(self.key(-value)).or_default() += 1;
+        } else {
+            self.zero_count += 1;
+        }
+        self.count += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.positive.clear();
+        self.negative.clear();
+        self.zero_count = 0;
+        self.count = 0;
+    }
+
+    /// Returns the estimate of the `quantile` (between 0 and 1) of the values, or None if the sketch
+    /// is empty.
+    pub fn quantile(&self, quantile: f64) -> Option<f64> {
+        if self.count == 0 || !(0.0..=1.0).contains(&quantile) {
+            return None;
+        }
+
+        let rank = (quantile * (self.count - 1) as f64) as u64;
+        let mut seen: u64 = 0;
+
+        // the most negative values have the highest keys
+        for (key, count) in self.negative.iter().rev() {
+            seen += count;
+            if seen > rank {
+                return Some(-self.value(*key));
+            }
+        }
+        seen += self.zero_count;
+        if seen > rank {
+            return Some(0.0);
+        }
+        for (key, count) in self.positive.iter() {
+            seen += count;
+            if seen > rank {
+                return Some(self.value(*key));
+            }
+        }
+        None
+    }
+
+    fn key(&self, value: f64) -> i32 {
+        (value.ln() / self.gamma.ln()).ceil() as i32
+    }
+
+    /// Returns the value representing a bucket, which is within the relative accuracy of any value
+    /// of the bucket.
+    fn value(&self, key: i32) -> f64 {
+        2.0 * self.gamma.powi(key) / (self.gamma + 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_relative_error(actual: f64, expected: f64) {
+        let error = ((actual - expected) / expected).abs();
+        assert!(error <= DEFAULT_RELATIVE_ACCURACY, "{actual} is not within 1% of {expected}");
+    }
+
+    #[test]
+    fn test_empty() {
+        let sketch = DDSketch::default();
+        assert_eq!(sketch.quantile(0.5), None);
+    }
+
+    #[test]
+    fn test_quantiles() {
+        let mut sketch = DDSketch::default();
+        for i in 1..=1000 {
+            sketch.add(i as f64);
+        }
+        assert_eq!(sketch.count(), 1000);
+        assert_relative_error(sketch.quantile(0.0).unwrap(), 1.0);
+        assert_relative_error(sketch.quantile(0.5).unwrap(), 500.0);
+        assert_relative_error(sketch.quantile(0.99).unwrap(), 990.0);
+        assert_relative_error(sketch.quantile(1.0).unwrap(), 1000.0);
+    }
+
+    #[test]
+    fn test_negative_and_zero_values() {
+        let mut sketch = DDSketch::default();
+        for value in [-100.0, -10.0, 0.0, 10.0, 100.0, f64::NAN] {
+            sketch.add(value);
+        }
+        assert_eq!(sketch.count(), 5);
+        assert_relative_error(sketch.quantile(0.0).unwrap(), -100.0);
+        assert_eq!(sketch.quantile(0.5), Some(0.0));
+        assert_relative_error(sketch.quantile(1.0).unwrap(), 100.0);
+    }
+}
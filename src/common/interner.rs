@@ -0,0 +1,267 @@
+use crate::common::types::Label;
+use ahash::AHashSet;
+use get_size::GetSize;
+use std::cmp::Ordering;
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::mem::ManuallyDrop;
+use std::sync::{Arc, LazyLock, Mutex};
+
+static LABEL_INTERNER: LazyLock<LabelInterner> = LazyLock::new(LabelInterner::new);
+
+pub fn get_label_interner() -> &'static LabelInterner {
+    &LABEL_INTERNER
+}
+
+/// A label name/value pair, interned in a global table. The pair is stored in the format used for
+/// keys in the label index ("name=value\0"), so the same allocation is shared by every series
+/// with the label as well as by the index.
+#[derive(Clone)]
+pub struct InternedLabel {
+    /// Dropped by the interner, under its lock (see `Drop`)
+    buf: ManuallyDrop<Arc<[u8]>>,
+    name_len: u32,
+}
+
+impl InternedLabel {
+    pub fn new(name: &str, value: &str) -> Self {
+        let mut buf = Vec::with_capacity(name.len() + value.len() + 2);
+        buf.extend_from_slice(name.as_bytes());
+        buf.push(b'=');
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(0);
+        Self {
+            buf: ManuallyDrop::new(get_label_interner().intern(&buf)),
+            name_len: name.len() as u32,
+        }
+    }
+
+    /// Interns a label from its index key format ("name=value\0", see `as_bytes`). Returns None
+    /// if `bytes` is not in that format.
+    pub fn from_key_bytes(bytes: &[u8]) -> Option<Self> {
+        let (&0, pair) = bytes.split_last()?;
+        let pair = std::str::from_utf8(pair).ok()?;
+        let name_len = pair.find('=')?;
+        Some(Self {
+            buf: ManuallyDrop::new(get_label_interner().intern(bytes)),
+            name_len: name_len as u32,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        let buf = &self.buf[..self.name_len as usize];
+        // Safety: constructed from a &str
+        unsafe { std::str::from_utf8_unchecked(buf) }
+    }
+
+    pub fn value(&self) -> &str {
+        let buf = &self.buf[self.name_len as usize + 1..self.buf.len() - 1];
+        // Safety: constructed from a &str
+        unsafe { std::str::from_utf8_unchecked(buf) }
+    }
+
+    /// The label in index key format, i.e. "name=value\0"
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn to_label(&self) -> Label {
+        Label {
+            name: self.name().to_string(),
+            value: self.value().to_string(),
+        }
+    }
+}
+
+impl Drop for InternedLabel {
+    fn drop(&mut self) {
+        // Safety: `buf` is not used after this
+        let buf = unsafe { ManuallyDrop::take(&mut self.buf) };
+        get_label_interner().release(buf);
+    }
+}
+
+impl PartialEq for InternedLabel {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.buf, &other.buf) || self.buf == other.buf
+    }
+}
+
+impl Eq for InternedLabel {}
+
+impl Hash for InternedLabel {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.buf.hash(state)
+    }
+}
+
+impl PartialOrd for InternedLabel {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InternedLabel {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.name()
+            .cmp(other.name())
+            .then_with(|| self.value().cmp(other.value()))
+    }
+}
+
+impl Debug for InternedLabel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InternedLabel")
+            .field("name", &self.name())
+            .field("value", &self.value())
+            .finish()
+    }
+}
+
+impl Display for InternedLabel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name(), self.value())
+    }
+}
+
+impl GetSize for InternedLabel {
+    fn get_heap_size(&self) -> usize {
+        // the allocation is shared, so report this label's share of it
+        let refs = Arc::strong_count(&self.buf).saturating_sub(1).max(1);
+        self.buf.len() / refs
+    }
+}
+
+impl From<&Label> for InternedLabel {
+    fn from(label: &Label) -> Self {
+        Self::new(&label.name, &label.value)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InternerStats {
+    /// Number of distinct label pairs
+    pub unique_labels: usize,
+    /// Number of references to interned labels, from series and the index
+    pub references: usize,
+    /// Bytes used by the interned strings
+    pub bytes: usize,
+    /// Bytes which would have been used had each reference held its own copy
+    pub saved_bytes: usize,
+}
+
+/// Reference counted table of label pairs. Entries are removed once the last reference outside
+/// the table is dropped.
+pub struct LabelInterner {
+    table: Mutex<AHashSet<Arc<[u8]>>>,
+}
+
+impl LabelInterner {
+    fn new() -> Self {
+        Self {
+            table: Mutex::new(AHashSet::new()),
+        }
+    }
+
+    fn intern(&self, bytes: &[u8]) -> Arc<[u8]> {
+        let mut table = self.table.lock().unwrap();
+        if let Some(existing) = table.get(bytes) {
+            return existing.clone();
+        }
+        let value: Arc<[u8]> = Arc::from(bytes);
+        table.insert(value.clone());
+        value
+    }
+
+    /// Drops the reference `bytes` of a label, removing it from the table if it was the last one
+    /// outside the table. Labels drop their references under the lock, so no other label may drop
+    /// its reference between the check and the drop, and no label may be interned meanwhile.
+    fn release(&self, bytes: Arc<[u8]>) {
+        let mut table = self.table.lock().unwrap();
+        // the table and ourselves
+        if Arc::strong_count(&bytes) == 2 {
+            table.remove(&bytes[..]);
+        }
+        drop(bytes);
+        drop(table);
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.lock().unwrap().len()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_interned(&self, bytes: &[u8]) -> bool {
+        self.table.lock().unwrap().contains(bytes)
+    }
+
+    pub fn stats(&self) -> InternerStats {
+        let table = self.table.lock().unwrap();
+        let mut stats = InternerStats {
+            unique_labels: table.len(),
+            ..Default::default()
+        };
+        for value in table.iter() {
+            let refs = Arc::strong_count(value) - 1;
+            stats.references += refs;
+            stats.bytes += value.len();
+            stats.saved_bytes += value.len() * refs.saturating_sub(1);
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name_and_value() {
+        let label = InternedLabel::new("region", "us=east");
+        assert_eq!(label.name(), "region");
+        assert_eq!(label.value(), "us=east");
+        assert_eq!(label.as_bytes(), b"region=us=east\0");
+    }
+
+    #[test]
+    fn test_labels_share_storage() {
+        let first = InternedLabel::new("interner_test", "shared");
+        let second = InternedLabel::new("interner_test", "shared");
+        assert!(Arc::ptr_eq(&first.buf, &second.buf));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_released_when_unused() {
+        let bytes = b"interner_test=released\0";
+        let label = InternedLabel::new("interner_test", "released");
+        let clone = label.clone();
+        drop(label);
+        assert!(get_label_interner().table.lock().unwrap().contains(&bytes[..]));
+        drop(clone);
+        assert!(!get_label_interner().table.lock().unwrap().contains(&bytes[..]));
+    }
+
+    #[test]
+    fn test_released_when_dropped_concurrently() {
+        let bytes = b"interner_test=concurrent\0";
+        for _ in 0..100 {
+            let labels: Vec<_> = (0..8)
+                .map(|_| InternedLabel::new("interner_test", "concurrent"))
+                .collect();
+            std::thread::scope(|scope| {
+                for label in labels {
+                    scope.spawn(move || drop(label));
+                }
+            });
+            assert!(!get_label_interner().table.lock().unwrap().contains(&bytes[..]));
+        }
+    }
+
+    #[test]
+    fn test_ordering() {
+        let a = InternedLabel::new("a", "z");
+        let a1 = InternedLabel::new("a1", "a");
+        assert!(a < a1);
+    }
+}
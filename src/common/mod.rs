@@ -1,7 +1,9 @@
 pub mod types;
+mod interner;
 mod utils;
 pub mod decimal;
 
+pub use interner::*;
 pub use utils::*;
 
 // todo: move elsewhere
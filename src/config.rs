@@ -6,10 +6,28 @@ use crate::storage::{DEFAULT_CHUNK_SIZE_BYTES, DuplicatePolicy};
 
 pub const DEFAULT_RULE_UPDATE_ENTRIES_LIMIT: usize = 10;
 pub const DEFAULT_MAX_SERIES_LIMIT: usize = 30_000;
+pub const DEFAULT_MAX_QUERY_SAMPLES: usize = 1_000_000_000;
+pub const DEFAULT_MAX_POINTS_PER_SERIES: usize = 30_000;
+pub const DEFAULT_QUERY_CACHE_SIZE: usize = 32 * 1024 * 1024;
 
 /// Default step used if not set.
 pub const DEFAULT_STEP: Duration = Duration::from_millis(5 * 60 * 1000);
 
+/// Default timeout for VKM.QUERY and VKM.QUERY-RANGE if TIMEOUT is not specified.
+pub const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default maximum number of queries executing concurrently.
+pub const DEFAULT_MAX_CONCURRENT_QUERIES: usize = 8;
+
+/// Default minimum number of series for which a query fetches series data in parallel.
+pub const DEFAULT_PARALLEL_FETCH_THRESHOLD: usize = 64;
+
+/// Default size of the time buckets used to index series by the time range of their samples.
+pub const DEFAULT_INDEX_TIME_BUCKET: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Default duration from now during which query results are considered volatile and not cached.
+pub const DEFAULT_QUERY_CACHE_HORIZON: Duration = Duration::from_millis(5 * 60 * 1000);
+
 // todo: Clap
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Settings {
@@ -21,6 +39,28 @@ pub struct Settings {
     ///max size of rollup cache
     pub max_rollup_cache_size: usize,
 
+    /// Max size (in bytes) of the range query result cache. 0 disables the cache.
+    pub max_query_cache_size: usize,
+
+    /// Points newer than now - query_cache_horizon are never cached, since samples may still
+    /// arrive for them.
+    pub query_cache_horizon: Duration,
+
+    /// The default maximum execution time of a query. It can be overridden per query with TIMEOUT.
+    pub query_timeout: Duration,
+
+    /// The maximum number of queries executing concurrently on the query worker pool. Queries
+    /// received when the limit is reached are rejected. Fixed once the module is loaded, as the
+    /// pool has a thread for each query.
+    pub max_concurrent_queries: usize,
+
+    /// Series data is decoded in parallel when a selector matches at least this many series.
+    pub parallel_fetch_threshold: usize,
+
+    /// Size of the time buckets used to index series by the time range of their samples. Queries
+    /// only consider series with samples in buckets overlapping the query range.
+    pub index_time_bucket: Duration,
+
     /// Limits the maximum duration for automatic alert expiration, which by default is 4 times
     /// evaluation_interval of the parent group.
     pub max_resolve_duration: Duration,
@@ -29,6 +69,29 @@ pub struct Settings {
     /// This option allows limiting memory usage
     pub max_series_limit: usize,  
 
+    /// The maximum number of series the selectors of a query can match in total. 0 means unlimited.
+    /// Can be overridden per query with MAX_SERIES.
+    pub max_query_series: usize,
+
+    /// The maximum number of samples a query can scan. 0 means unlimited.
+    /// Can be overridden per query with MAX_SAMPLES.
+    pub max_query_samples: usize,
+
+    /// The maximum number of points per series a range query can return. 0 means unlimited.
+    pub max_points_per_series: usize,
+
+    /// The maximum number of series in a db. 0 means unlimited.
+    /// Can be changed at runtime with VKM.SERIES-LIMITS.
+    pub max_series_per_db: usize,
+
+    /// The maximum number of series per metric name in a db. 0 means unlimited.
+    /// Can be changed (and overridden per metric name) at runtime with VKM.SERIES-LIMITS.
+    pub max_series_per_metric: usize,
+
+    /// The maximum number of series created per minute in a db. 0 means unlimited.
+    /// Can be changed at runtime with VKM.SERIES-LIMITS.
+    pub max_new_series_per_minute: usize,
+
     /// Minimum amount of time to wait before resending an alert to notifier
     pub resend_delay: Duration,
 
@@ -81,8 +144,20 @@ impl Default for Settings {
             duplicate_policy: DuplicatePolicy::Block,
             max_query_len: 0,
             max_rollup_cache_size: 0,
+            max_query_cache_size: DEFAULT_QUERY_CACHE_SIZE,
+            query_cache_horizon: DEFAULT_QUERY_CACHE_HORIZON,
+            query_timeout: DEFAULT_QUERY_TIMEOUT,
+            max_concurrent_queries: DEFAULT_MAX_CONCURRENT_QUERIES,
+            parallel_fetch_threshold: DEFAULT_PARALLEL_FETCH_THRESHOLD,
+            index_time_bucket: DEFAULT_INDEX_TIME_BUCKET,
             max_resolve_duration: Default::default(),
             max_series_limit: DEFAULT_MAX_SERIES_LIMIT,
+            max_query_series: DEFAULT_MAX_SERIES_LIMIT,
+            max_query_samples: DEFAULT_MAX_QUERY_SAMPLES,
+            max_points_per_series: DEFAULT_MAX_POINTS_PER_SERIES,
+            max_series_per_db: 0,
+            max_series_per_metric: 0,
+            max_new_series_per_minute: 0,
             resend_delay: Default::default(),
             external_labels: Default::default(),
             look_back: Duration::from_millis(ONE_HOUR_MILLIS),
@@ -45,9 +45,16 @@ pub enum TsdbError {
   #[error("Invalid series selector. {0}")]
   InvalidSeriesSelector(String),
 
+  #[error("Invalid metric. {0}")]
+  InvalidMetric(String),
+
   #[error("Sample timestamp exceeds retention period")]
   SampleTooOld,
 
+  /// A new series would exceed a series limit. The leading word is the error code of the reply
+  #[error("SERIESLIMIT {0}")]
+  SeriesLimitExceeded(String),
+
   #[error("{0}")]
   General(String)
 }
@@ -1,17 +1,25 @@
 use crate::index::{TimeSeriesIndex, TimeSeriesIndexMap};
+use crate::module::VKM_SERIES_TYPE;
 use crate::provider::TsdbDataProvider;
+use crate::query::QueryResultCache;
+use crate::storage::time_series::TimeSeries;
 use metricsql_runtime::prelude::Context as QueryContext;
 use papaya::Guard;
 use std::sync::{Arc, LazyLock};
-use valkey_module::{raw, Context, RedisModule_GetSelectedDb};
+use valkey_module::{raw, Context, RedisModule_GetSelectedDb, RedisModule_SelectDb};
 
 pub(crate) static TIMESERIES_INDEX: LazyLock<TimeSeriesIndexMap> = LazyLock::new(TimeSeriesIndexMap::new);
 static QUERY_CONTEXT: LazyLock<QueryContext> = LazyLock::new(create_query_context);
+static QUERY_RESULT_CACHE: LazyLock<QueryResultCache> = LazyLock::new(QueryResultCache::new);
 
 pub fn get_query_context() -> &'static QueryContext {
     &QUERY_CONTEXT
 }
 
+pub fn get_query_result_cache() -> &'static QueryResultCache {
+    &QUERY_RESULT_CACHE
+}
+
 fn create_query_context() -> QueryContext {
     // todo: read from config
     let provider = Arc::new(TsdbDataProvider{});
@@ -26,6 +34,26 @@ pub unsafe fn get_current_db(ctx: *mut raw::RedisModuleCtx) -> u32 {
     db as u32
 }
 
+// Safety: RedisModule_SelectDb is safe to call
+pub unsafe fn select_db(ctx: *mut raw::RedisModuleCtx, db: u32) {
+    RedisModule_SelectDb.unwrap()(ctx, db as std::os::raw::c_int);
+}
+
+/// Returns true if `db` is less than the configured number of databases.
+pub fn is_valid_db(ctx: &Context, db: u32) -> bool {
+    let current_db = unsafe { get_current_db(ctx.ctx) };
+    if db == current_db {
+        return true;
+    }
+    // selecting a db fails if it is out of range
+    let status = unsafe { RedisModule_SelectDb.unwrap()(ctx.ctx, db as std::os::raw::c_int) };
+    if status != raw::REDISMODULE_OK as std::os::raw::c_int {
+        return false;
+    }
+    unsafe { select_db(ctx.ctx, current_db) };
+    true
+}
+
 /// https://docs.rs/papaya/latest/papaya/#advanced-lifetimes
 fn get_timeseries_index<'guard>(ctx: &Context, guard: &'guard impl Guard) -> &'guard TimeSeriesIndex {
     let db = unsafe { get_current_db(ctx.ctx) };
@@ -55,6 +83,43 @@ pub fn clear_timeseries_index() {
     TIMESERIES_INDEX.clear(&guard);
 }
 
+pub fn clear_timeseries_index_for_db(db: u32) {
+    let guard = TIMESERIES_INDEX.guard();
+    if let Some(index) = TIMESERIES_INDEX.get(&db, &guard) {
+        index.clear();
+    }
+}
+
+/// Swaps the indexes of `first` and `second`, following a SWAPDB.
+pub fn swap_timeseries_indexes(first: u32, second: u32) {
+    if first == second {
+        return;
+    }
+    let guard = TIMESERIES_INDEX.guard();
+    let first_index = get_timeseries_index_for_db(first, &guard);
+    let second_index = get_timeseries_index_for_db(second, &guard);
+    first_index.swap(second_index);
+}
+
+/// Moves the series at `key` into the index of the current db, following a MOVE. Series ids are
+/// unique across dbs, so the source index is the one holding the id.
+pub fn move_series_to_current_db(ctx: &Context, key: &[u8]) {
+    let db = unsafe { get_current_db(ctx.ctx) };
+    let key_name = ctx.create_string(key);
+    let valkey_key = ctx.open_key(&key_name);
+    let Ok(Some(series)) = valkey_key.get_value::<TimeSeries>(&VKM_SERIES_TYPE) else {
+        return;
+    };
+    let guard = TIMESERIES_INDEX.guard();
+    for (index_db, index) in TIMESERIES_INDEX.iter(&guard) {
+        if *index_db != db && index.is_series_indexed(series.id) {
+            index.remove_series(series);
+        }
+    }
+    get_timeseries_index_for_db(db, &guard).index_time_series(series, key);
+    get_query_result_cache().invalidate_since(db, series.first_timestamp);
+}
+
 pub fn with_db_timeseries_index<F, R>(db: u32, f: F) -> R
 where
     F: FnOnce(&TimeSeriesIndex) -> R,
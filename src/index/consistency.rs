@@ -0,0 +1,228 @@
+use super::index_key::IndexKey;
+use super::timeseries_index::{IndexInner, KeyType, TimeSeriesIndex};
+use crate::module::VKM_SERIES_TYPE;
+use crate::storage::time_series::TimeSeries;
+use croaring::Bitmap64;
+use metricsql_common::hash::IntMap;
+use std::cell::RefCell;
+use valkey_module::{Context, KeysCursor, ValkeyKey, ValkeyString};
+
+/// A series found in the keyspace, with the label index keys it should be indexed under.
+pub(crate) struct KeyspaceSeries {
+    pub key: KeyType,
+    pub id: u64,
+    pub labels: Vec<IndexKey>,
+}
+
+impl KeyspaceSeries {
+    pub fn new(key: &[u8], series: &TimeSeries) -> Self {
+        let mut labels = Vec::with_capacity(series.labels.len() + 1);
+        if !series.metric_name.is_empty() {
+            labels.push(IndexKey::for_metric_name(&series.metric_name));
+        }
+        labels.extend(series.labels.iter().map(IndexKey::for_label));
+        Self {
+            key: key.to_vec().into_boxed_slice(),
+            id: series.id,
+            labels,
+        }
+    }
+}
+
+/// Discrepancies between the index and the keyspace of a db.
+#[derive(Debug, Default)]
+pub(crate) struct IndexCheckReport {
+    /// Number of series in the keyspace
+    pub series_count: usize,
+    /// Ids in the index with no corresponding series in the keyspace
+    pub orphaned_ids: Bitmap64,
+    /// Keys of series missing from the index, or indexed under a different key
+    pub unindexed_keys: Vec<KeyType>,
+    /// Keys of series whose labels in the index differ from the labels of the series
+    pub label_mismatches: Vec<KeyType>,
+}
+
+impl IndexCheckReport {
+    pub fn is_consistent(&self) -> bool {
+        self.orphaned_ids.is_empty() && self.unindexed_keys.is_empty() && self.label_mismatches.is_empty()
+    }
+}
+
+/// Returns all series in the keyspace of the current db.
+pub(crate) fn collect_keyspace_series(ctx: &Context) -> Vec<KeyspaceSeries> {
+    let result = RefCell::new(Vec::new());
+    let cursor = KeysCursor::new();
+    let callback = |_ctx: &Context, key_name: ValkeyString, key: Option<&ValkeyKey>| {
+        let Some(key) = key else {
+            return;
+        };
+        if let Ok(Some(series)) = key.get_value::<TimeSeries>(&VKM_SERIES_TYPE) {
+            result.borrow_mut().push(KeyspaceSeries::new(key_name.as_slice(), series));
+        }
+    };
+    while cursor.scan(ctx, &callback) {}
+    result.into_inner()
+}
+
+/// Compares the index of the current db with its keyspace.
+pub(crate) fn check_index(ctx: &Context, index: &TimeSeriesIndex) -> IndexCheckReport {
+    let keyspace = collect_keyspace_series(ctx);
+    let inner = index.get_inner();
+    check_index_inner(&inner, &keyspace)
+}
+
+/// Fixes the discrepancies in `report`. Orphaned ids are removed, and the series with unindexed
+/// keys or mismatched labels are re-indexed from the keyspace.
+pub(crate) fn repair_index(ctx: &Context, index: &TimeSeriesIndex, report: &IndexCheckReport) -> usize {
+    let keys: Vec<&KeyType> = report.unindexed_keys.iter()
+        .chain(report.label_mismatches.iter())
+        .collect();
+
+    let mut series: Vec<(&TimeSeries, &KeyType)> = Vec::with_capacity(keys.len());
+    let mut valkey_keys = Vec::with_capacity(keys.len());
+    for key in keys {
+        let key_name = ctx.create_string(&key[..]);
+        valkey_keys.push((ctx.open_key(&key_name), key));
+    }
+    for (valkey_key, key) in valkey_keys.iter() {
+        if let Ok(Some(ts)) = valkey_key.get_value::<TimeSeries>(&VKM_SERIES_TYPE) {
+            series.push((ts, key));
+        }
+    }
+
+    // drop everything the index holds for the affected ids, since we cannot tell which of the
+    // postings of a stale id are correct
+    let mut ids = report.orphaned_ids.clone();
+    for (ts, _) in series.iter() {
+        ids.add(ts.id);
+    }
+    index.remove_ids(&ids);
+
+    for (ts, key) in series.iter() {
+        index.index_time_series(ts, key);
+    }
+    report.orphaned_ids.cardinality() as usize + series.len()
+}
+
+pub(super) fn check_index_inner(inner: &IndexInner, keyspace: &[KeyspaceSeries]) -> IndexCheckReport {
+    let mut report = IndexCheckReport {
+        series_count: keyspace.len(),
+        ..Default::default()
+    };
+
+    let mut expected: IntMap<u64, &KeyspaceSeries> = IntMap::default();
+    for series in keyspace {
+        expected.insert(series.id, series);
+    }
+    let is_expected = |id: u64| expected.contains_key(&id);
+
+    // ids referenced anywhere in the index
+    let mut indexed_ids = Bitmap64::from_iter(inner.id_to_key.keys().copied());
+    for (_, bmp) in inner.label_index.iter() {
+        indexed_ids.or_inplace(bmp);
+    }
+    for (id, _, _) in inner.time_postings.iter() {
+        indexed_ids.add(id);
+    }
+    report.orphaned_ids = Bitmap64::from_iter(indexed_ids.iter().filter(|id| !is_expected(*id)));
+
+    let mut unindexed = Bitmap64::new();
+    let mut mismatched = Bitmap64::new();
+    for series in keyspace {
+        match inner.id_to_key.get(&series.id) {
+            Some(key) if key == &series.key => {}
+            _ => {
+                unindexed.add(series.id);
+                continue;
+            }
+        }
+        let has_labels = series.labels.iter().all(|label| {
+            inner.label_index.get(label).map_or(false, |bmp| bmp.contains(series.id))
+        });
+        if !has_labels {
+            mismatched.add(series.id);
+        }
+    }
+
+    // series indexed under labels they do not have
+    for (label, bmp) in inner.label_index.iter() {
+        for id in bmp.iter() {
+            if unindexed.contains(id) || mismatched.contains(id) {
+                continue;
+            }
+            if let Some(series) = expected.get(&id) {
+                if !series.labels.contains(label) {
+                    mismatched.add(id);
+                }
+            }
+        }
+    }
+
+    let key_of = |id: u64| expected.get(&id).map(|series| series.key.clone());
+    report.unindexed_keys = unindexed.iter().filter_map(key_of).collect();
+    report.label_mismatches = mismatched.iter().filter_map(key_of).collect();
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::InternedLabel;
+
+    fn create_series(id: u64, metric_name: &str, labels: &[(&str, &str)]) -> TimeSeries {
+        let mut ts = TimeSeries::new();
+        ts.id = id;
+        ts.metric_name = metric_name.to_string();
+        ts.labels = labels.iter().map(|(name, value)| InternedLabel::new(name, value)).collect();
+        ts
+    }
+
+    #[test]
+    fn test_consistent_index() {
+        let mut inner = IndexInner::new();
+        let series = create_series(1, "latency", &[("region", "us-east1")]);
+        inner.index_time_series(&series, b"latency:1");
+
+        let keyspace = vec![KeyspaceSeries::new(b"latency:1", &series)];
+        let report = check_index_inner(&inner, &keyspace);
+        assert!(report.is_consistent());
+        assert_eq!(report.series_count, 1);
+    }
+
+    #[test]
+    fn test_orphaned_and_unindexed() {
+        let mut inner = IndexInner::new();
+        let deleted = create_series(1, "latency", &[("region", "us-east1")]);
+        let renamed = create_series(2, "latency", &[("region", "us-east2")]);
+        let unindexed = create_series(3, "latency", &[("region", "us-east3")]);
+        inner.index_time_series(&deleted, b"latency:1");
+        inner.index_time_series(&renamed, b"latency:2");
+
+        let keyspace = vec![
+            KeyspaceSeries::new(b"latency:renamed", &renamed),
+            KeyspaceSeries::new(b"latency:3", &unindexed),
+        ];
+        let report = check_index_inner(&inner, &keyspace);
+        assert_eq!(report.orphaned_ids.iter().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(
+            report.unindexed_keys,
+            vec![b"latency:renamed".to_vec().into_boxed_slice(), b"latency:3".to_vec().into_boxed_slice()]
+        );
+        assert!(report.label_mismatches.is_empty());
+    }
+
+    #[test]
+    fn test_label_mismatches() {
+        let mut inner = IndexInner::new();
+        let indexed = create_series(1, "latency", &[("region", "us-east1"), ("env", "qa")]);
+        inner.index_time_series(&indexed, b"latency:1");
+
+        // labels were changed without re-indexing
+        let current = create_series(1, "latency", &[("region", "us-east1")]);
+        let keyspace = vec![KeyspaceSeries::new(b"latency:1", &current)];
+        let report = check_index_inner(&inner, &keyspace);
+        assert!(report.orphaned_ids.is_empty());
+        assert!(report.unindexed_keys.is_empty());
+        assert_eq!(report.label_mismatches, vec![b"latency:1".to_vec().into_boxed_slice()]);
+    }
+}
@@ -1,12 +1,24 @@
 use std::fmt::Write;
 use std::borrow::Borrow;
 use std::fmt::Display;
+use std::hash::{Hash, Hasher};
 use std::ops::Deref;
+use std::sync::Arc;
 use blart::{AsBytes, BytesMapping};
 use metricsql_runtime::prelude::METRIC_NAME_LABEL;
-
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct IndexKey(Box<[u8]>);
+use crate::common::InternedLabel;
+
+/// Key of the label index. Keys for series labels hold the interned labels of the series (see
+/// `InternedLabel`), so that a label is released from the interner once the last series and key
+/// holding it are dropped, including when the whole index is cleared or replaced.
+#[derive(Debug, Clone)]
+pub struct IndexKey(KeyBytes);
+
+#[derive(Debug, Clone)]
+enum KeyBytes {
+    Owned(Arc<[u8]>),
+    Interned(InternedLabel),
+}
 
 const SENTINEL: u8 = 0;
 
@@ -23,9 +35,20 @@ impl IndexKey {
         Self::from(format!("{label_name}={value}"))
     }
 
+    pub fn for_label(label: &InternedLabel) -> Self {
+        IndexKey(KeyBytes::Interned(label.clone()))
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match &self.0 {
+            KeyBytes::Owned(bytes) => bytes,
+            KeyBytes::Interned(label) => label.as_bytes(),
+        }
+    }
+
     pub fn as_str(&self) -> &str {
-        let buf = &self.0[..self.0.len()-1];
-        std::str::from_utf8(buf).unwrap()
+        let buf = self.bytes();
+        std::str::from_utf8(&buf[..buf.len() - 1]).unwrap()
     }
 
     pub fn split(&self) -> Option<(&str, &str)> {
@@ -38,16 +61,30 @@ impl IndexKey {
     }
 
     pub(super) fn sub_string(&self, start: usize) -> &str {
-        let buf = &self.0[start .. self.0.len() - 1];
-        std::str::from_utf8(buf).unwrap()
+        let buf = self.bytes();
+        std::str::from_utf8(&buf[start .. buf.len() - 1]).unwrap()
     }
 
     pub fn into_vec(self) -> Vec<u8> {
-        self.0.into_vec()
+        self.bytes().to_vec()
     }
 
     pub fn len(&self) -> usize {
-        self.0.len() - 1
+        self.bytes().len() - 1
+    }
+}
+
+impl PartialEq for IndexKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes() == other.bytes()
+    }
+}
+
+impl Eq for IndexKey {}
+
+impl Hash for IndexKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.bytes().hash(state)
     }
 }
 
@@ -61,13 +98,13 @@ impl Deref for IndexKey {
     type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        self.bytes()
     }
 }
 
 impl AsBytes for IndexKey {
     fn as_bytes(&self) -> &[u8] {
-        &self.0
+        self.bytes()
     }
 }
 
@@ -77,12 +114,18 @@ impl AsRef<[u8]> for IndexKey {
     }
 }
 
+impl From<InternedLabel> for IndexKey {
+    fn from(label: InternedLabel) -> Self {
+        IndexKey(KeyBytes::Interned(label))
+    }
+}
+
 impl From<&[u8]> for IndexKey {
     fn from(key: &[u8]) -> Self {
         let utf8 = String::from_utf8_lossy(key);
         let mut v = utf8.as_bytes().to_vec();
         v.push(SENTINEL);
-        IndexKey(v.into_boxed_slice())
+        IndexKey(KeyBytes::Owned(v.into()))
     }
 }
 
@@ -96,7 +139,7 @@ impl From<&str> for IndexKey {
     fn from(key: &str) -> Self {
         let mut key = key.as_bytes().to_vec();
         key.push(SENTINEL);
-        IndexKey(key.into_boxed_slice())
+        IndexKey(KeyBytes::Owned(key.into()))
     }
 }
 
@@ -195,6 +238,14 @@ mod tests {
         assert_eq!(key.into_vec(), b"test_key\0".to_vec());
     }
 
+    #[test]
+    fn test_for_label() {
+        let label = InternedLabel::new("label", "value");
+        let key = IndexKey::for_label(&label);
+        assert_eq!(key.as_str(), "label=value");
+        assert_eq!(key, IndexKey::for_label_value("label", "value"));
+    }
+
     #[test]
     fn test_len() {
         let key = IndexKey::new("test_key");
@@ -0,0 +1,244 @@
+//! Cardinality statistics of the label index, as reported by the Prometheus TSDB status API
+//! (`/api/v1/status/tsdb`).
+use super::timeseries_index::IndexInner;
+use crate::common::types::Timestamp;
+use crate::storage::time_series::TimeSeries;
+use croaring::Bitmap64;
+use metricsql_runtime::types::METRIC_NAME_LABEL;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+/// A named count in a top-N list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stat {
+    pub name: String,
+    pub count: u64,
+}
+
+impl Stat {
+    pub fn new(name: impl Into<String>, count: u64) -> Self {
+        Self { name: name.into(), count }
+    }
+}
+
+/// Orders stats by ascending count, with ties ranked by name so that results are deterministic.
+/// The "greatest" stat is the one ranked first in a top-N list.
+impl Ord for Stat {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.count.cmp(&other.count)
+            .then_with(|| other.name.cmp(&self.name))
+    }
+}
+
+impl PartialOrd for Stat {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Keeps the `limit` stats with the highest counts.
+struct TopN {
+    limit: usize,
+    heap: BinaryHeap<Reverse<Stat>>,
+}
+
+impl TopN {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            heap: BinaryHeap::with_capacity(limit.min(1024) + 1),
+        }
+    }
+
+    fn push(&mut self, name: &str, count: u64) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.heap.len() == self.limit {
+            // avoid allocating the name if it would be evicted right away
+            let Some(Reverse(min)) = self.heap.peek() else {
+                return;
+            };
+            if count < min.count || (count == min.count && name >= min.name.as_str()) {
+                return;
+            }
+            self.heap.pop();
+        }
+        self.heap.push(Reverse(Stat::new(name, count)));
+    }
+
+    /// Returns the stats ordered by descending count, then by name.
+    fn into_sorted_vec(self) -> Vec<Stat> {
+        // sorting Reverse<Stat> ascending orders stats descending
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(stat)| stat)
+            .collect()
+    }
+}
+
+/// Statistics of the postings of the label index.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PostingsStats {
+    pub num_series: u64,
+    pub num_label_pairs: u64,
+    pub series_count_by_metric_name: Vec<Stat>,
+    pub label_value_count_by_label_name: Vec<Stat>,
+    /// Sum over the values of each label of the value length times its number of series
+    pub memory_in_bytes_by_label_name: Vec<Stat>,
+    pub series_count_by_label_value_pair: Vec<Stat>,
+}
+
+/// Computes the postings stats of the index, keeping the top `limit` items of each list. If
+/// `series` is given, only those series are accounted for.
+pub(crate) fn compute_postings_stats(inner: &IndexInner, limit: usize, series: Option<&Bitmap64>) -> PostingsStats {
+    let mut by_metric_name = TopN::new(limit);
+    let mut value_count_by_label_name = TopN::new(limit);
+    let mut memory_by_label_name = TopN::new(limit);
+    let mut by_label_value_pair = TopN::new(limit);
+    let mut num_label_pairs = 0;
+
+    // keys are ordered, so all values of a label are adjacent
+    let mut current_label: Option<&str> = None;
+    let mut value_count: u64 = 0;
+    let mut memory: u64 = 0;
+
+    for (key, postings) in inner.label_index.iter() {
+        let Some((label, value)) = key.split() else {
+            continue;
+        };
+        let count = match series {
+            Some(series) => postings.and_cardinality(series),
+            None => postings.cardinality(),
+        };
+        if count == 0 {
+            continue;
+        }
+
+        if current_label != Some(label) {
+            if let Some(name) = current_label {
+                value_count_by_label_name.push(name, value_count);
+                memory_by_label_name.push(name, memory);
+            }
+            current_label = Some(label);
+            value_count = 0;
+            memory = 0;
+        }
+        value_count += 1;
+        memory += value.len() as u64 * count;
+        num_label_pairs += 1;
+
+        if label == METRIC_NAME_LABEL {
+            by_metric_name.push(value, count);
+        }
+        by_label_value_pair.push(key.as_str(), count);
+    }
+
+    if let Some(name) = current_label {
+        value_count_by_label_name.push(name, value_count);
+        memory_by_label_name.push(name, memory);
+    }
+
+    let num_series = match series {
+        Some(series) => series.cardinality(),
+        None => inner.id_to_key.len() as u64,
+    };
+
+    PostingsStats {
+        num_series,
+        num_label_pairs,
+        series_count_by_metric_name: by_metric_name.into_sorted_vec(),
+        label_value_count_by_label_name: value_count_by_label_name.into_sorted_vec(),
+        memory_in_bytes_by_label_name: memory_by_label_name.into_sorted_vec(),
+        series_count_by_label_value_pair: by_label_value_pair.into_sorted_vec(),
+    }
+}
+
+/// Chunk and time range stats of a set of series.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HeadStats {
+    pub chunk_count: u64,
+    /// Range of the sample timestamps, or None if there are no samples
+    pub time_range: Option<(Timestamp, Timestamp)>,
+}
+
+impl HeadStats {
+    pub fn add_series(&mut self, series: &TimeSeries) {
+        self.chunk_count += series.chunks.len() as u64;
+        if let Some((first, last)) = series.time_range() {
+            self.time_range = Some(match self.time_range {
+                Some((min, max)) => (min.min(first), max.max(last)),
+                None => (first, last),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::test_utils::create_index;
+
+    #[test]
+    fn test_top_n_orders_by_count() {
+        let mut top = TopN::new(3);
+        for (name, count) in [("a", 1), ("b", 5), ("c", 3), ("d", 5), ("e", 2)] {
+            top.push(name, count);
+        }
+        assert_eq!(
+            top.into_sorted_vec(),
+            vec![Stat::new("b", 5), Stat::new("d", 5), Stat::new("c", 3)]
+        );
+    }
+
+    #[test]
+    fn test_postings_stats() {
+        let inner = create_index();
+        let stats = compute_postings_stats(&inner, 10, None);
+        assert_eq!(stats.num_series, 4);
+        // __name__ x 2, region x 3, env x 2
+        assert_eq!(stats.num_label_pairs, 7);
+        assert_eq!(
+            stats.series_count_by_metric_name,
+            vec![Stat::new("latency", 3), Stat::new("errors", 1)]
+        );
+        assert_eq!(
+            stats.label_value_count_by_label_name,
+            vec![Stat::new("region", 3), Stat::new("__name__", 2), Stat::new("env", 2)]
+        );
+        assert_eq!(stats.series_count_by_label_value_pair[0], Stat::new("__name__=latency", 3));
+        assert_eq!(stats.series_count_by_label_value_pair[1], Stat::new("env=prod", 2));
+
+        let region = stats.memory_in_bytes_by_label_name
+            .iter()
+            .find(|stat| stat.name == "region")
+            .unwrap();
+        assert_eq!(region.count, 4 * "us-east1".len() as u64);
+    }
+
+    #[test]
+    fn test_postings_stats_limit() {
+        let inner = create_index();
+        let stats = compute_postings_stats(&inner, 1, None);
+        assert_eq!(stats.series_count_by_metric_name, vec![Stat::new("latency", 3)]);
+        assert_eq!(stats.label_value_count_by_label_name, vec![Stat::new("region", 3)]);
+        assert_eq!(stats.series_count_by_label_value_pair.len(), 1);
+    }
+
+    #[test]
+    fn test_postings_stats_for_series() {
+        let inner = create_index();
+        let series = Bitmap64::from_iter([1u64, 4]);
+        let stats = compute_postings_stats(&inner, 10, Some(&series));
+        assert_eq!(stats.num_series, 2);
+        assert_eq!(
+            stats.series_count_by_metric_name,
+            vec![Stat::new("errors", 1), Stat::new("latency", 1)]
+        );
+        assert_eq!(
+            stats.label_value_count_by_label_name,
+            vec![Stat::new("__name__", 2), Stat::new("env", 1), Stat::new("region", 1)]
+        );
+    }
+}
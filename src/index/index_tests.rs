@@ -1,6 +1,6 @@
 #[cfg(test)]
 mod tests {
-    use metricsql_common::label::Label;
+    use crate::common::InternedLabel;
     use valkey_module::ValkeyString;
     use crate::index::TimeSeriesIndex;
     use crate::storage::time_series::TimeSeries;
@@ -24,14 +24,8 @@ mod tests {
         let mut ts = create_series();
         ts.metric_name = "latency".to_string();
         ts.labels = vec![
-            Label {
-                name: "region".to_string(),
-                value: "us-east1".to_string(),
-            },
-            Label {
-                name: "env".to_string(),
-                value: "qa".to_string(),
-            },
+            InternedLabel::new("region", "us-east1"),
+            InternedLabel::new("env", "qa"),
         ];
 
         let mut index = TimeSeriesIndex::new();
@@ -48,24 +42,15 @@ mod tests {
 
         ts.metric_name = "latency".to_string();
         ts.labels = vec![
-            Label {
-                name: "region".to_string(),
-                value: "us-east1".to_string(),
-            },
-            Label {
-                name: "env".to_string(),
-                value: "dev".to_string(),
-            },
+            InternedLabel::new("region", "us-east1"),
+            InternedLabel::new("env", "dev"),
         ];
 
         let mut ts2 = ts.clone();
         ts2.id = TimeSeriesIndex::next_id();
 
-        ts2.labels[1].value = "qa".to_string();
-        ts2.labels.push(Label {
-            name: "version".to_string(),
-            value: "1.0.0".to_string(),
-        });
+        ts2.labels[1] = InternedLabel::new("env", "qa");
+        ts2.labels.push(InternedLabel::new("version", "1.0.0"));
 
         let mut index = TimeSeriesIndex::new();
 
@@ -89,24 +74,18 @@ mod tests {
 
         ts.metric_name = "latency".to_string();
         ts.labels = vec![
-            Label {
-                name: "region".to_string(),
-                value: "us-east-1".to_string(),
-            },
-            Label {
-                name: "env".to_string(),
-                value: "dev".to_string(),
-            },
+            InternedLabel::new("region", "us-east-1"),
+            InternedLabel::new("env", "dev"),
         ];
 
         let mut ts2 = ts.clone();
         ts2.id = TimeSeriesIndex::next_id();
 
-        ts2.labels[0].value = "us-east-2".to_string();
-        ts2.labels[1].value = "qa".to_string();
+        ts2.labels[0] = InternedLabel::new("region", "us-east-2");
+        ts2.labels[1] = InternedLabel::new("env", "qa");
 
         let mut ts3 = ts.clone();
-        ts3.labels[1].value = "prod".to_string();
+        ts3.labels[1] = InternedLabel::new("env", "prod");
 
         index_time_series(&mut index, &ts, "time-series-1");
         index_time_series(&mut index, &ts2, "time-series-2");
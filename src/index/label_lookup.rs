@@ -0,0 +1,202 @@
+//! Label name and value lookups answered from the label index, e.g. for query editor
+//! autocompletion. Results come out of the index (nearly) in order, so lookups stop soon after
+//! `limit` items are found. Restricting results to a set of series intersects postings rather than reading the
+//! series.
+use super::index_key::get_key_for_label_prefix;
+use super::timeseries_index::IndexInner;
+use croaring::Bitmap64;
+use std::collections::BTreeSet;
+
+/// Filter on label names or values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LabelSearch {
+    /// Matches strings starting with the given prefix. Evaluated as a prefix scan of the index
+    Prefix(String),
+    /// Matches strings containing the given string
+    Substring(String),
+}
+
+impl LabelSearch {
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            LabelSearch::Prefix(prefix) => value.starts_with(prefix.as_str()),
+            LabelSearch::Substring(needle) => value.contains(needle.as_str()),
+        }
+    }
+
+    fn prefix(&self) -> &str {
+        match self {
+            LabelSearch::Prefix(prefix) => prefix,
+            LabelSearch::Substring(_) => "",
+        }
+    }
+}
+
+fn has_series(postings: &Bitmap64, series: Option<&Bitmap64>) -> bool {
+    match series {
+        Some(series) => postings.intersect(series),
+        None => !postings.is_empty(),
+    }
+}
+
+/// Returns up to `limit` label names, sorted, optionally restricted to names of `series` and to
+/// those matching `search`.
+pub(crate) fn find_label_names(
+    inner: &IndexInner,
+    series: Option<&Bitmap64>,
+    search: Option<&LabelSearch>,
+    limit: usize
+) -> Vec<String> {
+    if limit == 0 {
+        return Vec::new();
+    }
+
+    // keys are ordered, so the values of a label are adjacent. A name is decided (found or
+    // rejected by `search`) at most once, but may need several of its values to find one of
+    // `series`.
+    // Names are not visited quite in order: the keys of a name sort before those of a shorter
+    // name it extends (e.g. `env2=` < `env=`). A name visited later can only sort before one
+    // already found if it is a prefix of it, so once `limit` names are found, the scan stops at
+    // the first name with a greater first byte than the last of them.
+    let prefix = search.map_or("", |search| search.prefix());
+    let mut names: BTreeSet<&str> = BTreeSet::new();
+    let mut decided: Option<&str> = None;
+    for (key, postings) in inner.label_index.prefix(prefix.as_bytes()) {
+        let Some((name, _)) = key.split() else {
+            continue;
+        };
+        if decided == Some(name) {
+            continue;
+        }
+        if names.len() >= limit {
+            let last = names.last().copied().unwrap_or_default();
+            if name.as_bytes().first() > last.as_bytes().first() {
+                break;
+            }
+            if name > last {
+                decided = Some(name);
+                continue;
+            }
+        }
+        if !search.map_or(true, |search| search.matches(name)) {
+            decided = Some(name);
+            continue;
+        }
+        if !has_series(postings, series) {
+            continue;
+        }
+        decided = Some(name);
+        names.insert(name);
+        if names.len() > limit {
+            names.pop_last();
+        }
+    }
+    names.into_iter().map(|name| name.to_string()).collect()
+}
+
+/// Returns up to `limit` values of `label` in order, optionally restricted to values of `series`
+/// and to those matching `search`.
+pub(crate) fn find_label_values(
+    inner: &IndexInner,
+    label: &str,
+    series: Option<&Bitmap64>,
+    search: Option<&LabelSearch>,
+    limit: usize
+) -> Vec<String> {
+    let mut values = Vec::new();
+    if limit == 0 {
+        return values;
+    }
+
+    let mut prefix = get_key_for_label_prefix(label);
+    let start_pos = prefix.len();
+    prefix.push_str(search.map_or("", |search| search.prefix()));
+
+    for (key, postings) in inner.label_index.prefix(prefix.as_bytes()) {
+        let value = key.sub_string(start_pos);
+        if !search.map_or(true, |search| search.matches(value)) {
+            continue;
+        }
+        if !has_series(postings, series) {
+            continue;
+        }
+        values.push(value.to_string());
+        if values.len() >= limit {
+            break;
+        }
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::test_utils;
+
+    /// The shared fixture, plus an `errors` series with an `env2` label instead of `env`.
+    fn create_index() -> IndexInner {
+        let mut inner = test_utils::create_index();
+        test_utils::index_series(&mut inner, &[(5, "errors", &[("region", "us-east1"), ("env2", "prod")])]);
+        inner
+    }
+
+    #[test]
+    fn test_label_names() {
+        let inner = create_index();
+        assert_eq!(
+            find_label_names(&inner, None, None, 10),
+            vec!["__name__", "env", "env2", "region"]
+        );
+        assert_eq!(find_label_names(&inner, None, None, 1), vec!["__name__"]);
+        // `env2` is visited before `env`, but `env` sorts first
+        assert_eq!(find_label_names(&inner, None, None, 2), vec!["__name__", "env"]);
+        assert_eq!(find_label_names(&inner, None, None, 3), vec!["__name__", "env", "env2"]);
+
+        let search = LabelSearch::Prefix("env".to_string());
+        assert_eq!(find_label_names(&inner, None, Some(&search), 10), vec!["env", "env2"]);
+        assert_eq!(find_label_names(&inner, None, Some(&search), 1), vec!["env"]);
+        let search = LabelSearch::Substring("gio".to_string());
+        assert_eq!(find_label_names(&inner, None, Some(&search), 10), vec!["region"]);
+    }
+
+    #[test]
+    fn test_label_names_for_series() {
+        let inner = create_index();
+        let series = Bitmap64::from_iter([3u64, 5]);
+        assert_eq!(
+            find_label_names(&inner, Some(&series), None, 10),
+            vec!["__name__", "env2", "region"]
+        );
+    }
+
+    #[test]
+    fn test_label_values() {
+        let inner = create_index();
+        assert_eq!(
+            find_label_values(&inner, "region", None, None, 10),
+            vec!["eu-west1", "us-east1", "us-east2"]
+        );
+        assert_eq!(find_label_values(&inner, "region", None, None, 1), vec!["eu-west1"]);
+        // "env2" values are not values of "env"
+        assert_eq!(find_label_values(&inner, "env", None, None, 10), vec!["prod", "qa"]);
+
+        let search = LabelSearch::Prefix("us-".to_string());
+        assert_eq!(
+            find_label_values(&inner, "region", None, Some(&search), 10),
+            vec!["us-east1", "us-east2"]
+        );
+        let search = LabelSearch::Substring("east2".to_string());
+        assert_eq!(find_label_values(&inner, "region", None, Some(&search), 10), vec!["us-east2"]);
+    }
+
+    #[test]
+    fn test_label_values_for_series() {
+        let inner = create_index();
+        let series = Bitmap64::from_iter([2u64, 3]);
+        assert_eq!(
+            find_label_values(&inner, "region", Some(&series), None, 10),
+            vec!["eu-west1", "us-east2"]
+        );
+        assert_eq!(find_label_values(&inner, "__name__", Some(&series), None, 10), vec!["latency"]);
+    }
+}
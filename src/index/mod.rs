@@ -2,7 +2,23 @@
 mod timeseries_index;
 #[cfg(test)]
 mod index_tests;
+#[cfg(test)]
+mod test_utils;
 mod filters;
 mod index_key;
+mod time_postings;
+mod persistence;
+mod consistency;
+mod planner;
+mod regex_analysis;
+mod series_limits;
+mod index_stats;
+mod label_lookup;
 
-pub use timeseries_index::*;
\ No newline at end of file
+pub use timeseries_index::*;
+pub use persistence::*;
+pub use consistency::*;
+pub use series_limits::*;
+pub use index_stats::*;
+pub use label_lookup::LabelSearch;
+pub(crate) use planner::{FilterPlan, FilterStrategy, GroupPlan, SelectorPlan};
\ No newline at end of file
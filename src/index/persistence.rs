@@ -0,0 +1,591 @@
+//! Persistence of the label index in RDB aux data, so that it need not be rebuilt from the
+//! keyspace on load.
+//!
+//! The index of every db is saved before the keyspace, streamed in chunks so that a large index
+//! is never serialized into a single buffer. On load, the chunks are restored into
+//! `TIMESERIES_INDEX`, and each series loaded afterward is checked against the restored index
+//! rather than indexed from scratch. If the aux data is missing, has an unknown version or cannot
+//! be decoded, series are indexed as they are loaded. A db whose restored index disagrees with its
+//! keyspace is rebuilt once loading ends.
+use super::index_key::IndexKey;
+use super::time_postings::TimePostings;
+use super::timeseries_index::{get_time_bucket_size, next_timeseries_id_value, set_timeseries_id_sequence, IndexInner, ARTBitmap};
+use crate::common::InternedLabel;
+use crate::error::{TsdbError, TsdbResult};
+use crate::globals::{get_current_db, get_timeseries_index_for_db, select_db, TIMESERIES_INDEX};
+use crate::module::VKM_SERIES_TYPE;
+use crate::storage::time_series::TimeSeries;
+use ahash::AHashMap;
+use croaring::{Bitmap64, Portable};
+use integer_encoding::VarInt;
+use metricsql_common::hash::IntMap;
+use std::sync::{LazyLock, Mutex};
+use valkey_module::{raw, Context, KeysCursor, ValkeyKey, ValkeyString};
+
+/// Version of the serialized index format. Aux data with a different version is ignored.
+const INDEX_AUX_VERSION: u64 = 3;
+
+/// Size above which serialized index data is saved to the rdb as a chunk.
+const AUX_CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Default)]
+struct RestoredDb {
+    /// Ids of series seen during load
+    seen: Bitmap64,
+    /// Whether the time postings were restored. False if the bucket size changed since save.
+    has_time_postings: bool,
+    /// Set if a loaded series disagrees with the restored index
+    stale: bool,
+    /// Number of label postings of each id in the restored index
+    postings: IntMap<u64, usize>,
+}
+
+/// State of the index restored from aux data while the keyspace is loading
+#[derive(Default)]
+struct IndexLoadState {
+    dbs: AHashMap<u32, RestoredDb>,
+}
+
+static INDEX_LOAD_STATE: LazyLock<Mutex<Option<IndexLoadState>>> = LazyLock::new(|| Mutex::new(None));
+
+/// Saves the index of all dbs to `rdb`. Each chunk is preceded by a 1, and the last followed by a 0.
+pub fn save_index_aux(rdb: *mut raw::RedisModuleIO) {
+    raw::save_unsigned(rdb, INDEX_AUX_VERSION);
+    let mut writer = ChunkWriter::new(AUX_CHUNK_SIZE, |chunk: &[u8]| {
+        raw::save_unsigned(rdb, 1);
+        raw::save_slice(rdb, chunk);
+    });
+    serialize_indexes(&mut writer);
+    writer.flush();
+    raw::save_unsigned(rdb, 0);
+}
+
+/// Returns the next chunk of index aux data in `rdb`, or None after the last.
+fn load_aux_chunk(rdb: *mut raw::RedisModuleIO) -> Result<Option<Vec<u8>>, valkey_module::error::Error> {
+    if raw::load_unsigned(rdb)? == 0 {
+        return Ok(None);
+    }
+    let buf = raw::load_string_buffer(rdb)?;
+    Ok(Some(buf.as_ref().to_vec()))
+}
+
+/// Restores the index of all dbs from `rdb`. Fails only if the aux data cannot be read, in which
+/// case the rdb is unusable anyway. Undecodable contents are discarded, and the index rebuilt as
+/// series are loaded.
+pub fn load_index_aux(rdb: *mut raw::RedisModuleIO) -> Result<(), valkey_module::error::Error> {
+    let version = raw::load_unsigned(rdb)?;
+    if version < 3 {
+        // earlier versions saved the index as a single buffer
+        raw::load_string_buffer(rdb)?;
+        return Ok(());
+    }
+
+    let mut rdb_error = None;
+    let mut ended = false;
+    let decoded = if version == INDEX_AUX_VERSION {
+        let mut reader = Reader::new(|| match load_aux_chunk(rdb) {
+            Ok(chunk) => {
+                ended = chunk.is_none();
+                Ok(chunk)
+            }
+            Err(err) => {
+                rdb_error = Some(err);
+                Err(truncated())
+            }
+        });
+        deserialize_indexes(&mut reader)
+    } else {
+        Err(TsdbError::CannotDeserialize("unknown index aux version".to_string()))
+    };
+    if let Some(err) = rdb_error {
+        return Err(err);
+    }
+    // skip whatever was not decoded
+    while !ended {
+        ended = load_aux_chunk(rdb)?.is_none();
+    }
+    let Ok((sequence, indexes)) = decoded else {
+        return Ok(());
+    };
+
+    let bucket_size = get_time_bucket_size();
+    let mut state = IndexLoadState::default();
+    let guard = TIMESERIES_INDEX.guard();
+    for (db, mut inner) in indexes {
+        let has_time_postings = inner.time_postings.bucket_size() == bucket_size;
+        if !has_time_postings {
+            inner.time_postings = TimePostings::new(bucket_size);
+        }
+        let postings = count_postings(&inner);
+        get_timeseries_index_for_db(db, &guard).replace_inner(inner);
+        state.dbs.insert(db, RestoredDb {
+            has_time_postings,
+            postings,
+            ..Default::default()
+        });
+    }
+    drop(guard);
+
+    set_timeseries_id_sequence(sequence);
+    *INDEX_LOAD_STATE.lock().unwrap() = Some(state);
+    Ok(())
+}
+
+/// Discards any index state restored from aux data. Called when loading starts or fails.
+pub fn reset_index_load_state() {
+    *INDEX_LOAD_STATE.lock().unwrap() = None;
+}
+
+/// Handles a series loaded into the current db of `ctx`. If the index of the db was restored
+/// from aux data, the series is checked against it, otherwise it is indexed.
+pub fn index_loaded_series(ctx: &Context, series: &TimeSeries, key: &[u8]) {
+    let db = unsafe { get_current_db(ctx.ctx) };
+    let guard = TIMESERIES_INDEX.guard();
+    let index = get_timeseries_index_for_db(db, &guard);
+
+    let mut state = INDEX_LOAD_STATE.lock().unwrap();
+    let Some(restored) = state.as_mut().and_then(|s| s.dbs.get_mut(&db)) else {
+        if index.is_series_indexed(series.id) {
+            // todo: log warning
+            let key = ctx.create_string(key);
+            index.remove_series_by_key(ctx, &key);
+            return;
+        }
+        index.index_time_series(series, key);
+        return;
+    };
+
+    restored.seen.add(series.id);
+    let matches = {
+        let inner = index.get_inner();
+        is_series_indexed_as(&inner, &restored.postings, series, key)
+    };
+    if !matches {
+        restored.stale = true;
+        return;
+    }
+    // label sets are not saved, so register them as the series load
+    index.index_label_set(series);
+    if !restored.has_time_postings {
+        index.update_series_time_range(series.id, None, series.time_range());
+    }
+}
+
+/// Completes loading of the index. Removes ids restored from aux data whose series were not
+/// loaded (e.g. expired keys), rebuilds the index of any db found to be stale, and sets the id
+/// sequence past the largest id in use.
+pub fn finish_index_load(ctx: &Context) {
+    let state = INDEX_LOAD_STATE.lock().unwrap().take();
+    if let Some(state) = state {
+        let guard = TIMESERIES_INDEX.guard();
+        for (db, restored) in state.dbs {
+            let index = get_timeseries_index_for_db(db, &guard);
+            if restored.stale {
+                ctx.log_warning(&format!("vkmetrics: index for db {db} is stale. Rebuilding"));
+                rebuild_index(ctx, db);
+                continue;
+            }
+            // ids with postings but no key are orphans as well
+            let mut orphans = index.get_inner().series_ids();
+            orphans.or_inplace(&Bitmap64::from_iter(restored.postings.keys().copied()));
+            orphans.andnot_inplace(&restored.seen);
+            if !orphans.is_empty() {
+                index.remove_ids(&orphans);
+            }
+        }
+    }
+
+    let guard = TIMESERIES_INDEX.guard();
+    let max_id = TIMESERIES_INDEX
+        .iter(&guard)
+        .filter_map(|(_, index)| index.get_inner().id_to_key.keys().max().copied())
+        .max()
+        .unwrap_or(0);
+    if max_id >= next_timeseries_id_value() {
+        set_timeseries_id_sequence(max_id + 1);
+    }
+}
+
+/// Clears the index of `db` and re-indexes all series in its keyspace.
+pub fn rebuild_index(ctx: &Context, db: u32) {
+    let guard = TIMESERIES_INDEX.guard();
+    let index = get_timeseries_index_for_db(db, &guard);
+    index.clear();
+
+    let saved_db = unsafe { get_current_db(ctx.ctx) };
+    unsafe { select_db(ctx.ctx, db) };
+    let cursor = KeysCursor::new();
+    let callback = |_ctx: &Context, key_name: ValkeyString, key: Option<&ValkeyKey>| {
+        let Some(key) = key else {
+            return;
+        };
+        if let Ok(Some(series)) = key.get_value::<TimeSeries>(&VKM_SERIES_TYPE) {
+            index.index_time_series(series, key_name.as_slice());
+        }
+    };
+    while cursor.scan(ctx, &callback) {}
+    unsafe { select_db(ctx.ctx, saved_db) };
+}
+
+/// Returns the number of label postings of each id in the label index of `inner`.
+fn count_postings(inner: &IndexInner) -> IntMap<u64, usize> {
+    let mut counts = IntMap::default();
+    for (_, bmp) in inner.label_index.iter() {
+        for id in bmp.iter() {
+            *counts.entry(id).or_default() += 1;
+        }
+    }
+    counts
+}
+
+/// Returns true if `series` is indexed under `key` with exactly its labels. `postings` holds the
+/// number of label postings of each id in `inner` (see `count_postings`).
+fn is_series_indexed_as(
+    inner: &IndexInner,
+    postings: &IntMap<u64, usize>,
+    series: &TimeSeries,
+    key: &[u8]
+) -> bool {
+    match inner.id_to_key.get(&series.id) {
+        Some(indexed_key) if indexed_key.as_ref() == key => {}
+        _ => return false,
+    }
+    // postings for labels the series does not have
+    let label_count = series.labels.len() + usize::from(!series.metric_name.is_empty());
+    if postings.get(&series.id).copied().unwrap_or_default() != label_count {
+        return false;
+    }
+    let has_label = |bytes: &[u8]| {
+        inner.label_index.get(bytes).map_or(false, |bmp| bmp.contains(series.id))
+    };
+    if !series.metric_name.is_empty() {
+        let key = IndexKey::for_metric_name(&series.metric_name);
+        if !has_label(key.as_ref()) {
+            return false;
+        }
+    }
+    series.labels.iter().all(|label| has_label(label.as_bytes()))
+}
+
+fn serialize_indexes<F: FnMut(&[u8])>(writer: &mut ChunkWriter<F>) {
+    let guard = TIMESERIES_INDEX.guard();
+    let indexes: Vec<_> = TIMESERIES_INDEX.iter(&guard).collect();
+    writer.write_u64(next_timeseries_id_value());
+    writer.write_u64(indexes.len() as u64);
+    for (db, index) in indexes {
+        writer.write_u64(*db as u64);
+        serialize_index(&index.get_inner(), writer);
+    }
+}
+
+fn serialize_index<F: FnMut(&[u8])>(inner: &IndexInner, writer: &mut ChunkWriter<F>) {
+    writer.write_u64(inner.label_count as u64);
+
+    writer.write_u64(inner.id_to_key.len() as u64);
+    for (id, key) in inner.id_to_key.iter() {
+        writer.write_u64(*id);
+        writer.write_bytes(key);
+        writer.end_entry();
+    }
+
+    writer.write_u64(inner.label_index.len() as u64);
+    for (key, bmp) in inner.label_index.iter() {
+        writer.write_bytes(key.as_ref());
+        writer.write_bitmap(bmp);
+        writer.end_entry();
+    }
+
+    let postings = &inner.time_postings;
+    writer.write_u64(postings.bucket_size() as u64);
+    writer.write_u64(postings.series_count() as u64);
+    for (id, first, last) in postings.iter() {
+        writer.write_u64(id);
+        writer.write_i64(first);
+        writer.write_i64(last);
+        writer.end_entry();
+    }
+}
+
+fn deserialize_indexes<F>(reader: &mut Reader<F>) -> TsdbResult<(u64, Vec<(u32, IndexInner)>)>
+where
+    F: FnMut() -> TsdbResult<Option<Vec<u8>>>
+{
+    let sequence = reader.read_u64()?;
+    let count = reader.read_u64()? as usize;
+    let mut indexes = Vec::with_capacity(count);
+    for _ in 0..count {
+        let db = reader.read_u64()? as u32;
+        let inner = deserialize_index(reader)?;
+        indexes.push((db, inner));
+    }
+    Ok((sequence, indexes))
+}
+
+fn deserialize_index<F>(reader: &mut Reader<F>) -> TsdbResult<IndexInner>
+where
+    F: FnMut() -> TsdbResult<Option<Vec<u8>>>
+{
+    let mut inner = IndexInner::new();
+    inner.label_count = reader.read_u64()? as usize;
+
+    let count = reader.read_u64()? as usize;
+    inner.id_to_key.reserve(count);
+    for _ in 0..count {
+        let id = reader.read_u64()?;
+        let key = reader.read_bytes()?;
+        inner.id_to_key.insert(id, key.to_vec().into_boxed_slice());
+    }
+
+    let count = reader.read_u64()? as usize;
+    let mut label_index = ARTBitmap::new();
+    for _ in 0..count {
+        let key = index_key_from_bytes(reader.read_bytes()?)?;
+        let bmp = reader.read_bitmap()?;
+        label_index
+            .try_insert(key, bmp)
+            .map_err(|_| TsdbError::CannotDeserialize("invalid label index key".to_string()))?;
+    }
+    inner.label_index = label_index;
+
+    let bucket_size = reader.read_u64()? as i64;
+    let count = reader.read_u64()? as usize;
+    let mut postings = TimePostings::new(bucket_size);
+    for _ in 0..count {
+        let id = reader.read_u64()?;
+        let first = reader.read_i64()?;
+        let last = reader.read_i64()?;
+        postings.add_range(id, first, last);
+    }
+    inner.time_postings = postings;
+    Ok(inner)
+}
+
+/// Converts a serialized label index key ("name=value\0") to an `IndexKey`, interning series
+/// labels so that the key is shared with the series loaded later.
+fn index_key_from_bytes(bytes: &[u8]) -> TsdbResult<IndexKey> {
+    let invalid = || TsdbError::CannotDeserialize("invalid label index key".to_string());
+    let metric_name = bytes
+        .strip_prefix(metricsql_runtime::types::METRIC_NAME_LABEL.as_bytes())
+        .and_then(|rest| rest.strip_prefix(b"="))
+        .and_then(|rest| rest.strip_suffix(&[0]));
+    if let Some(metric_name) = metric_name {
+        let metric_name = std::str::from_utf8(metric_name).map_err(|_| invalid())?;
+        return Ok(IndexKey::for_metric_name(metric_name));
+    }
+    // the key keeps the interned label alive until the series referencing it are loaded
+    let label = InternedLabel::from_key_bytes(bytes).ok_or_else(invalid)?;
+    Ok(IndexKey::from(label))
+}
+
+/// Buffers serialized index data, passing it to `sink` in chunks of about `chunk_size` bytes.
+/// Chunks end only between entries (see `end_entry`), so no value spans two chunks.
+struct ChunkWriter<F: FnMut(&[u8])> {
+    buf: Vec<u8>,
+    chunk_size: usize,
+    sink: F,
+}
+
+impl<F: FnMut(&[u8])> ChunkWriter<F> {
+    fn new(chunk_size: usize, sink: F) -> Self {
+        Self {
+            buf: Vec::with_capacity(1024),
+            chunk_size,
+            sink,
+        }
+    }
+
+    /// Marks the end of an entry, passing the buffered data to the sink if the chunk is full.
+    fn end_entry(&mut self) {
+        if self.buf.len() >= self.chunk_size {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.buf.is_empty() {
+            (self.sink)(&self.buf);
+            self.buf.clear();
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.encode_var_vec());
+    }
+
+    fn write_i64(&mut self, value: i64) {
+        self.buf.extend_from_slice(&value.encode_var_vec());
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u64(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn write_bitmap(&mut self, bmp: &Bitmap64) {
+        self.write_bytes(&bmp.serialize::<Portable>());
+    }
+}
+
+/// Reads serialized index data from the chunks returned by `next_chunk`.
+struct Reader<F: FnMut() -> TsdbResult<Option<Vec<u8>>>> {
+    chunk: Vec<u8>,
+    pos: usize,
+    next_chunk: F,
+}
+
+impl<F: FnMut() -> TsdbResult<Option<Vec<u8>>>> Reader<F> {
+    fn new(next_chunk: F) -> Self {
+        Self {
+            chunk: Vec::new(),
+            pos: 0,
+            next_chunk,
+        }
+    }
+
+    /// Returns the unread data of the current chunk, moving to the next one if it is exhausted.
+    fn remaining(&mut self) -> TsdbResult<&[u8]> {
+        while self.pos == self.chunk.len() {
+            self.chunk = (self.next_chunk)()?.ok_or_else(truncated)?;
+            self.pos = 0;
+        }
+        Ok(&self.chunk[self.pos..])
+    }
+
+    fn read_u64(&mut self) -> TsdbResult<u64> {
+        let (value, size) = u64::decode_var(self.remaining()?).ok_or_else(truncated)?;
+        self.pos += size;
+        Ok(value)
+    }
+
+    fn read_i64(&mut self) -> TsdbResult<i64> {
+        let (value, size) = i64::decode_var(self.remaining()?).ok_or_else(truncated)?;
+        self.pos += size;
+        Ok(value)
+    }
+
+    fn read_bytes(&mut self) -> TsdbResult<&[u8]> {
+        let len = self.read_u64()? as usize;
+        if len == 0 {
+            return Ok(&[]);
+        }
+        if len > self.remaining()?.len() {
+            return Err(truncated());
+        }
+        let start = self.pos;
+        self.pos += len;
+        Ok(&self.chunk[start..self.pos])
+    }
+
+    fn read_bitmap(&mut self) -> TsdbResult<Bitmap64> {
+        let bytes = self.read_bytes()?;
+        Bitmap64::try_deserialize::<Portable>(bytes)
+            .ok_or_else(|| TsdbError::CannotDeserialize("invalid bitmap".to_string()))
+    }
+}
+
+fn truncated() -> TsdbError {
+    TsdbError::CannotDeserialize("index aux data is truncated".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_series(id: u64, metric_name: &str, labels: &[(&str, &str)]) -> TimeSeries {
+        let mut ts = TimeSeries::new();
+        ts.id = id;
+        ts.metric_name = metric_name.to_string();
+        ts.labels = labels.iter().map(|(name, value)| InternedLabel::new(name, value)).collect();
+        ts.first_timestamp = 1000;
+        ts.last_timestamp = 2000;
+        ts.total_samples = 2;
+        ts
+    }
+
+    fn write_chunks(inner: &IndexInner, chunk_size: usize) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        let mut writer = ChunkWriter::new(chunk_size, |chunk: &[u8]| chunks.push(chunk.to_vec()));
+        serialize_index(inner, &mut writer);
+        writer.flush();
+        drop(writer);
+        chunks
+    }
+
+    fn read_chunks(chunks: Vec<Vec<u8>>) -> TsdbResult<IndexInner> {
+        let mut chunks = chunks.into_iter();
+        let mut reader = Reader::new(|| Ok(chunks.next()));
+        deserialize_index(&mut reader)
+    }
+
+    #[test]
+    fn test_index_round_trip() {
+        let mut inner = IndexInner::new();
+        let first = create_series(1, "latency", &[("region", "us-east1"), ("env", "qa")]);
+        let second = create_series(2, "latency", &[("region", "us-east2"), ("env", "qa")]);
+        inner.index_time_series(&first, b"latency:1");
+        inner.index_time_series(&second, b"latency:2");
+
+        let restored = read_chunks(write_chunks(&inner, AUX_CHUNK_SIZE)).unwrap();
+
+        assert_eq!(restored.label_count, inner.label_count);
+        assert_eq!(restored.id_to_key, inner.id_to_key);
+        assert_eq!(restored.label_index.len(), inner.label_index.len());
+        for (key, bmp) in inner.label_index.iter() {
+            assert_eq!(restored.label_index.get(key.as_ref()), Some(bmp));
+        }
+        assert_eq!(restored.time_postings.bucket_size(), inner.time_postings.bucket_size());
+        assert_eq!(
+            restored.time_postings.ids_in_range(1000, 2000),
+            inner.time_postings.ids_in_range(1000, 2000)
+        );
+
+        let postings = count_postings(&restored);
+        assert!(is_series_indexed_as(&restored, &postings, &first, b"latency:1"));
+        assert!(!is_series_indexed_as(&restored, &postings, &first, b"latency:2"));
+    }
+
+    #[test]
+    fn test_index_round_trip_in_chunks() {
+        let mut inner = IndexInner::new();
+        for id in 1..=20 {
+            let region = format!("region-{id}");
+            let series = create_series(id, "latency", &[("region", &region)]);
+            inner.index_time_series(&series, format!("latency:{id}").as_bytes());
+        }
+
+        let chunks = write_chunks(&inner, 16);
+        assert!(chunks.len() > 1);
+        let restored = read_chunks(chunks).unwrap();
+
+        assert_eq!(restored.id_to_key, inner.id_to_key);
+        assert_eq!(restored.label_index.len(), inner.label_index.len());
+        for (key, bmp) in inner.label_index.iter() {
+            assert_eq!(restored.label_index.get(key.as_ref()), Some(bmp));
+        }
+    }
+
+    #[test]
+    fn test_extra_postings_are_stale() {
+        let mut inner = IndexInner::new();
+        let series = create_series(1, "latency", &[("region", "us-east1")]);
+        let other = create_series(1, "latency", &[("region", "us-east1"), ("env", "qa")]);
+        inner.index_time_series(&other, b"latency:1");
+
+        // the index claims the series has a label it does not have
+        let postings = count_postings(&inner);
+        assert!(!is_series_indexed_as(&inner, &postings, &series, b"latency:1"));
+        assert!(is_series_indexed_as(&inner, &postings, &other, b"latency:1"));
+    }
+
+    #[test]
+    fn test_truncated_data_is_rejected() {
+        let mut inner = IndexInner::new();
+        let series = create_series(1, "latency", &[("region", "us-east1")]);
+        inner.index_time_series(&series, b"latency:1");
+
+        let mut chunks = write_chunks(&inner, AUX_CHUNK_SIZE);
+        let last = chunks.last_mut().unwrap();
+        last.truncate(last.len() - 1);
+        assert!(read_chunks(chunks).is_err());
+    }
+}
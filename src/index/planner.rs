@@ -0,0 +1,577 @@
+//! Planning of label matcher evaluation against the label index.
+//!
+//! Each filter of a selector matches a label value predicate, where a missing label is treated as
+//! the empty value (as in Prometheus). Filters which do not match the empty value select series
+//! from the postings of the values they match. Filters which do match it (e.g. `env!="prod"`)
+//! also select series without the label, so they are evaluated by removing the postings of the
+//! values they do not match from the candidates found so far.
+//!
+//! Filters are evaluated in order of their estimated result size, so that the intersection is
+//! seeded with the most selective filter and short-circuits as soon as it becomes empty. Lookups
+//! are estimated exactly, but scans only count postings until they are known to be less selective
+//! than another filter, as a label may have many values.
+//!
+//! Regex filters are analyzed (see `regex_analysis`), so that literal alternations become point
+//! lookups, literal prefixes become prefix scans of the label index, and `.+`/`.*` become
+//! label-exists checks, instead of running the regex against every value of the label.
+use super::index_key::{get_key_for_label_prefix, get_key_for_label_value};
+use super::regex_analysis::{analyze_regex, RegexShape};
+use super::timeseries_index::{ARTBitmap, IndexInner};
+use croaring::Bitmap64;
+use metricsql_parser::prelude::{LabelFilter, LabelFilterOp, Matchers};
+use regex::Regex;
+use std::fmt::{Display, Formatter};
+
+/// How a filter is evaluated against the label index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum FilterStrategy {
+    /// The filter matches no series, e.g. `env!~".*"`
+    MatchNone,
+    /// Point lookups of the postings of one or more values
+    Lookup,
+    /// Union of the postings of the values of the label under a literal prefix
+    PrefixScan,
+    /// Union of the postings of all values of the label, i.e. series having the label
+    Exists,
+    /// Union of the postings of all values of the label matching the filter
+    Scan,
+    /// Removal of the postings of one or more values from the candidates
+    SubtractLookup,
+    /// Removal of the postings of the values under a literal prefix not matching the filter
+    SubtractPrefixScan,
+    /// Removal of series having the label from the candidates, e.g. `env=""`
+    SubtractExists,
+    /// Removal of the postings of all values of the label not matching the filter from the candidates
+    SubtractScan,
+    /// The filter matches every series, e.g. `env=~".*"`, and need not be evaluated
+    MatchAll,
+}
+
+impl FilterStrategy {
+    pub fn is_subtraction(&self) -> bool {
+        matches!(
+            self,
+            FilterStrategy::SubtractLookup |
+            FilterStrategy::SubtractPrefixScan |
+            FilterStrategy::SubtractExists |
+            FilterStrategy::SubtractScan
+        )
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilterStrategy::MatchNone => "match-none",
+            FilterStrategy::Lookup => "lookup",
+            FilterStrategy::PrefixScan => "prefix-scan",
+            FilterStrategy::Exists => "exists",
+            FilterStrategy::Scan => "scan",
+            FilterStrategy::SubtractLookup => "subtract-lookup",
+            FilterStrategy::SubtractPrefixScan => "subtract-prefix-scan",
+            FilterStrategy::SubtractExists => "subtract-exists",
+            FilterStrategy::SubtractScan => "subtract-scan",
+            FilterStrategy::MatchAll => "match-all",
+        }
+    }
+}
+
+impl Display for FilterStrategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Predicate on a label value. A missing label is matched as the empty value.
+pub(crate) enum ValuePredicate {
+    Equal(String),
+    NotEqual(String),
+    Regex(Regex),
+    NotRegex(Regex),
+    /// An invalid regex. Should not happen, since selectors are validated when parsed
+    MatchNone,
+}
+
+impl ValuePredicate {
+    pub fn new(filter: &LabelFilter) -> Self {
+        match filter.op {
+            LabelFilterOp::Equal => ValuePredicate::Equal(filter.value.clone()),
+            LabelFilterOp::NotEqual => ValuePredicate::NotEqual(filter.value.clone()),
+            LabelFilterOp::RegexEqual => match compile_regex(&filter.value) {
+                Some(regex) => ValuePredicate::Regex(regex),
+                None => ValuePredicate::MatchNone,
+            },
+            LabelFilterOp::RegexNotEqual => match compile_regex(&filter.value) {
+                Some(regex) => ValuePredicate::NotRegex(regex),
+                None => ValuePredicate::MatchNone,
+            },
+        }
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            ValuePredicate::Equal(expected) => value == expected,
+            ValuePredicate::NotEqual(expected) => value != expected,
+            ValuePredicate::Regex(regex) => regex.is_match(value),
+            ValuePredicate::NotRegex(regex) => !regex.is_match(value),
+            ValuePredicate::MatchNone => false,
+        }
+    }
+}
+
+/// Label regexes are anchored at both ends, and `.` matches newlines
+fn compile_regex(pattern: &str) -> Option<Regex> {
+    Regex::new(&format!("^(?s:{pattern})$")).ok()
+}
+
+/// Plan for a single filter.
+pub(crate) struct FilterPlan<'a> {
+    pub filter: &'a LabelFilter,
+    pub predicate: ValuePredicate,
+    pub strategy: FilterStrategy,
+    /// Values looked up by `Lookup` and `SubtractLookup`
+    pub values: Vec<String>,
+    /// Value prefix of `PrefixScan` and `SubtractPrefixScan`
+    pub prefix: String,
+    /// Whether values found by a prefix scan must be checked against the predicate. False if every
+    /// value under the prefix matches (e.g. `path=~"/api/.*"`)
+    pub check_values: bool,
+    /// Estimated number of series selected by the filter or, for subtracted lookups, removed by
+    /// it. Exact for lookups. For scans, counting stops once the estimate exceeds that of a more
+    /// selective filter or `MAX_ESTIMATED_VALUES` values were counted, so it is a lower bound.
+    /// Subtracted scans are not estimated, as subtractions are evaluated last anyway.
+    pub estimate: Option<u64>,
+}
+
+impl<'a> FilterPlan<'a> {
+    fn new(filter: &'a LabelFilter, predicate: ValuePredicate, strategy: FilterStrategy) -> Self {
+        Self {
+            filter,
+            predicate,
+            strategy,
+            values: Vec::new(),
+            prefix: String::new(),
+            check_values: true,
+            estimate: None,
+        }
+    }
+}
+
+/// Plan for a list of ANDed filters.
+#[derive(Default)]
+pub(crate) struct GroupPlan<'a> {
+    /// Filters in evaluation order
+    pub filters: Vec<FilterPlan<'a>>,
+}
+
+impl GroupPlan<'_> {
+    /// True if the group cannot match any series, i.e. a lookup found no postings
+    pub fn is_empty_result(&self) -> bool {
+        self.filters.iter().any(|plan| match plan.strategy {
+            FilterStrategy::MatchNone => true,
+            FilterStrategy::Lookup => plan.estimate == Some(0),
+            _ => false,
+        })
+    }
+
+    /// True if evaluation starts from all series, as the group has no selections
+    pub fn starts_from_all_series(&self) -> bool {
+        self.filters.first().map_or(true, |plan| {
+            plan.strategy.is_subtraction() || plan.strategy == FilterStrategy::MatchAll
+        })
+    }
+}
+
+/// Plan for a selector. The groups are ORed.
+#[derive(Default)]
+pub(crate) struct SelectorPlan<'a> {
+    pub groups: Vec<GroupPlan<'a>>,
+}
+
+pub(crate) fn plan_selector<'a>(label_index: &ARTBitmap, matchers: &'a Matchers) -> SelectorPlan<'a> {
+    let mut groups = Vec::with_capacity(1 + matchers.or_matchers.len());
+    if !matchers.matchers.is_empty() {
+        groups.push(plan_group(label_index, &matchers.matchers));
+    }
+    for filters in matchers.or_matchers.iter().filter(|filters| !filters.is_empty()) {
+        groups.push(plan_group(label_index, filters));
+    }
+    SelectorPlan { groups }
+}
+
+pub(crate) fn plan_group<'a>(label_index: &ARTBitmap, filters: &'a [LabelFilter]) -> GroupPlan<'a> {
+    use FilterStrategy::*;
+
+    let mut plans: Vec<FilterPlan> = filters
+        .iter()
+        .map(|filter| plan_filter(label_index, filter))
+        .collect();
+
+    // scans only need to be counted until they are less selective than the best filter so far
+    let mut best = plans
+        .iter()
+        .filter(|plan| plan.strategy == Lookup)
+        .filter_map(|plan| plan.estimate)
+        .min()
+        .unwrap_or(u64::MAX);
+    for plan in plans.iter_mut() {
+        let prefix = match plan.strategy {
+            PrefixScan => plan.prefix.as_str(),
+            Exists | Scan => "",
+            _ => continue,
+        };
+        let estimate = prefix_cardinality(label_index, &plan.filter.label, prefix, best);
+        best = best.min(estimate);
+        plan.estimate = Some(estimate);
+    }
+
+    // selections first, most selective first. Among subtractions, lookups are cheaper
+    plans.sort_by_key(|plan| {
+        let is_subtraction = plan.strategy.is_subtraction();
+        let estimate = if is_subtraction { 0 } else { plan.estimate.unwrap_or(0) };
+        (plan.strategy == MatchAll, is_subtraction, estimate, plan.strategy)
+    });
+    GroupPlan { filters: plans }
+}
+
+fn plan_filter<'a>(label_index: &ARTBitmap, filter: &'a LabelFilter) -> FilterPlan<'a> {
+    use FilterStrategy::*;
+
+    let predicate = ValuePredicate::new(filter);
+    let matches_empty = predicate.matches("");
+    let shape = match filter.op {
+        LabelFilterOp::RegexEqual | LabelFilterOp::RegexNotEqual => analyze_regex(&filter.value),
+        _ => RegexShape::General,
+    };
+    let is_negated = matches!(filter.op, LabelFilterOp::NotEqual | LabelFilterOp::RegexNotEqual);
+
+    let mut values = Vec::new();
+    let mut prefix = String::new();
+    let mut check_values = true;
+    let strategy = match (&predicate, shape) {
+        (ValuePredicate::MatchNone, _) => MatchNone,
+        (ValuePredicate::Equal(value), _) if value.is_empty() => SubtractExists,
+        (ValuePredicate::Equal(value), _) => {
+            values.push(value.clone());
+            Lookup
+        }
+        (ValuePredicate::NotEqual(value), _) if value.is_empty() => Exists,
+        (ValuePredicate::NotEqual(value), _) => {
+            values.push(value.clone());
+            SubtractLookup
+        }
+        (_, RegexShape::Any) => if is_negated { MatchNone } else { MatchAll },
+        (_, RegexShape::NonEmpty) => if is_negated { SubtractExists } else { Exists },
+        // a literal set including the empty value also matches missing labels, so it cannot be
+        // evaluated by lookups alone
+        (_, RegexShape::Literals(literals)) if !literals.iter().any(|v| v.is_empty()) => {
+            values = literals;
+            if is_negated { SubtractLookup } else { Lookup }
+        }
+        (_, RegexShape::Prefix { prefix: literal_prefix, any_suffix }) => {
+            prefix = literal_prefix;
+            check_values = !any_suffix;
+            if is_negated { SubtractPrefixScan } else { PrefixScan }
+        }
+        _ => if matches_empty { SubtractScan } else { Scan },
+    };
+
+    // scans are estimated by `plan_group`, which knows the estimates of the other filters
+    let label = &filter.label;
+    let estimate = match strategy {
+        Lookup | SubtractLookup => Some(values
+            .iter()
+            .map(|value| lookup_cardinality(label_index, label, value))
+            .sum()),
+        MatchNone | MatchAll => Some(0),
+        _ => None,
+    };
+
+    let mut plan = FilterPlan::new(filter, predicate, strategy);
+    plan.values = values;
+    plan.prefix = prefix;
+    plan.check_values = check_values;
+    plan.estimate = estimate;
+    plan
+}
+
+fn lookup_cardinality(label_index: &ARTBitmap, label: &str, value: &str) -> u64 {
+    let key = get_key_for_label_value(label, value);
+    label_index.get(key.as_bytes()).map_or(0, |bmp| bmp.cardinality())
+}
+
+/// The most values of a label counted to estimate a scan.
+const MAX_ESTIMATED_VALUES: usize = 1000;
+
+/// Number of series having a value of `label` starting with `prefix`, i.e. an upper bound on the
+/// number selected by a scan. Counting stops once the count exceeds `limit`, or after
+/// `MAX_ESTIMATED_VALUES` values.
+fn prefix_cardinality(label_index: &ARTBitmap, label: &str, prefix: &str, limit: u64) -> u64 {
+    let mut count = 0;
+    let mut values = 0;
+    for_each_label_value(label_index, label, prefix, |_, bmp| {
+        count += bmp.cardinality();
+        values += 1;
+        count <= limit && values < MAX_ESTIMATED_VALUES
+    });
+    count
+}
+
+pub(crate) fn execute_selector_plan(inner: &IndexInner, plan: &SelectorPlan, dest: &mut Bitmap64) {
+    for group in plan.groups.iter() {
+        let ids = execute_group_plan(inner, group);
+        dest.or_inplace(&ids);
+    }
+}
+
+pub(crate) fn execute_group_plan(inner: &IndexInner, plan: &GroupPlan) -> Bitmap64 {
+    if plan.is_empty_result() {
+        return Bitmap64::new();
+    }
+
+    let mut filters = plan.filters
+        .iter()
+        .filter(|filter| filter.strategy != FilterStrategy::MatchAll);
+    let mut acc = if plan.starts_from_all_series() {
+        inner.series_ids()
+    } else {
+        // checked by starts_from_all_series
+        let first = filters.next().unwrap();
+        select(&inner.label_index, first)
+    };
+
+    for filter in filters {
+        if acc.is_empty() {
+            break;
+        }
+        if filter.strategy.is_subtraction() {
+            subtract(&inner.label_index, filter, &mut acc);
+        } else {
+            acc.and_inplace(&select(&inner.label_index, filter));
+        }
+    }
+    acc
+}
+
+fn select(label_index: &ARTBitmap, plan: &FilterPlan) -> Bitmap64 {
+    let mut result = Bitmap64::new();
+    let label = &plan.filter.label;
+    match plan.strategy {
+        FilterStrategy::Lookup => {
+            for value in plan.values.iter() {
+                let key = get_key_for_label_value(label, value);
+                if let Some(bmp) = label_index.get(key.as_bytes()) {
+                    result.or_inplace(bmp);
+                }
+            }
+        }
+        FilterStrategy::PrefixScan => {
+            for_each_label_value(label_index, label, &plan.prefix, |value, bmp| {
+                if !plan.check_values || plan.predicate.matches(value) {
+                    result.or_inplace(bmp);
+                }
+                true
+            });
+        }
+        FilterStrategy::Exists => {
+            for_each_label_value(label_index, label, "", |_, bmp| {
+                result.or_inplace(bmp);
+                true
+            });
+        }
+        _ => {
+            for_each_label_value(label_index, label, "", |value, bmp| {
+                if plan.predicate.matches(value) {
+                    result.or_inplace(bmp);
+                }
+                true
+            });
+        }
+    }
+    result
+}
+
+fn subtract(label_index: &ARTBitmap, plan: &FilterPlan, acc: &mut Bitmap64) {
+    let label = &plan.filter.label;
+    match plan.strategy {
+        FilterStrategy::SubtractLookup => {
+            for value in plan.values.iter() {
+                let key = get_key_for_label_value(label, value);
+                if let Some(bmp) = label_index.get(key.as_bytes()) {
+                    acc.andnot_inplace(bmp);
+                }
+            }
+        }
+        FilterStrategy::SubtractPrefixScan => {
+            for_each_label_value(label_index, label, &plan.prefix, |value, bmp| {
+                if !plan.check_values || !plan.predicate.matches(value) {
+                    acc.andnot_inplace(bmp);
+                }
+                !acc.is_empty()
+            });
+        }
+        FilterStrategy::SubtractExists => {
+            for_each_label_value(label_index, label, "", |_, bmp| {
+                acc.andnot_inplace(bmp);
+                !acc.is_empty()
+            });
+        }
+        _ => {
+            for_each_label_value(label_index, label, "", |value, bmp| {
+                if !plan.predicate.matches(value) {
+                    acc.andnot_inplace(bmp);
+                }
+                !acc.is_empty()
+            });
+        }
+    }
+}
+
+/// Calls `f` with each value of `label` starting with `prefix` and its postings, until `f`
+/// returns false.
+fn for_each_label_value(
+    label_index: &ARTBitmap,
+    label: &str,
+    prefix: &str,
+    mut f: impl FnMut(&str, &Bitmap64) -> bool
+) {
+    let label_prefix = get_key_for_label_prefix(label);
+    let start_pos = label_prefix.len();
+    let mut key_prefix = label_prefix;
+    key_prefix.push_str(prefix);
+    for (key, bmp) in label_index.prefix(key_prefix.as_bytes()) {
+        if !f(key.sub_string(start_pos), bmp) {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::test_utils::create_index;
+    use crate::module::arg_parse::parse_series_selector;
+
+    fn select_ids(inner: &IndexInner, selector: &str) -> Vec<u64> {
+        let matchers = parse_series_selector(selector).unwrap();
+        let plan = plan_selector(&inner.label_index, &matchers);
+        let mut dest = Bitmap64::new();
+        execute_selector_plan(inner, &plan, &mut dest);
+        dest.iter().collect()
+    }
+
+    #[test]
+    fn test_equality() {
+        let inner = create_index();
+        assert_eq!(select_ids(&inner, r#"latency{env="prod"}"#), vec![1]);
+        assert_eq!(select_ids(&inner, r#"latency{env="staging"}"#), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn test_negative_matchers_include_missing_labels() {
+        let inner = create_index();
+        assert_eq!(select_ids(&inner, r#"latency{env!="prod"}"#), vec![2, 3]);
+        assert_eq!(select_ids(&inner, r#"latency{env=""}"#), vec![3]);
+        assert_eq!(select_ids(&inner, r#"latency{env!=""}"#), vec![1, 2]);
+        assert_eq!(select_ids(&inner, r#"latency{region!~"us-.*"}"#), vec![3]);
+    }
+
+    #[test]
+    fn test_regex_is_anchored() {
+        let inner = create_index();
+        assert_eq!(select_ids(&inner, r#"latency{region=~"us-east.*"}"#), vec![1, 2]);
+        assert_eq!(select_ids(&inner, r#"latency{region=~"east"}"#), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn test_or_groups() {
+        let inner = create_index();
+        assert_eq!(select_ids(&inner, r#"{env="qa" or region="eu-west1"}"#), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_plan_order() {
+        let inner = create_index();
+        let matchers = parse_series_selector(r#"errors{region=~"us-.*", env!="qa"}"#).unwrap();
+        let plan = plan_selector(&inner.label_index, &matchers);
+        let group = &plan.groups[0];
+        let strategies: Vec<_> = group.filters.iter().map(|plan| plan.strategy).collect();
+        assert_eq!(
+            strategies,
+            vec![FilterStrategy::Lookup, FilterStrategy::PrefixScan, FilterStrategy::SubtractLookup]
+        );
+        assert_eq!(group.filters[0].filter.label, "__name__");
+        assert_eq!(group.filters[0].estimate, Some(1));
+        assert!(!group.starts_from_all_series());
+        // subtracted lookups are estimated, subtracted scans are not
+        assert_eq!(group.filters[2].estimate, Some(1));
+        let matchers = parse_series_selector(r#"errors{region!~"us-.*"}"#).unwrap();
+        let plan = plan_selector(&inner.label_index, &matchers);
+        assert_eq!(plan.groups[0].filters[1].strategy, FilterStrategy::SubtractPrefixScan);
+        assert_eq!(plan.groups[0].filters[1].estimate, None);
+    }
+
+    #[test]
+    fn test_scan_estimate_stops_at_best_estimate() {
+        let inner = create_index();
+        // `errors` has 1 series, so counting stops at the first region value, with 2 series
+        let matchers = parse_series_selector(r#"errors{region=~"us-.*"}"#).unwrap();
+        let plan = plan_selector(&inner.label_index, &matchers);
+        let scan = &plan.groups[0].filters[1];
+        assert_eq!(scan.strategy, FilterStrategy::PrefixScan);
+        assert_eq!(scan.estimate, Some(2));
+
+        // without a lookup, the first scan is counted in full
+        let matchers = parse_series_selector(r#"{region=~"us-.*"}"#).unwrap();
+        let plan = plan_selector(&inner.label_index, &matchers);
+        assert_eq!(plan.groups[0].filters[0].estimate, Some(3));
+        assert_eq!(select_ids(&inner, r#"errors{region=~"us-.*"}"#), vec![4]);
+    }
+
+    #[test]
+    fn test_empty_lookup_short_circuits() {
+        let inner = create_index();
+        let matchers = parse_series_selector(r#"latency{env="staging", region=~".+"}"#).unwrap();
+        let plan = plan_selector(&inner.label_index, &matchers);
+        assert!(plan.groups[0].is_empty_result());
+    }
+
+    #[test]
+    fn test_regex_alternation_becomes_lookups() {
+        let inner = create_index();
+        let matchers = parse_series_selector(r#"latency{env=~"prod|qa|staging"}"#).unwrap();
+        let plan = plan_selector(&inner.label_index, &matchers);
+        let filter = plan.groups[0].filters.iter().find(|plan| plan.filter.label == "env").unwrap();
+        assert_eq!(filter.strategy, FilterStrategy::Lookup);
+        assert_eq!(filter.values, vec!["prod", "qa", "staging"]);
+        assert_eq!(filter.estimate, Some(3));
+
+        assert_eq!(select_ids(&inner, r#"latency{env=~"prod|qa"}"#), vec![1, 2]);
+        assert_eq!(select_ids(&inner, r#"latency{env!~"prod|staging"}"#), vec![2, 3]);
+        // the empty alternative also matches series without the label
+        assert_eq!(select_ids(&inner, r#"latency{env=~"qa|"}"#), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_regex_prefix_scan() {
+        let inner = create_index();
+        assert_eq!(select_ids(&inner, r#"latency{region=~"us-.*1"}"#), vec![1]);
+        assert_eq!(select_ids(&inner, r#"latency{region!~"us-.*1"}"#), vec![2, 3]);
+        assert_eq!(select_ids(&inner, r#"latency{region!~"eu-.*"}"#), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_regex_exists() {
+        let inner = create_index();
+        let matchers = parse_series_selector(r#"latency{env=~".+", region=~".*"}"#).unwrap();
+        let plan = plan_selector(&inner.label_index, &matchers);
+        let strategies: Vec<_> = plan.groups[0].filters.iter().map(|plan| plan.strategy).collect();
+        assert_eq!(
+            strategies,
+            vec![FilterStrategy::Lookup, FilterStrategy::Exists, FilterStrategy::MatchAll]
+        );
+
+        assert_eq!(select_ids(&inner, r#"latency{env=~".+"}"#), vec![1, 2]);
+        assert_eq!(select_ids(&inner, r#"latency{env!~".+"}"#), vec![3]);
+        assert_eq!(select_ids(&inner, r#"latency{env=~".*"}"#), vec![1, 2, 3]);
+        assert_eq!(select_ids(&inner, r#"latency{env!~".*"}"#), Vec::<u64>::new());
+    }
+}
@@ -0,0 +1,215 @@
+//! Analysis of label regexes, so that common shapes can be evaluated with index lookups instead
+//! of running the regex against every value of a label.
+use regex_syntax::hir::{Class, Hir, HirKind};
+use regex_syntax::ParserBuilder;
+
+/// Max number of distinct values a regex may match to be evaluated as point lookups
+const MAX_LITERAL_VALUES: usize = 64;
+
+/// Number of unicode scalar values, i.e. all chars except surrogates
+const UNICODE_SCALAR_COUNT: u32 = 0x110000 - 0x800;
+
+/// Shape of a (fully anchored) label regex.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum RegexShape {
+    /// Matches exactly the given values, e.g. `a|b|c` or `api_v(1|2)`
+    Literals(Vec<String>),
+    /// Matches values starting with a literal prefix. If `any_suffix` is true, every such value
+    /// matches (e.g. `/api/v1/.*`), otherwise values under the prefix must still be checked
+    Prefix { prefix: String, any_suffix: bool },
+    /// `.+`: matches any non-empty value
+    NonEmpty,
+    /// `.*`: matches any value
+    Any,
+    /// Anything else
+    General,
+}
+
+/// Analyzes `pattern` as matched against a label value. Patterns are matched in full and `.`
+/// matches newlines, as in Prometheus.
+pub(crate) fn analyze_regex(pattern: &str) -> RegexShape {
+    let Ok(hir) = ParserBuilder::new().dot_matches_new_line(true).build().parse(pattern) else {
+        return RegexShape::General;
+    };
+    let hir = strip_captures(&hir);
+
+    if let Some(values) = exact_strings(hir, MAX_LITERAL_VALUES) {
+        return RegexShape::Literals(values);
+    }
+
+    match any_repetition_min(hir) {
+        Some(0) => return RegexShape::Any,
+        Some(1) => return RegexShape::NonEmpty,
+        _ => {}
+    }
+
+    if let HirKind::Concat(items) = hir.kind() {
+        let mut prefix = String::new();
+        let mut rest = items.as_slice();
+        while let Some((first, tail)) = rest.split_first() {
+            match exact_strings(strip_captures(first), 1) {
+                Some(values) => {
+                    prefix.push_str(&values[0]);
+                    rest = tail;
+                }
+                None => break,
+            }
+        }
+        if !prefix.is_empty() {
+            let any_suffix = rest
+                .iter()
+                .all(|item| any_repetition_min(strip_captures(item)) == Some(0));
+            return RegexShape::Prefix { prefix, any_suffix };
+        }
+    }
+
+    RegexShape::General
+}
+
+fn strip_captures(hir: &Hir) -> &Hir {
+    match hir.kind() {
+        HirKind::Capture(capture) => strip_captures(&capture.sub),
+        _ => hir,
+    }
+}
+
+/// If `hir` is a repetition of any char without an upper bound (e.g. `.*` or `.+`), returns its
+/// min count.
+fn any_repetition_min(hir: &Hir) -> Option<u32> {
+    let HirKind::Repetition(rep) = hir.kind() else {
+        return None;
+    };
+    if rep.max.is_some() {
+        return None;
+    }
+    match strip_captures(&rep.sub).kind() {
+        HirKind::Class(Class::Unicode(class)) => {
+            let count: u32 = class
+                .ranges()
+                .iter()
+                .map(|range| range.end() as u32 - range.start() as u32 + 1)
+                .sum();
+            (count >= UNICODE_SCALAR_COUNT).then_some(rep.min)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the finite set of strings matched by `hir`, if it has at most `limit` of them.
+fn exact_strings(hir: &Hir, limit: usize) -> Option<Vec<String>> {
+    match hir.kind() {
+        HirKind::Empty => Some(vec![String::new()]),
+        HirKind::Literal(literal) => {
+            let value = std::str::from_utf8(&literal.0).ok()?;
+            Some(vec![value.to_string()])
+        }
+        HirKind::Class(Class::Unicode(class)) => {
+            let mut values = Vec::new();
+            for range in class.ranges() {
+                for c in range.start()..=range.end() {
+                    if values.len() == limit {
+                        return None;
+                    }
+                    values.push(c.to_string());
+                }
+            }
+            Some(values)
+        }
+        HirKind::Capture(capture) => exact_strings(&capture.sub, limit),
+        HirKind::Concat(items) => {
+            let mut result = vec![String::new()];
+            for item in items {
+                let suffixes = exact_strings(item, limit)?;
+                if result.len() * suffixes.len() > limit {
+                    return None;
+                }
+                result = result
+                    .iter()
+                    .flat_map(|prefix| suffixes.iter().map(move |suffix| format!("{prefix}{suffix}")))
+                    .collect();
+            }
+            Some(result)
+        }
+        HirKind::Alternation(items) => {
+            let mut result: Vec<String> = Vec::new();
+            for item in items {
+                result.extend(exact_strings(item, limit)?);
+                if result.len() > limit {
+                    return None;
+                }
+            }
+            result.sort();
+            result.dedup();
+            Some(result)
+        }
+        HirKind::Repetition(rep) if rep.max == Some(rep.min) || (rep.min == 0 && rep.max == Some(1)) => {
+            let values = exact_strings(&rep.sub, limit)?;
+            if rep.min == 0 {
+                // optional
+                let mut result = vec![String::new()];
+                result.extend(values);
+                return (result.len() <= limit).then_some(result);
+            }
+            let mut result = vec![String::new()];
+            for _ in 0..rep.min {
+                if result.len() * values.len() > limit {
+                    return None;
+                }
+                result = result
+                    .iter()
+                    .flat_map(|prefix| values.iter().map(move |value| format!("{prefix}{value}")))
+                    .collect();
+            }
+            Some(result)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn literals(values: &[&str]) -> RegexShape {
+        RegexShape::Literals(values.iter().map(|v| v.to_string()).collect())
+    }
+
+    #[test]
+    fn test_literals() {
+        assert_eq!(analyze_regex("foo"), literals(&["foo"]));
+        assert_eq!(analyze_regex("foo|bar|baz"), literals(&["bar", "baz", "foo"]));
+        assert_eq!(analyze_regex("api_v(1|2)"), literals(&["api_v1", "api_v2"]));
+        assert_eq!(analyze_regex("(prod|staging)?"), literals(&["", "prod", "staging"]));
+        assert_eq!(analyze_regex("x[ab]"), literals(&["xa", "xb"]));
+    }
+
+    #[test]
+    fn test_any() {
+        assert_eq!(analyze_regex(".*"), RegexShape::Any);
+        assert_eq!(analyze_regex("(.*)"), RegexShape::Any);
+        assert_eq!(analyze_regex(".+"), RegexShape::NonEmpty);
+    }
+
+    #[test]
+    fn test_prefix() {
+        assert_eq!(
+            analyze_regex("/api/v1/.*"),
+            RegexShape::Prefix { prefix: "/api/v1/".to_string(), any_suffix: true }
+        );
+        assert_eq!(
+            analyze_regex("/api/v1/.+"),
+            RegexShape::Prefix { prefix: "/api/v1/".to_string(), any_suffix: false }
+        );
+        assert_eq!(
+            analyze_regex("us-.*-1"),
+            RegexShape::Prefix { prefix: "us-".to_string(), any_suffix: false }
+        );
+    }
+
+    #[test]
+    fn test_general() {
+        assert_eq!(analyze_regex(".*foo"), RegexShape::General);
+        assert_eq!(analyze_regex("[a-z]+"), RegexShape::General);
+        assert_eq!(analyze_regex("(foo"), RegexShape::General);
+    }
+}
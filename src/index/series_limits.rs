@@ -0,0 +1,320 @@
+//! Limits on the number of series and on series churn, enforced when a series is created.
+//!
+//! Limits apply to each db separately. A value of 0 means unlimited.
+use crate::common::current_time_millis;
+use crate::config::get_global_settings;
+use crate::error::{TsdbError, TsdbResult};
+use ahash::AHashMap;
+use std::sync::{LazyLock, Mutex, RwLock};
+
+const MILLIS_PER_MINUTE: i64 = 60 * 1000;
+
+/// Max number of metric names with their own counters in a db. Further metric names are counted
+/// under `OTHER_METRIC_NAME`, so that clients creating many metric names cannot grow the counters
+/// without bound.
+pub const MAX_TRACKED_METRIC_NAMES: usize = 1000;
+
+/// Name under which the series of untracked metric names are counted.
+pub const OTHER_METRIC_NAME: &str = "__other__";
+
+/// Configured series limits.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SeriesLimits {
+    /// Max number of series in a db
+    pub max_series: usize,
+    /// Max number of series per metric name in a db, unless overridden in `metric_quotas`
+    pub max_series_per_metric: usize,
+    /// Max number of series created per minute in a db
+    pub max_new_series_per_minute: usize,
+    /// Per metric name overrides of `max_series_per_metric`
+    pub metric_quotas: AHashMap<String, usize>,
+}
+
+impl SeriesLimits {
+    pub fn from_settings() -> Self {
+        let settings = get_global_settings();
+        Self {
+            max_series: settings.max_series_per_db,
+            max_series_per_metric: settings.max_series_per_metric,
+            max_new_series_per_minute: settings.max_new_series_per_minute,
+            metric_quotas: Default::default(),
+        }
+    }
+
+    pub fn max_series_for_metric(&self, metric_name: &str) -> usize {
+        self.metric_quotas
+            .get(metric_name)
+            .copied()
+            .unwrap_or(self.max_series_per_metric)
+    }
+}
+
+/// The limit exceeded by a rejected series.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeriesLimitKind {
+    Series,
+    SeriesPerMetric,
+    NewSeriesPerMinute,
+}
+
+impl SeriesLimitKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SeriesLimitKind::Series => "maxSeries",
+            SeriesLimitKind::SeriesPerMetric => "maxSeriesPerMetric",
+            SeriesLimitKind::NewSeriesPerMinute => "maxNewSeriesPerMinute",
+        }
+    }
+}
+
+/// Counts of series created and rejected for a metric name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetricSeriesCounters {
+    pub created: u64,
+    pub rejected: u64,
+}
+
+/// Counts of rejected series by limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SeriesLimitRejections {
+    pub series: u64,
+    pub series_per_metric: u64,
+    pub new_series_per_minute: u64,
+}
+
+#[derive(Default)]
+struct DbSeriesCounters {
+    /// Start of the current one-minute window, in minutes since the epoch
+    window_minute: i64,
+    /// Number of series created in the current window
+    window_created: usize,
+    rejections: SeriesLimitRejections,
+    by_metric_name: AHashMap<String, MetricSeriesCounters>,
+}
+
+/// Tracks series creation against the configured limits.
+#[derive(Default)]
+pub struct SeriesLimiter {
+    limits: RwLock<SeriesLimits>,
+    counters: Mutex<AHashMap<u32, DbSeriesCounters>>,
+}
+
+impl SeriesLimiter {
+    pub fn new(limits: SeriesLimits) -> Self {
+        Self {
+            limits: RwLock::new(limits),
+            counters: Default::default(),
+        }
+    }
+
+    pub fn limits(&self) -> SeriesLimits {
+        self.limits.read().unwrap().clone()
+    }
+
+    pub fn set_limits(&self, limits: SeriesLimits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    /// Checks whether a series of `metric_name` can be created in `db`, given the number of
+    /// series in the db and of the metric name, and records the creation or rejection.
+    pub fn admit(
+        &self,
+        db: u32,
+        metric_name: &str,
+        series_count: usize,
+        metric_series_count: usize,
+        now: i64
+    ) -> TsdbResult<()> {
+        let limits = self.limits.read().unwrap();
+        let mut counters = self.counters.lock().unwrap();
+        let db_counters = counters.entry(db).or_default();
+
+        let minute = now.div_euclid(MILLIS_PER_MINUTE);
+        if db_counters.window_minute != minute {
+            db_counters.window_minute = minute;
+            db_counters.window_created = 0;
+        }
+
+        let max_for_metric = limits.max_series_for_metric(metric_name);
+        let exceeded = if limits.max_series > 0 && series_count >= limits.max_series {
+            Some((SeriesLimitKind::Series, limits.max_series))
+        } else if max_for_metric > 0 && metric_series_count >= max_for_metric {
+            Some((SeriesLimitKind::SeriesPerMetric, max_for_metric))
+        } else if limits.max_new_series_per_minute > 0 &&
+            db_counters.window_created >= limits.max_new_series_per_minute {
+            Some((SeriesLimitKind::NewSeriesPerMinute, limits.max_new_series_per_minute))
+        } else {
+            None
+        };
+
+        let by_metric_name = &mut db_counters.by_metric_name;
+        let counted_name = if by_metric_name.contains_key(metric_name) ||
+            by_metric_name.len() < MAX_TRACKED_METRIC_NAMES {
+            metric_name
+        } else {
+            OTHER_METRIC_NAME
+        };
+        let metric_counters = match by_metric_name.get_mut(counted_name) {
+            Some(metric_counters) => metric_counters,
+            None => by_metric_name.entry(counted_name.to_string()).or_default(),
+        };
+
+        let Some((kind, limit)) = exceeded else {
+            metric_counters.created += 1;
+            db_counters.window_created += 1;
+            return Ok(());
+        };
+
+        metric_counters.rejected += 1;
+        let rejections = &mut db_counters.rejections;
+        let msg = match kind {
+            SeriesLimitKind::Series => {
+                rejections.series += 1;
+                format!("the number of series has reached the limit of {limit}")
+            }
+            SeriesLimitKind::SeriesPerMetric => {
+                rejections.series_per_metric += 1;
+                format!("the number of series of metric '{metric_name}' has reached the limit of {limit}")
+            }
+            SeriesLimitKind::NewSeriesPerMinute => {
+                rejections.new_series_per_minute += 1;
+                format!("the number of series created in the last minute has reached the limit of {limit}")
+            }
+        };
+        Err(TsdbError::SeriesLimitExceeded(msg))
+    }
+
+    pub fn rejections(&self, db: u32) -> SeriesLimitRejections {
+        let counters = self.counters.lock().unwrap();
+        counters.get(&db).map(|c| c.rejections).unwrap_or_default()
+    }
+
+    /// Returns the counters of the metric names of `db`, ordered by the number of rejected series
+    /// and then of created series, descending.
+    pub fn metric_counters(&self, db: u32, limit: usize) -> Vec<(String, MetricSeriesCounters)> {
+        let counters = self.counters.lock().unwrap();
+        let Some(db_counters) = counters.get(&db) else {
+            return Vec::new();
+        };
+        let mut items: Vec<_> = db_counters.by_metric_name
+            .iter()
+            .map(|(name, counters)| (name.clone(), *counters))
+            .collect();
+        items.sort_by(|(a_name, a), (b_name, b)| {
+            b.rejected.cmp(&a.rejected)
+                .then(b.created.cmp(&a.created))
+                .then_with(|| a_name.cmp(b_name))
+        });
+        items.truncate(limit);
+        items
+    }
+}
+
+static SERIES_LIMITER: LazyLock<SeriesLimiter> = LazyLock::new(|| {
+    SeriesLimiter::new(SeriesLimits::from_settings())
+});
+
+pub fn get_series_limiter() -> &'static SeriesLimiter {
+    &SERIES_LIMITER
+}
+
+/// Checks the limits for creating a series of `metric_name` in `db`.
+pub fn admit_new_series(db: u32, metric_name: &str, series_count: usize, metric_series_count: usize) -> TsdbResult<()> {
+    let now = current_time_millis();
+    SERIES_LIMITER.admit(db, metric_name, series_count, metric_series_count, now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000_000;
+
+    fn limiter(max_series: usize, max_series_per_metric: usize, max_new_series_per_minute: usize) -> SeriesLimiter {
+        SeriesLimiter::new(SeriesLimits {
+            max_series,
+            max_series_per_metric,
+            max_new_series_per_minute,
+            metric_quotas: Default::default(),
+        })
+    }
+
+    #[test]
+    fn test_unlimited_by_default() {
+        let limiter = SeriesLimiter::new(SeriesLimits::default());
+        for i in 0..1000 {
+            assert!(limiter.admit(0, "latency", i, i, NOW).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_max_series() {
+        let limiter = limiter(10, 0, 0);
+        assert!(limiter.admit(0, "latency", 9, 0, NOW).is_ok());
+        let err = limiter.admit(0, "latency", 10, 0, NOW).unwrap_err();
+        assert!(matches!(err, TsdbError::SeriesLimitExceeded(_)));
+        assert_eq!(limiter.rejections(0).series, 1);
+        // limits apply per db
+        assert_eq!(limiter.rejections(1).series, 0);
+    }
+
+    #[test]
+    fn test_metric_quotas() {
+        let mut limits = SeriesLimits {
+            max_series_per_metric: 5,
+            ..Default::default()
+        };
+        limits.metric_quotas.insert("requests".to_string(), 100);
+        let limiter = SeriesLimiter::new(limits);
+
+        assert!(limiter.admit(0, "latency", 5, 5, NOW).is_err());
+        assert!(limiter.admit(0, "requests", 5, 5, NOW).is_ok());
+        assert!(limiter.admit(0, "requests", 100, 100, NOW).is_err());
+        assert_eq!(limiter.rejections(0).series_per_metric, 2);
+
+        let counters = limiter.metric_counters(0, 10);
+        assert_eq!(counters[0].0, "latency");
+        assert_eq!(counters[0].1, MetricSeriesCounters { created: 0, rejected: 1 });
+        assert_eq!(counters[1].0, "requests");
+        assert_eq!(counters[1].1, MetricSeriesCounters { created: 1, rejected: 1 });
+    }
+
+    #[test]
+    fn test_new_series_per_minute() {
+        let limiter = limiter(0, 0, 2);
+        assert!(limiter.admit(0, "latency", 0, 0, NOW).is_ok());
+        assert!(limiter.admit(0, "latency", 1, 1, NOW + 1).is_ok());
+        assert!(limiter.admit(0, "latency", 2, 2, NOW + 2).is_err());
+        assert_eq!(limiter.rejections(0).new_series_per_minute, 1);
+
+        // a new window starts every minute
+        let next_minute = (NOW / MILLIS_PER_MINUTE + 1) * MILLIS_PER_MINUTE;
+        assert!(limiter.admit(0, "latency", 2, 2, next_minute).is_ok());
+    }
+
+    #[test]
+    fn test_metric_names_are_capped() {
+        let limiter = limiter(0, 0, 0);
+        for i in 0..MAX_TRACKED_METRIC_NAMES + 10 {
+            let metric_name = format!("metric_{i}");
+            assert!(limiter.admit(0, &metric_name, i, 0, NOW).is_ok());
+        }
+        // names already tracked keep their own counters
+        assert!(limiter.admit(0, "metric_0", 0, 1, NOW).is_ok());
+
+        let counters = limiter.metric_counters(0, usize::MAX);
+        assert_eq!(counters.len(), MAX_TRACKED_METRIC_NAMES + 1);
+        assert_eq!(counters[0].0, OTHER_METRIC_NAME);
+        assert_eq!(counters[0].1, MetricSeriesCounters { created: 10, rejected: 0 });
+        assert_eq!(counters[1].0, "metric_0");
+        assert_eq!(counters[1].1.created, 2);
+    }
+
+    #[test]
+    fn test_set_limits() {
+        let limiter = limiter(1, 0, 0);
+        assert!(limiter.admit(0, "latency", 1, 1, NOW).is_err());
+        limiter.set_limits(SeriesLimits::default());
+        assert!(limiter.admit(0, "latency", 1, 1, NOW).is_ok());
+    }
+}
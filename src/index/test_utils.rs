@@ -0,0 +1,28 @@
+//! Fixtures shared by the tests of the index modules.
+use super::timeseries_index::IndexInner;
+use crate::common::InternedLabel;
+use crate::storage::time_series::TimeSeries;
+
+/// Indexes series given as (id, metric name, labels), keyed by `series:{id}`.
+pub(super) fn index_series(inner: &mut IndexInner, series: &[(u64, &str, &[(&str, &str)])]) {
+    for &(id, metric_name, labels) in series {
+        let mut ts = TimeSeries::new();
+        ts.id = id;
+        ts.metric_name = metric_name.to_string();
+        ts.labels = labels.iter().map(|(name, value)| InternedLabel::new(name, value)).collect();
+        inner.index_time_series(&ts, format!("series:{id}").as_bytes());
+    }
+}
+
+/// Returns an index of 3 `latency` series and 1 `errors` series, with `region` and `env` labels.
+/// Series 3 has no `env`.
+pub(super) fn create_index() -> IndexInner {
+    let mut inner = IndexInner::new();
+    index_series(&mut inner, &[
+        (1, "latency", &[("region", "us-east1"), ("env", "prod")]),
+        (2, "latency", &[("region", "us-east2"), ("env", "qa")]),
+        (3, "latency", &[("region", "eu-west1")]),
+        (4, "errors", &[("region", "us-east1"), ("env", "prod")]),
+    ]);
+    inner
+}
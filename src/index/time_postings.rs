@@ -0,0 +1,282 @@
+use crate::common::types::Timestamp;
+use croaring::Bitmap64;
+use metricsql_common::hash::IntMap;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+/// Postings of series ids by time bucket. Each series has the range of buckets overlapping the
+/// range [first_timestamp, last_timestamp] of its samples. This over-approximates series with gaps,
+/// but allows matching to exclude series with no samples in a query range without opening their
+/// keys.
+///
+/// A series is posted only under the buckets its range starts and ends in, rather than under every
+/// bucket of its range, so that long-lived series cost the same as short ones.
+#[derive(Debug)]
+pub(crate) struct TimePostings {
+    bucket_size: i64,
+    /// The first and last bucket of each series
+    ranges: IntMap<u64, (Timestamp, Timestamp)>,
+    /// Series ids by the first bucket of their range
+    starts: BTreeMap<Timestamp, Bitmap64>,
+    /// Series ids by the last bucket of their range
+    ends: BTreeMap<Timestamp, Bitmap64>,
+}
+
+impl TimePostings {
+    pub fn new(bucket_size: i64) -> Self {
+        Self {
+            bucket_size: bucket_size.max(1),
+            ranges: IntMap::default(),
+            starts: BTreeMap::new(),
+            ends: BTreeMap::new(),
+        }
+    }
+
+    pub fn bucket_size(&self) -> i64 {
+        self.bucket_size
+    }
+
+    /// Number of series with a time range.
+    pub fn series_count(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.ranges.clear();
+        self.starts.clear();
+        self.ends.clear();
+    }
+
+    #[inline]
+    fn bucket_start(&self, ts: Timestamp) -> Timestamp {
+        bucket_start(ts, self.bucket_size)
+    }
+
+    /// Sets the range of `id` to the buckets overlapping [`start`, `end`]
+    pub fn add_range(&mut self, id: u64, start: Timestamp, end: Timestamp) {
+        self.remove(id);
+        let first = self.bucket_start(start);
+        let last = self.bucket_start(end);
+        self.ranges.insert(id, (first, last));
+        self.starts.entry(first).or_default().add(id);
+        self.ends.entry(last).or_default().add(id);
+    }
+
+    /// Remove `id` from the postings.
+    pub fn remove(&mut self, id: u64) {
+        let Some((first, last)) = self.ranges.remove(&id) else {
+            return;
+        };
+        remove_posting(&mut self.starts, first, id);
+        remove_posting(&mut self.ends, last, id);
+    }
+
+    /// Update the range of `id` after its time range changes from `old` to `new`.
+    pub fn update(&mut self, id: u64, old: Option<(Timestamp, Timestamp)>, new: Option<(Timestamp, Timestamp)>) {
+        if !time_range_buckets_changed(old, new, self.bucket_size) {
+            return;
+        }
+        match new {
+            Some((start, end)) => self.add_range(id, start, end),
+            None => self.remove(id),
+        }
+    }
+
+    /// Returns each series with its first and last bucket.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, Timestamp, Timestamp)> + '_ {
+        self.ranges.iter().map(|(id, (first, last))| (*id, *first, *last))
+    }
+
+    /// Returns the ids of series having samples in buckets overlapping [`start`, `end`], i.e. the
+    /// series whose range ends at or after the first bucket, less those starting after the last.
+    pub fn ids_in_range(&self, start: Timestamp, end: Timestamp) -> Bitmap64 {
+        let first = self.bucket_start(start);
+        let last = self.bucket_start(end);
+        if first > last {
+            return Bitmap64::new();
+        }
+        self.posted_in_buckets(first, last, None)
+    }
+
+    /// Returns the ids of `candidates` having samples in buckets overlapping [`start`, `end`]. If
+    /// there are fewer candidates than series whose range ends in or after the first bucket, the
+    /// range of each candidate is checked instead of combining the postings of every bucket.
+    pub fn filter_in_range(&self, candidates: &Bitmap64, start: Timestamp, end: Timestamp) -> Bitmap64 {
+        let first = self.bucket_start(start);
+        let last = self.bucket_start(end);
+        if first > last || candidates.is_empty() {
+            return Bitmap64::new();
+        }
+        let count = candidates.cardinality();
+        let mut posted = 0;
+        let few_candidates = self.ends.range(first..).any(|(_, bmp)| {
+            posted += bmp.cardinality();
+            posted > count
+        });
+        if !few_candidates {
+            return self.posted_in_buckets(first, last, Some(candidates));
+        }
+        candidates
+            .iter()
+            .filter(|id| {
+                self.ranges
+                    .get(id)
+                    .is_some_and(|&(series_first, series_last)| series_first <= last && series_last >= first)
+            })
+            .collect()
+    }
+
+    /// Returns the series whose range ends at or after the bucket `first`, less those starting
+    /// after the bucket `last`, restricted to `candidates` if given.
+    fn posted_in_buckets(&self, first: Timestamp, last: Timestamp, candidates: Option<&Bitmap64>) -> Bitmap64 {
+        let mut result = Bitmap64::new();
+        for (_, bmp) in self.ends.range(first..) {
+            result.or_inplace(bmp);
+        }
+        if let Some(candidates) = candidates {
+            result.and_inplace(candidates);
+        }
+        for (_, bmp) in self.starts.range((Bound::Excluded(last), Bound::Unbounded)) {
+            if result.is_empty() {
+                break;
+            }
+            result.andnot_inplace(bmp);
+        }
+        result
+    }
+}
+
+fn remove_posting(postings: &mut BTreeMap<Timestamp, Bitmap64>, bucket: Timestamp, id: u64) {
+    if let Some(bmp) = postings.get_mut(&bucket) {
+        bmp.remove(id);
+        if bmp.is_empty() {
+            postings.remove(&bucket);
+        }
+    }
+}
+
+#[inline]
+fn bucket_start(ts: Timestamp, bucket_size: i64) -> Timestamp {
+    ts - ts.rem_euclid(bucket_size)
+}
+
+/// Returns true if going from time range `old` to `new` changes the set of buckets a series is in.
+/// Used to avoid taking the index write lock for samples landing in already indexed buckets.
+pub(crate) fn time_range_buckets_changed(
+    old: Option<(Timestamp, Timestamp)>,
+    new: Option<(Timestamp, Timestamp)>,
+    bucket_size: i64
+) -> bool {
+    let bucket_size = bucket_size.max(1);
+    match (old, new) {
+        (None, None) => false,
+        (Some((old_start, old_end)), Some((new_start, new_end))) => {
+            bucket_start(old_start, bucket_size) != bucket_start(new_start, bucket_size) ||
+                bucket_start(old_end, bucket_size) != bucket_start(new_end, bucket_size)
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(bmp: Bitmap64) -> Vec<u64> {
+        bmp.iter().collect()
+    }
+
+    #[test]
+    fn test_ids_in_range() {
+        let mut postings = TimePostings::new(100);
+        postings.add_range(1, 0, 250);
+        postings.add_range(2, 300, 350);
+        assert_eq!(postings.series_count(), 2);
+
+        assert_eq!(ids(postings.ids_in_range(120, 180)), vec![1]);
+        assert_eq!(ids(postings.ids_in_range(250, 300)), vec![1, 2]);
+        assert!(postings.ids_in_range(400, 1000).is_empty());
+        assert!(postings.ids_in_range(-100, -1).is_empty());
+    }
+
+    #[test]
+    fn test_filter_in_range() {
+        let mut postings = TimePostings::new(100);
+        for id in 1..=10 {
+            postings.add_range(id, 0, 250);
+        }
+        postings.add_range(11, 300, 350);
+        postings.add_range(12, 0, 50);
+
+        // fewer candidates than posted series, checked one by one
+        let candidates = Bitmap64::from_iter([1u64, 11, 12, 13]);
+        assert_eq!(ids(postings.filter_in_range(&candidates, 120, 180)), vec![1]);
+        assert_eq!(ids(postings.filter_in_range(&candidates, 250, 300)), vec![1, 11]);
+        assert!(postings.filter_in_range(&candidates, 400, 1000).is_empty());
+
+        // more candidates than posted series, combining postings
+        let candidates = Bitmap64::from_iter(0u64..100);
+        assert_eq!(
+            ids(postings.filter_in_range(&candidates, 120, 180)),
+            (1..=10).collect::<Vec<_>>()
+        );
+        assert_eq!(ids(postings.filter_in_range(&candidates, 300, 400)), vec![11]);
+        assert!(postings.filter_in_range(&Bitmap64::new(), 0, 1000).is_empty());
+    }
+
+    #[test]
+    fn test_long_range_is_posted_twice() {
+        let mut postings = TimePostings::new(1);
+        postings.add_range(1, 0, 1_000_000_000);
+        assert_eq!(postings.starts.len(), 1);
+        assert_eq!(postings.ends.len(), 1);
+        assert_eq!(ids(postings.ids_in_range(500_000, 500_000)), vec![1]);
+        assert!(postings.ids_in_range(1_000_000_001, 2_000_000_000).is_empty());
+    }
+
+    #[test]
+    fn test_negative_timestamps() {
+        let mut postings = TimePostings::new(100);
+        postings.add_range(1, -150, -120);
+        assert_eq!(postings.ids_in_range(-200, -101).cardinality(), 1);
+        assert!(postings.ids_in_range(-100, 0).is_empty());
+    }
+
+    #[test]
+    fn test_update() {
+        let mut postings = TimePostings::new(100);
+        postings.update(1, None, Some((10, 20)));
+        assert_eq!(postings.ids_in_range(0, 99).cardinality(), 1);
+
+        postings.update(1, Some((10, 20)), Some((10, 120)));
+        assert_eq!(postings.ids_in_range(100, 199).cardinality(), 1);
+
+        // samples removed from the start
+        postings.update(1, Some((10, 120)), Some((110, 120)));
+        assert!(postings.ids_in_range(0, 99).is_empty());
+        assert_eq!(postings.iter().collect::<Vec<_>>(), vec![(1, 100, 100)]);
+
+        postings.update(1, Some((110, 120)), None);
+        assert_eq!(postings.series_count(), 0);
+        assert!(postings.starts.is_empty() && postings.ends.is_empty());
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut postings = TimePostings::new(100);
+        postings.add_range(1, 0, 250);
+        postings.add_range(2, 0, 50);
+        postings.remove(1);
+        assert_eq!(postings.series_count(), 1);
+        assert_eq!(ids(postings.ids_in_range(0, 1000)), vec![2]);
+        assert_eq!(postings.ends.len(), 1);
+    }
+
+    #[test]
+    fn test_time_range_buckets_changed() {
+        assert!(!time_range_buckets_changed(Some((10, 20)), Some((10, 30)), 100));
+        assert!(time_range_buckets_changed(Some((10, 20)), Some((10, 130)), 100));
+        assert!(time_range_buckets_changed(None, Some((10, 20)), 100));
+        assert!(!time_range_buckets_changed(None, None, 100));
+    }
+}
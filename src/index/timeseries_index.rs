@@ -1,23 +1,28 @@
 use super::index_key::*;
-use crate::common::types::Label;
+use super::time_postings::{time_range_buckets_changed, TimePostings};
+use crate::common::types::Timestamp;
+use crate::common::InternedLabel;
+use crate::config::get_global_settings;
 use crate::error::TsdbResult;
-use crate::index::filters::{get_ids_by_matchers_optimized, process_equals_match, process_iterator};
-use crate::module::{with_timeseries, VKM_SERIES_TYPE};
+use crate::index::filters::get_ids_by_matchers_optimized;
+use crate::index::label_lookup::{find_label_names, find_label_values, LabelSearch};
+use crate::index::planner::{execute_group_plan, execute_selector_plan, plan_selector, SelectorPlan};
+use crate::module::VKM_SERIES_TYPE;
 use crate::storage::time_series::TimeSeries;
 use crate::storage::utils::format_prometheus_metric_name;
 use blart::AsBytes;
 use croaring::Bitmap64;
 use metricsql_common::hash::IntMap;
-use metricsql_parser::prelude::{LabelFilter, LabelFilterOp, Matchers};
+use metricsql_parser::prelude::Matchers;
 use metricsql_runtime::types::METRIC_NAME_LABEL;
 use papaya::HashMap;
 use std::collections::BTreeSet;
+use std::hash::Hasher;
 use std::ops::ControlFlow;
 use std::ops::ControlFlow::Continue;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{RwLock, RwLockReadGuard};
-use valkey_module::redisvalue::ValkeyValueKey;
-use valkey_module::{Context, ValkeyString, ValkeyValue};
+use valkey_module::{Context, ValkeyString};
 
 /// Type for the key of the index. Use instead of `String` because Valkey keys are binary safe not utf8 safe.
 pub type KeyType = Box<[u8]>;
@@ -29,27 +34,25 @@ pub type TimeSeriesIndexMap = HashMap<u32, TimeSeriesIndex>;
 // label=value
 pub type ARTBitmap = blart::TreeMap<IndexKey, Bitmap64>;
 
-// todo: in on_load, we need to set this to the last id + 1
 static TIMESERIES_ID_SEQUENCE: AtomicU64 = AtomicU64::new(1);
-static TIMESERIES_ID_MAX: AtomicU64 = AtomicU64::new(1);
 
 pub fn next_timeseries_id() -> u64 {
     // we use Relaxed here since we only need uniqueness, not monotonicity
-    let id = TIMESERIES_ID_SEQUENCE.fetch_add(1, Ordering::Relaxed);
-    TIMESERIES_ID_MAX.fetch_max(id, Ordering::Relaxed);
-    id
+    TIMESERIES_ID_SEQUENCE.fetch_add(1, Ordering::Relaxed)
 }
 
 pub fn reset_timeseries_id_sequence() {
     TIMESERIES_ID_SEQUENCE.store(1, Ordering::SeqCst);
-    TIMESERIES_ID_MAX.store(1, Ordering::SeqCst);
 }
 
-pub fn reset_timeseries_id_after_load() -> u64 {
-    let mut max = TIMESERIES_ID_MAX.fetch_max(1, Ordering::SeqCst);
-    max += 1;
-    TIMESERIES_ID_SEQUENCE.store(max, Ordering::SeqCst);
-    max
+/// Returns the id which will be assigned to the next series.
+pub(super) fn next_timeseries_id_value() -> u64 {
+    TIMESERIES_ID_SEQUENCE.load(Ordering::SeqCst)
+}
+
+/// Sets the id assigned to the next series, e.g. after the index is loaded.
+pub(super) fn set_timeseries_id_sequence(value: u64) {
+    TIMESERIES_ID_SEQUENCE.store(value.max(1), Ordering::SeqCst);
 }
 
 #[derive(Clone, Copy)]
@@ -68,13 +71,49 @@ impl PartialEq for SetOperation {
     }
 }
 
-#[derive(Default, Debug)]
+/// Returns the configured size of the index time buckets in milliseconds.
+pub(super) fn get_time_bucket_size() -> i64 {
+    get_global_settings().index_time_bucket.as_millis() as i64
+}
+
+#[derive(Debug)]
 pub(crate) struct IndexInner {
     /// Map from timeseries id to timeseries key.
     pub id_to_key: IntMap<u64, KeyType>,
     /// Map from label name and (label name,  label value) to set of timeseries ids.
     pub label_index: ARTBitmap,
+    /// Bucketed time range of the samples of each series.
+    pub time_postings: TimePostings,
     pub label_count: usize,
+    /// Map from the hash of a metric name and label set (see `label_set_hash`) to the ids of
+    /// the series having it.
+    label_sets: IntMap<u64, Bitmap64>,
+    /// Map from timeseries id to the hash of its label set.
+    series_label_set: IntMap<u64, u64>,
+}
+
+/// Returns a hash of the metric name and the set of `labels`, independent of their order.
+fn label_set_hash(metric: &str, labels: &[InternedLabel]) -> u64 {
+    let mut sorted: Vec<&InternedLabel> = labels.iter().collect();
+    sorted.sort_unstable();
+    let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+    hasher.write(metric.as_bytes());
+    for label in sorted {
+        hasher.write_u8(0);
+        hasher.write(label.as_bytes());
+    }
+    hasher.digest()
+}
+
+/// Returns true if `series_labels` and `labels` contain the same labels in any order.
+fn has_label_set(series_labels: &[InternedLabel], labels: &[InternedLabel]) -> bool {
+    series_labels.len() == labels.len() && labels.iter().all(|label| series_labels.contains(label))
+}
+
+impl Default for IndexInner {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl IndexInner {
@@ -82,17 +121,23 @@ impl IndexInner {
         IndexInner {
             id_to_key: Default::default(),
             label_index: Default::default(),
+            time_postings: TimePostings::new(get_time_bucket_size()),
             label_count: 0,
+            label_sets: Default::default(),
+            series_label_set: Default::default(),
         }
     }
 
     fn clear(&mut self) {
         self.id_to_key.clear();
         self.label_index.clear();
+        self.time_postings.clear();
         self.label_count = 0;
+        self.label_sets.clear();
+        self.series_label_set.clear();
     }
 
-    fn index_time_series(&mut self, ts: &TimeSeries, key: &[u8]) {
+    pub(super) fn index_time_series(&mut self, ts: &TimeSeries, key: &[u8]) {
         debug_assert!(ts.id != 0);
 
         let boxed_key = key.to_vec().into_boxed_slice();
@@ -102,11 +147,50 @@ impl IndexInner {
             self.index_series_by_label(ts.id, METRIC_NAME_LABEL, &ts.metric_name);
         }
 
-        for Label { name, value } in ts.labels.iter() {
-            self.index_series_by_label(ts.id, name, value);
+        for label in ts.labels.iter() {
+            self.index_series_by_interned_label(ts.id, label);
+        }
+
+        if let Some((start, end)) = ts.time_range() {
+            self.time_postings.add_range(ts.id, start, end);
+        }
+        self.index_label_set(ts);
+    }
+
+    /// Registers the label set of `ts` for lookups by exact metric name and labels.
+    pub(super) fn index_label_set(&mut self, ts: &TimeSeries) {
+        let hash = label_set_hash(&ts.metric_name, &ts.labels);
+        if let Some(old) = self.series_label_set.insert(ts.id, hash) {
+            if old == hash {
+                return;
+            }
+            self.remove_label_set(ts.id, old);
+        }
+        self.label_sets.entry(hash).or_default().add(ts.id);
+    }
+
+    fn remove_label_set(&mut self, id: u64, hash: u64) {
+        if let Some(bmp) = self.label_sets.get_mut(&hash) {
+            bmp.remove(id);
+            if bmp.is_empty() {
+                self.label_sets.remove(&hash);
+            }
+        }
+    }
+
+    fn remove_series_label_set(&mut self, id: u64) {
+        if let Some(hash) = self.series_label_set.remove(&id) {
+            self.remove_label_set(id, hash);
         }
     }
 
+    /// Returns the ids of series which may have exactly the metric name `metric` and `labels`.
+    /// Hash collisions are possible, so callers must check the labels of the series.
+    fn ids_by_label_set(&self, metric: &str, labels: &[InternedLabel]) -> Bitmap64 {
+        let hash = label_set_hash(metric, labels);
+        self.label_sets.get(&hash).cloned().unwrap_or_default()
+    }
+
     fn reindex_timeseries(&mut self, ts: &TimeSeries, key: &[u8]) {
         self.remove_series_by_id(ts.id, &ts.metric_name, &ts.labels);
         self.index_time_series(ts, key);
@@ -117,8 +201,10 @@ impl IndexInner {
         self.id_to_key.remove(&ts.id);
     }
 
-    fn remove_series_by_id(&mut self, id: u64, metric_name: &str, labels: &[Label]) {
+    fn remove_series_by_id(&mut self, id: u64, metric_name: &str, labels: &[InternedLabel]) {
         self.id_to_key.remove(&id);
+        self.time_postings.remove(id);
+        self.remove_series_label_set(id);
         // should never happen, but just in case
         if metric_name.is_empty() && labels.is_empty() {
             return;
@@ -128,9 +214,58 @@ impl IndexInner {
             self.remove_label_value(METRIC_NAME_LABEL, metric_name, id);
         }
 
-        for Label { name, value} in labels.iter() {
-            self.remove_label_value(name, value, id);
+        for label in labels.iter() {
+            self.remove_label_value(label.name(), label.value(), id);
+        }
+    }
+
+    /// Removes `ids` from all postings. Used when the labels of the series are not known.
+    fn remove_ids(&mut self, ids: &Bitmap64) {
+        for id in ids.iter() {
+            self.id_to_key.remove(&id);
+            self.time_postings.remove(id);
+            self.remove_series_label_set(id);
+        }
+        let mut emptied: Vec<IndexKey> = Vec::new();
+        for (key, bmp) in self.label_index.iter_mut() {
+            bmp.andnot_inplace(ids);
+            if bmp.is_empty() {
+                emptied.push(key.clone());
+            }
+        }
+        for key in emptied {
+            self.label_index.remove(&key);
+            if let Some((label, _)) = key.split() {
+                if !self.has_label(label) {
+                    self.label_count -= 1;
+                }
+            }
+        }
+    }
+
+    /// Returns the ids of all indexed series.
+    pub(super) fn series_ids(&self) -> Bitmap64 {
+        Bitmap64::from_iter(self.id_to_key.keys().copied())
+    }
+
+    /// Returns the ids of series with the metric name `metric` having all of `labels`.
+    fn ids_by_name_and_labels(&self, metric: &str, labels: &[InternedLabel]) -> Bitmap64 {
+        let key = get_key_for_metric_name(metric);
+        let Some(metric_bmp) = self.label_index.get(key.as_bytes()) else {
+            return Bitmap64::new();
+        };
+        let mut acc = metric_bmp.clone();
+        for label in labels.iter() {
+            match self.label_index.get(label.as_bytes()) {
+                Some(bmp) => acc.and_inplace(bmp),
+                // no series has the label
+                None => return Bitmap64::new(),
+            }
+            if acc.is_empty() {
+                break;
+            }
         }
+        acc
     }
 
     fn index_series_by_metric_name(&mut self, ts_id: u64, metric_name: &str) {
@@ -142,8 +277,7 @@ impl IndexInner {
         self.label_index.prefix_keys(prefix.as_bytes()).next().is_some()
     }
 
-    fn add_or_insert(&mut self, label: &str, value: &str, ts_id: u64) -> bool {
-        let key = IndexKey::for_label_value(label, value);
+    fn add_or_insert(&mut self, key: IndexKey, label: &str, ts_id: u64) -> bool {
         if let Some(bmp) = self.label_index.get_mut(&key) {
             bmp.add(ts_id);
             false
@@ -161,7 +295,14 @@ impl IndexInner {
     }
 
     fn index_series_by_label(&mut self, ts_id: u64, label: &str, value: &str) {
-        self.add_or_insert(label, value, ts_id);
+        let key = IndexKey::for_label_value(label, value);
+        self.add_or_insert(key, label, ts_id);
+    }
+
+    /// Index a series label, sharing the key storage with the interned label.
+    fn index_series_by_interned_label(&mut self, ts_id: u64, label: &InternedLabel) {
+        let key = IndexKey::for_label(label);
+        self.add_or_insert(key, label.name(), ts_id);
     }
 
     fn remove_label_value(&mut self, label: &str, value: &str, ts_id: u64) {
@@ -178,37 +319,41 @@ impl IndexInner {
     }
 
     /// Returns a list of all series matching `matchers` while having samples in the range
-    /// [`start`, `end`]
-    fn series_ids_by_matchers(&self, matchers: &[Matchers]) -> Bitmap64 {
-        if matchers.is_empty() {
-            return Default::default();
+    /// [`start`, `end`], if given.
+    fn series_ids_by_matchers(&self, matchers: &[Matchers], range: Option<(Timestamp, Timestamp)>) -> Bitmap64 {
+        let dest = self.series_ids_by_label_matchers(matchers);
+        match range {
+            Some((start, end)) => self.time_postings.filter_in_range(&dest, start, end),
+            None => dest,
         }
+    }
 
-        let mut dest = Bitmap64::new();
-
-        if matchers.len() == 1 {
-            let filter = &matchers[0];
-            find_ids_by_matchers(&self.label_index, filter, &mut dest);
-            return dest;
+    fn series_keys(&self, ctx: &Context, ids: &Bitmap64) -> Vec<ValkeyString> {
+        let mut result: Vec<ValkeyString> = Vec::with_capacity(ids.cardinality() as usize);
+        for id in ids.iter() {
+            if let Some(value) = self.id_to_key.get(&id) {
+                let key = ctx.create_string(&value[0..]);
+                result.push(key)
+            }
         }
+        result
+    }
 
-        // todo: if we get a None from get_series_by_id, we should log an error
-        // and remove the id from the index
-
-        // todo: determine if we should use rayon here. Some ideas
-        // - look at label cardinality for each filter in matcher
-        // - look at complexity of matchers (regex vs no regex)
+    fn series_ids_by_label_matchers(&self, matchers: &[Matchers]) -> Bitmap64 {
         let mut dest = Bitmap64::new();
-        let mut acc = Bitmap64::new();
         for matcher in matchers.iter() {
-            find_ids_by_matchers(&self.label_index, matcher, &mut acc);
-            dest.or_inplace(&acc);
-            acc.clear();
+            self.find_ids_by_matchers(matcher, &mut dest);
         }
-
         dest
     }
 
+    /// Adds the ids of series matching `matchers` to `dest`. See `planner` for how the matchers
+    /// are evaluated.
+    fn find_ids_by_matchers(&self, matchers: &Matchers, dest: &mut Bitmap64) {
+        let plan = plan_selector(&self.label_index, matchers);
+        execute_selector_plan(self, &plan, dest);
+    }
+
     fn process_label_values<T, CONTEXT, F, PRED>(
         &self,
         label: &str,
@@ -254,6 +399,22 @@ impl TimeSeriesIndex {
         inner.clear();
     }
 
+    /// Swaps the contents of two indexes.
+    pub fn swap(&self, other: &TimeSeriesIndex) {
+        if std::ptr::eq(self, other) {
+            return;
+        }
+        // lock in a consistent order to avoid deadlocking with a concurrent swap
+        let (first, second) = if (self as *const Self) < (other as *const Self) {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        let mut first = first.inner.write().unwrap();
+        let mut second = second.inner.write().unwrap();
+        std::mem::swap(&mut *first, &mut *second);
+    }
+
     pub fn label_count(&self) -> usize {
         let inner = self.inner.read().unwrap();
         inner.label_count
@@ -263,6 +424,15 @@ impl TimeSeriesIndex {
         inner.id_to_key.len()
     }
 
+    /// Returns the number of series with the given metric name.
+    pub fn metric_series_count(&self, metric_name: &str) -> usize {
+        let inner = self.inner.read().unwrap();
+        let key = get_key_for_label_value(METRIC_NAME_LABEL, metric_name);
+        inner.label_index
+            .get(key.as_bytes())
+            .map_or(0, |bmp| bmp.cardinality() as usize)
+    }
+
     pub(crate) fn next_id() -> u64 {
         next_timeseries_id()
     }
@@ -283,15 +453,33 @@ impl TimeSeriesIndex {
         inner.remove_series(ts);
     }
 
-    pub fn remove_series_by_id(&self, id: u64, metric_name: &str, labels: &[Label]) {
+    pub fn remove_series_by_id(&self, id: u64, metric_name: &str, labels: &[InternedLabel]) {
         let mut inner = self.inner.write().unwrap();
         inner.remove_series_by_id(id, metric_name, labels);
     }
 
-    fn index_series_by_labels(&self, ts_id: u64, labels: &[Label]) {
+    /// Update the time postings of a series after its time range changes from `old` to `new`.
+    /// Should be called after samples are added to or removed from a series.
+    pub fn update_series_time_range(
+        &self,
+        id: u64,
+        old: Option<(Timestamp, Timestamp)>,
+        new: Option<(Timestamp, Timestamp)>
+    ) {
+        // most writes land in an already indexed bucket, so avoid the write lock if possible. The
+        // index keeps the bucket size it was created with, which may differ from the configured one
+        let bucket_size = self.inner.read().unwrap().time_postings.bucket_size();
+        if !time_range_buckets_changed(old, new, bucket_size) {
+            return;
+        }
         let mut inner = self.inner.write().unwrap();
-        for Label { name, value} in labels.iter() {
-            inner.index_series_by_label(ts_id, name, value)
+        inner.time_postings.update(id, old, new);
+    }
+
+    fn index_series_by_labels(&self, ts_id: u64, labels: &[InternedLabel]) {
+        let mut inner = self.inner.write().unwrap();
+        for label in labels.iter() {
+            inner.index_series_by_interned_label(ts_id, label)
         }
     }
 
@@ -309,55 +497,59 @@ impl TimeSeriesIndex {
     /// This exists primarily to ensure that we disallow duplicate metric names, since the
     /// metric name and valkey key are distinct. IE we can have the metric http_requests_total{status="200"}
     /// stored at requests:http:total:200
-    pub fn get_id_by_name_and_labels(&self, metric: &str, labels: &[Label]) -> TsdbResult<Option<u64>> {
+    pub fn get_id_by_name_and_labels(&self, metric: &str, labels: &[InternedLabel]) -> TsdbResult<Option<u64>> {
+        if labels.is_empty() {
+            return Ok(None);
+        }
         let inner = self.inner.read().unwrap();
-        let mut key: String = String::new();
-        format_key_for_metric_name(&mut key, metric);
-        if let Some(measurement_bmp) = inner.label_index.get(key.as_bytes()) {
-            let mut first = true;
-            let mut acc = Bitmap64::new();
-            for label in labels.iter() {
-                format_key_for_label_value(&mut key, &label.name, &label.value);
-                if let Some(bmp) = inner.label_index.get(key.as_bytes()) {
-                    if bmp.is_empty() {
-                        break;
-                    }
-                    if first {
-                        acc = measurement_bmp.and(bmp);
-                        first = false;
-                    } else {
-                        acc.and_inplace(bmp);
-                    }
-                }
+        let acc = inner.ids_by_name_and_labels(metric, labels);
+        match acc.cardinality() {
+            0 => Ok(None),
+            1 => Ok(Some(acc.iter().next().unwrap())),
+            _ => {
+                let metric_name = format_prometheus_metric_name(metric, labels);
+                // todo: show keys in the error message ?
+                let msg = format!("Multiple series with the same metric: {metric_name}");
+                Err(msg.into())
             }
-            match acc.cardinality() {
-                0 => Ok(None),
-                1 => Ok(Some(acc.iter().next().unwrap())),
-                _ => {
-                    let metric_name = format_prometheus_metric_name(metric, labels);
-                    // todo: show keys in the error message ?
-                    let msg = format!("Multiple series with the same metric: {metric_name}");
-                    Err(msg.into())
-                }
-            }
-        } else {
-            Ok(None)
         }
     }
 
-    pub fn prometheus_name_exists(&self, metric: &str, labels: &[Label]) -> bool {
+    pub fn prometheus_name_exists(&self, metric: &str, labels: &[InternedLabel]) -> bool {
         matches!(self.get_id_by_name_and_labels(metric, labels), Ok(Some(_)))
     }
 
-    pub fn get_key_by_name_and_labels(&self, metric: &str, labels: &[Label]) -> TsdbResult<Option<KeyType>> {
-        let possible_id = self.get_id_by_name_and_labels(metric, labels)?;
-        match possible_id {
-            Some(id) => {
-                let inner = self.inner.read().unwrap();
-                Ok(inner.id_to_key.get(&id).cloned())
+    /// Returns the key of the series with exactly the given metric name and labels. Candidates
+    /// from the index (series with the same label set hash) are checked against the keyspace.
+    pub fn get_key_by_name_and_labels(
+        &self,
+        ctx: &Context,
+        metric: &str,
+        labels: &[InternedLabel]
+    ) -> TsdbResult<Option<KeyType>> {
+        let keys: Vec<KeyType> = {
+            let inner = self.inner.read().unwrap();
+            inner.ids_by_label_set(metric, labels)
+                .iter()
+                .filter_map(|id| inner.id_to_key.get(&id).cloned())
+                .collect()
+        };
+        for key in keys {
+            let key_name = ctx.create_string(&key[0..]);
+            let valkey_key = ctx.open_key(&key_name);
+            if let Ok(Some(series)) = valkey_key.get_value::<TimeSeries>(&VKM_SERIES_TYPE) {
+                if series.metric_name == metric && has_label_set(&series.labels, labels) {
+                    return Ok(Some(key));
+                }
             }
-            None => Ok(None)
         }
+        Ok(None)
+    }
+
+    /// Registers the label set of a series whose postings were restored from aux data.
+    pub(super) fn index_label_set(&self, ts: &TimeSeries) {
+        let mut inner = self.inner.write().unwrap();
+        inner.index_label_set(ts);
     }
 
     pub(super) fn get_ids_by_metric_name(&self, metric: &str) -> Bitmap64 {
@@ -370,17 +562,6 @@ impl TimeSeriesIndex {
         }
     }
 
-    pub fn rename_series(&self, ctx: &Context, new_key: &ValkeyString) -> bool {
-        let mut inner = self.inner.write().unwrap();
-        with_timeseries(ctx, new_key, | series | {
-            let id = series.id;
-            // slow, but we don't expect this to be called often
-            let key = new_key.as_slice().to_vec().into_boxed_slice();
-            inner.id_to_key.insert(id, key);
-            Ok(ValkeyValue::from(0i64))
-        }).is_ok()
-    }
-
     /// Return a bitmap of series ids that have the given label and pass the filter `predicate`.
     pub(crate) fn get_label_value_bitmap<F>(
         &self,
@@ -412,6 +593,37 @@ impl TimeSeriesIndex {
         result
     }
 
+    /// Returns up to `limit` label names, optionally restricted to names of `series` and to those
+    /// matching `search`.
+    pub(crate) fn find_label_names(
+        &self,
+        series: Option<&Bitmap64>,
+        search: Option<&LabelSearch>,
+        limit: usize
+    ) -> Vec<String> {
+        let inner = self.inner.read().unwrap();
+        find_label_names(&inner, series, search, limit)
+    }
+
+    /// Returns up to `limit` values of `label`, optionally restricted to values of `series` and to
+    /// those matching `search`.
+    pub(crate) fn find_label_values(
+        &self,
+        label: &str,
+        series: Option<&Bitmap64>,
+        search: Option<&LabelSearch>,
+        limit: usize
+    ) -> Vec<String> {
+        let inner = self.inner.read().unwrap();
+        find_label_values(&inner, label, series, search, limit)
+    }
+
+    /// Returns the ids of series with samples in buckets overlapping [`start`, `end`].
+    pub(crate) fn series_ids_in_range(&self, start: Timestamp, end: Timestamp) -> Bitmap64 {
+        let inner = self.inner.read().unwrap();
+        inner.time_postings.ids_in_range(start, end)
+    }
+
     pub fn is_series_indexed(&self, id: u64) -> bool {
         let inner = self.inner.read().unwrap();
         inner.id_to_key.contains_key(&id)
@@ -424,31 +636,54 @@ impl TimeSeriesIndex {
     }
 
     /// Returns a list of all series matching `matchers` while having samples in the range
-    /// [`start`, `end`]
-    pub(crate) fn series_ids_by_matchers(&self, matchers: &[Matchers]) -> Bitmap64 {
+    /// [`start`, `end`], if given.
+    pub(crate) fn series_ids_by_matchers(&self, matchers: &[Matchers], range: Option<(Timestamp, Timestamp)>) -> Bitmap64 {
         let inner = self.inner.read().unwrap();
-        inner.series_ids_by_matchers(matchers)
+        inner.series_ids_by_matchers(matchers, range)
     }
 
     /// Returns a list of all series matching `matchers` while having samples in the range
-    /// [`start`, `end`]
-    pub(crate) fn series_keys_by_matchers(&self, ctx: &Context, matchers: &[Matchers]) -> Vec<ValkeyString> {
+    /// [`start`, `end`], if given.
+    pub(crate) fn series_keys_by_matchers(
+        &self,
+        ctx: &Context,
+        matchers: &[Matchers],
+        range: Option<(Timestamp, Timestamp)>
+    ) -> Vec<ValkeyString> {
         let inner = self.inner.read().unwrap();
-        let bitmap = inner.series_ids_by_matchers(matchers);
-        let mut result: Vec<ValkeyString> = Vec::with_capacity(bitmap.cardinality() as usize);
-        for id in bitmap.iter() {
-            if let Some(value) = inner.id_to_key.get(&id) {
-                let key = ctx.create_string(&value[0..]);
-                result.push(key)
-            }
-        }
-        result
+        let bitmap = inner.series_ids_by_matchers(matchers, range);
+        inner.series_keys(ctx, &bitmap)
+    }
+
+    /// Returns the keys of the series in `ids` having samples in the range [`start`, `end`].
+    pub(crate) fn series_keys_in_range(
+        &self,
+        ctx: &Context,
+        ids: &Bitmap64,
+        start: Timestamp,
+        end: Timestamp
+    ) -> Vec<ValkeyString> {
+        let inner = self.inner.read().unwrap();
+        let bitmap = inner.time_postings.filter_in_range(ids, start, end);
+        inner.series_keys(ctx, &bitmap)
+    }
+
+    /// Returns the plan used to evaluate `matchers`, along with the number of series matched by
+    /// each of its groups.
+    pub(crate) fn explain_selector<'a>(&self, matchers: &'a Matchers) -> (SelectorPlan<'a>, Vec<u64>) {
+        let inner = self.inner.read().unwrap();
+        let plan = plan_selector(&inner.label_index, matchers);
+        let counts = plan.groups
+            .iter()
+            .map(|group| execute_group_plan(&inner, group).cardinality())
+            .collect();
+        (plan, counts)
     }
 
     pub(crate) fn find_ids_by_matchers(&self, matchers: &Matchers) -> Bitmap64 {
         let inner = self.inner.read().unwrap();
         let mut dest = Bitmap64::new();
-        find_ids_by_matchers(&inner.label_index, matchers, &mut dest);
+        inner.find_ids_by_matchers(matchers, &mut dest);
         dest
     }
 
@@ -462,22 +697,6 @@ impl TimeSeriesIndex {
         dest
     }
 
-    pub fn get_series_count_by_metric_name(&self, limit: usize, start: Option<&str>) -> Vec<(ValkeyValueKey, usize)> {
-        let inner = self.inner.read().unwrap();
-        let prefix = get_key_for_label_value(METRIC_NAME_LABEL, start.unwrap_or(""));
-        let prefix_len = prefix.len();
-        inner.label_index
-            .prefix(prefix.as_bytes())
-            .filter_map(|(key,  map)| {
-                // keys and values are expected to be utf-8. If we panic, we have bigger issues
-                let key = key.sub_string(prefix_len);
-                let k = ValkeyValueKey::from(key);
-                Some((k, map.cardinality() as usize))
-            })
-            .take(limit)
-            .collect()
-    }
-
     pub fn process_label_values<T, CONTEXT, PRED, F>(
         &self,
         label: &str,
@@ -495,95 +714,14 @@ impl TimeSeriesIndex {
     pub(crate) fn get_inner(&self) -> RwLockReadGuard<IndexInner> {
         self.inner.read().unwrap()
     }
-}
-
-
-fn filter_by_label_value_predicate(
-    label_index: &ARTBitmap,
-    dest: &mut Bitmap64,
-    op: SetOperation,
-    label: &str,
-    predicate: impl Fn(&str) -> bool,
-) {
-    let prefix = get_key_for_label_prefix(label);
-    let start_pos = prefix.len();
-    let iter = label_index
-        .prefix(prefix.as_bytes())
-        .filter_map(|(key, map)| {
-            let value = key.sub_string(start_pos);
-            if predicate(&value) {
-                Some(map)
-            } else {
-                None
-            }
-        });
 
-    process_iterator(iter, dest, op);
-}
-
-fn find_ids_by_label_filter(
-    label_index: &ARTBitmap,
-    filter: &LabelFilter,
-    dest: &mut Bitmap64,
-    op: SetOperation,
-    key_buf: &mut String,
-) {
-    use LabelFilterOp::*;
-
-    match filter.op {
-        Equal => {
-            key_buf.clear();
-            format_key_for_label_value(key_buf, &filter.label, &filter.value);
-            process_equals_match(label_index, key_buf, dest, op);
-        }
-        NotEqual => {
-            let predicate = |value: &str| value != filter.value;
-            filter_by_label_value_predicate(label_index, dest, op, &filter.label, predicate)
-        }
-        RegexEqual => {
-            // todo: return Result. However if we get an invalid regex here,
-            // we have a problem with the base metricsql library.
-            let regex = regex::Regex::new(&filter.value).unwrap();
-            let predicate = |value: &str| regex.is_match(value);
-            filter_by_label_value_predicate(label_index, dest, op, &filter.label, predicate)
-        }
-        RegexNotEqual => {
-            // todo: return Result. However if we get an invalid regex here,
-            // we have a problem with the base metricsql library.
-            let regex = regex::Regex::new(&filter.value).unwrap();
-            let predicate = |value: &str| !regex.is_match(value);
-            filter_by_label_value_predicate(label_index, dest, op, &filter.label, predicate)
-        }
+    pub(super) fn replace_inner(&self, inner: IndexInner) {
+        *self.inner.write().unwrap() = inner;
     }
-}
 
-fn find_ids_by_multiple_filters(
-    label_index: &ARTBitmap,
-    filters: &[LabelFilter],
-    dest: &mut Bitmap64,
-    operation: SetOperation,
-    key_buf: &mut String, // used to minimize allocations
-) {
-    for filter in filters.iter() {
-        find_ids_by_label_filter(label_index, filter, dest, operation, key_buf);
-    }
-}
-
-fn find_ids_by_matchers(
-    label_index: &ARTBitmap,
-    matchers: &Matchers,
-    dest: &mut Bitmap64
-) {
-    let mut key_buf = String::with_capacity(64);
-
-    if !matchers.matchers.is_empty() {
-        find_ids_by_multiple_filters(label_index, &matchers.matchers, dest, SetOperation::Intersection, &mut key_buf);
-    }
-
-    if !matchers.or_matchers.is_empty() {
-        for filter in matchers.or_matchers.iter() {
-            find_ids_by_multiple_filters(label_index, filter, dest, SetOperation::Union, &mut key_buf);
-        }
+    pub(crate) fn remove_ids(&self, ids: &Bitmap64) {
+        let mut inner = self.inner.write().unwrap();
+        inner.remove_ids(ids);
     }
 }
 
@@ -591,7 +729,9 @@ fn find_ids_by_matchers(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::common::get_label_interner;
     use crate::common::types::Label;
+    use crate::module::arg_parse::parse_series_selector;
     use crate::storage::time_series::TimeSeries;
     use metricsql_parser::prelude::parse_metric_name;
 
@@ -602,7 +742,7 @@ mod tests {
             if label.name == METRIC_NAME_LABEL {
                 ts.metric_name = label.value;
             } else {
-                ts.labels.push(label);
+                ts.labels.push(InternedLabel::from(&label));
             }
         }
         ts.id = TimeSeriesIndex::next_id();
@@ -613,7 +753,7 @@ mod tests {
         let mut ts = TimeSeries::new();
         ts.id = TimeSeriesIndex::next_id();
         ts.metric_name = metric_name.to_string();
-        ts.labels = labels;
+        ts.labels = labels.iter().map(InternedLabel::from).collect();
         ts
     }
 
@@ -656,6 +796,22 @@ mod tests {
         assert_eq!(index.label_count(), 0);
     }
 
+    #[test]
+    fn test_clear_releases_interned_labels() {
+        let bytes = b"index_test=cleared\0";
+        let index = TimeSeriesIndex::new();
+        let ts = create_series("latency", vec![
+            Label { name: "index_test".to_string(), value: "cleared".to_string() },
+        ]);
+        index.index_time_series(&ts, b"time-series-1");
+        drop(ts);
+        // held by the index alone
+        assert!(get_label_interner().is_interned(bytes));
+
+        index.clear();
+        assert!(!get_label_interner().is_interned(bytes));
+    }
+
     #[test]
     fn test_get_label_values() {
         let mut index = TimeSeriesIndex::new();
@@ -693,6 +849,30 @@ mod tests {
         assert_eq!(id, Some(ts.id));
     }
 
+    #[test]
+    fn test_ids_by_label_set() {
+        let index = TimeSeriesIndex::new();
+        let ts1 = create_series_from_metric_name(r#"latency{region="us-east-1"}"#);
+        let ts2 = create_series_from_metric_name(r#"latency{region="us-east-1",env="qa"}"#);
+
+        index.index_time_series(&ts1, b"time-series-1");
+        index.index_time_series(&ts2, b"time-series-2");
+
+        // a superset of the labels does not match
+        let inner = index.get_inner();
+        let ids = inner.ids_by_label_set("latency", &ts1.labels);
+        assert_eq!(ids.iter().collect::<Vec<_>>(), vec![ts1.id]);
+
+        // the order of the labels does not matter
+        let reversed: Vec<_> = ts2.labels.iter().rev().cloned().collect();
+        let ids = inner.ids_by_label_set("latency", &reversed);
+        assert_eq!(ids.iter().collect::<Vec<_>>(), vec![ts2.id]);
+        drop(inner);
+
+        index.remove_series(&ts2);
+        assert!(index.get_inner().ids_by_label_set("latency", &ts2.labels).is_empty());
+    }
+
     #[test]
     fn test_prometheus_name_exists() {
         let mut index = TimeSeriesIndex::new();
@@ -705,4 +885,37 @@ mod tests {
 
         assert!(index.prometheus_name_exists("latency", &ts.labels));
     }
+
+    #[test]
+    fn test_series_ids_by_matchers_in_range() {
+        const ONE_DAY: i64 = 24 * 60 * 60 * 1000;
+
+        let index = TimeSeriesIndex::new();
+        let mut ts1 = create_series_from_metric_name(r#"latency{region="us-east-1"}"#);
+        ts1.add(1000, 1.0, None).unwrap();
+        let mut ts2 = create_series_from_metric_name(r#"latency{region="us-east-2"}"#);
+        ts2.add(10 * ONE_DAY, 1.0, None).unwrap();
+
+        index.index_time_series(&ts1, b"time-series-1");
+        index.index_time_series(&ts2, b"time-series-2");
+
+        let matchers = vec![parse_series_selector("latency").unwrap()];
+
+        let ids = index.series_ids_by_matchers(&matchers, None);
+        assert_eq!(ids.cardinality(), 2);
+
+        let ids = index.series_ids_by_matchers(&matchers, Some((0, 2000)));
+        assert_eq!(ids.iter().collect::<Vec<_>>(), vec![ts1.id]);
+
+        let ids = index.series_ids_by_matchers(&matchers, Some((5 * ONE_DAY, 11 * ONE_DAY)));
+        assert_eq!(ids.iter().collect::<Vec<_>>(), vec![ts2.id]);
+
+        // extend ts1 into the range of ts2
+        let prev_range = ts1.time_range();
+        ts1.add(10 * ONE_DAY, 1.0, None).unwrap();
+        index.update_series_time_range(ts1.id, prev_range, ts1.time_range());
+
+        let ids = index.series_ids_by_matchers(&matchers, Some((5 * ONE_DAY, 11 * ONE_DAY)));
+        assert_eq!(ids.cardinality(), 2);
+    }
 }
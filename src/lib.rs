@@ -5,9 +5,9 @@ extern crate get_size;
 extern crate smallvec;
 extern crate valkey_module_macros;
 
-use valkey_module::server_events::{FlushSubevent, LoadingSubevent};
-use valkey_module::{valkey_module, Context as ValkeyContext, NotifyEvent, ValkeyString};
-use valkey_module_macros::{config_changed_event_handler, flush_event_handler, loading_event_handler};
+use valkey_module::server_events::LoadingSubevent;
+use valkey_module::{valkey_module, Context as ValkeyContext, NotifyEvent, Status, ValkeyString};
+use valkey_module_macros::{config_changed_event_handler, loading_event_handler};
 
 mod aggregators;
 mod common;
@@ -17,14 +17,15 @@ mod globals;
 mod index;
 mod module;
 mod provider;
+mod query;
 mod storage;
 
 #[cfg(test)]
 mod tests;
 mod gorilla;
 
-use crate::globals::{clear_timeseries_index, with_timeseries_index};
-use crate::index::reset_timeseries_id_after_load;
+use crate::globals::{clear_timeseries_index, get_current_db, get_query_result_cache, move_series_to_current_db, with_timeseries_index};
+use crate::index::{finish_index_load, index_loaded_series, reset_index_load_state};
 use crate::storage::time_series::TimeSeries;
 use module::*;
 
@@ -37,25 +38,24 @@ fn config_changed_event_handler(ctx: &ValkeyContext, _changed_configs: &[&str])
     ctx.log_notice("config changed")
 }
 
-#[flush_event_handler]
-fn flushed_event_handler(_ctx: &ValkeyContext, flush_event: FlushSubevent) {
-    if let FlushSubevent::Ended = flush_event {
-        clear_timeseries_index();
-    }
-}
-
 #[loading_event_handler]
-fn loading_event_handler(_ctx: &ValkeyContext, values: LoadingSubevent) {
+fn loading_event_handler(ctx: &ValkeyContext, values: LoadingSubevent) {
     match values {
+        LoadingSubevent::RdbStarted => {
+            reset_index_load_state();
+        }
         LoadingSubevent::ReplStarted |
         LoadingSubevent::AofStarted => {
-            // TODO!: limit to current db
+            // a full load replaces the data of every db
+            reset_index_load_state();
             clear_timeseries_index();
         }
         LoadingSubevent::Ended => {
-            reset_timeseries_id_after_load();
+            finish_index_load(ctx);
+        }
+        LoadingSubevent::Failed => {
+            reset_index_load_state();
         }
-        _ => {}
     }
 }
 
@@ -67,17 +67,34 @@ fn remove_key_from_index(ctx: &ValkeyContext, key: &[u8]) {
 }
 
 fn index_timeseries_by_key(ctx: &ValkeyContext, key: &[u8]) {
+    let _key: ValkeyString = ctx.create_string(key);
+    let redis_key = ctx.open_key(&_key);
+    let series = redis_key.get_value::<TimeSeries>(&VKM_SERIES_TYPE);
+    if let Ok(Some(series)) = series {
+        index_loaded_series(ctx, series, key);
+    }
+}
+
+/// The series is unlinked from the index when the source key is deleted by the rename, so it's
+/// indexed again under its new key.
+fn reindex_renamed_series(ctx: &ValkeyContext, key: &[u8]) {
     with_timeseries_index(ctx, |ts_index| {
         let _key: ValkeyString = ctx.create_string(key);
-        let redis_key = ctx.open_key_writable(&_key);
-        let series = redis_key.get_value::<TimeSeries>(&VKM_SERIES_TYPE);
-        if let Ok(Some(series)) = series {
-            if ts_index.is_series_indexed(series.id) {
-                // todo: log warning
-                ts_index.remove_series_by_key(ctx, &_key);
-                return;
-            }
+        let redis_key = ctx.open_key(&_key);
+        if let Ok(Some(series)) = redis_key.get_value::<TimeSeries>(&VKM_SERIES_TYPE) {
+            ts_index.reindex_timeseries(series, key);
+        }
+    });
+}
+
+fn index_copied_series(ctx: &ValkeyContext, key: &[u8]) {
+    with_timeseries_index(ctx, |ts_index| {
+        let _key: ValkeyString = ctx.create_string(key);
+        let redis_key = ctx.open_key(&_key);
+        if let Ok(Some(series)) = redis_key.get_value::<TimeSeries>(&VKM_SERIES_TYPE) {
             ts_index.index_time_series(series, key);
+            let db = unsafe { get_current_db(ctx.ctx) };
+            get_query_result_cache().invalidate_since(db, series.first_timestamp);
         }
     });
 }
@@ -91,9 +108,9 @@ fn on_event(ctx: &ValkeyContext, _event_type: NotifyEvent, event: &str, key: &[u
         "loaded" => {
             index_timeseries_by_key(ctx, key);
         }
-        "rename_from" => {
-            // RenameSeriesFrom(ctx, key);
-        }
+        "rename_to" => reindex_renamed_series(ctx, key),
+        "move_to" => move_series_to_current_db(ctx, key),
+        "copy_to" => index_copied_series(ctx, key),
         "storage.alter" => remove_key_from_index(ctx, key),
         _ => {
             // ctx.log_warning(&format!("Unknown event: {}", event));
@@ -115,11 +132,16 @@ macro_rules! get_allocator {
     };
 }
 
+fn init(ctx: &ValkeyContext, _args: &[ValkeyString]) -> Status {
+    subscribe_to_server_events(ctx)
+}
+
 valkey_module! {
     name: MODULE_NAME,
     version: VKMETRICS_VERSION,
     allocator: (get_allocator!(), get_allocator!()),
     data_types: [VKM_SERIES_TYPE],
+    init: init,
     commands: [
         ["VKM.CREATE-SERIES", commands::create, "write deny-oom", 1, 1, 1],
         ["VKM.ALTER-SERIES", commands::alter, "write deny-oom", 1, 1, 1],
@@ -127,6 +149,8 @@ valkey_module! {
         ["VKM.GET", commands::get, "write deny-oom", 1, 1, 1],
         ["VKM.SERIES-INFO", commands::info, "write deny-oom", 1, 1, 1],
         ["VKM.MADD", commands::madd, "write deny-oom", 1, 1, 1],
+        ["VKM.INCRBY", commands::incrby, "write deny-oom", 1, 1, 1],
+        ["VKM.DECRBY", commands::decrby, "write deny-oom", 1, 1, 1],
         ["VKM.DELETE-KEY_RANGE", commands::delete_key_range, "write deny-oom", 1, 1, 1],
         ["VKM.DELETE-RANGE", commands::delete_range, "write deny-oom", 1, 1, 1],
         ["VKM.DELETE-SERIES", commands::delete_series, "write deny-oom", 1, 1, 1],
@@ -136,13 +160,18 @@ valkey_module! {
         ["VKM.SERIES", commands::series, "write deny-oom", 1, 1, 1],
         ["VKM.TOP-QUERIES", commands::top_queries, "write deny-oom", 1, 1, 1],
         ["VKM.ACTIVE-QUERIES", commands::active_queries, "write deny-oom", 1, 1, 1],
+        ["VKM.KILL-QUERY", commands::kill_query, "write deny-oom", 1, 1, 1],
         ["VKM.CARDINALITY", commands::cardinality, "write deny-oom", 1, 1, 1],
         ["VKM.LABEL-NAMES", commands::label_names, "write deny-oom", 1, 1, 1],
         ["VKM.LABEL-VALUES", commands::label_values, "write deny-oom", 1, 1, 1],
         ["VKM.STATS", commands::stats, "write deny-oom", 1, 1, 1],
         ["VKM.RESET-ROLLUP-CACHE", commands::reset_rollup_cache, "write deny-oom", 1, 1, 1],
+        ["VKM.INDEX-CHECK", commands::index_check, "write deny-oom", 1, 1, 1],
+        ["VKM.INDEX-REPAIR", commands::index_repair, "write deny-oom", 1, 1, 1],
+        ["VKM.EXPLAIN-SELECTOR", commands::explain_selector, "write deny-oom", 1, 1, 1],
+        ["VKM.SERIES-LIMITS", commands::series_limits, "write deny-oom", 1, 1, 1],
     ],
      event_handlers: [
-        [@SET @STRING @GENERIC @EVICTED @EXPIRED @TRIMMED: on_event]
+        [@SET @STRING @GENERIC @EVICTED @EXPIRED @TRIMMED @LOADED: on_event]
     ],
 }
@@ -3,13 +3,17 @@ use std::fmt::Display;
 use std::time::Duration;
 use chrono::DateTime;
 use metricsql_runtime::types::{TimestampTrait};
-use metricsql_parser::prelude::Matchers;
+use ahash::AHashMap;
+use metricsql_parser::prelude::{LabelFilterOp, Matchers};
+use metricsql_runtime::types::METRIC_NAME_LABEL;
 use metricsql_parser::parser::{parse_duration_value, parse_number};
 use metricsql_runtime::parse_metric_selector;
 use valkey_module::{ValkeyError, ValkeyResult, ValkeyString};
 use crate::common::current_time_millis;
 use crate::common::types::Timestamp;
 use crate::error::{TsdbError, TsdbResult};
+use crate::index::LabelSearch;
+use crate::module::result::ResultFormat;
 use crate::storage::{MAX_CHUNK_SIZE, MAX_TIMESTAMP, MIN_CHUNK_SIZE};
 use crate::storage::time_series::TimeSeries;
 
@@ -162,8 +166,12 @@ pub struct MetadataFunctionArgs {
     pub label_name: Option<String>,
     pub start: Timestamp,
     pub end: Timestamp,
+    /// Whether START or END was given. Otherwise the range only applies to MATCH selectors
+    pub has_range: bool,
     pub matchers: Vec<Matchers>,
+    pub search: Option<LabelSearch>,
     pub limit: Option<usize>,
+    pub format: ResultFormat,
 }
 
 pub(crate) fn normalize_range_timestamps(
@@ -311,6 +319,54 @@ pub fn parse_series_selector(arg: &str) -> TsdbResult<Matchers> {
     })
 }
 
+/// Returns true if `arg` looks like a Prometheus metric with labels, e.g.
+/// `http_requests_total{method="GET"}`, as opposed to a key. Keys may contain hash tags
+/// (e.g. `{api}:latency`), so this only checks the overall shape.
+pub fn is_metric_string(arg: &str) -> bool {
+    arg.ends_with('}') && arg.find('{').is_some_and(|pos| pos > 0)
+}
+
+/// Parses a key argument naming a series by metric name and labels. Returns `None` if `arg` is not
+/// a valid metric with labels, in which case it is a plain key (e.g. `foo{bar}`).
+pub fn parse_metric_key(arg: &str) -> Option<(String, AHashMap<String, String>)> {
+    if !is_metric_string(arg) {
+        return None;
+    }
+    parse_metric_name(arg).ok()
+}
+
+/// Parses a Prometheus metric with labels, e.g. `http_requests_total{method="GET"}`, into its
+/// metric name and labels. Only `=` matchers are allowed, and labels with empty values are
+/// dropped, as they are equivalent to missing labels.
+pub fn parse_metric_name(arg: &str) -> TsdbResult<(String, AHashMap<String, String>)> {
+    let invalid = |msg: &str| TsdbError::InvalidMetric(format!("{msg}: {arg}"));
+
+    let matchers = parse_metric_selector(arg).map_err(|_| invalid("cannot parse metric"))?;
+    if !matchers.or_matchers.is_empty() {
+        return Err(invalid("expected a single metric"));
+    }
+
+    let mut metric_name: Option<String> = None;
+    let mut labels: AHashMap<String, String> = AHashMap::with_capacity(matchers.matchers.len());
+    for filter in matchers.matchers.into_iter() {
+        if !matches!(filter.op, LabelFilterOp::Equal) {
+            return Err(invalid("only '=' is allowed in label pairs"));
+        }
+        if filter.label == METRIC_NAME_LABEL {
+            metric_name = Some(filter.value);
+        } else if labels.contains_key(&filter.label) {
+            return Err(invalid("duplicate label"));
+        } else if !filter.value.is_empty() {
+            labels.insert(filter.label, filter.value);
+        }
+    }
+
+    match metric_name {
+        Some(name) if !name.is_empty() => Ok((name, labels)),
+        _ => Err(invalid("missing metric name")),
+    }
+}
+
 pub fn parse_chunk_size(arg: &str) -> ValkeyResult<usize> {
     fn get_error_result() -> ValkeyResult<usize> {
         let msg = format!("TSDB: CHUNK_SIZE value must be an integer multiple of 2 in the range [{MIN_CHUNK_SIZE} .. {MAX_CHUNK_SIZE}]");
@@ -332,4 +388,74 @@ pub fn parse_chunk_size(arg: &str) -> ValkeyResult<usize> {
         return get_error_result()
     }
     Ok(chunk_size)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> AHashMap<String, String> {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_is_metric_string() {
+        assert!(is_metric_string(r#"http_requests_total{method="GET"}"#));
+        assert!(is_metric_string("foo{bar}"));
+        assert!(!is_metric_string("http_requests_total"));
+        // hash tags
+        assert!(!is_metric_string("{api}:latency"));
+        assert!(!is_metric_string("{api}"));
+        assert!(!is_metric_string("latency:{api}:eu"));
+    }
+
+    #[test]
+    fn test_parse_metric_name() {
+        let (name, parsed) = parse_metric_name(r#"http_requests_total{method="GET", code="200"}"#).unwrap();
+        assert_eq!(name, "http_requests_total");
+        assert_eq!(parsed, labels(&[("method", "GET"), ("code", "200")]));
+
+        let (name, parsed) = parse_metric_name("up").unwrap();
+        assert_eq!(name, "up");
+        assert!(parsed.is_empty());
+
+        // empty label values are dropped
+        let (_, parsed) = parse_metric_name(r#"up{job="api", env=""}"#).unwrap();
+        assert_eq!(parsed, labels(&[("job", "api")]));
+
+        let (name, _) = parse_metric_name(r#"{__name__="up", job="api"}"#).unwrap();
+        assert_eq!(name, "up");
+    }
+
+    #[test]
+    fn test_parse_metric_name_errors() {
+        let invalid = [
+            r#"up{job=~"api"}"#,
+            r#"up{job!="api"}"#,
+            r#"up{job="api", job="web"}"#,
+            r#"up{job="api" or job="web"}"#,
+            r#"{job="api"}"#,
+            "foo{bar}",
+        ];
+        for metric in invalid {
+            assert!(
+                matches!(parse_metric_name(metric), Err(TsdbError::InvalidMetric(_))),
+                "{metric} should be invalid"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_metric_key() {
+        let (name, parsed) = parse_metric_key(r#"up{job="api"}"#).unwrap();
+        assert_eq!(name, "up");
+        assert_eq!(parsed, labels(&[("job", "api")]));
+        // plain keys
+        assert_eq!(parse_metric_key("up"), None);
+        assert_eq!(parse_metric_key("{api}:latency"), None);
+        assert_eq!(parse_metric_key("foo{bar}"), None);
+    }
+}
@@ -1,6 +1,10 @@
-use crate::arg_parse::{parse_duration_arg, parse_number_with_unit, parse_timestamp};
+use crate::arg_parse::{parse_duration_arg, parse_metric_key, parse_number_with_unit, parse_timestamp};
+use crate::common::InternedLabel;
+use crate::globals::{get_current_db, get_query_result_cache, with_timeseries_index};
 use crate::module::commands::create_series;
-use crate::module::{with_timeseries_mut, VKM_SERIES_TYPE};
+use crate::error::{TsdbError, TsdbResult};
+use crate::module::VKM_SERIES_TYPE;
+use crate::common::types::Timestamp;
 use crate::storage::time_series::TimeSeries;
 use crate::storage::{DuplicatePolicy, TimeSeriesOptions};
 use ahash::AHashMap;
@@ -12,43 +16,78 @@ const CMD_ARG_DUPLICATE_POLICY: &str = "DUPLICATE_POLICY";
 const CMD_ARG_DEDUPE_INTERVAL: &str = "DEDUPE_INTERVAL";
 const CMD_ARG_CHUNK_SIZE: &str = "CHUNK_SIZE";
 const CMD_ARG_LABELS: &str = "LABELS";
+const CMD_ARG_ON_DUPLICATE: &str = "ON_DUPLICATE";
+const CMD_ARG_COUNTER: &str = "COUNTER";
 
 ///
-/// VKM.ADD key timestamp value
+/// VKM.ADD key|metric timestamp value
 /// [RETENTION duration]
 /// [DUPLICATE_POLICY policy]
 /// [DEDUPE_INTERVAL duration]
 /// [CHUNK_SIZE size]
+/// [COUNTER]
+/// [ON_DUPLICATE policy]
 /// [LABELS name value ...]
 ///
+/// ON_DUPLICATE overrides the duplicate policy of the series for this sample. LABELS takes the
+/// remaining arguments, so it comes last.
+///
+/// Instead of a key, the series may be given as a Prometheus metric with labels, e.g.
+/// `http_requests_total{method="GET"}`. The series with exactly that metric name and labels is
+/// written to, or created under the argument itself if it does not exist. An existing key, or an
+/// argument which does not parse as a metric (e.g. `foo{bar}`), is taken as a key.
+///
 pub fn add(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
     let mut args = args.into_iter().skip(1);
 
     let key = args.next_arg()?;
     let timestamp = parse_timestamp(args.next_str()?)?;
     let value = args.next_f64()?;
+    let AddOptions { options, on_duplicate } = parse_add_options(&mut args)?;
 
     let redis_key = ctx.open_key_writable(&key);
-    let series = redis_key.get_value::<TimeSeries>(&VKM_SERIES_TYPE)?;
-    if let Some(series) = series {
-        args.done()?;
-        if series.add(timestamp, value, None).is_ok() {
-            return Ok(ValkeyValue::Integer(timestamp));
-        }
-        todo!("handle error");
+    if let Some(series) = redis_key.get_value::<TimeSeries>(&VKM_SERIES_TYPE)? {
+        // creation options are ignored for existing series
+        add_sample(ctx, series, timestamp, value, on_duplicate)?;
+        return Ok(ValkeyValue::Integer(timestamp));
     }
 
-    let existing_result = with_timeseries_mut(ctx, &key, |series| {
-        series.add(timestamp, value, None)?;
-        Ok(ValkeyValue::Integer(timestamp))
-    });
+    if let Some(metric) = key.try_as_str().ok().and_then(parse_metric_key) {
+        if options.labels.is_some() {
+            return Err(ValkeyError::Str("ERR LABELS cannot be used with a metric name"));
+        }
+        add_by_metric_name(ctx, &key, metric, timestamp, value, options, on_duplicate)?;
+        return Ok(ValkeyValue::Integer(timestamp));
+    }
 
-    if let Ok(result) = existing_result {
-        args.done()?;
-        return Ok(result);
+    let mut ts = create_series(&key, options, ctx)?;
+    if let Err(err) = ts.add(timestamp, value, on_duplicate) {
+        // the series was indexed on creation, but is not stored
+        with_timeseries_index(ctx, |index| index.remove_series(&ts));
+        return Err(err.into());
     }
+    update_time_index(ctx, &ts, None);
+
+    let redis_key = ValkeyKeyWritable::open(ctx.ctx, &key);
+    redis_key.set_value(&VKM_SERIES_TYPE, ts)?;
+
+    // a new series may match any cached query
+    let db = unsafe { get_current_db(ctx.ctx) };
+    get_query_result_cache().invalidate_since(db, timestamp);
+
+    Ok(ValkeyValue::Integer(timestamp))
+}
+
+pub(super) struct AddOptions {
+    /// Options of the series, if it is created
+    pub options: TimeSeriesOptions,
+    /// Overrides the duplicate policy of the series for this sample
+    pub on_duplicate: Option<DuplicatePolicy>,
+}
 
+pub(super) fn parse_add_options(args: &mut impl Iterator<Item = ValkeyString>) -> ValkeyResult<AddOptions> {
     let mut options = TimeSeriesOptions::default();
+    let mut on_duplicate = None;
 
     while let Ok(arg) = args.next_str() {
         match arg {
@@ -78,12 +117,18 @@ pub fn add(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
             }
             arg if arg.eq_ignore_ascii_case(CMD_ARG_DUPLICATE_POLICY) => {
                 let next = args.next_str()?;
-                if let Ok(policy) = DuplicatePolicy::try_from(next) {
+                if let Ok(policy) = next.parse::<DuplicatePolicy>() {
                     options.duplicate_policy(policy);
                 } else {
                     return Err(ValkeyError::Str("ERR invalid DUPLICATE_POLICY"));
                 }
             }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_ON_DUPLICATE) => {
+                on_duplicate = Some(parse_on_duplicate(args.next_str()?)?);
+            }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_COUNTER) => {
+                options.counter = true;
+            }
             arg if arg.eq_ignore_ascii_case(CMD_ARG_LABELS) => {
                 let mut labels = AHashMap::new();
                 while let Ok(name) = args.next_str() {
@@ -99,11 +144,85 @@ pub fn add(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
         };
     }
 
-    let mut ts = create_series(&key, options, ctx)?;
-    ts.add(timestamp, value, None)?;
+    Ok(AddOptions { options, on_duplicate })
+}
 
-    let redis_key = ValkeyKeyWritable::open(ctx.ctx, &key);
-    redis_key.set_value(&VKM_SERIES_TYPE, ts)?;
+pub(super) fn parse_on_duplicate(arg: &str) -> ValkeyResult<DuplicatePolicy> {
+    arg.parse::<DuplicatePolicy>()
+        .map_err(|_| ValkeyError::Str("ERR invalid ON_DUPLICATE policy"))
+}
 
-    Ok(ValkeyValue::Integer(timestamp))
-}
\ No newline at end of file
+/// Adds a sample to `series`, keeping the time index and query cache up to date.
+pub(super) fn add_sample(
+    ctx: &Context,
+    series: &mut TimeSeries,
+    timestamp: Timestamp,
+    value: f64,
+    on_duplicate: Option<DuplicatePolicy>
+) -> TsdbResult<()> {
+    let prev_range = series.time_range();
+    series.add(timestamp, value, on_duplicate)?;
+    update_time_index(ctx, series, prev_range);
+    get_query_result_cache().invalidate_series(series.id, timestamp);
+    Ok(())
+}
+
+/// Adds a sample to the series named by a Prometheus metric with labels, as parsed from the key
+/// argument `key`, creating the series with `options` if it does not exist.
+pub(super) fn add_by_metric_name(
+    ctx: &Context,
+    key: &ValkeyString,
+    (metric_name, labels): (String, AHashMap<String, String>),
+    timestamp: Timestamp,
+    value: f64,
+    mut options: TimeSeriesOptions,
+    on_duplicate: Option<DuplicatePolicy>
+) -> TsdbResult<()> {
+    let existing_key = {
+        let interned: Vec<InternedLabel> = labels
+            .iter()
+            .map(|(name, value)| InternedLabel::new(name, value))
+            .collect();
+        with_timeseries_index(ctx, |index| {
+            index.get_key_by_name_and_labels(ctx, &metric_name, &interned)
+        })?
+    };
+
+    if let Some(key) = existing_key {
+        let key = ctx.create_string(&key[0..]);
+        let redis_key = ctx.open_key_writable(&key);
+        return match redis_key.get_value::<TimeSeries>(&VKM_SERIES_TYPE) {
+            Ok(Some(series)) => add_sample(ctx, series, timestamp, value, on_duplicate),
+            _ => Err(TsdbError::General("the series of the metric is missing".to_string())),
+        };
+    }
+
+    options.metric_name = Some(metric_name);
+    options.labels(labels);
+    // the series is stored under the argument, which is declared as the key of the command, so
+    // that it is checked by ACLs, routed by its slot in cluster mode, and written to the same key
+    // when the command is replicated
+    let mut ts = create_series(key, options, ctx)?;
+    if let Err(err) = ts.add(timestamp, value, on_duplicate) {
+        // the series was indexed on creation, but is not stored
+        with_timeseries_index(ctx, |index| index.remove_series(&ts));
+        return Err(err);
+    }
+    update_time_index(ctx, &ts, None);
+
+    let redis_key = ValkeyKeyWritable::open(ctx.ctx, key);
+    redis_key.set_value(&VKM_SERIES_TYPE, ts)
+        .map_err(|err| TsdbError::General(err.to_string()))?;
+
+    // a new series may match any cached query
+    let db = unsafe { get_current_db(ctx.ctx) };
+    get_query_result_cache().invalidate_since(db, timestamp);
+
+    Ok(())
+}
+
+pub(super) fn update_time_index(ctx: &Context, series: &TimeSeries, prev_range: Option<(Timestamp, Timestamp)>) {
+    with_timeseries_index(ctx, |index| {
+        index.update_series_time_range(series.id, prev_range, series.time_range());
+    });
+}
@@ -1,10 +1,10 @@
-use crate::globals::with_timeseries_index;
+use crate::globals::{get_current_db, get_query_result_cache, with_timeseries_index};
 use crate::module::commands::parse_create_options;
 use crate::module::with_timeseries_mut;
 use crate::storage::time_series::TimeSeries;
 use crate::storage::{TimeSeriesOptions};
 use valkey_module::{Context, NotifyEvent, ValkeyResult, ValkeyString, VALKEY_OK};
-use crate::common::types::Label;
+use crate::common::InternedLabel;
 
 pub fn alter(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
     let (parsed_key, options) = parse_create_options(args)?;
@@ -17,7 +17,10 @@ pub fn alter(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
             with_timeseries_index(ctx, |ts_index| {
                 let key = parsed_key.as_slice();
                 ts_index.reindex_timeseries(series, key);
-            })
+            });
+            // the series may now match a different set of queries
+            let db = unsafe { get_current_db(ctx.ctx) };
+            get_query_result_cache().invalidate_since(db, series.first_timestamp);
         }
 
         ctx.replicate_verbatim();
@@ -44,10 +47,7 @@ fn update_series(series: &mut TimeSeries, options: TimeSeriesOptions) -> bool {
     let mut labels_changed = false;
     if let Some(labels) = options.labels {
         for (k,v) in labels.iter() {
-            series.labels.push( Label{
-                name: k.to_string(),
-                value: v.to_string(),
-            });
+            series.labels.push(InternedLabel::new(k, v));
             labels_changed = true;
         }
     }
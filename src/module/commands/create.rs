@@ -1,14 +1,14 @@
 use crate::arg_parse::{parse_chunk_size, parse_duration_arg};
-use crate::error::TsdbResult;
-use crate::globals::with_timeseries_index;
-use crate::index::TimeSeriesIndex;
+use crate::error::{TsdbError, TsdbResult};
+use crate::globals::{get_current_db, with_timeseries_index};
+use crate::index::{admit_new_series, TimeSeriesIndex};
 use crate::module::VKM_SERIES_TYPE;
 use crate::storage::time_series::TimeSeries;
 use crate::storage::{DuplicatePolicy, TimeSeriesOptions};
 use ahash::AHashMap;
 use valkey_module::key::ValkeyKeyWritable;
 use valkey_module::NotifyEvent;
-use valkey_module::{Context, NextArg, ValkeyError, ValkeyResult, ValkeyString, VALKEY_OK};
+use valkey_module::{Context, ContextFlags, NextArg, ValkeyError, ValkeyResult, ValkeyString, VALKEY_OK};
 
 const CMD_ARG_RETENTION: &str = "RETENTION";
 const CMD_ARG_DUPLICATE_POLICY: &str = "DUPLICATE_POLICY";
@@ -17,6 +17,7 @@ const CMD_ARG_DEDUPE_INTERVAL: &str = "DEDUPE_INTERVAL";
 const CMD_ARG_LABELS: &str = "LABELS";
 const CMD_ARG_METRIC_NAME: &str = "METRIC_NAME";
 const CMD_ARG_SIGNIFICANT_DIGITS: &str = "SIGNIFICANT_DIGITS";
+const CMD_ARG_COUNTER: &str = "COUNTER";
 const MAX_SIGNIFICANT_DIGITS: u8 = 16;
 
 pub fn create(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
@@ -28,7 +29,10 @@ pub fn create(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
     }
 
     let ts = create_series(&parsed_key, options, ctx)
-        .map_err(|_| ValkeyError::Str("TSDB: failed to create series"))?;
+        .map_err(|err| match err {
+            TsdbError::SeriesLimitExceeded(_) => ValkeyError::String(err.to_string()),
+            _ => ValkeyError::Str("TSDB: failed to create series"),
+        })?;
 
     key.set_value(&VKM_SERIES_TYPE, ts)?;
 
@@ -89,6 +93,9 @@ pub fn parse_create_options(args: Vec<ValkeyString>) -> ValkeyResult<(ValkeyStri
                     return Err(ValkeyError::Str("ERR invalid CHUNK_SIZE value"));
                 }
             }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_COUNTER) => {
+                options.counter = true;
+            }
             arg if arg.eq_ignore_ascii_case(CMD_ARG_LABELS) => {
                 let mut labels: AHashMap<String, String> = Default::default();
                 while let Ok(name) = args.next_str() {
@@ -118,6 +125,17 @@ pub(crate) fn create_series(
         // will return an error if the series already exists
         index.get_id_by_name_and_labels(&ts.metric_name, &ts.labels)?;
 
+        // series created on the primary are replicated as is, so limits are only enforced there
+        if !ctx.get_flags().intersects(ContextFlags::REPLICATED | ContextFlags::LOADING) {
+            let db = unsafe { get_current_db(ctx.ctx) };
+            admit_new_series(
+                db,
+                &ts.metric_name,
+                index.series_count(),
+                index.metric_series_count(&ts.metric_name),
+            )?;
+        }
+
         ts.id = TimeSeriesIndex::next_id();
         index.index_time_series(&ts, key.iter().as_slice());
         Ok(ts)
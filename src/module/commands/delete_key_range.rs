@@ -1,3 +1,4 @@
+use crate::globals::{get_query_result_cache, with_timeseries_index};
 use crate::module::{parse_timestamp_arg, with_timeseries_mut};
 use valkey_module::{Context, NextArg, ValkeyError, ValkeyResult, ValkeyString, ValkeyValue};
 
@@ -20,7 +21,12 @@ pub fn delete_key_range(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult
             return Err(ValkeyError::Str("ERR invalid range"));
         }
 
+        let prev_range = series.time_range();
         let sample_count = series.remove_range(start, end)?;
+        with_timeseries_index(ctx, |index| {
+            index.update_series_time_range(series.id, prev_range, series.time_range());
+        });
+        get_query_result_cache().invalidate_series(series.id, start);
 
         Ok(ValkeyValue::from(sample_count))
     })
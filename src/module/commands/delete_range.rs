@@ -1,4 +1,4 @@
-use crate::globals::with_timeseries_index;
+use crate::globals::{get_query_result_cache, with_timeseries_index};
 use crate::module::arg_parse::{parse_series_selector, TimestampRangeValue};
 use crate::module::{normalize_range_args, parse_timestamp_arg, VKM_SERIES_TYPE};
 use crate::storage::time_series::TimeSeries;
@@ -55,7 +55,7 @@ pub fn delete_range(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
     }
 
     let res = with_timeseries_index(ctx, move |ts_index| {
-        let keys = ts_index.series_keys_by_matchers(ctx, &matchers);
+        let keys = ts_index.series_keys_by_matchers(ctx, &matchers, Some((start_ts, end_ts)));
         if keys.is_empty() {
             return Err(ValkeyError::Str("ERR no series found"));
         }
@@ -65,7 +65,10 @@ pub fn delete_range(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
             // get series from redis
             match redis_key.get_value::<TimeSeries>(&VKM_SERIES_TYPE) {
                 Ok(Some(series)) => {
+                    let prev_range = series.time_range();
                     deleted += series.remove_range(start_ts, end_ts)?;
+                    ts_index.update_series_time_range(series.id, prev_range, series.time_range());
+                    get_query_result_cache().invalidate_series(series.id, start_ts);
                 }
                 Err(e) => {
                     return Err(e);
@@ -19,7 +19,7 @@ pub fn delete_series(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
     }
 
     let res = with_timeseries_index(ctx, move |index| {
-        let keys = index.series_keys_by_matchers(ctx, &matchers);
+        let keys = index.series_keys_by_matchers(ctx, &matchers, None);
         if keys.is_empty() {
             return Err(ValkeyError::Str("ERR no series found"));
         }
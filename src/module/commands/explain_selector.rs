@@ -0,0 +1,73 @@
+use crate::globals::with_timeseries_index;
+use crate::index::{FilterPlan, FilterStrategy, GroupPlan};
+use crate::module::arg_parse::parse_series_selector;
+use std::collections::HashMap;
+use valkey_module::redisvalue::ValkeyValueKey;
+use valkey_module::{Context, NextArg, ValkeyError, ValkeyResult, ValkeyString, ValkeyValue};
+
+///
+/// VKM.EXPLAIN-SELECTOR selector
+///
+/// Shows how `selector` is evaluated against the label index. For each group of ANDed filters
+/// (selectors with `or` have several), the filters are listed in evaluation order with their
+/// strategy and estimated number of series, followed by the number of series matched.
+pub fn explain_selector(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
+    let mut args = args.into_iter().skip(1);
+    let selector = args.next_str()?;
+    args.done()?;
+
+    let Ok(matchers) = parse_series_selector(selector) else {
+        return Err(ValkeyError::Str("ERR invalid series selector"));
+    };
+
+    with_timeseries_index(ctx, |index| {
+        let (plan, counts) = index.explain_selector(&matchers);
+        let groups = plan.groups
+            .iter()
+            .zip(counts)
+            .map(|(group, count)| format_group(group, count))
+            .collect::<Vec<_>>();
+
+        let mut res: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(2);
+        res.insert("selector".into(), selector.into());
+        res.insert("groups".into(), ValkeyValue::Array(groups));
+        Ok(ValkeyValue::Map(res))
+    })
+}
+
+fn format_group(group: &GroupPlan, count: u64) -> ValkeyValue {
+    let steps = group.filters
+        .iter()
+        .map(format_filter)
+        .collect::<Vec<_>>();
+
+    let mut res: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(4);
+    res.insert("steps".into(), ValkeyValue::Array(steps));
+    res.insert("startsFromAllSeries".into(), ValkeyValue::Bool(group.starts_from_all_series()));
+    res.insert("emptyResult".into(), ValkeyValue::Bool(group.is_empty_result()));
+    res.insert("series".into(), ValkeyValue::Integer(count as i64));
+    ValkeyValue::Map(res)
+}
+
+fn format_filter(plan: &FilterPlan) -> ValkeyValue {
+    let mut res: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(5);
+    res.insert("filter".into(), plan.filter.to_string().into());
+    res.insert("strategy".into(), plan.strategy.as_str().into());
+    if let Some(estimate) = plan.estimate {
+        res.insert("estimate".into(), ValkeyValue::Integer(estimate as i64));
+    }
+    match plan.strategy {
+        FilterStrategy::Lookup | FilterStrategy::SubtractLookup => {
+            let values = plan.values
+                .iter()
+                .map(|value| value.as_str().into())
+                .collect::<Vec<_>>();
+            res.insert("values".into(), ValkeyValue::Array(values));
+        }
+        FilterStrategy::PrefixScan | FilterStrategy::SubtractPrefixScan => {
+            res.insert("prefix".into(), plan.prefix.as_str().into());
+        }
+        _ => {}
+    }
+    ValkeyValue::Map(res)
+}
@@ -0,0 +1,142 @@
+use super::add::{parse_add_options, update_time_index, AddOptions};
+use crate::arg_parse::{parse_duration_arg, parse_timestamp};
+use crate::common::current_time_millis;
+use crate::common::types::Timestamp;
+use crate::globals::{get_current_db, get_query_result_cache, with_timeseries_index};
+use crate::module::commands::create_series;
+use crate::module::VKM_SERIES_TYPE;
+use crate::storage::time_series::TimeSeries;
+use std::time::Duration;
+use valkey_module::key::ValkeyKeyWritable;
+use valkey_module::{Context, NextArg, NotifyEvent, ValkeyError, ValkeyResult, ValkeyString, ValkeyValue};
+
+const CMD_ARG_TIMESTAMP: &str = "TIMESTAMP";
+const CMD_ARG_RESOLUTION: &str = "RESOLUTION";
+const CMD_ARG_LABELS: &str = "LABELS";
+
+///
+/// VKM.INCRBY key delta
+/// [TIMESTAMP timestamp]
+/// [RESOLUTION duration]
+/// [RETENTION duration]
+/// [DUPLICATE_POLICY policy]
+/// [DEDUPE_INTERVAL duration]
+/// [CHUNK_SIZE size]
+/// [COUNTER]
+/// [LABELS name value ...]
+///
+/// Adds `delta` to the last value of the series, at `timestamp` (default now). If the timestamp
+/// falls in the RESOLUTION wide bucket of the last sample, the last sample is updated in place,
+/// otherwise a sample is appended. The series is created with the given options if it does not
+/// exist. Returns the timestamp of the written sample. A negative `delta` fails for counters.
+pub fn incrby(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
+    increment(ctx, args, false)
+}
+
+///
+/// VKM.DECRBY key delta
+/// [TIMESTAMP timestamp]
+/// [RESOLUTION duration]
+/// [RETENTION duration]
+/// [DUPLICATE_POLICY policy]
+/// [DEDUPE_INTERVAL duration]
+/// [CHUNK_SIZE size]
+/// [COUNTER]
+/// [LABELS name value ...]
+///
+/// As VKM.INCRBY, subtracting `delta` from the last value. Fails for counters.
+pub fn decrby(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
+    increment(ctx, args, true)
+}
+
+fn increment(ctx: &Context, args: Vec<ValkeyString>, negate: bool) -> ValkeyResult {
+    let mut args = args.into_iter();
+
+    let command = args.next_arg()?;
+    let key = args.next_arg()?;
+    let delta_arg = args.next_arg()?;
+    let delta = delta_arg.parse_float()?;
+    let delta = if negate { -delta } else { delta };
+
+    // TIMESTAMP and RESOLUTION are specific to increments, the other options are those of VKM.ADD
+    let mut timestamp: Option<Timestamp> = None;
+    let mut resolution = Duration::ZERO;
+    let mut add_args: Vec<ValkeyString> = Vec::new();
+    while let Some(arg) = args.next() {
+        let name = arg.try_as_str()?;
+        if name.eq_ignore_ascii_case(CMD_ARG_TIMESTAMP) {
+            timestamp = Some(parse_timestamp(args.next_str()?)?);
+        } else if name.eq_ignore_ascii_case(CMD_ARG_RESOLUTION) {
+            let Ok(value) = parse_duration_arg(&args.next_arg()?) else {
+                return Err(ValkeyError::Str("ERR invalid RESOLUTION value"));
+            };
+            resolution = value;
+        } else if name.eq_ignore_ascii_case(CMD_ARG_LABELS) {
+            // labels take the remaining arguments
+            add_args.push(arg);
+            add_args.extend(args.by_ref());
+        } else {
+            add_args.push(arg);
+        }
+    }
+    let AddOptions { options, on_duplicate } = parse_add_options(&mut add_args.iter().cloned())?;
+    if on_duplicate.is_some() {
+        return Err(ValkeyError::Str("ERR ON_DUPLICATE is not supported for increments"));
+    }
+
+    let timestamp = timestamp.unwrap_or_else(current_time_millis);
+
+    let redis_key = ctx.open_key_writable(&key);
+    if let Some(series) = redis_key.get_value::<TimeSeries>(&VKM_SERIES_TYPE)? {
+        // creation options are ignored for existing series
+        let prev_range = series.time_range();
+        let written = series.increment(timestamp, delta, resolution)?;
+        update_time_index(ctx, series, prev_range);
+        get_query_result_cache().invalidate_series(series.id, written);
+        replicate_increment(ctx, &command, &key, &delta_arg, written, &add_args, negate);
+        return Ok(ValkeyValue::Integer(written));
+    }
+
+    let mut ts = create_series(&key, options, ctx)?;
+    let written = match ts.increment(timestamp, delta, resolution) {
+        Ok(written) => written,
+        Err(err) => {
+            // the series was indexed on creation, but is not stored
+            with_timeseries_index(ctx, |index| index.remove_series(&ts));
+            return Err(err.into());
+        }
+    };
+    update_time_index(ctx, &ts, None);
+
+    let redis_key = ValkeyKeyWritable::open(ctx.ctx, &key);
+    redis_key.set_value(&VKM_SERIES_TYPE, ts)?;
+
+    // a new series may match any cached query
+    let db = unsafe { get_current_db(ctx.ctx) };
+    get_query_result_cache().invalidate_since(db, written);
+
+    replicate_increment(ctx, &command, &key, &delta_arg, written, &add_args, negate);
+    Ok(ValkeyValue::Integer(written))
+}
+
+/// Replicates an increment with the timestamp of the written sample and without RESOLUTION, since
+/// the default timestamp and the bucket of the sample depend on the clock of the primary.
+fn replicate_increment(
+    ctx: &Context,
+    command: &ValkeyString,
+    key: &ValkeyString,
+    delta: &ValkeyString,
+    written: Timestamp,
+    add_args: &[ValkeyString],
+    negate: bool,
+) {
+    let timestamp_arg = ctx.create_string(CMD_ARG_TIMESTAMP);
+    let written_arg = ctx.create_string(written.to_string());
+    // LABELS takes the remaining arguments, so TIMESTAMP goes before the VKM.ADD options
+    let mut args: Vec<&ValkeyString> = vec![key, delta, &timestamp_arg, &written_arg];
+    args.extend(add_args.iter());
+    ctx.replicate(&command.to_string_lossy(), &args[..]);
+
+    let event = if negate { "PROM.DECRBY" } else { "PROM.INCRBY" };
+    ctx.notify_keyspace_event(NotifyEvent::MODULE, event, key);
+}
@@ -0,0 +1,103 @@
+use crate::globals::with_timeseries_index;
+use crate::index::{check_index, repair_index, IndexCheckReport, KeyType};
+use std::collections::HashMap;
+use valkey_module::redisvalue::ValkeyValueKey;
+use valkey_module::{Context, NextArg, ValkeyError, ValkeyResult, ValkeyString, ValkeyValue};
+
+const DEFAULT_LIMIT: usize = 100;
+static CMD_ARG_LIMIT: &str = "LIMIT";
+
+///
+/// VKM.INDEX-CHECK [LIMIT limit]
+///
+/// Compares the index of the current db with the series in its keyspace, reporting orphaned ids,
+/// unindexed keys and series with mismatched labels. At most `limit` ids or keys are listed for
+/// each kind of discrepancy.
+pub fn index_check(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
+    let limit = parse_limit(args)?;
+    with_timeseries_index(ctx, |index| {
+        let report = check_index(ctx, index);
+        Ok(format_report(&report, limit, None))
+    })
+}
+
+///
+/// VKM.INDEX-REPAIR [LIMIT limit]
+///
+/// Like VKM.INDEX-CHECK, but also fixes the discrepancies found. Orphaned ids are removed from
+/// the index, and series with unindexed keys or mismatched labels are re-indexed.
+pub fn index_repair(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
+    let limit = parse_limit(args)?;
+    with_timeseries_index(ctx, |index| {
+        let report = check_index(ctx, index);
+        let repaired = if report.is_consistent() {
+            0
+        } else {
+            repair_index(ctx, index, &report)
+        };
+        Ok(format_report(&report, limit, Some(repaired)))
+    })
+}
+
+fn parse_limit(args: Vec<ValkeyString>) -> ValkeyResult<usize> {
+    let mut args = args.into_iter().skip(1);
+    let mut limit = DEFAULT_LIMIT;
+    while let Ok(arg) = args.next_str() {
+        match arg {
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_LIMIT) => {
+                let next = args.next_u64()?;
+                if next > usize::MAX as u64 {
+                    return Err(ValkeyError::Str("ERR LIMIT too large"));
+                }
+                limit = next as usize;
+            }
+            _ => {
+                let msg = format!("ERR invalid argument '{}'", arg);
+                return Err(ValkeyError::String(msg));
+            }
+        }
+    }
+    Ok(limit)
+}
+
+fn format_report(report: &IndexCheckReport, limit: usize, repaired: Option<usize>) -> ValkeyValue {
+    let orphaned_ids = report.orphaned_ids
+        .iter()
+        .take(limit)
+        .map(|id| ValkeyValue::Integer(id as i64))
+        .collect::<Vec<_>>();
+
+    let mut res: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(6);
+    res.insert("series".into(), report.series_count.into());
+    res.insert("consistent".into(), ValkeyValue::Bool(report.is_consistent()));
+    res.insert(
+        "orphanedIds".into(),
+        format_discrepancies(report.orphaned_ids.cardinality() as usize, orphaned_ids),
+    );
+    res.insert(
+        "unindexedKeys".into(),
+        format_discrepancies(report.unindexed_keys.len(), format_keys(&report.unindexed_keys, limit)),
+    );
+    res.insert(
+        "labelMismatches".into(),
+        format_discrepancies(report.label_mismatches.len(), format_keys(&report.label_mismatches, limit)),
+    );
+    if let Some(repaired) = repaired {
+        res.insert("repaired".into(), repaired.into());
+    }
+    ValkeyValue::Map(res)
+}
+
+fn format_discrepancies(count: usize, items: Vec<ValkeyValue>) -> ValkeyValue {
+    let mut res: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(2);
+    res.insert("count".into(), count.into());
+    res.insert("items".into(), ValkeyValue::Array(items));
+    ValkeyValue::Map(res)
+}
+
+fn format_keys(keys: &[KeyType], limit: usize) -> Vec<ValkeyValue> {
+    keys.iter()
+        .take(limit)
+        .map(|key| ValkeyValue::StringBuffer(key.to_vec()))
+        .collect()
+}
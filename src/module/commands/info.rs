@@ -1,4 +1,3 @@
-use crate::common::types::Label;
 use crate::module::result::META_KEY_LABEL;
 use crate::module::with_timeseries_mut;
 use crate::storage::time_series::TimeSeries;
@@ -43,6 +42,10 @@ fn get_ts_info(ts: &TimeSeries, debug: bool, key: Option<&ValkeyString>) -> Valk
     map.insert("chunkCount".into(), (ts.chunks.len() as f64).into());
     map.insert("chunkSize".into(), ts.chunk_size_bytes.into());
     map.insert("chunkType".into(), ts.chunk_compression.name().into());
+    map.insert("counter".into(), ValkeyValue::Bool(ts.counter));
+    if ts.counter {
+        map.insert("counterResets".into(), ts.counter_resets.into());
+    }
 
     map.insert(
         ValkeyValueKey::String(METRIC_NAME_LABEL.into()),
@@ -52,8 +55,8 @@ fn get_ts_info(ts: &TimeSeries, debug: bool, key: Option<&ValkeyString>) -> Valk
         map.insert(ValkeyValueKey::String(META_KEY_LABEL.into()), ValkeyValue::from(key));
     }
     let mut labels_map: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(ts.labels.len() + 1);
-    for Label { name, value } in ts.labels.iter() {
-        labels_map.insert(ValkeyValueKey::String(name.into()), ValkeyValue::from(value));
+    for label in ts.labels.iter() {
+        labels_map.insert(ValkeyValueKey::String(label.name().into()), ValkeyValue::from(label.value()));
     }
     map.insert("labels".into(), ValkeyValue::from(labels_map));
 
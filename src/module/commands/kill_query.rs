@@ -0,0 +1,18 @@
+use crate::query::cancel_query;
+use valkey_module::{Context, NextArg, ValkeyError, ValkeyResult, ValkeyString, ValkeyValue};
+
+///
+/// VKM.KILL-QUERY id
+///
+/// Cancels a running query. `id` is the id reported by VKM.ACTIVE-QUERIES.
+/// Returns the number of queries cancelled.
+pub fn kill_query(_ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
+    let mut args = args.into_iter().skip(1);
+    let id = args.next_u64()?;
+    args.done()?;
+
+    if !cancel_query(id) {
+        return Err(ValkeyError::Str("ERR query not found"));
+    }
+    Ok(ValkeyValue::from(1i64))
+}
@@ -1,18 +1,47 @@
-use crate::arg_parse::parse_timestamp;
-use crate::module::with_timeseries_mut;
+use crate::arg_parse::{parse_metric_key, parse_timestamp};
+use crate::error::TsdbError;
+use crate::storage::time_series::TimeSeries;
+use crate::storage::{DuplicatePolicy, TimeSeriesOptions};
+use super::add::{add_by_metric_name, add_sample, parse_on_duplicate};
+use crate::module::VKM_SERIES_TYPE;
 use valkey_module::{Context, NextArg, ValkeyError, ValkeyResult, ValkeyString, ValkeyValue};
 use crate::common::types::Timestamp;
 
+const CMD_ARG_ON_DUPLICATE: &str = "ON_DUPLICATE";
+
+///
+/// VKM.MADD [ON_DUPLICATE policy] key|metric timestamp value [key|metric timestamp value ...]
+///
+/// As with VKM.ADD, series may be given as Prometheus metrics with labels instead of keys, in which
+/// case they are created with default options if they do not exist. ON_DUPLICATE overrides the
+/// duplicate policy of the series for all samples.
+///
+/// Samples are added independently of each other. The reply holds, for each sample, either its
+/// timestamp or an error:
+///
+/// - `DUPLICATE_SAMPLE` if the duplicate policy rejected the sample
+/// - `SAMPLE_TOO_OLD` if the sample is older than the retention of the series
+/// - `WRONGTYPE` if the key does not hold a series
+/// - `NO_SERIES` if the key does not exist
+/// - `SERIESLIMIT` if creating the series of a metric would exceed a series limit
 pub fn madd(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
-    let arg_count = args.len() - 1;
-    let mut args = args.into_iter().skip(1);
+    let mut args = args.into_iter().skip(1).peekable();
+
+    let mut on_duplicate = None;
+    if let Some(arg) = args.peek() {
+        if arg.try_as_str().is_ok_and(|arg| arg.eq_ignore_ascii_case(CMD_ARG_ON_DUPLICATE)) {
+            args.next();
+            on_duplicate = Some(parse_on_duplicate(args.next_str()?)?);
+        }
+    }
 
+    let arg_count = args.len();
     if arg_count < 3 {
         return Err(ValkeyError::WrongArity);
     }
 
     if arg_count % 3 != 0 {
-        return Err(ValkeyError::Str("ERR TSDB: wrong number of arguments for 'TS.MADD' command"));
+        return Err(ValkeyError::Str("ERR TSDB: wrong number of arguments for 'VKM.MADD' command"));
     }
 
     let sample_count = arg_count / 3;
@@ -27,22 +56,50 @@ pub fn madd(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
     }
 
     for (key, timestamp, value) in inputs {
-        let value = with_timeseries_mut(ctx, &key, |series| {
-            if series.add(timestamp, value, None).is_ok() {
-                Ok(ValkeyValue::from(timestamp))
-            } else {
-                // todo !!!!!
-                Ok(ValkeyValue::SimpleString("ERR".to_string()))
-            }
-        });
-        match value {
-            Ok(value) => values.push(value),
-            Err(err) => values.push(
-                ValkeyValue::SimpleString(format!("ERR TSDB: {}", err)),
-            ),
-        }
+        let value = match add_one(ctx, &key, timestamp, value, on_duplicate) {
+            Ok(()) => ValkeyValue::Integer(timestamp),
+            Err(err) => err,
+        };
+        values.push(value);
     }
 
     Ok(ValkeyValue::Array(values))
+}
+
+fn add_one(
+    ctx: &Context,
+    key: &ValkeyString,
+    timestamp: Timestamp,
+    value: f64,
+    on_duplicate: Option<DuplicatePolicy>
+) -> Result<(), ValkeyValue> {
+    let redis_key = ctx.open_key_writable(key);
+    match redis_key.get_value::<TimeSeries>(&VKM_SERIES_TYPE) {
+        Ok(Some(series)) => {
+            add_sample(ctx, series, timestamp, value, on_duplicate).map_err(|err| sample_error(&err))
+        }
+        Ok(None) => {
+            let Some(metric) = key.try_as_str().ok().and_then(parse_metric_key) else {
+                return Err(ValkeyValue::StaticError("NO_SERIES the key does not exist"));
+            };
+            let options = TimeSeriesOptions::default();
+            add_by_metric_name(ctx, key, metric, timestamp, value, options, on_duplicate)
+                .map_err(|err| sample_error(&err))
+        }
+        Err(_) => Err(ValkeyValue::StaticError(
+            "WRONGTYPE Operation against a key holding the wrong kind of value"
+        )),
+    }
+}
 
+/// Maps the error of adding a sample to its entry in the reply.
+fn sample_error(err: &TsdbError) -> ValkeyValue {
+    let msg = match err {
+        TsdbError::DuplicateSample(_) => "DUPLICATE_SAMPLE the sample was rejected by the duplicate policy",
+        TsdbError::SampleTooOld => "SAMPLE_TOO_OLD the sample timestamp is older than the retention period",
+        TsdbError::SeriesLimitExceeded(_) => "SERIESLIMIT a series limit was exceeded",
+        TsdbError::InvalidMetric(_) => "ERR TSDB: invalid metric",
+        _ => "ERR TSDB: cannot add the sample",
+    };
+    ValkeyValue::StaticError(msg)
 }
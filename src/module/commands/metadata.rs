@@ -1,10 +1,10 @@
-use crate::common::METRIC_NAME_LABEL;
 use crate::globals::with_timeseries_index;
+use crate::index::{LabelSearch, TimeSeriesIndex};
 use crate::module::arg_parse::{parse_series_selector, MetadataFunctionArgs, TimestampRangeValue};
-use crate::module::result::{format_array_result, get_ts_metric_selector};
+use crate::module::result::{format_series_result, format_string_array_result, Annotations, ResultFormat, SeriesLabels};
 use crate::module::{normalize_range_args, parse_timestamp_arg, VKM_SERIES_TYPE};
 use crate::storage::time_series::TimeSeries;
-use std::collections::BTreeSet;
+use croaring::Bitmap64;
 use valkey_module::{
     Context as RedisContext, Context, NextArg, ValkeyError, ValkeyResult, ValkeyString, ValkeyValue,
 };
@@ -12,72 +12,86 @@ use valkey_module::{
 
 /// https://prometheus.io/docs/prometheus/latest/querying/api/#finding-series-by-label-matchers
 pub fn series(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
-    let label_args = parse_metadata_command_args(ctx, args, true)?;
+    let label_args = parse_metadata_command_args(ctx, args, MetadataCommand::Series)?;
     let limit = label_args.limit.unwrap_or(usize::MAX);
 
+    let format = label_args.format;
+    let mut truncated = false;
+
     let values = with_matched_series(ctx, Vec::new(), label_args, |mut acc, ts, key| {
         if acc.len() < limit {
-            acc.push(get_ts_metric_selector(ts, Some(key)));
+            acc.push(SeriesLabels::new(ts, key));
+        } else {
+            truncated = true;
         }
         acc
     })?;
 
-    Ok(format_array_result(values))
+    Ok(format_series_result(&values, format, &get_annotations(truncated)))
 }
 
 pub fn cardinality(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
-    let label_args = parse_metadata_command_args(ctx, args, true)?;
+    let label_args = parse_metadata_command_args(ctx, args, MetadataCommand::Cardinality)?;
     let count = with_matched_series(ctx, 0, label_args, |acc, _, _| acc + 1)?;
 
     Ok(ValkeyValue::from(count as i64))
 }
 
+/// VKM.LABEL-NAMES [MATCH selector...] [START timestamp] [END timestamp] [SEARCH PREFIX|SUBSTRING term] [LIMIT limit]
+///
 /// https://prometheus.io/docs/prometheus/latest/querying/api/#getting-label-names
 pub fn label_names(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
-    // todo: this does a lot of cloning :-(
-    let label_args = parse_metadata_command_args(ctx, args, false)?;
+    let label_args = parse_metadata_command_args(ctx, args, MetadataCommand::LabelNames)?;
     let limit = label_args.limit.unwrap_or(usize::MAX);
 
-    let mut acc: BTreeSet<String> = BTreeSet::new();
-    acc.insert(METRIC_NAME_LABEL.to_string());
-
-    let names = with_matched_series(ctx, acc, label_args, |mut acc, ts, _| {
-        for label in ts.labels.iter() {
-            acc.insert(label.name.clone());
-        }
-        acc
-    })?;
-
-    let labels = names
-        .into_iter()
-        .take(limit)
-        .map(ValkeyValue::from)
-        .collect::<Vec<_>>();
+    // fetch one more name than the limit to tell whether the result is truncated
+    let mut names = with_timeseries_index(ctx, |index| {
+        let series = get_label_series_filter(index, &label_args);
+        index.find_label_names(series.as_ref(), label_args.search.as_ref(), limit.saturating_add(1))
+    });
+    let truncated = names.len() > limit;
+    names.truncate(limit);
 
-    Ok(format_array_result(labels))
+    Ok(format_string_array_result(&names, label_args.format, &get_annotations(truncated)))
 }
 
-// PROM.LABEL_VALUES <label_name> [MATCH <match>] [START <timestamp_ms>] [END <timestamp_ms>]
-// https://prometheus.io/docs/prometheus/latest/querying/api/#querying-label-values
+/// VKM.LABEL-VALUES label [MATCH selector...] [START timestamp] [END timestamp] [SEARCH PREFIX|SUBSTRING term] [LIMIT limit]
+///
+/// https://prometheus.io/docs/prometheus/latest/querying/api/#querying-label-values
 pub(crate) fn label_values(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
-    let label_args = parse_metadata_command_args(ctx, args, true)?;
+    let label_args = parse_metadata_command_args(ctx, args, MetadataCommand::LabelValues)?;
     let limit = label_args.limit.unwrap_or(usize::MAX);
+    // checked by parse_metadata_command_args
+    let label_name = label_args.label_name.as_deref().unwrap_or_default();
 
-    let acc: BTreeSet<String> = BTreeSet::new();
-    let names = with_matched_series(ctx, acc, label_args, |mut acc, ts, _| {
-        for label in ts.labels.iter() {
-            acc.insert(label.value.clone());
-        }
-        acc
-    })?;
+    let mut values = with_timeseries_index(ctx, |index| {
+        let series = get_label_series_filter(index, &label_args);
+        index.find_label_values(label_name, series.as_ref(), label_args.search.as_ref(), limit.saturating_add(1))
+    });
+    let truncated = values.len() > limit;
+    values.truncate(limit);
 
-    let label_values = names
-        .into_iter()
-        .take(limit)
-        .map(ValkeyValue::from)
-        .collect::<Vec<_>>();
+    Ok(format_string_array_result(&values, label_args.format, &get_annotations(truncated)))
+}
 
-    Ok(format_array_result(label_values))
+/// Prometheus warns when a LIMIT drops results
+fn get_annotations(truncated: bool) -> Annotations {
+    let mut annotations = Annotations::default();
+    if truncated {
+        annotations.warnings.push("results truncated due to limit".to_string());
+    }
+    annotations
+}
+
+/// Returns the series whose labels are considered by label lookups, or None for all series.
+fn get_label_series_filter(index: &TimeSeriesIndex, args: &MetadataFunctionArgs) -> Option<Bitmap64> {
+    if !args.matchers.is_empty() {
+        Some(index.series_ids_by_matchers(&args.matchers, Some((args.start, args.end))))
+    } else if args.has_range {
+        Some(index.series_ids_in_range(args.start, args.end))
+    } else {
+        None
+    }
 }
 
 pub(crate) fn with_matched_series<F, R>(ctx: &Context, mut acc: R, args: MetadataFunctionArgs, mut f: F) -> ValkeyResult<R>
@@ -85,7 +99,7 @@ where
     F: FnMut(R, &TimeSeries, &ValkeyString) -> R,
 {
     with_timeseries_index(ctx, move |index| {
-        let keys = index.series_keys_by_matchers(ctx, &args.matchers);
+        let keys = index.series_keys_by_matchers(ctx, &args.matchers, Some((args.start, args.end)));
         if keys.is_empty() {
             return Err(ValkeyError::Str("ERR no series found"));
         }
@@ -112,18 +126,51 @@ static CMD_ARG_START: &str = "START";
 static CMD_ARG_END: &str = "END";
 static CMD_ARG_MATCH: &str = "MATCH";
 static CMD_ARG_LIMIT: &str = "LIMIT";
+static CMD_ARG_SEARCH: &str = "SEARCH";
+static CMD_ARG_PREFIX: &str = "PREFIX";
+static CMD_ARG_SUBSTRING: &str = "SUBSTRING";
+static CMD_ARG_FORMAT: &str = "FORMAT";
+
+fn is_option_name(arg: &str) -> bool {
+    [CMD_ARG_START, CMD_ARG_END, CMD_ARG_MATCH, CMD_ARG_LIMIT, CMD_ARG_SEARCH, CMD_ARG_FORMAT]
+        .iter()
+        .any(|name| arg.eq_ignore_ascii_case(name))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MetadataCommand {
+    /// MATCH is required
+    Series,
+    /// As Series, but only counts the series
+    Cardinality,
+    LabelNames,
+    /// Takes a label name as first argument
+    LabelValues,
+}
+
+impl MetadataCommand {
+    fn matches_series(self) -> bool {
+        matches!(self, MetadataCommand::Series | MetadataCommand::Cardinality)
+    }
+}
 
 fn parse_metadata_command_args(
-    _ctx: &RedisContext,
+    ctx: &RedisContext,
     args: Vec<ValkeyString>,
-    require_matchers: bool,
+    command: MetadataCommand,
 ) -> ValkeyResult<MetadataFunctionArgs> {
-    let mut args = args.into_iter().skip(1);
-    let label_name = None;
+    let mut args = args.into_iter().skip(1).peekable();
+    let label_name = if command == MetadataCommand::LabelValues {
+        Some(args.next_string()?)
+    } else {
+        None
+    };
     let mut matchers = Vec::with_capacity(4);
     let mut start_value: Option<TimestampRangeValue> = None;
     let mut end_value: Option<TimestampRangeValue> = None;
+    let mut search: Option<LabelSearch> = None;
     let mut limit: Option<usize> = None;
+    let mut format = ResultFormat::resp(ctx);
 
     while let Ok(arg) = args.next_str() {
         match arg {
@@ -136,14 +183,29 @@ fn parse_metadata_command_args(
                 end_value = Some(parse_timestamp_arg(next, "END")?);
             }
             arg if arg.eq_ignore_ascii_case(CMD_ARG_MATCH) => {
-                while let Ok(matcher) = args.next_str() {
+                // selectors run up to the next option
+                while let Some(next) = args.peek() {
+                    let matcher = next.try_as_str()?;
+                    if is_option_name(matcher) {
+                        break;
+                    }
                     if let Ok(selector) = parse_series_selector(matcher) {
                         matchers.push(selector);
                     } else {
                         return Err(ValkeyError::Str("ERR invalid MATCH series selector"));
                     }
+                    args.next();
                 }
             }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_SEARCH) && !command.matches_series() => {
+                let mode = args.next_str()?;
+                let term = args.next_string()?;
+                search = match mode {
+                    mode if mode.eq_ignore_ascii_case(CMD_ARG_PREFIX) => Some(LabelSearch::Prefix(term)),
+                    mode if mode.eq_ignore_ascii_case(CMD_ARG_SUBSTRING) => Some(LabelSearch::Substring(term)),
+                    _ => return Err(ValkeyError::Str("ERR SEARCH mode must be PREFIX or SUBSTRING")),
+                };
+            }
             arg if arg.eq_ignore_ascii_case(CMD_ARG_LIMIT) => {
                 let next = args.next_u64()?;
                 if next > usize::MAX as u64 {
@@ -151,6 +213,9 @@ fn parse_metadata_command_args(
                 }
                 limit = Some(next as usize);
             }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_FORMAT) && command != MetadataCommand::Cardinality => {
+                format = ResultFormat::parse(ctx, args.next_str()?)?;
+            }
             _ => {
                 let msg = format!("ERR invalid argument '{}'", arg);
                 return Err(ValkeyError::String(msg));
@@ -158,9 +223,10 @@ fn parse_metadata_command_args(
         };
     }
 
+    let has_range = start_value.is_some() || end_value.is_some();
     let (start, end) = normalize_range_args(start_value, end_value)?;
 
-    if require_matchers && matchers.is_empty() {
+    if command.matches_series() && matchers.is_empty() {
         return Err(ValkeyError::Str(
             "ERR at least 1 MATCH series selector required",
         ));
@@ -170,7 +236,10 @@ fn parse_metadata_command_args(
         label_name,
         start,
         end,
+        has_range,
         matchers,
+        search,
         limit,
+        format,
     })
 }
@@ -13,8 +13,13 @@ mod stats;
 mod delete_series;
 mod top_queries;
 mod active_queries;
+mod kill_query;
 mod reset_rollup_cache;
 mod info;
+mod index_check;
+mod explain_selector;
+mod series_limits;
+mod incrby;
 
 pub use alter::*;
 pub use delete_range::*;
@@ -31,4 +36,9 @@ pub use delete_series::*;
 pub use stats::*;
 pub use top_queries::*;
 pub use active_queries::*;
+pub use kill_query::*;
 pub use reset_rollup_cache::*;
+pub use index_check::*;
+pub use explain_selector::*;
+pub use series_limits::*;
+pub use incrby::*;
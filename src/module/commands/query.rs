@@ -1,13 +1,19 @@
 use crate::common::{current_time_millis, duration_to_chrono};
 use crate::config::get_global_settings;
-use crate::globals::get_query_context;
-use crate::module::result::to_matrix_result;
+use crate::globals::{get_current_db, get_query_context, get_query_result_cache};
+use crate::query::{align_to_step, check_points_limit, collect_trace, merge_query_results, query_deadline, run_cancellable, run_query, spawn_blocking_query, QueryCacheKey, QueryLimits, QueryState, QueryStats};
+use crate::module::result::{format_query_error, format_query_result, QueryExtras, QueryResultData, ResultFormat};
 use crate::module::{normalize_range_args, parse_timestamp_arg};
+use metricsql_parser::parser::parse;
+use metricsql_parser::prelude::{Expr, ValueType};
 use metricsql_runtime::execution::query::{
     query as engine_query, query_range as engine_query_range,
 };
 use metricsql_runtime::prelude::query::QueryParams;
-use metricsql_runtime::{QueryResult, RuntimeResult};
+use metricsql_runtime::{Deadline, QueryResult, RuntimeResult};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info_span};
 use valkey_module::{Context, NextArg, ValkeyError, ValkeyResult, ValkeyString};
 use crate::module::arg_parse::{parse_duration_arg, TimestampRangeValue};
 
@@ -16,6 +22,12 @@ const CMD_ARG_END: &str = "END";
 const CMD_ARG_TIME: &str = "TIME";
 const CMD_ARG_STEP: &str = "STEP";
 const CMD_ARG_ROUNDING: &str = "ROUNDING";
+const CMD_ARG_TIMEOUT: &str = "TIMEOUT";
+const CMD_ARG_MAX_SERIES: &str = "MAX_SERIES";
+const CMD_ARG_MAX_SAMPLES: &str = "MAX_SAMPLES";
+const CMD_ARG_FORMAT: &str = "FORMAT";
+const CMD_ARG_STATS: &str = "STATS";
+const CMD_ARG_TRACE: &str = "TRACE";
 
 
 ///
@@ -24,8 +36,14 @@ const CMD_ARG_ROUNDING: &str = "ROUNDING";
 ///     [END rfc3339 | unix_timestamp | + | - | * ]
 ///     [STEP duration]
 ///     [ROUNDING digits]
+///     [TIMEOUT duration]
+///     [MAX_SERIES count]
+///     [MAX_SAMPLES count]
+///     [FORMAT resp|json|prometheus]
+///     [STATS]
+///     [TRACE]
 ///
-pub(crate) fn query_range(_ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
+pub(crate) fn query_range(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
     let mut args = args.into_iter().skip(1);
     let query = args.next_string()?;
     let mut start_value: Option<TimestampRangeValue> = None;
@@ -34,6 +52,11 @@ pub(crate) fn query_range(_ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResu
 
     let config = get_global_settings();
     let mut round_digits: u8 = config.round_digits.unwrap_or(100);
+    let mut timeout = config.query_timeout;
+    let mut limits = QueryLimits::default();
+    let mut format = ResultFormat::resp(ctx);
+    let mut with_stats = false;
+    let mut with_trace = false;
 
     while let Ok(arg) = args.next_str() {
         match arg {
@@ -52,6 +75,25 @@ pub(crate) fn query_range(_ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResu
             arg if arg.eq_ignore_ascii_case(CMD_ARG_ROUNDING) => {
                 round_digits = args.next_u64()?.max(100) as u8;
             }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_TIMEOUT) => {
+                let next = args.next_arg()?;
+                timeout = parse_timeout(&next)?;
+            }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_MAX_SERIES) => {
+                limits.max_series = args.next_u64()? as usize;
+            }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_MAX_SAMPLES) => {
+                limits.max_samples = args.next_u64()? as usize;
+            }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_FORMAT) => {
+                format = ResultFormat::parse(ctx, args.next_str()?)?;
+            }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_STATS) => {
+                with_stats = true;
+            }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_TRACE) => {
+                with_trace = true;
+            }
             _ => {
                 let msg = format!("ERR invalid argument '{}'", arg);
                 return Err(ValkeyError::String(msg));
@@ -63,6 +105,9 @@ pub(crate) fn query_range(_ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResu
 
     let step = normalize_step(step_value)?;
 
+    check_points_limit(&limits, start, end, step.num_milliseconds())
+        .map_err(|e| ValkeyError::String(format!("ERR {e}")))?;
+
     let mut query_params: QueryParams = get_default_query_params();
     query_params.query = query.to_string();
     query_params.start = start;
@@ -70,8 +115,80 @@ pub(crate) fn query_range(_ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResu
     query_params.step = step;
     query_params.round_digits = round_digits;
 
-    let query_context = get_query_context();
-    handle_query_result(engine_query_range(query_context, &query_params))
+    let db = unsafe { get_current_db(ctx.ctx) };
+    let cache_key = if get_query_result_cache().is_enabled() {
+        QueryCacheKey::new(db, &query, step.num_milliseconds(), round_digits)
+    } else {
+        None
+    };
+
+    let mut state = QueryState::new(db, limits);
+    if with_stats {
+        state = state.with_stats();
+    }
+    if cache_key.is_some() {
+        state = state.with_series_ids();
+    }
+    let state = Arc::new(state);
+
+    let step_millis = step.num_milliseconds();
+    spawn_blocking_query(ctx, move || {
+        let (result, extras) = run_with_extras(&query, &state, with_trace, || {
+            run_cancellable(&query, start, end, step_millis, &state, |id| {
+                query_params.deadline = query_deadline(timeout, id)?;
+                match cache_key {
+                    Some(key) => query_range_cached(key, query_params, &state),
+                    None => exec_query_range(&query_params, &state),
+                }
+            })
+        });
+        handle_query_result(result.map(QueryResultData::Matrix), format, &extras)
+    })
+}
+
+/// Executes a range query, reusing any cached results for a prefix of the (step-aligned) range
+/// and computing only the missing tail.
+fn query_range_cached(
+    key: QueryCacheKey,
+    mut query_params: QueryParams,
+    state: &Arc<QueryState>
+) -> RuntimeResult<Vec<QueryResult>> {
+    let cache = get_query_result_cache();
+    let config = get_global_settings();
+    let step = key.step;
+    let start = align_to_step(query_params.start, step);
+    let end = align_to_step(query_params.end, step);
+
+    // samples may still arrive for recent points, so they are never cached
+    let horizon = current_time_millis() - config.query_cache_horizon.as_millis() as i64;
+    let cacheable_end = end.min(align_to_step(horizon, step));
+
+    // read before the cached head, which invalidations may also have removed since
+    let generation = cache.generation();
+    let (head, compute_start) = match cache.get(&key, start, end) {
+        Some(cached) if cached.end >= end => {
+            debug!(cache = "hit", "query result cache");
+            return Ok(cached.results);
+        }
+        Some(cached) => {
+            debug!(cache = "partial", cached_end = cached.end, "query result cache");
+            (cached.results, cached.end + step)
+        }
+        None => {
+            debug!(cache = "miss", "query result cache");
+            (vec![], start)
+        }
+    };
+
+    query_params.start = compute_start;
+    query_params.end = end;
+    let tail = exec_query_range(&query_params, state)?;
+    let results = merge_query_results(head, tail);
+
+    if cacheable_end >= start {
+        cache.put(key, start, cacheable_end, &results, state.series_ids(), generation);
+    }
+    Ok(results)
 }
 
 ///
@@ -79,14 +196,24 @@ pub(crate) fn query_range(_ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResu
 ///         [TIME rfc3339 | unix_timestamp | * | + ]
 ///         [TIMEOUT duration]
 ///         [ROUNDING digits]
+///         [MAX_SERIES count]
+///         [MAX_SAMPLES count]
+///         [FORMAT resp|json|prometheus]
+///         [STATS]
+///         [TRACE]
 ///
-pub fn query(_ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
+pub fn query(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
     let mut args = args.into_iter().skip(1);
     let query = args.next_string()?;
     let mut time_value: Option<TimestampRangeValue> = None;
 
     let config = get_global_settings();
     let mut round_digits: u8 = config.round_digits.unwrap_or(100);
+    let mut timeout = config.query_timeout;
+    let mut limits = QueryLimits::default();
+    let mut format = ResultFormat::resp(ctx);
+    let mut with_stats = false;
+    let mut with_trace = false;
 
     while let Ok(arg) = args.next_str() {
         match arg {
@@ -97,6 +224,25 @@ pub fn query(_ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
             arg if arg.eq_ignore_ascii_case(CMD_ARG_ROUNDING) => {
                 round_digits = args.next_u64()?.max(100) as u8;
             }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_TIMEOUT) => {
+                let next = args.next_arg()?;
+                timeout = parse_timeout(&next)?;
+            }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_MAX_SERIES) => {
+                limits.max_series = args.next_u64()? as usize;
+            }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_MAX_SAMPLES) => {
+                limits.max_samples = args.next_u64()? as usize;
+            }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_FORMAT) => {
+                format = ResultFormat::parse(ctx, args.next_str()?)?;
+            }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_STATS) => {
+                with_stats = true;
+            }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_TRACE) => {
+                with_trace = true;
+            }
             _ => {
                 let msg = format!("ERR invalid argument '{}'", arg);
                 return Err(ValkeyError::String(msg));
@@ -116,8 +262,97 @@ pub fn query(_ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
     query_params.end = start;
     query_params.round_digits = round_digits;
 
-    let query_context = get_query_context();
-    handle_query_result(engine_query(query_context, &query_params))
+    if let Some(value) = parse_string_literal(&query) {
+        // a string literal evaluates to itself
+        let data = QueryResultData::String(start, value);
+        let extras = QueryExtras {
+            stats: with_stats.then(QueryStats::default),
+            trace: with_trace.then(Vec::new),
+            ..Default::default()
+        };
+        return Ok(format_query_result(data, format, &extras));
+    }
+
+    let db = unsafe { get_current_db(ctx.ctx) };
+    let mut state = QueryState::new(db, limits);
+    if with_stats {
+        state = state.with_stats();
+    }
+    let state = Arc::new(state);
+
+    spawn_blocking_query(ctx, move || {
+        let (result, extras) = run_with_extras(&query, &state, with_trace, || {
+            run_cancellable(&query, start, start, 0, &state, |id| {
+                query_params.deadline = query_deadline(timeout, id)?;
+                run_query(&state, || engine_query(get_query_context(), &query_params))
+            })
+        });
+        let data = result.map(|results| to_instant_result_data(&query, start, results));
+        handle_query_result(data, format, &extras)
+    })
+}
+
+/// Returns the value of a query consisting of a string literal. Only queries starting with a quote
+/// are parsed, so that other queries are parsed by the engine alone.
+fn parse_string_literal(query: &str) -> Option<String> {
+    if !query.trim_start().starts_with(['"', '\'', '`']) {
+        return None;
+    }
+    match parse(query) {
+        Ok(Expr::StringLiteral(value)) => Some(value),
+        // syntax errors are reported by the engine
+        _ => None,
+    }
+}
+
+/// Returns the result of an instant query by its Prometheus result type, which the engine doesn't
+/// report. A scalar is returned by the engine as a single series without labels, so the query is
+/// only parsed to tell a scalar from a vector for such results.
+fn to_instant_result_data(query: &str, start: i64, results: Vec<QueryResult>) -> QueryResultData {
+    let may_be_scalar = results.len() <= 1 && results
+        .iter()
+        .all(|r| r.metric.measurement.is_empty() && r.metric.labels.is_empty());
+    let is_scalar = may_be_scalar && parse(query)
+        .is_ok_and(|expr| matches!(expr.return_type(), ValueType::Scalar));
+    if !is_scalar {
+        return QueryResultData::Vector(results);
+    }
+    let (ts, value) = results
+        .first()
+        .and_then(|r| Some((*r.timestamps.first()?, *r.values.first()?)))
+        .unwrap_or((start, f64::NAN));
+    QueryResultData::Scalar(ts, value)
+}
+
+/// Runs a query, returning the statistics collected in `state` and the trace if requested.
+fn run_with_extras<R>(
+    query: &str,
+    state: &QueryState,
+    with_trace: bool,
+    f: impl FnOnce() -> R
+) -> (R, QueryExtras) {
+    let start = Instant::now();
+    let run = || {
+        let res = f();
+        state.update_stats(|stats| stats.total_time = start.elapsed());
+        res
+    };
+    let mut extras = QueryExtras::default();
+    let res = if with_trace {
+        let (res, trace) = collect_trace(|| {
+            info_span!("query", query).in_scope(run)
+        });
+        extras.trace = Some(trace);
+        res
+    } else {
+        run()
+    };
+    extras.stats = state.stats();
+    (res, extras)
+}
+
+fn exec_query_range(query_params: &QueryParams, state: &Arc<QueryState>) -> RuntimeResult<Vec<QueryResult>> {
+    run_query(state, || engine_query_range(get_query_context(), query_params))
 }
 
 fn parse_step(arg: &ValkeyString) -> ValkeyResult<chrono::Duration> {
@@ -128,6 +363,13 @@ fn parse_step(arg: &ValkeyString) -> ValkeyResult<chrono::Duration> {
     }
 }
 
+fn parse_timeout(arg: &ValkeyString) -> ValkeyResult<Duration> {
+    match parse_duration_arg(arg) {
+        Ok(duration) if !duration.is_zero() && Deadline::new(duration).is_ok() => Ok(duration),
+        _ => Err(ValkeyError::Str("ERR invalid TIMEOUT duration")),
+    }
+}
+
 fn normalize_step(step: Option<chrono::Duration>) -> ValkeyResult<chrono::Duration> {
     let config = get_global_settings();
     if let Some(val) = step {
@@ -147,12 +389,16 @@ fn get_default_query_params() -> QueryParams {
     result
 }
 
-fn handle_query_result(result: RuntimeResult<Vec<QueryResult>>) -> ValkeyResult {
+fn handle_query_result(
+    result: RuntimeResult<QueryResultData>,
+    format: ResultFormat,
+    extras: &QueryExtras
+) -> ValkeyResult {
     match result {
-        Ok(result) => Ok(to_matrix_result(result)),
+        Ok(data) => Ok(format_query_result(data, format, extras)),
         Err(e) => {
             let err_msg = format!("PROM: Error: {:?}", e);
-            Err(ValkeyError::String(err_msg.to_string()))
+            format_query_error(err_msg, format)
         }
     }
 }
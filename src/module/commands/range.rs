@@ -1,13 +1,16 @@
 use crate::aggregators::Aggregator;
 use crate::arg_parse::{parse_duration_arg, parse_integer_arg, parse_number_with_unit, parse_timestamp};
 use crate::common::types::Timestamp;
+use crate::config::get_global_settings;
 use crate::module::result::sample_to_result;
 use crate::module::{parse_timestamp_arg, with_timeseries_mut};
-use crate::storage::{AggregationOptions, BucketTimestamp, RangeAlignment, RangeOptions};
+use crate::query::{check_points_limit, QueryLimits};
+use crate::storage::{AggregationOptions, BucketTimestamp, FillMode, RangeAlignment, RangeOptions, ResampleOptions};
 use valkey_module::{Context, NextArg, ValkeyError, ValkeyResult, ValkeyString, ValkeyValue};
 use std::iter::Skip;
+use std::time::Duration;
 use std::vec::IntoIter;
-use crate::module::commands::range_utils::get_range;
+use crate::module::commands::range_utils::get_range_with_empty_buckets;
 
 const CMD_ARG_FILTER_BY_VALUE: &str = "FILTER_BY_VALUE";
 const CMD_ARG_FILTER_BY_TS: &str = "FILTER_BY_TS";
@@ -16,7 +19,12 @@ const CMD_ARG_COUNT: &str = "COUNT";
 const CMD_ARG_EMPTY: &str = "EMPTY";
 const CMD_ARG_AGGREGATION: &str = "AGGREGATION";
 const CMD_ARG_BUCKET_TIMESTAMP: &str = "BUCKETTIMESTAMP";
+const CMD_ARG_FILL: &str = "FILL";
+const CMD_ARG_STEP: &str = "STEP";
+const CMD_ARG_LOOKBACK: &str = "LOOKBACK";
 const MAX_TS_VALUES_FILTER: usize = 25;
+/// The LOOKBACK of resampling when neither it nor the max_look_back setting are given
+const DEFAULT_RESAMPLE_LOOKBACK: Duration = Duration::from_secs(5 * 60);
 
 pub fn range(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
     let mut args = args.into_iter().skip(1);
@@ -34,8 +42,26 @@ pub fn range(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
             return Err(ValkeyError::Str("ERR invalid range"));
         }
 
-        let samples = get_range(series, &options, false);
-        let result = samples.iter().map(|s| sample_to_result(s.timestamp, s.value)).collect();
+        if let Some(resample) = &options.resample {
+            // each step may produce a point, so the range is limited as that of a range query
+            let step = resample.step.as_millis() as i64;
+            check_points_limit(&QueryLimits::default(), start, end, step)
+                .map_err(|e| ValkeyError::String(format!("TSDB: {e}")))?;
+        }
+
+        let (samples, empty_buckets) = get_range_with_empty_buckets(series, &options, false);
+        // only empty buckets are null, not those whose aggregate is NaN
+        let fill_null = options.aggregation
+            .as_ref()
+            .is_some_and(|aggr| aggr.fill == Some(FillMode::Null));
+        let null_buckets: &[usize] = if fill_null { &empty_buckets } else { &[] };
+        let result = samples.iter().enumerate().map(|(i, s)| {
+            if null_buckets.binary_search(&i).is_ok() {
+                ValkeyValue::Array(vec![ValkeyValue::Integer(s.timestamp), ValkeyValue::Null])
+            } else {
+                sample_to_result(s.timestamp, s.value)
+            }
+        }).collect();
         Ok(ValkeyValue::Array(result))
     })
 }
@@ -52,8 +78,11 @@ pub fn parse_range_options(args: &mut Skip<IntoIter<ValkeyString>>) -> ValkeyRes
         count: None,
         aggregation: None,
         filter: None,
+        resample: None,
         latest: false,
     };
+    let mut step: Option<Duration> = None;
+    let mut lookback: Option<Duration> = None;
 
     while let Ok(arg) = args.next_str() {
         match arg {
@@ -83,9 +112,43 @@ pub fn parse_range_options(args: &mut Skip<IntoIter<ValkeyString>>) -> ValkeyRes
                 }
                 options.count = Some(count as usize);
             }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_STEP) => {
+                let duration = parse_duration_arg(&args.next_arg()?)
+                    .map_err(|_| ValkeyError::Str("TSDB: cannot parse STEP"))?;
+                if duration.as_millis() == 0 {
+                    return Err(ValkeyError::Str("TSDB: STEP must be at least 1ms"));
+                }
+                step = Some(duration);
+            }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_LOOKBACK) => {
+                let duration = parse_duration_arg(&args.next_arg()?)
+                    .map_err(|_| ValkeyError::Str("TSDB: cannot parse LOOKBACK"))?;
+                lookback = Some(duration);
+            }
             _ => {}
         }
     }
+
+    match step {
+        Some(step) => {
+            if options.aggregation.is_some() {
+                return Err(ValkeyError::Str("TSDB: STEP cannot be combined with AGGREGATION"));
+            }
+            let lookback = lookback.unwrap_or_else(|| {
+                let max_look_back = get_global_settings().max_look_back;
+                if max_look_back.is_zero() {
+                    DEFAULT_RESAMPLE_LOOKBACK
+                } else {
+                    max_look_back
+                }
+            });
+            options.resample = Some(ResampleOptions { step, lookback });
+        }
+        None if lookback.is_some() => {
+            return Err(ValkeyError::Str("TSDB: LOOKBACK requires STEP"));
+        }
+        None => {}
+    }
     Ok(options)
 }
 
@@ -118,6 +181,9 @@ fn is_range_command_keyword(arg: &str) -> bool {
         arg if arg.eq_ignore_ascii_case(CMD_ARG_BUCKET_TIMESTAMP) => true,
         arg if arg.eq_ignore_ascii_case(CMD_ARG_FILTER_BY_TS) => true,
         arg if arg.eq_ignore_ascii_case(CMD_ARG_FILTER_BY_VALUE) => true,
+        arg if arg.eq_ignore_ascii_case(CMD_ARG_FILL) => true,
+        arg if arg.eq_ignore_ascii_case(CMD_ARG_STEP) => true,
+        arg if arg.eq_ignore_ascii_case(CMD_ARG_LOOKBACK) => true,
         _ => false,
     }
 }
@@ -150,16 +216,20 @@ pub fn parse_aggregation_args(args: &mut Skip<IntoIter<ValkeyString>>) -> Valkey
     // AGGREGATION token already seen
     let agg_str = args.next_str()
         .map_err(|_e| ValkeyError::Str("TSDB: Error parsing AGGREGATION"))?;
-    let aggregator = Aggregator::try_from(agg_str)?;
+    let mut aggregator = Aggregator::try_from(agg_str)?;
     let bucket_duration = parse_duration_arg(&args.next_arg()?)
         .map_err(|_e| ValkeyError::Str("Error parsing bucketDuration"))?;
+    if bucket_duration.as_millis() == 0 {
+        return Err(ValkeyError::Str("TSDB: bucketDuration must be at least 1ms"));
+    }
+    aggregator.set_bucket_duration(bucket_duration);
 
     let mut aggr: AggregationOptions = AggregationOptions {
         aggregator,
         bucket_duration,
         timestamp_output: BucketTimestamp::Start,
-        time_delta: 0,
         empty: false,
+        fill: None,
     };
     let mut arg_count: usize = 0;
 
@@ -174,6 +244,12 @@ pub fn parse_aggregation_args(args: &mut Skip<IntoIter<ValkeyString>>) -> Valkey
                 arg_count += 1;
                 aggr.timestamp_output = BucketTimestamp::try_from(next)?;
             }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_FILL) => {
+                arg_count += 1;
+                aggr.fill = Some(parse_fill_mode(args)?);
+                // filling implies reporting empty buckets
+                aggr.empty = true;
+            }
             _ => {
                 return Err(ValkeyError::Str("TSDB: unknown AGGREGATION option"))
             }
@@ -185,3 +261,20 @@ pub fn parse_aggregation_args(args: &mut Skip<IntoIter<ValkeyString>>) -> Valkey
 
     Ok(aggr)
 }
+
+fn parse_fill_mode(args: &mut Skip<IntoIter<ValkeyString>>) -> ValkeyResult<FillMode> {
+    let mode = args.next_str()
+        .map_err(|_| ValkeyError::Str("TSDB: missing FILL mode"))?;
+    let fill = match mode {
+        arg if arg.eq_ignore_ascii_case("null") => FillMode::Null,
+        arg if arg.eq_ignore_ascii_case("previous") => FillMode::Previous,
+        arg if arg.eq_ignore_ascii_case("linear") => FillMode::Linear,
+        arg if arg.eq_ignore_ascii_case("value") => {
+            let value = parse_number_with_unit(args.next_str()?)
+                .map_err(|_| ValkeyError::Str("TSDB: cannot parse FILL value"))?;
+            FillMode::Value(value)
+        }
+        _ => return Err(ValkeyError::Str("TSDB: unknown FILL mode")),
+    };
+    Ok(fill)
+}
@@ -1,129 +1,7 @@
-use crate::aggregators::{AggOp, Aggregator};
+use crate::aggregators::{resample, AggrIterator};
 use crate::common::types::{Sample, Timestamp};
 use crate::storage::time_series::TimeSeries;
-use crate::storage::{AggregationOptions, BucketTimestamp, RangeAlignment, RangeOptions};
-
-pub(crate) struct AggrIterator {
-    aggregator: Aggregator,
-    time_delta: i64,
-    bucket_ts: BucketTimestamp,
-    start_timestamp: Timestamp,
-    end_timestamp: Timestamp,
-    last_timestamp: Timestamp,
-    timestamp_alignment: i64,
-    count: Option<usize>,
-    empty: bool
-}
-
-impl AggrIterator {
-    fn normalize_bucket_start(&mut self) -> Timestamp {
-        self.last_timestamp = self.last_timestamp.max(0);
-        self.last_timestamp
-    }
-
-    fn fill_empty_buckets(&self,
-                          samples: &mut Vec<Sample>,
-                          first_bucket_ts: Timestamp,
-                          end_bucket_ts: Timestamp,
-                          max_count: usize) {
-        let time_delta = self.time_delta;
-
-        debug_assert!(end_bucket_ts >= first_bucket_ts);
-        assert_eq!((end_bucket_ts - first_bucket_ts) % time_delta, 0);
-
-        let mut empty_bucket_count = (((end_bucket_ts - first_bucket_ts) / time_delta) + 1) as usize;
-        let remainder = max_count - samples.len();
-
-        empty_bucket_count = empty_bucket_count.min(remainder);
-
-        debug_assert!(empty_bucket_count > 0);
-
-        let value = self.aggregator.empty_value();
-        for _ in 0..empty_bucket_count {
-            samples.push(Sample {
-                timestamp: end_bucket_ts,
-                value,
-            });
-        }
-    }
-
-    fn calc_bucket_start(&self) -> Timestamp {
-        self.bucket_ts.calculate(self.last_timestamp, self.time_delta)
-    }
-
-    fn finalize_bucket(&mut self) -> Sample {
-        let value = self.aggregator.finalize();
-        let timestamp = self.calc_bucket_start();
-        self.aggregator.reset();
-        Sample {
-            timestamp,
-            value,
-        }
-    }
-
-    pub fn calculate(&mut self, iterator: impl Iterator<Item=Sample>) -> Vec<Sample> {
-        let time_delta = self.time_delta;
-        let mut bucket_right_ts = self.last_timestamp + time_delta;
-        self.normalize_bucket_start();
-        let mut buckets: Vec<Sample> = Default::default();
-        let count = self.count.unwrap_or(usize::MAX - 1);
-
-        for sample in iterator {
-            let timestamp = sample.timestamp;
-            let value = sample.value;
-
-            // (1) time_delta > 0,
-            // (2) self.aggregation_last_timestamp > sample.timestamp -
-            // time_delta (3) self.aggregation_last_timestamp = samples.timestamps[0]
-            // - mod where 0 <= mod from (1)+(2) context_scope > chunk->samples.timestamps[0]
-            // from (3) chunk->samples.timestamps[0] >= self.aggregation_last_timestamp so the
-            // following condition should always be false on the first iteration
-            if timestamp >= bucket_right_ts {
-
-                buckets.push(self.finalize_bucket());
-                if buckets.len() >= count {
-                    return buckets;
-                }
-
-                self.last_timestamp = calc_bucket_start(timestamp, time_delta, self.timestamp_alignment);
-                if self.empty {
-                    let first_bucket = bucket_right_ts;
-                    let last_bucket = (self.last_timestamp - time_delta).max(0);
-
-                    let has_empty_buckets = first_bucket < self.last_timestamp;
-                    if has_empty_buckets {
-                        self.fill_empty_buckets(&mut buckets, first_bucket, last_bucket, count);
-                        if buckets.len() >= count {
-                            return buckets;
-                        }
-                    }
-                }
-
-                bucket_right_ts = self.last_timestamp + time_delta;
-                self.normalize_bucket_start();
-
-                self.aggregator.update(value);
-            }
-        }
-        // todo: write out last bucket value
-        buckets.truncate(count);
-
-        buckets
-
-    }
-}
-
-/// Calculate the beginning of aggregation bucket
-#[inline]
-pub(crate) fn calc_bucket_start(ts: Timestamp, bucket_duration: i64, timestamp_alignment: i64) -> Timestamp {
-    let timestamp_diff = ts - timestamp_alignment;
-    ts - ((timestamp_diff % bucket_duration + bucket_duration) % bucket_duration)
-}
-
-#[inline]
-fn bucket_start_normalize(bucket_ts: Timestamp) -> Timestamp {
-    bucket_ts.max(0)
-}
+use crate::storage::{AggregationOptions, RangeAlignment, RangeOptions, ResampleOptions};
 
 pub(crate) fn get_range_internal(
     series: &TimeSeries,
@@ -168,15 +46,49 @@ pub fn get_date_range(series: &TimeSeries, args: &RangeOptions, check_retention:
     (start_timestamp, end_timestamp)
 }
 
-pub(crate) fn get_range(series: &TimeSeries, args: &RangeOptions, check_retention: bool) -> Vec<Sample> {
+/// Returns the samples of the range, resampled or aggregated as per `args`, along with the indexes
+/// of the empty buckets of an aggregation (see `AggrIterator::calculate_with_empty_buckets`).
+pub(crate) fn get_range_with_empty_buckets(
+    series: &TimeSeries,
+    args: &RangeOptions,
+    check_retention: bool
+) -> (Vec<Sample>, Vec<usize>) {
+    if let Some(resample_options) = &args.resample {
+        let samples = get_resampled_range(series, args, resample_options, check_retention);
+        return (samples, Vec::new());
+    }
     let range = get_range_internal(series, args, check_retention);
     if let Some(aggr_options) = &args.aggregation {
         let mut aggr_iterator = get_series_aggregator(series, args, aggr_options, check_retention);
-        aggr_iterator.calculate(range.into_iter())
+        aggr_iterator.calculate_with_empty_buckets(range.into_iter())
     } else {
-        range
+        (range, Vec::new())
     }
 }
+
+fn get_resampled_range(
+    series: &TimeSeries,
+    args: &RangeOptions,
+    resample_options: &ResampleOptions,
+    check_retention: bool
+) -> Vec<Sample> {
+    let (start_timestamp, end_timestamp) = get_date_range(series, args, check_retention);
+    let step = resample_options.step.as_millis() as i64;
+    let lookback = resample_options.lookback.as_millis() as i64;
+
+    // the first step may take a sample from up to `lookback` before the start
+    let mut samples = series
+        .iter_range(start_timestamp.saturating_sub(lookback), end_timestamp)
+        .collect::<Vec<_>>();
+    if let Some(timestamps) = args.filter.as_ref().and_then(|filter| filter.timestamps.as_ref()) {
+        samples.retain(|s| timestamps.contains(&s.timestamp));
+    }
+    if let Some(filter) = args.get_value_filter() {
+        samples.retain(|s| s.value >= filter.min && s.value <= filter.max);
+    }
+    resample(samples.into_iter(), start_timestamp, end_timestamp, step, lookback, args.count)
+}
+
 pub(crate) fn get_series_aggregator(series: &TimeSeries, args: &RangeOptions, aggr_options: &AggregationOptions, check_retention: bool) -> AggrIterator {
     let (start_timestamp, end_timestamp) = get_date_range(series, args, check_retention);
 
@@ -190,15 +102,13 @@ pub(crate) fn get_series_aggregator(series: &TimeSeries, args: &RangeOptions, ag
         }
     }
 
-    AggrIterator {
+    AggrIterator::new(
+        aggr_options.aggregator.clone(),
+        aggr_options.bucket_duration.as_millis() as i64,
+        aggr_options.timestamp_output,
         timestamp_alignment,
-        empty: aggr_options.empty,
-        aggregator: aggr_options.aggregator.clone(),
-        time_delta: aggr_options.time_delta,
-        bucket_ts: aggr_options.timestamp_output,
-        start_timestamp,
-        end_timestamp,
-        last_timestamp: 0,
-        count: args.count,
-    }
+        args.count,
+        aggr_options.empty,
+        aggr_options.fill,
+    )
 }
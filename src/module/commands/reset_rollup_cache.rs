@@ -1,11 +1,13 @@
 use valkey_module::{Context, ValkeyError, ValkeyResult, ValkeyString, VALKEY_OK};
-use crate::globals::get_query_context;
+use crate::globals::{get_query_context, get_query_result_cache};
 
 pub fn reset_rollup_cache(_ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
     if args.len() != 1 {
         return Err(ValkeyError::WrongArity);
     }
 
+    get_query_result_cache().clear();
+
     let mut context = get_query_context();
     if context.config.disable_cache {
         return Err(ValkeyError::Str("Cache is already disabled"));
@@ -0,0 +1,84 @@
+use crate::index::{get_series_limiter, SeriesLimits};
+use std::collections::HashMap;
+use valkey_module::redisvalue::ValkeyValueKey;
+use valkey_module::{Context, NextArg, ValkeyError, ValkeyResult, ValkeyString, ValkeyValue};
+
+static CMD_ARG_MAX_SERIES: &str = "MAX_SERIES";
+static CMD_ARG_MAX_SERIES_PER_METRIC: &str = "MAX_SERIES_PER_METRIC";
+static CMD_ARG_MAX_NEW_SERIES_PER_MINUTE: &str = "MAX_NEW_SERIES_PER_MINUTE";
+static CMD_ARG_METRIC_QUOTA: &str = "METRIC_QUOTA";
+static CMD_ARG_DEFAULT: &str = "DEFAULT";
+
+///
+/// VKM.SERIES-LIMITS
+/// [MAX_SERIES limit]
+/// [MAX_SERIES_PER_METRIC limit]
+/// [MAX_NEW_SERIES_PER_MINUTE limit]
+/// [METRIC_QUOTA metric_name limit|DEFAULT ...]
+///
+/// Shows, and optionally changes, the limits enforced when series are created. The limits apply
+/// to each db separately, and a limit of 0 means unlimited. Returns the limits in effect.
+pub fn series_limits(_ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
+    let mut args = args.into_iter().skip(1);
+    let limiter = get_series_limiter();
+    let mut limits = limiter.limits();
+    let mut changed = false;
+
+    while let Ok(arg) = args.next_str() {
+        match arg {
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_MAX_SERIES) => {
+                limits.max_series = parse_limit(args.next_u64()?)?;
+            }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_MAX_SERIES_PER_METRIC) => {
+                limits.max_series_per_metric = parse_limit(args.next_u64()?)?;
+            }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_MAX_NEW_SERIES_PER_MINUTE) => {
+                limits.max_new_series_per_minute = parse_limit(args.next_u64()?)?;
+            }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_METRIC_QUOTA) => {
+                let metric_name = args.next_string()?;
+                let value = args.next_str()?;
+                if value.eq_ignore_ascii_case(CMD_ARG_DEFAULT) {
+                    limits.metric_quotas.remove(&metric_name);
+                } else {
+                    let Ok(quota) = value.parse::<u64>() else {
+                        return Err(ValkeyError::Str("ERR invalid METRIC_QUOTA value"));
+                    };
+                    limits.metric_quotas.insert(metric_name, parse_limit(quota)?);
+                }
+            }
+            _ => {
+                let msg = format!("ERR invalid argument '{}'", arg);
+                return Err(ValkeyError::String(msg));
+            }
+        }
+        changed = true;
+    }
+
+    if changed {
+        limiter.set_limits(limits.clone());
+    }
+
+    Ok(format_limits(&limits))
+}
+
+fn parse_limit(value: u64) -> ValkeyResult<usize> {
+    if value > usize::MAX as u64 {
+        return Err(ValkeyError::Str("ERR limit too large"));
+    }
+    Ok(value as usize)
+}
+
+pub(super) fn format_limits(limits: &SeriesLimits) -> ValkeyValue {
+    let quotas: HashMap<ValkeyValueKey, ValkeyValue> = limits.metric_quotas
+        .iter()
+        .map(|(name, quota)| (name.as_str().into(), (*quota).into()))
+        .collect();
+
+    let mut res: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(4);
+    res.insert("maxSeries".into(), limits.max_series.into());
+    res.insert("maxSeriesPerMetric".into(), limits.max_series_per_metric.into());
+    res.insert("maxNewSeriesPerMinute".into(), limits.max_new_series_per_minute.into());
+    res.insert("metricQuotas".into(), ValkeyValue::Map(quotas));
+    ValkeyValue::Map(res)
+}
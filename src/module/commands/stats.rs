@@ -1,72 +1,88 @@
-use crate::globals::with_timeseries_index;
-use crate::index::{IndexInner, TimeSeriesIndex};
-use crate::module::arg_parse::parse_integer_arg;
+use super::series_limits::format_limits;
+use crate::common::get_label_interner;
+use crate::globals::{get_current_db, is_valid_db, select_db, with_db_timeseries_index};
+use crate::index::{compute_postings_stats, get_series_limiter, HeadStats, Stat, TimeSeriesIndex};
+use crate::module::arg_parse::parse_series_selector;
+use crate::module::VKM_SERIES_TYPE;
+use crate::query::get_query_limit_breaches;
+use crate::storage::time_series::TimeSeries;
+use croaring::Bitmap64;
+use metricsql_parser::prelude::Matchers;
 use std::collections::HashMap;
-use std::sync::RwLockReadGuard;
 use valkey_module::redisvalue::ValkeyValueKey;
-use valkey_module::{Context, ValkeyError, ValkeyResult, ValkeyString, ValkeyValue};
+use valkey_module::{Context, NextArg, ValkeyError, ValkeyResult, ValkeyString, ValkeyValue};
 
 const DEFAULT_LIMIT: usize = 10;
 
-/// If limit is above of equal this threshold, we run the constituent functions in parallel.
-const LIMIT_PARALLELISM_THRESHOLD: usize = 1000;
+static CMD_ARG_LIMIT: &str = "LIMIT";
+static CMD_ARG_MATCH: &str = "MATCH";
+static CMD_ARG_DB: &str = "DB";
 
-const SENTINEL_VALUE: &str = "\u{E000}";
+struct StatsArgs {
+    limit: usize,
+    matchers: Vec<Matchers>,
+    db: Option<u32>,
+}
 
+///
+/// VKM.STATS [LIMIT limit] [MATCH selector ...] [DB db]
+///
+/// Returns cardinality statistics of the series of a db, as in the Prometheus TSDB status API.
+/// Top-N lists hold the `limit` items with the highest counts. If MATCH is given (possibly more than
+/// once), only series matching any of the selectors are accounted for. The db defaults to the
+/// current one.
+///
 /// https://prometheus.io/docs/prometheus/latest/querying/api/#tsdb-stats
 pub fn stats(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
-    let limit = match args.len() {
-        0 => DEFAULT_LIMIT,
-        1 => {
-            let next = parse_integer_arg(&args[0], "limit", false)?;
-            if next > usize::MAX as i64 {
-                return Err(ValkeyError::Str("ERR LIMIT too large"));
-            } else if next == 0 {
-                return Err(ValkeyError::Str("ERR LIMIT must be greater than 0"));
-            }
-            next as usize
-        },
-        _ => {
-            return Err(ValkeyError::WrongArity);
-        },
-    };
+    let args = parse_stats_args(args)?;
+    let current_db = unsafe { get_current_db(ctx.ctx) };
+    let db = args.db.unwrap_or(current_db);
+    // checked before the index of the db is created on first use
+    if !is_valid_db(ctx, db) {
+        return Err(ValkeyError::Str("ERR DB index is out of range"));
+    }
 
-    with_timeseries_index(ctx, |index| {
-        let inner = index.get_inner();
+    with_db_timeseries_index(db, |index| {
+        let series = if args.matchers.is_empty() {
+            None
+        } else {
+            Some(index.series_ids_by_matchers(&args.matchers, None))
+        };
 
-        let series_count = index.series_count();
+        let postings_stats = {
+            let inner = index.get_inner();
+            compute_postings_stats(&inner, args.limit, series.as_ref())
+        };
 
-        let (series_count_by_metric_name,
-            label_value_count_by_label_name,
-            series_count_by_label_pairs,
-            memory_in_bytes_by_label_name) = if series_count == 0 {
-            (ValkeyValue::Array(vec![]), ValkeyValue::Array(vec![]), ValkeyValue::Array(vec![]), ValkeyValue::Array(vec![]))
+        // series are read from the keyspace of the db
+        if db != current_db {
+            unsafe { select_db(ctx.ctx, db) };
+        }
+        let head_stats = get_head_stats(ctx, index, series.as_ref());
+        if db != current_db {
+            unsafe { select_db(ctx.ctx, current_db) };
         }
-        // else if limit >= LIMIT_PARALLELISM_THRESHOLD && series_count >= LIMIT_PARALLELISM_THRESHOLD {
-        //     // rayon really needs an nary join!
-        //     let (series_count, label_value_counts) = rayon::join(
-        //         || get_series_count_by_metric_name(&inner, limit),
-        //         || get_label_value_count_by_label_name(&inner, limit));
-        //     let (label_pair_count, memory_bytes) = rayon::join(
-        //         || get_series_count_by_label_pair(&inner, limit),
-        //         || get_memory_in_bytes_by_label_pair(&inner, limit));
-        //
-        //     (series_count, label_value_counts, label_pair_count, memory_bytes)
-        else {
-            (
-                get_series_count_by_metric_name(index, limit),
-                get_label_value_count_by_label_name(&inner, limit),
-                get_series_count_by_label_pair(&inner, limit),
-                get_memory_in_bytes_by_label_pair(&inner, limit),
-            )
-        };
 
-        let mut data = HashMap::with_capacity(4);
-        data.insert("numSeries".into(), series_count.into());
-        data.insert("seriesCountByMetricName".into(), series_count_by_metric_name);
-        data.insert("labelValueCountByLabelName".into(), label_value_count_by_label_name);
-        data.insert("memoryInBytesByLabelPair".into(), memory_in_bytes_by_label_name);
-        data.insert("seriesCountByLabelPair".into(), series_count_by_label_pairs);
+        let mut head: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(5);
+        head.insert("numSeries".into(), (postings_stats.num_series as i64).into());
+        head.insert("numLabelPairs".into(), (postings_stats.num_label_pairs as i64).into());
+        head.insert("chunkCount".into(), (head_stats.chunk_count as i64).into());
+        let (min_time, max_time) = match head_stats.time_range {
+            Some((min, max)) => (ValkeyValue::Integer(min), ValkeyValue::Integer(max)),
+            None => (ValkeyValue::Null, ValkeyValue::Null),
+        };
+        head.insert("minTime".into(), min_time);
+        head.insert("maxTime".into(), max_time);
+
+        let mut data: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(8);
+        data.insert("headStats".into(), ValkeyValue::Map(head));
+        data.insert("seriesCountByMetricName".into(), format_stats(postings_stats.series_count_by_metric_name));
+        data.insert("labelValueCountByLabelName".into(), format_stats(postings_stats.label_value_count_by_label_name));
+        data.insert("memoryInBytesByLabelName".into(), format_stats(postings_stats.memory_in_bytes_by_label_name));
+        data.insert("seriesCountByLabelValuePair".into(), format_stats(postings_stats.series_count_by_label_value_pair));
+        data.insert("queryLimitsExceeded".into(), get_query_limits_exceeded());
+        data.insert("labelInterner".into(), get_label_interner_stats());
+        data.insert("seriesLimits".into(), get_series_limits_stats(db, args.limit));
 
         let mut res = HashMap::new();
         res.insert("status".into(), "success".into());
@@ -75,105 +91,134 @@ pub fn stats(ctx: &Context, args: Vec<ValkeyString>) -> ValkeyResult {
     })
 }
 
-fn append_key_value(map: &mut Vec<ValkeyValue>, key: &str, value: ValkeyValue) {
-    let mut res: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(1);
-    res.insert(key.into(), value);
-    map.push(ValkeyValue::Map(res));
-}
-
-
-fn get_series_count_by_metric_name(index: &TimeSeriesIndex, limit: usize) -> ValkeyValue {
-    let items = index.get_series_count_by_metric_name(limit, None);
-    let arr: Vec<ValkeyValue> = items.into_iter().map(|(name, count)| {
-        let mut res: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(1);
-        res.insert(name, count.into());
-        ValkeyValue::Map(res)
-    }).collect();
-    ValkeyValue::Array(arr)
-}
+fn parse_stats_args(args: Vec<ValkeyString>) -> ValkeyResult<StatsArgs> {
+    let mut args = args.into_iter().skip(1);
+    let mut res = StatsArgs {
+        limit: DEFAULT_LIMIT,
+        matchers: Vec::new(),
+        db: None,
+    };
 
-fn get_label_value_count_by_label_name(inner: &RwLockReadGuard<IndexInner>, limit: usize) -> ValkeyValue {
-    let items = inner.label_index
-        .iter()
-        .filter_map(|(key,  _)| {
-            if let Some((name, _)) = key.split() {
-                Some(name)
-            } else {
-                None
+    while let Ok(arg) = args.next_str() {
+        match arg {
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_LIMIT) => {
+                let next = args.next_u64()?;
+                if next > usize::MAX as u64 {
+                    return Err(ValkeyError::Str("ERR LIMIT too large"));
+                } else if next == 0 {
+                    return Err(ValkeyError::Str("ERR LIMIT must be greater than 0"));
+                }
+                res.limit = next as usize;
+            }
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_MATCH) => {
+                let next = args.next_str()?;
+                let Ok(selector) = parse_series_selector(next) else {
+                    return Err(ValkeyError::Str("ERR invalid MATCH series selector"));
+                };
+                res.matchers.push(selector);
             }
-        });
-
-    let capacity = if limit < 100 { limit } else { 10 }; // ??
-    let mut current = SENTINEL_VALUE;
-    let mut count: usize = 0;
-    let mut arr: Vec<ValkeyValue> = Vec::with_capacity(capacity);
-
-    for name in items {
-        count += 1;
-        if current != name && current != SENTINEL_VALUE {
-            append_key_value(&mut arr, current, count.into());
-            if arr.len() >= limit {
-                break;
+            arg if arg.eq_ignore_ascii_case(CMD_ARG_DB) => {
+                let next = args.next_u64()?;
+                if next > i32::MAX as u64 {
+                    return Err(ValkeyError::Str("ERR invalid DB index"));
+                }
+                res.db = Some(next as u32);
+            }
+            _ => {
+                let msg = format!("ERR invalid argument '{}'", arg);
+                return Err(ValkeyError::String(msg));
             }
-            current = name;
-            count = 0;
         }
     }
 
-    ValkeyValue::Array(arr)
+    Ok(res)
 }
 
-fn get_series_count_by_label_pair(inner: &RwLockReadGuard<IndexInner>, limit: usize) -> ValkeyValue {
-    let items: Vec<_> = inner.label_index
-        .iter()
-        .filter_map(|(key,  map)| {
-            if let Some((name, _)) = key.split() {
-                Some((name, map.cardinality() as usize))
-            } else {
-                None
-            }
+/// Accounts for the chunks and samples of the series of `index`, or of `series` if given. Reads
+/// series from the keyspace of the selected db.
+fn get_head_stats(ctx: &Context, index: &TimeSeriesIndex, series: Option<&Bitmap64>) -> HeadStats {
+    let keys: Vec<ValkeyString> = {
+        let inner = index.get_inner();
+        match series {
+            Some(series) => series
+                .iter()
+                .filter_map(|id| inner.id_to_key.get(&id))
+                .map(|key| ctx.create_string(key.as_ref()))
+                .collect(),
+            None => inner.id_to_key
+                .values()
+                .map(|key| ctx.create_string(key.as_ref()))
+                .collect(),
+        }
+    };
+
+    let mut stats = HeadStats::default();
+    for key in keys {
+        let valkey_key = ctx.open_key(&key);
+        if let Ok(Some(series)) = valkey_key.get_value::<TimeSeries>(&VKM_SERIES_TYPE) {
+            stats.add_series(series);
+        }
+    }
+    stats
+}
+
+/// Formats a top-N list as in Prometheus, i.e. as a list of `{name, value}` maps.
+fn format_stats(stats: Vec<Stat>) -> ValkeyValue {
+    let items = stats
+        .into_iter()
+        .map(|stat| {
+            let mut res: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(2);
+            res.insert("name".into(), stat.name.into());
+            res.insert("value".into(), (stat.count as i64).into());
+            ValkeyValue::Map(res)
         })
-        .take(limit)
         .collect();
+    ValkeyValue::Array(items)
+}
 
-    let arr: Vec<ValkeyValue> = items.into_iter().map(|(name, count)| {
-        let mut res: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(1);
-        res.insert(name.into(), count.into());
-        ValkeyValue::Map(res)
-    }).collect();
-    ValkeyValue::Array(arr)
+fn get_query_limits_exceeded() -> ValkeyValue {
+    let breaches = get_query_limit_breaches();
+    let mut res: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(3);
+    res.insert("maxSeries".into(), (breaches.series as i64).into());
+    res.insert("maxSamples".into(), (breaches.samples as i64).into());
+    res.insert("maxPointsPerSeries".into(), (breaches.points as i64).into());
+    ValkeyValue::Map(res)
 }
 
-fn get_memory_in_bytes_by_label_pair(inner: &RwLockReadGuard<IndexInner>, limit: usize) -> ValkeyValue {
-    let items = inner.label_index
-        .iter()
-        .filter_map(|(key,  map)| {
-            if let Some((name, value)) = key.split() {
-                // since we currently also store labels with the timeseries, we need to account for that
-                // hopefully we can use an interner to reduce this overhead
-                let series_count = map.cardinality() as usize;
-                Some((name, value.len() * (1 + series_count)))
-            } else {
-                None
-            }
-        });
-
-    let capacity = if limit < 100 { limit } else { 10 }; // ??
-    let mut current = SENTINEL_VALUE;
-    let mut acc: usize = 0;
-    let mut arr: Vec<ValkeyValue> = Vec::with_capacity(capacity);
-
-    for (key, sum) in items {
-        acc += sum;
-        if key != SENTINEL_VALUE && current != key {
-            append_key_value(&mut arr, &current, acc.into());
-            if arr.len() >= limit {
-                break;
-            }
-            current = key;
-            acc = 0;
-        }
-    }
+fn get_series_limits_stats(db: u32, limit: usize) -> ValkeyValue {
+    let limiter = get_series_limiter();
+
+    let rejections = limiter.rejections(db);
+    let mut rejected: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(3);
+    rejected.insert("maxSeries".into(), (rejections.series as i64).into());
+    rejected.insert("maxSeriesPerMetric".into(), (rejections.series_per_metric as i64).into());
+    rejected.insert("maxNewSeriesPerMinute".into(), (rejections.new_series_per_minute as i64).into());
+
+    let by_metric_name: Vec<ValkeyValue> = limiter.metric_counters(db, limit)
+        .into_iter()
+        .map(|(name, counters)| {
+            let mut item: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(2);
+            item.insert("created".into(), (counters.created as i64).into());
+            item.insert("rejected".into(), (counters.rejected as i64).into());
+            let mut res: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(1);
+            res.insert(name.into(), ValkeyValue::Map(item));
+            ValkeyValue::Map(res)
+        })
+        .collect();
+
+    let mut res: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(3);
+    res.insert("limits".into(), format_limits(&limiter.limits()));
+    res.insert("rejected".into(), ValkeyValue::Map(rejected));
+    res.insert("seriesByMetricName".into(), ValkeyValue::Array(by_metric_name));
+    ValkeyValue::Map(res)
+}
 
-    ValkeyValue::Array(arr)
+fn get_label_interner_stats() -> ValkeyValue {
+    let stats = get_label_interner().stats();
+    let mut res: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(4);
+    res.insert("uniqueLabels".into(), stats.unique_labels.into());
+    res.insert("references".into(), stats.references.into());
+    res.insert("bytes".into(), stats.bytes.into());
+    res.insert("savedBytes".into(), stats.saved_bytes.into());
+    ValkeyValue::Map(res)
 }
@@ -1,12 +1,14 @@
 use valkey_module::{Context, ValkeyError, ValkeyResult, ValkeyString};
 pub(crate) use ts_db::*;
 pub(crate) use utils::*;
+pub(crate) use server_events::*;
 use crate::storage::time_series::TimeSeries;
 
 mod timeseries_api;
 mod result;
 mod utils;
 mod ts_db;
+mod server_events;
 pub mod arg_parse;
 pub(crate) mod commands;
 
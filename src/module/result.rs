@@ -1,14 +1,102 @@
 use crate::common::types::{Label, Timestamp};
+use crate::common::InternedLabel;
+use crate::query::{QueryStats, TraceNode};
 use crate::storage::time_series::TimeSeries;
 use metricsql_runtime::types::{MetricName, METRIC_NAME_LABEL};
 use std::collections::HashMap;
 use std::fmt::Display;
 use metricsql_runtime::QueryResult;
+use serde_json::{json, Map as JsonMap, Number as JsonNumber, Value as JsonValue};
 use valkey_module::redisvalue::ValkeyValueKey;
-use valkey_module::{ValkeyString, ValkeyValue};
+use valkey_module::{Context, ContextFlags, ValkeyError, ValkeyResult, ValkeyString, ValkeyValue};
 
 pub static META_KEY_LABEL: &str = "__meta:key__";
 
+/// How the results of query and metadata commands are returned, as selected with `FORMAT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultFormat {
+    /// Native replies, with values as strings
+    Resp2,
+    /// Native replies, with values as doubles
+    Resp3,
+    /// The RESP3 reply encoded as a JSON string
+    Json,
+    /// The response of the Prometheus HTTP API as a JSON string
+    Prometheus,
+}
+
+impl ResultFormat {
+    /// Returns the native format for the protocol negotiated by the client.
+    pub fn resp(ctx: &Context) -> Self {
+        if ctx.get_flags().contains(ContextFlags::FLAGS_RESP3) {
+            ResultFormat::Resp3
+        } else {
+            ResultFormat::Resp2
+        }
+    }
+
+    pub fn parse(ctx: &Context, arg: &str) -> ValkeyResult<Self> {
+        match arg {
+            arg if arg.eq_ignore_ascii_case("resp") => Ok(Self::resp(ctx)),
+            arg if arg.eq_ignore_ascii_case("json") => Ok(ResultFormat::Json),
+            arg if arg.eq_ignore_ascii_case("prometheus") => Ok(ResultFormat::Prometheus),
+            _ => Err(ValkeyError::Str("ERR FORMAT must be one of resp, json or prometheus")),
+        }
+    }
+
+    fn value(&self, value: f64) -> ValkeyValue {
+        match self {
+            ResultFormat::Resp2 => ValkeyValue::SimpleString(value.to_string()),
+            _ => ValkeyValue::Float(value),
+        }
+    }
+
+    /// Encodes a native reply as JSON if requested.
+    fn finish(&self, value: ValkeyValue) -> ValkeyValue {
+        match self {
+            ResultFormat::Json => ValkeyValue::BulkString(valkey_value_to_json(&value).to_string()),
+            _ => value,
+        }
+    }
+}
+
+/// Warnings and informational notices returned along with a result.
+#[derive(Debug, Default, Clone)]
+pub struct Annotations {
+    pub warnings: Vec<String>,
+    pub infos: Vec<String>,
+}
+
+/// What is returned along with the result of a query.
+#[derive(Debug, Default)]
+pub struct QueryExtras {
+    pub annotations: Annotations,
+    /// Returned with the STATS option
+    pub stats: Option<QueryStats>,
+    /// Returned with the TRACE option
+    pub trace: Option<Vec<TraceNode>>,
+}
+
+/// The result of a query, by Prometheus result type.
+pub enum QueryResultData {
+    Matrix(Vec<QueryResult>),
+    /// Series holding a single sample each
+    Vector(Vec<QueryResult>),
+    Scalar(Timestamp, f64),
+    String(Timestamp, String),
+}
+
+impl QueryResultData {
+    fn result_type(&self) -> ResultType {
+        match self {
+            QueryResultData::Matrix(_) => ResultType::Matrix,
+            QueryResultData::Vector(_) => ResultType::Vector,
+            QueryResultData::Scalar(..) => ResultType::Scalar,
+            QueryResultData::String(..) => ResultType::String,
+        }
+    }
+}
+
 pub enum ResultType {
     Matrix,
     Vector,
@@ -60,11 +148,15 @@ pub(super) fn sample_to_result(timestamp: Timestamp, value: f64) -> ValkeyValue
     vec![epoch, value].into()
 }
 
-pub(super) fn samples_to_result(timestamps: &[i64], values: &[f64]) -> ValkeyValue {
+fn format_sample(timestamp: Timestamp, value: f64, format: ResultFormat) -> ValkeyValue {
+    vec![ValkeyValue::Integer(timestamp), format.value(value)].into()
+}
+
+pub(super) fn samples_to_result(timestamps: &[i64], values: &[f64], format: ResultFormat) -> ValkeyValue {
     timestamps
         .iter()
         .zip(values.iter())
-        .map(|(ts, val)| sample_to_result(*ts, *val))
+        .map(|(ts, val)| format_sample(*ts, *val, format))
         .collect::<Vec<ValkeyValue>>()
         .into()
 }
@@ -105,12 +197,16 @@ pub(super) fn samples_to_result(timestamps: &[i64], values: &[f64]) -> ValkeyVal
 ///     }
 /// }
 /// ```
-pub fn to_matrix_result(vals: Vec<QueryResult>) -> ValkeyValue {
+pub fn to_matrix_result(
+    vals: Vec<QueryResult>,
+    format: ResultFormat,
+    extras: &QueryExtras
+) -> ValkeyValue {
     let map: Vec<ValkeyValue> = vals
         .into_iter()
         .map(|val| {
             let metric_name = metric_name_to_valkey_value(&val.metric, None);
-            let samples = samples_to_result(&val.timestamps, &val.values);
+            let samples = samples_to_result(&val.timestamps, &val.values, format);
             let map: HashMap<ValkeyValueKey, ValkeyValue> = vec![
                 (ValkeyValueKey::from("metric"), metric_name),
                 (ValkeyValueKey::from("values"), samples),
@@ -122,7 +218,7 @@ pub fn to_matrix_result(vals: Vec<QueryResult>) -> ValkeyValue {
         .into_iter()
         .collect();
 
-    to_success_result(map.into(), ResultType::Matrix)
+    to_success_result(map.into(), ResultType::Matrix, format, extras)
 }
 
 /// Convert to Prometheus Instant Vector output format
@@ -153,9 +249,9 @@ pub fn to_matrix_result(vals: Vec<QueryResult>) -> ValkeyValue {
 ///     }
 /// }
 /// ```
-pub fn to_instant_vector_result(metric: &MetricName, ts: Timestamp, value: f64) -> ValkeyValue {
+pub fn to_instant_vector_result(metric: &MetricName, ts: Timestamp, value: f64, format: ResultFormat) -> ValkeyValue {
     let metric_name = metric_name_to_valkey_value(metric, None);
-    let sample = sample_to_result(ts, value);
+    let sample = format_sample(ts, value, format);
     let map: HashMap<ValkeyValueKey, ValkeyValue> = vec![
         (ValkeyValueKey::from("metric"), metric_name),
         (ValkeyValueKey::from("value"), sample),
@@ -166,21 +262,73 @@ pub fn to_instant_vector_result(metric: &MetricName, ts: Timestamp, value: f64)
     ValkeyValue::Map(map)
 }
 
-fn to_single_vector_result(metric: &MetricName, ts: Timestamp, value: f64) -> ValkeyValue {
-    let metric_name = metric_name_to_valkey_value(metric, None);
-    let sample = sample_to_result(ts, value);
-    let map: HashMap<ValkeyValueKey, ValkeyValue> = vec![
-        (ValkeyValueKey::from("metric"), metric_name),
-        (ValkeyValueKey::from("value"), sample),
-    ]
-    .into_iter()
-    .collect();
+/// Returns the result of a query in the requested format.
+pub fn format_query_result(data: QueryResultData, format: ResultFormat, extras: &QueryExtras) -> ValkeyValue {
+    if format == ResultFormat::Prometheus {
+        let mut data = json!({
+            "resultType": data.result_type().as_str(),
+            "result": prometheus_query_data(&data),
+        });
+        if let Some(stats) = &extras.stats {
+            data["stats"] = query_stats_to_json(stats);
+        }
+        let mut response = prometheus_success(data, &extras.annotations);
+        if let Some(trace) = &extras.trace {
+            response["trace"] = JsonValue::Array(trace.iter().map(trace_node_to_json).collect());
+        }
+        return json_reply(response);
+    }
+    let reply = match data {
+        QueryResultData::Matrix(vals) => return format.finish(to_matrix_result(vals, format, extras)),
+        QueryResultData::Vector(vals) => {
+            let result = vals
+                .iter()
+                .filter_map(|val| {
+                    let (ts, value) = last_sample(val)?;
+                    Some(to_instant_vector_result(&val.metric, ts, value, format))
+                })
+                .collect::<Vec<_>>();
+            to_success_result(ValkeyValue::Array(result), ResultType::Vector, format, extras)
+        }
+        QueryResultData::Scalar(ts, value) => {
+            let sample = format_sample(ts, value, format);
+            to_success_result(sample, ResultType::Scalar, format, extras)
+        }
+        QueryResultData::String(ts, value) => {
+            let sample = vec![ValkeyValue::Integer(ts), ValkeyValue::BulkString(value)];
+            to_success_result(ValkeyValue::Array(sample), ResultType::String, format, extras)
+        }
+    };
+    format.finish(reply)
+}
 
-    ValkeyValue::Map(map)
+/// Returns the error of a query in the requested format. Prometheus errors are replied as an
+/// error response of the HTTP API rather than an error reply.
+pub fn format_query_error(message: String, format: ResultFormat) -> ValkeyResult {
+    if format == ResultFormat::Prometheus {
+        let response = json!({
+            "status": "error",
+            "errorType": "execution",
+            "error": message,
+        });
+        return Ok(json_reply(response));
+    }
+    Err(ValkeyError::String(message))
+}
+
+fn last_sample(val: &QueryResult) -> Option<(Timestamp, f64)> {
+    let ts = val.timestamps.last()?;
+    let value = val.values.last()?;
+    Some((*ts, *value))
 }
 
-pub fn to_success_result(data: ValkeyValue, response_type: ResultType) -> ValkeyValue {
-    let data_map: HashMap<ValkeyValueKey, ValkeyValue> = vec![
+pub fn to_success_result(
+    data: ValkeyValue,
+    response_type: ResultType,
+    format: ResultFormat,
+    extras: &QueryExtras
+) -> ValkeyValue {
+    let mut data_map: HashMap<ValkeyValueKey, ValkeyValue> = vec![
         (
             ValkeyValueKey::from("resultType"),
             ValkeyValue::SimpleStringStatic(response_type.as_str()),
@@ -189,8 +337,11 @@ pub fn to_success_result(data: ValkeyValue, response_type: ResultType) -> Valkey
     ]
     .into_iter()
     .collect();
+    if let Some(stats) = &extras.stats {
+        data_map.insert(ValkeyValueKey::from("stats"), query_stats_to_valkey_value(stats, format));
+    }
 
-    let map: HashMap<ValkeyValueKey, ValkeyValue> = vec![
+    let mut map: HashMap<ValkeyValueKey, ValkeyValue> = vec![
         status_element(true),
         (
             ValkeyValueKey::from("data"),
@@ -199,17 +350,36 @@ pub fn to_success_result(data: ValkeyValue, response_type: ResultType) -> Valkey
     ]
     .into_iter()
     .collect();
+    add_annotations(&mut map, &extras.annotations);
+    if let Some(trace) = &extras.trace {
+        let nodes = trace.iter().map(|node| trace_node_to_valkey_value(node, format)).collect();
+        map.insert(ValkeyValueKey::from("trace"), ValkeyValue::Array(nodes));
+    }
 
     ValkeyValue::Map(map)
 }
 
-pub fn format_string_array_result(arr: &[String]) -> ValkeyValue {
+pub fn format_string_array_result(arr: &[String], format: ResultFormat, annotations: &Annotations) -> ValkeyValue {
+    if format == ResultFormat::Prometheus {
+        return json_reply(prometheus_success(json!(arr), annotations));
+    }
     let converted = arr.iter().map(ValkeyValue::from).collect();
-    format_array_result(converted)
+    format.finish(format_array_result(converted, annotations))
 }
 
-pub fn format_array_result(arr: Vec<ValkeyValue>) -> ValkeyValue {
-    let map: HashMap<ValkeyValueKey, ValkeyValue> = [
+/// Returns the label sets of series in the requested format. The series key is returned under the
+/// `__meta:key__` label, except in the Prometheus format.
+pub fn format_series_result(series: &[SeriesLabels], format: ResultFormat, annotations: &Annotations) -> ValkeyValue {
+    if format == ResultFormat::Prometheus {
+        let data = series.iter().map(SeriesLabels::to_json).collect();
+        return json_reply(prometheus_success(JsonValue::Array(data), annotations));
+    }
+    let values = series.iter().map(SeriesLabels::to_valkey_value).collect();
+    format.finish(format_array_result(values, annotations))
+}
+
+pub fn format_array_result(arr: Vec<ValkeyValue>, annotations: &Annotations) -> ValkeyValue {
+    let mut map: HashMap<ValkeyValueKey, ValkeyValue> = [
         status_element(true),
         (
             ValkeyValueKey::from("data"),
@@ -218,10 +388,23 @@ pub fn format_array_result(arr: Vec<ValkeyValue>) -> ValkeyValue {
     ]
         .into_iter()
         .collect();
+    add_annotations(&mut map, annotations);
 
     ValkeyValue::Map(map)
 }
 
+/// Adds warnings and infos to a response, omitting them when empty as Prometheus does.
+fn add_annotations(map: &mut HashMap<ValkeyValueKey, ValkeyValue>, annotations: &Annotations) {
+    if !annotations.warnings.is_empty() {
+        let warnings = annotations.warnings.iter().map(ValkeyValue::from).collect();
+        map.insert(ValkeyValueKey::from("warnings"), ValkeyValue::Array(warnings));
+    }
+    if !annotations.infos.is_empty() {
+        let infos = annotations.infos.iter().map(ValkeyValue::from).collect();
+        map.insert(ValkeyValueKey::from("infos"), ValkeyValue::Array(infos));
+    }
+}
+
 fn status_element(success: bool) -> (ValkeyValueKey, ValkeyValue) {
     let status = if success { "success" } else { "error" };
     (
@@ -236,17 +419,450 @@ pub fn std_duration_to_redis_value(duration: &std::time::Duration) -> ValkeyValu
 pub fn string_hash_map_to_redis_value(map: &HashMap<String, String>) -> ValkeyValue {
     ValkeyValue::from(map.clone())
 }
-pub(super) fn get_ts_metric_selector(ts: &TimeSeries, key: Option<&ValkeyString>) -> ValkeyValue {
-    let mut map: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(ts.labels.len() + 1);
-    map.insert(
-        ValkeyValueKey::String(METRIC_NAME_LABEL.into()),
-        ValkeyValue::from(&ts.metric_name),
-    );
-    if let Some(key) = key {
-        map.insert(ValkeyValueKey::String(META_KEY_LABEL.into()), ValkeyValue::from(key));
+/// The labels of a series matched by a metadata command, kept to be formatted once all series are
+/// collected.
+pub struct SeriesLabels {
+    key: String,
+    metric_name: String,
+    labels: Vec<InternedLabel>,
+}
+
+impl SeriesLabels {
+    pub fn new(ts: &TimeSeries, key: &ValkeyString) -> Self {
+        Self {
+            key: key.to_string_lossy(),
+            metric_name: ts.metric_name.clone(),
+            labels: ts.labels.clone(),
+        }
+    }
+
+    fn to_valkey_value(&self) -> ValkeyValue {
+        let mut map: HashMap<ValkeyValueKey, ValkeyValue> = HashMap::with_capacity(self.labels.len() + 2);
+        map.insert(
+            ValkeyValueKey::String(METRIC_NAME_LABEL.into()),
+            ValkeyValue::from(&self.metric_name),
+        );
+        map.insert(ValkeyValueKey::String(META_KEY_LABEL.into()), ValkeyValue::from(&self.key));
+        for label in self.labels.iter() {
+            map.insert(ValkeyValueKey::String(label.name().into()), ValkeyValue::from(label.value()));
+        }
+        ValkeyValue::Map(map)
     }
-    for Label { name, value } in ts.labels.iter() {
-        map.insert(ValkeyValueKey::String(name.into()), ValkeyValue::from(value));
+
+    fn to_json(&self) -> JsonValue {
+        let mut labels = JsonMap::new();
+        labels.insert(METRIC_NAME_LABEL.into(), self.metric_name.clone().into());
+        for label in self.labels.iter() {
+            labels.insert(label.name().into(), label.value().into());
+        }
+        JsonValue::Object(labels)
     }
+}
+
+
+/// Query statistics are returned as `timings`, in seconds as Prometheus does, and `counts`.
+fn query_stats_to_valkey_value(stats: &QueryStats, format: ResultFormat) -> ValkeyValue {
+    let timings: HashMap<ValkeyValueKey, ValkeyValue> = query_stats_timings(stats)
+        .into_iter()
+        .map(|(name, secs)| (ValkeyValueKey::from(name), format.value(secs)))
+        .collect();
+    let counts: HashMap<ValkeyValueKey, ValkeyValue> = query_stats_counts(stats)
+        .into_iter()
+        .map(|(name, count)| (ValkeyValueKey::from(name), ValkeyValue::Integer(count as i64)))
+        .collect();
+    let map: HashMap<ValkeyValueKey, ValkeyValue> = [
+        (ValkeyValueKey::from("timings"), ValkeyValue::Map(timings)),
+        (ValkeyValueKey::from("counts"), ValkeyValue::Map(counts)),
+    ]
+        .into_iter()
+        .collect();
+    ValkeyValue::Map(map)
+}
+
+fn query_stats_to_json(stats: &QueryStats) -> JsonValue {
+    let timings = query_stats_timings(stats)
+        .into_iter()
+        .map(|(name, secs)| (name.to_string(), JsonValue::from(secs)))
+        .collect::<JsonMap<_, _>>();
+    let counts = query_stats_counts(stats)
+        .into_iter()
+        .map(|(name, count)| (name.to_string(), JsonValue::from(count)))
+        .collect::<JsonMap<_, _>>();
+    json!({
+        "timings": timings,
+        "counts": counts,
+    })
+}
+
+fn query_stats_timings(stats: &QueryStats) -> [(&'static str, f64); 4] {
+    [
+        ("indexLookupTime", stats.index_lookup_time.as_secs_f64()),
+        ("fetchTime", stats.fetch_time.as_secs_f64()),
+        ("evalTime", stats.eval_time().as_secs_f64()),
+        ("totalTime", stats.total_time.as_secs_f64()),
+    ]
+}
+
+fn query_stats_counts(stats: &QueryStats) -> [(&'static str, usize); 4] {
+    [
+        ("seriesMatched", stats.series_matched),
+        ("seriesFetched", stats.series_fetched),
+        ("chunksDecoded", stats.chunks_decoded),
+        ("samplesScanned", stats.samples_scanned),
+    ]
+}
+
+/// Trace nodes are returned as their `name`, `duration` in seconds, recorded `fields` and
+/// `children`.
+fn trace_node_to_valkey_value(node: &TraceNode, format: ResultFormat) -> ValkeyValue {
+    let fields: HashMap<ValkeyValueKey, ValkeyValue> = node.fields
+        .iter()
+        .map(|(name, value)| (ValkeyValueKey::from(name.as_str()), ValkeyValue::from(value.as_str())))
+        .collect();
+    let children = node.children
+        .iter()
+        .map(|child| trace_node_to_valkey_value(child, format))
+        .collect();
+    let map: HashMap<ValkeyValueKey, ValkeyValue> = [
+        (ValkeyValueKey::from("name"), ValkeyValue::from(node.name.as_str())),
+        (ValkeyValueKey::from("duration"), format.value(node.duration.as_secs_f64())),
+        (ValkeyValueKey::from("fields"), ValkeyValue::Map(fields)),
+        (ValkeyValueKey::from("children"), ValkeyValue::Array(children)),
+    ]
+        .into_iter()
+        .collect();
     ValkeyValue::Map(map)
 }
+
+fn trace_node_to_json(node: &TraceNode) -> JsonValue {
+    let fields = node.fields
+        .iter()
+        .map(|(name, value)| (name.clone(), JsonValue::from(value.as_str())))
+        .collect::<JsonMap<_, _>>();
+    json!({
+        "name": node.name,
+        "duration": node.duration.as_secs_f64(),
+        "fields": fields,
+        "children": node.children.iter().map(trace_node_to_json).collect::<Vec<_>>(),
+    })
+}
+
+fn json_reply(value: JsonValue) -> ValkeyValue {
+    ValkeyValue::BulkString(value.to_string())
+}
+
+/// Encodes a native reply as JSON. Non-finite doubles, which JSON can't represent, are encoded as
+/// strings.
+fn valkey_value_to_json(value: &ValkeyValue) -> JsonValue {
+    match value {
+        ValkeyValue::SimpleStringStatic(s) => JsonValue::from(*s),
+        ValkeyValue::SimpleString(s) | ValkeyValue::BulkString(s) => JsonValue::from(s.as_str()),
+        ValkeyValue::BulkValkeyString(s) => JsonValue::from(s.to_string_lossy()),
+        ValkeyValue::Integer(i) => JsonValue::from(*i),
+        ValkeyValue::Bool(b) => JsonValue::from(*b),
+        ValkeyValue::Float(f) => match JsonNumber::from_f64(*f) {
+            Some(n) => JsonValue::Number(n),
+            None => JsonValue::from(format_prometheus_value(*f)),
+        },
+        ValkeyValue::Array(values) => JsonValue::Array(values.iter().map(valkey_value_to_json).collect()),
+        ValkeyValue::Map(map) => {
+            let object = map
+                .iter()
+                .map(|(key, value)| (valkey_key_to_string(key), valkey_value_to_json(value)))
+                .collect::<JsonMap<_, _>>();
+            JsonValue::Object(object)
+        }
+        _ => JsonValue::Null,
+    }
+}
+
+fn valkey_key_to_string(key: &ValkeyValueKey) -> String {
+    match key {
+        ValkeyValueKey::String(s) => s.clone(),
+        ValkeyValueKey::Integer(i) => i.to_string(),
+        ValkeyValueKey::BulkValkeyString(s) => s.to_string_lossy(),
+        ValkeyValueKey::BulkString(b) => String::from_utf8_lossy(b).into_owned(),
+        ValkeyValueKey::Bool(b) => b.to_string(),
+    }
+}
+
+/// Wraps the data of a response in the envelope of the Prometheus HTTP API:
+/// https://prometheus.io/docs/prometheus/latest/querying/api/#format-overview
+fn prometheus_success(data: JsonValue, annotations: &Annotations) -> JsonValue {
+    let mut response = JsonMap::new();
+    response.insert("status".into(), "success".into());
+    response.insert("data".into(), data);
+    if !annotations.warnings.is_empty() {
+        response.insert("warnings".into(), json!(annotations.warnings));
+    }
+    if !annotations.infos.is_empty() {
+        response.insert("infos".into(), json!(annotations.infos));
+    }
+    JsonValue::Object(response)
+}
+
+fn prometheus_query_data(data: &QueryResultData) -> JsonValue {
+    match data {
+        QueryResultData::Matrix(vals) => {
+            let result = vals.iter().map(|val| {
+                let values = val.timestamps
+                    .iter()
+                    .zip(val.values.iter())
+                    .map(|(ts, value)| prometheus_sample(*ts, *value))
+                    .collect::<Vec<_>>();
+                json!({
+                    "metric": metric_name_to_json(&val.metric),
+                    "values": values,
+                })
+            }).collect::<Vec<_>>();
+            JsonValue::Array(result)
+        }
+        QueryResultData::Vector(vals) => {
+            let result = vals.iter().filter_map(|val| {
+                let (ts, value) = last_sample(val)?;
+                Some(json!({
+                    "metric": metric_name_to_json(&val.metric),
+                    "value": prometheus_sample(ts, value),
+                }))
+            }).collect::<Vec<_>>();
+            JsonValue::Array(result)
+        }
+        QueryResultData::Scalar(ts, value) => prometheus_sample(*ts, *value),
+        QueryResultData::String(ts, value) => json!([prometheus_timestamp(*ts), value]),
+    }
+}
+
+fn metric_name_to_json(metric_name: &MetricName) -> JsonValue {
+    let mut labels = JsonMap::new();
+    if !metric_name.measurement.is_empty() {
+        labels.insert(METRIC_NAME_LABEL.into(), metric_name.measurement.clone().into());
+    }
+    for Label { name, value } in metric_name.labels.iter() {
+        labels.insert(name.clone(), value.clone().into());
+    }
+    JsonValue::Object(labels)
+}
+
+/// Prometheus returns samples as `[<unix seconds>, "<value>"]`
+fn prometheus_sample(timestamp: Timestamp, value: f64) -> JsonValue {
+    json!([prometheus_timestamp(timestamp), format_prometheus_value(value)])
+}
+
+fn prometheus_timestamp(timestamp: Timestamp) -> JsonValue {
+    if timestamp % 1000 == 0 {
+        JsonValue::from(timestamp / 1000)
+    } else {
+        JsonValue::from(timestamp as f64 / 1000.0)
+    }
+}
+
+/// Formats a value as Prometheus does, e.g. `1`, `0.25`, `NaN` or `+Inf`.
+fn format_prometheus_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_result(name: &str, labels: &[(&str, &str)], timestamps: Vec<i64>, values: Vec<f64>) -> QueryResult {
+        let mut metric = MetricName::new(name);
+        for &(name, value) in labels {
+            metric.add_label(name, value);
+        }
+        QueryResult::new(metric, timestamps, values)
+    }
+
+    fn parse_json_reply(reply: ValkeyValue) -> JsonValue {
+        let ValkeyValue::BulkString(json) = reply else {
+            panic!("expected a JSON string reply");
+        };
+        serde_json::from_str(&json).unwrap()
+    }
+
+    fn prometheus_response(data: QueryResultData, extras: &QueryExtras) -> JsonValue {
+        parse_json_reply(format_query_result(data, ResultFormat::Prometheus, extras))
+    }
+
+    #[test]
+    fn test_prometheus_envelope() {
+        let data = QueryResultData::Matrix(vec![
+            create_result("up", &[("job", "node")], vec![1000, 1500], vec![1.0, 0.25]),
+        ]);
+        let extras = QueryExtras {
+            annotations: Annotations {
+                warnings: vec!["results truncated due to limit".to_string()],
+                infos: vec![],
+            },
+            ..Default::default()
+        };
+        let response = prometheus_response(data, &extras);
+        assert_eq!(response, json!({
+            "status": "success",
+            "data": {
+                "resultType": "matrix",
+                "result": [{
+                    "metric": { "__name__": "up", "job": "node" },
+                    "values": [[1, "1"], [1.5, "0.25"]],
+                }],
+            },
+            "warnings": ["results truncated due to limit"],
+        }));
+    }
+
+    #[test]
+    fn test_prometheus_vector() {
+        let data = QueryResultData::Vector(vec![
+            create_result("up", &[], vec![1000, 2000], vec![0.0, 1.0]),
+        ]);
+        let response = prometheus_response(data, &QueryExtras::default());
+        assert_eq!(response["data"], json!({
+            "resultType": "vector",
+            "result": [{ "metric": { "__name__": "up" }, "value": [2, "1"] }],
+        }));
+        // annotations are omitted when empty
+        assert!(response.get("warnings").is_none());
+        assert!(response.get("infos").is_none());
+    }
+
+    #[test]
+    fn test_prometheus_timestamps_are_seconds() {
+        assert_eq!(prometheus_timestamp(1_435_781_430_000), json!(1_435_781_430));
+        assert_eq!(prometheus_timestamp(1_435_781_430_781), json!(1_435_781_430.781));
+        assert_eq!(prometheus_timestamp(-500), json!(-0.5));
+    }
+
+    #[test]
+    fn test_prometheus_special_values() {
+        assert_eq!(format_prometheus_value(f64::NAN), "NaN");
+        assert_eq!(format_prometheus_value(f64::INFINITY), "+Inf");
+        assert_eq!(format_prometheus_value(f64::NEG_INFINITY), "-Inf");
+        assert_eq!(format_prometheus_value(-0.5), "-0.5");
+
+        let data = QueryResultData::Matrix(vec![
+            create_result("up", &[], vec![1000, 2000, 3000], vec![f64::NAN, f64::INFINITY, f64::NEG_INFINITY]),
+        ]);
+        let response = prometheus_response(data, &QueryExtras::default());
+        assert_eq!(response["data"]["result"][0]["values"], json!([[1, "NaN"], [2, "+Inf"], [3, "-Inf"]]));
+    }
+
+    #[test]
+    fn test_prometheus_scalar_and_string() {
+        let response = prometheus_response(QueryResultData::Scalar(2000, 3.5), &QueryExtras::default());
+        assert_eq!(response["data"], json!({ "resultType": "scalar", "result": [2, "3.5"] }));
+
+        let data = QueryResultData::String(2500, "foo".to_string());
+        let response = prometheus_response(data, &QueryExtras::default());
+        assert_eq!(response["data"], json!({ "resultType": "string", "result": [2.5, "foo"] }));
+    }
+
+    #[test]
+    fn test_prometheus_error() {
+        let reply = format_query_error("query cancelled".to_string(), ResultFormat::Prometheus).unwrap();
+        assert_eq!(parse_json_reply(reply), json!({
+            "status": "error",
+            "errorType": "execution",
+            "error": "query cancelled",
+        }));
+        assert!(format_query_error("query cancelled".to_string(), ResultFormat::Resp3).is_err());
+    }
+
+    #[test]
+    fn test_json_format() {
+        let reply = format_query_result(QueryResultData::Scalar(2000, f64::NAN), ResultFormat::Json, &QueryExtras::default());
+        assert_eq!(parse_json_reply(reply), json!({
+            "status": "success",
+            "data": { "resultType": "scalar", "result": [2000, "NaN"] },
+        }));
+    }
+
+    fn create_trace() -> Vec<TraceNode> {
+        let search = TraceNode {
+            name: "search".to_string(),
+            fields: vec![("keys".to_string(), "3".to_string())],
+            duration: std::time::Duration::from_millis(125),
+            children: vec![],
+        };
+        vec![TraceNode {
+            name: "query".to_string(),
+            fields: vec![("query".to_string(), "up".to_string())],
+            duration: std::time::Duration::from_millis(250),
+            children: vec![search],
+        }]
+    }
+
+    fn map_entry<'a>(value: &'a ValkeyValue, key: &str) -> &'a ValkeyValue {
+        let ValkeyValue::Map(map) = value else {
+            panic!("expected a map, got {value:?}");
+        };
+        map.get(&ValkeyValueKey::from(key)).unwrap_or_else(|| panic!("missing {key}"))
+    }
+
+    fn string_value(value: &ValkeyValue) -> &str {
+        match value {
+            ValkeyValue::SimpleStringStatic(s) => s,
+            ValkeyValue::SimpleString(s) | ValkeyValue::BulkString(s) => s.as_str(),
+            _ => panic!("expected a string, got {value:?}"),
+        }
+    }
+
+    #[test]
+    fn test_prometheus_trace() {
+        let extras = QueryExtras {
+            trace: Some(create_trace()),
+            ..Default::default()
+        };
+        let response = prometheus_response(QueryResultData::Scalar(1000, 1.0), &extras);
+        assert_eq!(response["trace"], json!([{
+            "name": "query",
+            "duration": 0.25,
+            "fields": { "query": "up" },
+            "children": [{
+                "name": "search",
+                "duration": 0.125,
+                "fields": { "keys": "3" },
+                "children": [],
+            }],
+        }]));
+    }
+
+    #[test]
+    fn test_resp_trace() {
+        let extras = QueryExtras {
+            trace: Some(create_trace()),
+            ..Default::default()
+        };
+        let reply = format_query_result(QueryResultData::Scalar(1000, 1.0), ResultFormat::Resp3, &extras);
+        let ValkeyValue::Array(trace) = map_entry(&reply, "trace") else {
+            panic!("expected the trace to be an array");
+        };
+        assert_eq!(trace.len(), 1);
+        let root = &trace[0];
+        assert_eq!(string_value(map_entry(root, "name")), "query");
+        assert!(matches!(map_entry(root, "duration"), ValkeyValue::Float(secs) if *secs == 0.25));
+        assert_eq!(string_value(map_entry(map_entry(root, "fields"), "query")), "up");
+
+        let ValkeyValue::Array(children) = map_entry(root, "children") else {
+            panic!("expected the children to be an array");
+        };
+        assert_eq!(children.len(), 1);
+        let search = &children[0];
+        assert_eq!(string_value(map_entry(search, "name")), "search");
+        assert!(matches!(map_entry(search, "duration"), ValkeyValue::Float(secs) if *secs == 0.125));
+        assert_eq!(string_value(map_entry(map_entry(search, "fields"), "keys")), "3");
+        assert!(matches!(map_entry(search, "children"), ValkeyValue::Array(children) if children.is_empty()));
+
+        // durations are strings in RESP2, as values are
+        let reply = format_query_result(QueryResultData::Scalar(1000, 1.0), ResultFormat::Resp2, &extras);
+        let ValkeyValue::Array(trace) = map_entry(&reply, "trace") else {
+            panic!("expected the trace to be an array");
+        };
+        assert_eq!(string_value(map_entry(&trace[0], "duration")), "0.25");
+    }
+}
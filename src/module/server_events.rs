@@ -0,0 +1,62 @@
+use crate::globals::{clear_timeseries_index, clear_timeseries_index_for_db, get_query_result_cache, swap_timeseries_indexes};
+use std::os::raw::{c_int, c_void};
+use valkey_module::{raw, Context, Status};
+
+// Server events whose data is not exposed by the event handler macros. FLUSHDB needs the db
+// being flushed, and SWAPDB the dbs being swapped.
+
+pub(crate) fn subscribe_to_server_events(ctx: &Context) -> Status {
+    let events: [(u32, unsafe extern "C" fn(*mut raw::RedisModuleCtx, raw::RedisModuleEvent, u64, *mut c_void)); 2] = [
+        (raw::REDISMODULE_EVENT_FLUSHDB, on_flush_event),
+        (raw::REDISMODULE_EVENT_SWAPDB, on_swapdb_event),
+    ];
+    for (id, callback) in events {
+        let event = raw::RedisModuleEvent {
+            id: id as u64,
+            dataver: 1,
+        };
+        let res = unsafe {
+            raw::RedisModule_SubscribeToServerEvent.unwrap()(ctx.ctx, event, Some(callback))
+        };
+        if res != raw::REDISMODULE_OK as c_int {
+            ctx.log_warning("vkmetrics: failed to subscribe to server events");
+            return Status::Err;
+        }
+    }
+    Status::Ok
+}
+
+unsafe extern "C" fn on_flush_event(
+    _ctx: *mut raw::RedisModuleCtx,
+    _eid: raw::RedisModuleEvent,
+    subevent: u64,
+    data: *mut c_void,
+) {
+    if subevent != raw::REDISMODULE_SUBEVENT_FLUSHDB_END as u64 || data.is_null() {
+        return;
+    }
+    let info = &*(data as *const raw::RedisModuleFlushInfo);
+    // FLUSHALL is reported with a db of -1
+    if info.dbnum < 0 {
+        clear_timeseries_index();
+        get_query_result_cache().clear();
+    } else {
+        clear_timeseries_index_for_db(info.dbnum as u32);
+        get_query_result_cache().clear_db(info.dbnum as u32);
+    }
+}
+
+unsafe extern "C" fn on_swapdb_event(
+    _ctx: *mut raw::RedisModuleCtx,
+    _eid: raw::RedisModuleEvent,
+    _subevent: u64,
+    data: *mut c_void,
+) {
+    if data.is_null() {
+        return;
+    }
+    let info = &*(data as *const raw::RedisModuleSwapDbInfo);
+    swap_timeseries_indexes(info.dbnum_first as u32, info.dbnum_second as u32);
+    get_query_result_cache().clear_db(info.dbnum_first as u32);
+    get_query_result_cache().clear_db(info.dbnum_second as u32);
+}
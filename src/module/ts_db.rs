@@ -1,16 +1,17 @@
 use valkey_module::RedisModuleTypeMethods;
 use valkey_module::REDISMODULE_AUX_BEFORE_RDB;
-use valkey_module::{native_types::ValkeyType, RedisModuleDefragCtx, RedisModuleString, ValkeyString};
+use valkey_module::{native_types::ValkeyType, RedisModuleDefragCtx, RedisModuleString};
 
-use crate::globals::with_timeseries_index;
-use crate::index::TimeSeriesIndex;
+use crate::globals::{get_query_result_cache, with_db_timeseries_index};
+use crate::index::{load_index_aux, save_index_aux, TimeSeriesIndex};
 use crate::storage::defrag_series;
 use crate::storage::time_series::TimeSeries;
 use std::os::raw::{c_int, c_void};
 use valkey_module::raw;
 // see https://github.com/redis/redis/blob/unstable/tests/modules
 
-pub static VKM_SERIES_VERSION: i32 = 1;
+// version 2 added the counter state of series
+pub static VKM_SERIES_VERSION: i32 = 2;
 pub static VKM_SERIES_TYPE: ValkeyType = ValkeyType::new(
     "vkmseries",
     VKM_SERIES_VERSION,
@@ -22,16 +23,16 @@ pub static VKM_SERIES_TYPE: ValkeyType = ValkeyType::new(
         free: Some(free),
         mem_usage: Some(mem_usage),
         digest: None,
-        aux_load: None,
-        aux_save: None,
+        aux_load: Some(aux_load),
+        aux_save: Some(aux_save),
         aux_save_triggers: REDISMODULE_AUX_BEFORE_RDB as i32,
         free_effort: None,
-        unlink: Some(unlink),
+        unlink: None,
         copy: Some(copy),
         defrag: Some(defrag),
         mem_usage2: None,
         free_effort2: None,
-        unlink2: None,
+        unlink2: Some(unlink),
         copy2: None,
         aux_save2: None,
     },
@@ -47,6 +48,22 @@ unsafe extern "C" fn rdb_load(rdb: *mut raw::RedisModuleIO, encver: c_int) -> *m
     // index.index_time_series(&new_series, &tmp);
 }
 
+unsafe extern "C" fn aux_save(rdb: *mut raw::RedisModuleIO, when: c_int) {
+    if when == REDISMODULE_AUX_BEFORE_RDB as c_int {
+        save_index_aux(rdb);
+    }
+}
+
+unsafe extern "C" fn aux_load(rdb: *mut raw::RedisModuleIO, _encver: c_int, when: c_int) -> c_int {
+    if when != REDISMODULE_AUX_BEFORE_RDB as c_int {
+        return raw::REDISMODULE_OK as c_int;
+    }
+    match load_index_aux(rdb) {
+        Ok(_) => raw::REDISMODULE_OK as c_int,
+        Err(_) => raw::REDISMODULE_ERR as c_int,
+    }
+}
+
 unsafe extern "C" fn mem_usage(value: *const c_void) -> usize {
     let sm = unsafe { &*(value as *mut TimeSeries) };
     sm.memory_usage()
@@ -61,32 +78,31 @@ unsafe extern "C" fn free(value: *mut c_void) {
     Box::from_raw(sm);
 }
 
+/// The copy is indexed on the "copy_to" keyspace event, which is raised in the destination db
 #[allow(non_snake_case, unused)]
 unsafe extern "C" fn copy(
     fromkey: *mut RedisModuleString,
     tokey: *mut RedisModuleString,
     value: *const c_void,
 ) -> *mut c_void {
-    let guard = valkey_module::MODULE_CONTEXT.lock();
-    with_timeseries_index(&guard, |index| {
-        let sm = &*(value as *mut TimeSeries);
-        let mut new_series = sm.clone();
-        new_series.id = TimeSeriesIndex::next_id();
-        let key = ValkeyString::from_redis_module_string(guard.ctx, tokey);
-        index.index_time_series(&new_series, key.as_slice());
-        Box::into_raw(Box::new(new_series)).cast::<c_void>()
-    })
+    let sm = &*(value as *mut TimeSeries);
+    let mut new_series = sm.clone();
+    new_series.id = TimeSeriesIndex::next_id();
+    Box::into_raw(Box::new(new_series)).cast::<c_void>()
 }
 
-unsafe extern "C" fn unlink(_key: *mut RedisModuleString, value: *const c_void) {
-    let series = &*(value as *mut TimeSeries);
+/// Removes the series from the index of the db holding the key, which need not be the db selected
+/// on the module context (e.g. the source db of a MOVE).
+unsafe extern "C" fn unlink(ctx: *mut raw::RedisModuleKeyOptCtx, value: *const c_void) {
     if value.is_null() {
         return;
     }
-    let guard = valkey_module::MODULE_CONTEXT.lock();
-    with_timeseries_index(&guard, |ts_index| {
+    let series = &*(value as *mut TimeSeries);
+    let db = raw::RedisModule_GetDbIdFromOptCtx.unwrap()(ctx) as u32;
+    with_db_timeseries_index(db, |ts_index| {
         ts_index.remove_series(series);
     });
+    get_query_result_cache().invalidate_series(series.id, series.first_timestamp);
 }
 
 unsafe extern "C" fn defrag(
@@ -1,89 +1,221 @@
-use crate::common::types::{Label, Timestamp};
-use crate::globals::with_timeseries_index;
+use crate::common::types::Timestamp;
+use crate::config::get_global_settings;
+use crate::globals::{get_current_db, select_db, with_db_timeseries_index};
 use crate::index::TimeSeriesIndex;
 use crate::module::VKM_SERIES_TYPE;
-use crate::storage::time_series::TimeSeries;
+use crate::query::{get_query_state, QueryLimits, QueryState};
+use crate::storage::time_series::{select_chunks_raw, TimeSeries};
+use crate::storage::TimeSeriesChunk;
 use async_trait::async_trait;
 use metricsql_runtime::{Deadline, MetricStorage, QueryResult, QueryResults, RuntimeError, RuntimeResult, SearchQuery};
 use metricsql_runtime::types::MetricName;
-use valkey_module::{Context, ValkeyString};
+use rayon::prelude::*;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::dispatcher::{self, Dispatch};
+use tracing::field::Empty;
+use tracing::{info_span, Span};
+use valkey_module::key::ValkeyKey;
+use valkey_module::Context;
 
 pub struct TsdbDataProvider {}
 
+/// The chunks of a series overlapping the range of a search. They are copied while the module
+/// lock is held, so that they can be decoded after it is released.
+struct SeriesChunks {
+    metric: MetricName,
+    chunks: Vec<TimeSeriesChunk>,
+    /// The estimated number of samples in the range
+    capacity: usize,
+}
+
 impl TsdbDataProvider {
 
-    fn get_series(&self, ctx: &Context, key: &ValkeyString, start_ts: Timestamp, end_ts: Timestamp) -> RuntimeResult<Option<QueryResult>> {
-        let valkey_key = ctx.open_key(key);
-        match valkey_key.get_value::<TimeSeries>(&VKM_SERIES_TYPE) {
-            Ok(Some(series)) => {
-                if series.overlaps(start_ts, end_ts) {
-                    let mut timestamps: Vec<Timestamp> = Vec::new();
-                    let mut values: Vec<f64> = Vec::new();
-                    return match series.select_raw(
-                        start_ts,
-                        end_ts,
-                        &mut timestamps,
-                        &mut values,
-                    ) {
-                        Ok(_) => {
-                            // what do wee do in case of error ?
-                            let metric = to_metric_name(series);
-                            Ok(Some(QueryResult::new(metric, timestamps, values)))
-                        }
-                        Err(e) => {
-                            ctx.log_warning(&format!("PROMQL: Error: {:?}", e));
-                            // TODO!: we need a specific error for storage backends
-                            Err(RuntimeError::General("Error".to_string()))
-                        }
-                    }
+    /// Returns the chunks of the series matched by the search which overlap its range. Called
+    /// with the module lock held.
+    fn get_series_chunks(
+        &self,
+        ctx: &Context,
+        index: &TimeSeriesIndex,
+        state: &QueryState,
+        search_query: &SearchQuery,
+        span: &Span,
+    ) -> RuntimeResult<Vec<SeriesChunks>> {
+        let start_ts = search_query.start;
+        let end_ts = search_query.end;
+
+        let lookup_start = Instant::now();
+        let matched = index.series_ids_by_matchers(std::slice::from_ref(&search_query.matchers), None);
+        // all matched series are recorded, as samples added to any of them in the range of the
+        // query change its result
+        state.record_series_ids(&matched);
+        let keys = index.series_keys_in_range(ctx, &matched, start_ts, end_ts);
+        let lookup_time = lookup_start.elapsed();
+        span.record("keys", keys.len());
+        state.add_matched_series(keys.len())?;
+
+        // Series are only valid while the lock is held, since the main thread may modify them once
+        // it is released, so the chunks overlapping the range are copied out to be decoded later.
+        let valkey_keys: Vec<ValkeyKey> = keys.iter().map(|key| ctx.open_key(key)).collect();
+        let mut series: Vec<SeriesChunks> = Vec::with_capacity(valkey_keys.len());
+        for key in valkey_keys.iter() {
+            match key.get_value::<TimeSeries>(&VKM_SERIES_TYPE) {
+                Ok(Some(ts)) if ts.overlaps(start_ts, end_ts) => series.push(SeriesChunks {
+                    metric: to_metric_name(ts),
+                    chunks: ts.overlapping_chunks(start_ts, end_ts).to_vec(),
+                    capacity: estimate_samples_in_range(ts, start_ts, end_ts),
+                }),
+                Ok(_) => {}
+                Err(e) => {
+                    ctx.log_warning(&format!("PROMQL: Error: {:?}", e));
                 }
             }
-            Err(e) => {
-                ctx.log_warning(&format!("PROMQL: Error: {:?}", e));
-            }
-            _ => {}
         }
-        Ok(None)
+
+        state.update_stats(|stats| {
+            stats.index_lookup_time += lookup_time;
+            stats.series_matched += keys.len();
+        });
+        Ok(series)
     }
 
     fn get_series_data(
         &self,
-        ctx: &Context,
-        index: &TimeSeriesIndex,
+        state: &QueryState,
         search_query: SearchQuery,
+        deadline: &Deadline,
     ) -> RuntimeResult<Vec<QueryResult>> {
-        let map = index.series_keys_by_matchers(ctx, &[search_query.matchers]);
-        let mut results: Vec<QueryResult> = Vec::with_capacity(map.len());
         let start_ts = search_query.start;
         let end_ts = search_query.end;
+        // shown by the TRACE option of queries
+        let span = info_span!(
+            "search",
+            selector = %search_query.matchers,
+            start = start_ts,
+            end = end_ts,
+            keys = Empty,
+            series = Empty,
+            samples = Empty
+        );
+        let _entered = span.enter();
 
-        // use rayon ?
-        for key in map.iter() {
-            if let Some(result) = self.get_series(ctx, key, start_ts, end_ts)? {
-                results.push(result);
+        let series = {
+            // see: https://github.com/RedisLabsModules/redismodule-rs/blob/master/examples/call.rs#L144
+            let ctx_guard = valkey_module::MODULE_CONTEXT.lock();
+            // keys are opened in the db selected on the shared context, so select the db of the
+            // query for the duration of the lookup and restore the previous one afterwards
+            let prev_db = unsafe { get_current_db(ctx_guard.ctx) };
+            let db = state.db;
+            if db != prev_db {
+                unsafe { select_db(ctx_guard.ctx, db) };
             }
-        }
+            let series = with_db_timeseries_index(db, |index| {
+                self.get_series_chunks(&ctx_guard, index, state, &search_query, &span)
+            });
+            if db != prev_db {
+                unsafe { select_db(ctx_guard.ctx, prev_db) };
+            }
+            series?
+        };
+        let series_count = series.len();
+        let chunks_decoded = series.iter().map(|data| data.chunks.len()).sum::<usize>();
+
+        // samples are counted as each series is decoded, so that a query exceeding the limit stops
+        // decoding, even while series are decoded in parallel
+        let fetch = |data: SeriesChunks| -> RuntimeResult<QueryResult> {
+            check_deadline(deadline, state)?;
+            let result = decode_series(data, end_ts)?;
+            state.add_scanned_samples(result.timestamps.len())?;
+            Ok(result)
+        };
+
+        let fetch_start = Instant::now();
+        let results = if series_count < get_global_settings().parallel_fetch_threshold {
+            series
+                .into_iter()
+                .map(fetch)
+                .collect::<RuntimeResult<Vec<_>>>()?
+        } else {
+            // the series are decoded on any thread of the pool, which records spans to the
+            // dispatcher of the search
+            let dispatch = dispatcher::get_default(Dispatch::clone);
+            series
+                .into_par_iter()
+                .map(|data| dispatcher::with_default(&dispatch, || fetch(data)))
+                .collect::<RuntimeResult<Vec<_>>>()?
+        };
+        let fetch_time = fetch_start.elapsed();
+
+        let samples = results.iter().map(|r| r.timestamps.len()).sum::<usize>();
+        span.record("series", series_count);
+        span.record("samples", samples);
+
+        state.update_stats(|stats| {
+            stats.fetch_time += fetch_time;
+            stats.series_fetched += series_count;
+            stats.chunks_decoded += chunks_decoded;
+            stats.samples_scanned += samples;
+        });
         Ok(results)
     }
 }
 
 #[async_trait]
 impl MetricStorage for TsdbDataProvider {
-    async fn search(&self, sq: SearchQuery, _deadline: Deadline) -> RuntimeResult<QueryResults> {
-        // see: https://github.com/RedisLabsModules/redismodule-rs/blob/master/examples/call.rs#L144
-        let ctx_guard = valkey_module::MODULE_CONTEXT.lock();
-        with_timeseries_index(&ctx_guard, |index| {
-            let data = self.get_series_data(&ctx_guard, index, sq)?;
-            let result = QueryResults::new(data);
-            Ok(result)
+    async fn search(&self, sq: SearchQuery, deadline: Deadline) -> RuntimeResult<QueryResults> {
+        // Queries run off the main thread, and the module lock is only held while the chunks of
+        // the matched series are copied out, so that decoding them does not block the server.
+        let state = get_query_state(&deadline).unwrap_or_else(|| {
+            let ctx_guard = valkey_module::MODULE_CONTEXT.lock();
+            let db = unsafe { get_current_db(ctx_guard.ctx) };
+            Arc::new(QueryState::new(db, QueryLimits::default()))
+        });
+        state.in_scope(|| {
+            let data = self.get_series_data(&state, sq, &deadline)?;
+            Ok(QueryResults::new(data))
         })
     }
 }
 
+/// Decodes the samples of `data` up to `end_ts`.
+fn decode_series(data: SeriesChunks, end_ts: Timestamp) -> RuntimeResult<QueryResult> {
+    let mut timestamps = Vec::with_capacity(data.capacity);
+    let mut values = Vec::with_capacity(data.capacity);
+    select_chunks_raw(&data.chunks, end_ts, &mut timestamps, &mut values)
+        .map_err(|e| {
+            // TODO!: we need a specific error for storage backends
+            RuntimeError::General(format!("PROMQL: error reading series: {:?}", e))
+        })?;
+    Ok(QueryResult::new(data.metric, timestamps, values))
+}
+
+/// Estimates the number of samples of `series` in the range, assuming they are evenly spaced.
+fn estimate_samples_in_range(series: &TimeSeries, start_ts: Timestamp, end_ts: Timestamp) -> usize {
+    let first = series.first_timestamp.max(start_ts);
+    let last = series.last_timestamp.min(end_ts);
+    if series.total_samples == 0 || last < first {
+        return 0;
+    }
+    let span = (series.last_timestamp - series.first_timestamp) as f64 + 1.0;
+    let ratio = ((last - first) as f64 + 1.0) / span;
+    ((series.total_samples as f64 * ratio).ceil() as usize).min(series.total_samples)
+}
+
+fn check_deadline(deadline: &Deadline, state: &QueryState) -> RuntimeResult<()> {
+    if state.is_cancelled() {
+        return Err(RuntimeError::General("query cancelled".to_string()));
+    }
+    if deadline.exceeded() {
+        let msg = format!("query timed out after {:?}", deadline.timeout);
+        return Err(RuntimeError::General(msg));
+    }
+    Ok(())
+}
+
 fn to_metric_name(ts: &TimeSeries) -> MetricName {
     let mut mn = MetricName::new(&ts.metric_name);
-    for Label { name, value } in ts.labels.iter() {
-        mn.add_label(name, &value);
+    for label in ts.labels.iter() {
+        mn.add_label(label.name(), label.value());
     }
     mn
 }
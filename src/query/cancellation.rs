@@ -0,0 +1,206 @@
+use super::QueryState;
+use ahash::AHashMap;
+use metricsql_runtime::{Deadline, RuntimeResult};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// A query registered for cancellation, identified by an id assigned by the module. The ids
+/// assigned by the metricsql engine are internal to it, so `VKM.ACTIVE-QUERIES` reports ours.
+struct RunningQuery {
+    query: String,
+    start: i64,
+    end: i64,
+    step: i64,
+    started: Instant,
+    state: Arc<QueryState>,
+}
+
+/// A running query, as returned by `VKM.ACTIVE-QUERIES`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActiveQuery {
+    pub id: u64,
+    pub query: String,
+    pub start: i64,
+    pub end: i64,
+    pub step: i64,
+    pub db: u32,
+    pub duration: Duration,
+}
+
+#[derive(Default)]
+struct RunningQueries {
+    next_id: AtomicU64,
+    queries: Mutex<AHashMap<u64, RunningQuery>>,
+}
+
+static RUNNING_QUERIES: LazyLock<RunningQueries> = LazyLock::new(RunningQueries::default);
+
+/// Nanoseconds below a millisecond, used to carry the id of a query in its timeout.
+const NANOS_PER_MILLI: u64 = 1_000_000;
+
+/// Runs `f` as a cancellable query, passing it the id of the query. While `f` runs, the query is
+/// listed by `get_active_queries`, and `cancel_query` with its id cancels `state`, which the data
+/// provider checks before fetching each series.
+pub fn run_cancellable<R>(
+    query: &str,
+    start: i64,
+    end: i64,
+    step: i64,
+    state: &Arc<QueryState>,
+    f: impl FnOnce(u64) -> R
+) -> R {
+    let id = RUNNING_QUERIES.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+    {
+        let mut queries = RUNNING_QUERIES.queries.lock().unwrap();
+        queries.insert(id, RunningQuery {
+            query: query.to_string(),
+            start,
+            end,
+            step,
+            started: Instant::now(),
+            state: state.clone(),
+        });
+    }
+
+    let _guard = scopeguard::guard((), |_| {
+        RUNNING_QUERIES.queries.lock().unwrap().remove(&id);
+    });
+
+    f(id)
+}
+
+/// Creates the deadline of the query with the given id. The engine passes the deadline of a query
+/// unchanged to the data provider, and gives it no other way to tell which query a search serves,
+/// so the id is carried in the nanoseconds of the timeout, lengthening it by under a millisecond.
+pub fn query_deadline(timeout: Duration, id: u64) -> RuntimeResult<Deadline> {
+    let timeout = Duration::from_millis(timeout.as_millis() as u64)
+        + Duration::from_nanos(query_token(id));
+    Deadline::new(timeout)
+}
+
+/// Returns the state of the running query a deadline was created for by `query_deadline`.
+pub fn get_query_state(deadline: &Deadline) -> Option<Arc<QueryState>> {
+    let token = deadline.timeout.subsec_nanos() as u64 % NANOS_PER_MILLI;
+    if token == 0 {
+        return None;
+    }
+    let queries = RUNNING_QUERIES.queries.lock().unwrap();
+    queries
+        .iter()
+        .find(|(id, _)| query_token(**id) == token)
+        .map(|(_, running)| running.state.clone())
+}
+
+/// A non-zero number of nanoseconds identifying a query among those running, as the ids of queries
+/// running at the same time are close to each other.
+fn query_token(id: u64) -> u64 {
+    id % (NANOS_PER_MILLI - 1) + 1
+}
+
+/// Returns the running queries, in the order they were started.
+pub fn get_active_queries() -> Vec<ActiveQuery> {
+    let queries = RUNNING_QUERIES.queries.lock().unwrap();
+    let mut result: Vec<ActiveQuery> = queries
+        .iter()
+        .map(|(id, running)| ActiveQuery {
+            id: *id,
+            query: running.query.clone(),
+            start: running.start,
+            end: running.end,
+            step: running.step,
+            db: running.state.db,
+            duration: running.started.elapsed(),
+        })
+        .collect();
+    result.sort_by_key(|query| query.id);
+    result
+}
+
+/// Cancels the running query with the given id. Returns false if there is no such query.
+pub fn cancel_query(id: u64) -> bool {
+    let queries = RUNNING_QUERIES.queries.lock().unwrap();
+    match queries.get(&id) {
+        Some(running) => {
+            running.state.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::QueryLimits;
+
+    fn create_state() -> Arc<QueryState> {
+        let limits = QueryLimits {
+            max_series: 0,
+            max_samples: 0,
+            max_points_per_series: 0,
+        };
+        Arc::new(QueryState::new(0, limits))
+    }
+
+    fn find_active_query(query: &str) -> Option<ActiveQuery> {
+        get_active_queries().into_iter().find(|active| active.query == query)
+    }
+
+    #[test]
+    fn test_cancel_running_query() {
+        let state = create_state();
+        let (res, id) = run_cancellable("cancel_test", 10, 20, 5, &state, |_| {
+            assert!(!state.is_cancelled());
+            let active = find_active_query("cancel_test").expect("the running query");
+            assert_eq!((active.start, active.end, active.step), (10, 20, 5));
+            assert!(cancel_query(active.id));
+            (state.is_cancelled(), active.id)
+        });
+        assert!(res);
+        // the query is unregistered once it completes
+        assert!(find_active_query("cancel_test").is_none());
+        assert!(!cancel_query(id));
+    }
+
+    #[test]
+    fn test_queries_with_same_parameters_are_cancelled_separately() {
+        let first = create_state();
+        let second = create_state();
+        run_cancellable("same_params", 10, 20, 5, &first, |_| {
+            run_cancellable("same_params", 10, 20, 5, &second, |_| {
+                let ids = get_active_queries()
+                    .into_iter()
+                    .filter(|active| active.query == "same_params")
+                    .map(|active| active.id)
+                    .collect::<Vec<_>>();
+                assert_eq!(ids.len(), 2);
+                assert!(cancel_query(ids[1]));
+            });
+        });
+        assert!(!first.is_cancelled());
+        assert!(second.is_cancelled());
+    }
+
+    #[test]
+    fn test_query_state_by_deadline() {
+        let first = create_state();
+        let second = create_state();
+        let timeout = Duration::from_secs(1);
+        let deadline = run_cancellable("by_deadline", 10, 20, 5, &first, |first_id| {
+            run_cancellable("by_deadline", 10, 20, 5, &second, |second_id| {
+                let first_deadline = query_deadline(timeout, first_id).unwrap();
+                let second_deadline = query_deadline(timeout, second_id).unwrap();
+                let found = get_query_state(&first_deadline).expect("the first query");
+                assert!(Arc::ptr_eq(&found, &first));
+                let found = get_query_state(&second_deadline).expect("the second query");
+                assert!(Arc::ptr_eq(&found, &second));
+                first_deadline
+            })
+        });
+        // the query is no longer running
+        assert!(get_query_state(&deadline).is_none());
+        // nor was this deadline created for a query
+        assert!(get_query_state(&Deadline::new(timeout).unwrap()).is_none());
+    }
+}
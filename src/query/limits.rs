@@ -0,0 +1,168 @@
+use crate::config::get_global_settings;
+use metricsql_runtime::{RuntimeError, RuntimeResult};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Resource limits applied to a single query. A value of 0 means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueryLimits {
+    /// Max number of series matched by the selectors of a query, in total
+    pub max_series: usize,
+    /// Max number of samples scanned over the whole query
+    pub max_samples: usize,
+    /// Max number of output points per series in a range query
+    pub max_points_per_series: usize,
+}
+
+impl Default for QueryLimits {
+    fn default() -> Self {
+        let settings = get_global_settings();
+        Self {
+            max_series: settings.max_query_series,
+            max_samples: settings.max_query_samples,
+            max_points_per_series: settings.max_points_per_series,
+        }
+    }
+}
+
+/// Counts of queries which failed because a limit was exceeded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QueryLimitBreaches {
+    pub series: u64,
+    pub samples: u64,
+    pub points: u64,
+}
+
+static SERIES_LIMIT_BREACHES: AtomicU64 = AtomicU64::new(0);
+static SAMPLES_LIMIT_BREACHES: AtomicU64 = AtomicU64::new(0);
+static POINTS_LIMIT_BREACHES: AtomicU64 = AtomicU64::new(0);
+
+pub fn get_query_limit_breaches() -> QueryLimitBreaches {
+    QueryLimitBreaches {
+        series: SERIES_LIMIT_BREACHES.load(Ordering::Relaxed),
+        samples: SAMPLES_LIMIT_BREACHES.load(Ordering::Relaxed),
+        points: POINTS_LIMIT_BREACHES.load(Ordering::Relaxed),
+    }
+}
+
+impl QueryLimits {
+    /// Fails if `total` series matched by a query, after matching `added` more, exceeds the limit.
+    pub fn check_series(&self, total: usize, added: usize) -> RuntimeResult<()> {
+        let max = self.max_series;
+        if max > 0 && total > max {
+            // selectors are evaluated in parallel, so count the breach only for the one crossing it
+            if total - added <= max {
+                SERIES_LIMIT_BREACHES.fetch_add(1, Ordering::Relaxed);
+            }
+            let msg = format!("the number of matching series ({total}) exceeds the limit of {max}. \
+            Use more specific selectors or increase MAX_SERIES");
+            return Err(RuntimeError::General(msg));
+        }
+        Ok(())
+    }
+
+    /// Fails if `total` samples scanned by a query, after scanning `added` more, exceeds the limit.
+    pub fn check_samples(&self, total: usize, added: usize) -> RuntimeResult<()> {
+        let max = self.max_samples;
+        if max > 0 && total > max {
+            // series are fetched in parallel, so count the breach only for the fetch crossing it
+            if total - added <= max {
+                SAMPLES_LIMIT_BREACHES.fetch_add(1, Ordering::Relaxed);
+            }
+            let msg = format!("the number of samples scanned exceeds the limit of {max}. \
+            Reduce the query range or increase MAX_SAMPLES");
+            return Err(RuntimeError::General(msg));
+        }
+        Ok(())
+    }
+}
+
+/// Fails if a range query over [`start`, `end`] with the given step would produce more points
+/// per series than allowed.
+pub fn check_points_limit(limits: &QueryLimits, start: i64, end: i64, step: i64) -> Result<(), String> {
+    let max = limits.max_points_per_series;
+    if max == 0 || step <= 0 {
+        return Ok(());
+    }
+    if end < start {
+        return Err("the query range ends before it starts".to_string());
+    }
+    // in i128, since the range may not fit in an i64
+    let points = (end as i128 - start as i128) / step as i128 + 1;
+    if points > max as i128 {
+        POINTS_LIMIT_BREACHES.fetch_add(1, Ordering::Relaxed);
+        return Err(format!("the query would return {points} points per series, exceeding the limit of {max}. \
+        Reduce the query range or increase STEP"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(max_series: usize, max_samples: usize) -> QueryLimits {
+        QueryLimits {
+            max_series,
+            max_samples,
+            max_points_per_series: 10,
+        }
+    }
+
+    #[test]
+    fn test_zero_is_unlimited() {
+        let limits = limits(0, 0);
+        assert!(limits.check_series(usize::MAX, 1).is_ok());
+        assert!(limits.check_samples(usize::MAX, 1).is_ok());
+    }
+
+    #[test]
+    fn test_series_limit() {
+        let limits = limits(2, 0);
+        assert!(limits.check_series(2, 2).is_ok());
+        assert!(limits.check_series(3, 1).is_err());
+    }
+
+    #[test]
+    fn test_samples_limit() {
+        let limits = limits(0, 100);
+        assert!(limits.check_samples(100, 40).is_ok());
+        assert!(limits.check_samples(101, 1).is_err());
+        assert!(limits.check_samples(150, 10).is_err());
+    }
+
+    #[test]
+    fn test_points_limit() {
+        let limits = limits(0, 0);
+        assert!(check_points_limit(&limits, 0, 90, 10).is_ok());
+        assert!(check_points_limit(&limits, 0, 100, 10).is_err());
+        // a range ending before it starts does not wrap around
+        assert!(check_points_limit(&limits, 100, 0, 10).is_err());
+        assert!(check_points_limit(&limits, i64::MAX, i64::MIN, 10).is_err());
+        assert!(check_points_limit(&limits, i64::MIN, i64::MAX, 10).is_err());
+    }
+
+    #[test]
+    fn test_limit_messages() {
+        let Err(RuntimeError::General(msg)) = limits(2, 0).check_series(3, 3) else {
+            panic!("expected the series limit to be exceeded");
+        };
+        assert_eq!(
+            msg,
+            "the number of matching series (3) exceeds the limit of 2. Use more specific selectors or increase MAX_SERIES"
+        );
+
+        let Err(RuntimeError::General(msg)) = limits(0, 100).check_samples(101, 101) else {
+            panic!("expected the samples limit to be exceeded");
+        };
+        assert_eq!(
+            msg,
+            "the number of samples scanned exceeds the limit of 100. Reduce the query range or increase MAX_SAMPLES"
+        );
+
+        let msg = check_points_limit(&limits(0, 0), 0, 100, 10).unwrap_err();
+        assert_eq!(
+            msg,
+            "the query would return 11 points per series, exceeding the limit of 10. Reduce the query range or increase STEP"
+        );
+    }
+}
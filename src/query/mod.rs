@@ -0,0 +1,15 @@
+mod cancellation;
+mod limits;
+mod pool;
+mod result_cache;
+mod state;
+mod stats;
+mod trace;
+
+pub use cancellation::*;
+pub use limits::*;
+pub use pool::*;
+pub use result_cache::*;
+pub use state::*;
+pub use stats::*;
+pub use trace::*;
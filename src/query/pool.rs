@@ -0,0 +1,180 @@
+use super::state::QueryState;
+use crate::config::get_global_settings;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::LazyLock;
+use tracing::dispatcher::{self, Dispatch};
+use valkey_module::{Context, ContextFlags, ThreadSafeContext, ValkeyError, ValkeyResult, ValkeyValue};
+
+static QUERY_POOL: LazyLock<rayon::ThreadPool> = LazyLock::new(create_query_pool);
+static EVAL_POOL: LazyLock<rayon::ThreadPool> = LazyLock::new(create_eval_pool);
+static RUNNING_QUERY_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Creates the pool running queries, with a thread for each query which may run at a time. Settings
+/// are fixed once loaded, so the pool is never resized.
+fn create_query_pool() -> rayon::ThreadPool {
+    let num_threads = get_global_settings().max_concurrent_queries.max(1);
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .thread_name(|i| format!("vkm-query-{i}"))
+        .build()
+        .expect("failed to create query thread pool")
+}
+
+fn create_eval_pool() -> rayon::ThreadPool {
+    let num_threads = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .thread_name(|i| format!("vkm-eval-{i}"))
+        .build()
+        .expect("failed to create query evaluation thread pool")
+}
+
+/// A slot in the set of concurrently running queries. Released on drop.
+struct QuerySlot;
+
+impl QuerySlot {
+    fn acquire() -> Option<Self> {
+        let max = get_global_settings().max_concurrent_queries;
+        RUNNING_QUERY_COUNT
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < max).then_some(count + 1)
+            })
+            .ok()
+            .map(|_| QuerySlot)
+    }
+}
+
+impl Drop for QuerySlot {
+    fn drop(&mut self) {
+        RUNNING_QUERY_COUNT.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Runs `f` on the query worker pool, blocking the client until it completes. The data provider
+/// only holds the module lock while copying the chunks of the series it matches, so the main
+/// thread is not held while they are decoded nor for the rest of the evaluation.
+pub fn spawn_blocking_query<F>(ctx: &Context, f: F) -> ValkeyResult
+where
+    F: FnOnce() -> ValkeyResult + Send + 'static,
+{
+    if ctx.get_flags().contains(ContextFlags::DENY_BLOCKING) {
+        return Err(ValkeyError::Str("ERR queries cannot be run inside MULTI or scripts"));
+    }
+
+    let Some(slot) = QuerySlot::acquire() else {
+        return Err(ValkeyError::Str("ERR too many concurrent queries"));
+    };
+
+    let blocked_client = ctx.block_client();
+    QUERY_POOL.spawn(move || {
+        let _slot = slot;
+        let thread_ctx = ThreadSafeContext::with_blocked_client(blocked_client);
+        let result = f();
+        thread_ctx.reply(result);
+    });
+
+    Ok(ValkeyValue::NoReply)
+}
+
+/// Runs `f` on the pool evaluating queries, shared by every running query. The engine evaluates
+/// parts of a query in parallel on that pool, so the data provider may be called from any of its
+/// threads, and finds `state` by the deadline of the search (see `get_query_state`). Spans are
+/// recorded to the `tracing` dispatcher of the calling thread, which is kept in `state` for the
+/// data provider to include its spans in the trace of the query.
+pub fn run_query<R: Send>(state: &QueryState, f: impl FnOnce() -> R + Send) -> R {
+    let dispatch = dispatcher::get_default(Dispatch::clone);
+    state.set_dispatch(dispatch.clone());
+    EVAL_POOL.install(|| dispatcher::with_default(&dispatch, f))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::{collect_trace, QueryLimits};
+    use rayon::prelude::*;
+    use tracing::{debug, info_span};
+
+    fn create_state() -> QueryState {
+        let limits = QueryLimits {
+            max_series: 0,
+            max_samples: 0,
+            max_points_per_series: 0,
+        };
+        QueryState::new(0, limits)
+    }
+
+    #[test]
+    fn test_run_query_runs_on_eval_pool() {
+        let state = create_state();
+        let names = run_query(&state, || {
+            (0..64)
+                .into_par_iter()
+                .map(|_| std::thread::current().name().map(str::to_string))
+                .collect::<Vec<_>>()
+        });
+        assert!(names
+            .iter()
+            .all(|name| name.as_deref().is_some_and(|name| name.starts_with("vkm-eval-"))));
+    }
+
+    #[test]
+    fn test_run_query_traces_all_threads() {
+        let state = create_state();
+        let (_, trace) = collect_trace(|| {
+            info_span!("query", query = "up").in_scope(|| {
+                run_query(&state, || {
+                    // as the engine calls the data provider, from tasks of the pool
+                    (0..16).into_par_iter().for_each(|i| {
+                        state.in_scope(|| {
+                            let _search = info_span!("search", i).entered();
+                            debug!(series = 1, "fetched");
+                        });
+                    });
+                })
+            })
+        });
+
+        assert_eq!(trace.len(), 1);
+        let root = &trace[0];
+        assert_eq!(root.name, "query");
+        assert_eq!(root.children.len(), 16);
+        for child in root.children.iter() {
+            assert_eq!(child.name, "search");
+            assert_eq!(child.children.len(), 1);
+            assert_eq!(child.children[0].name, "fetched");
+        }
+        let mut indexes = root.children
+            .iter()
+            .map(|child| child.fields[0].1.parse::<usize>().unwrap())
+            .collect::<Vec<_>>();
+        indexes.sort_unstable();
+        assert_eq!(indexes, (0..16).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_run_query_traces_are_separate() {
+        let first = create_state();
+        let second = create_state();
+        let ((_, inner), outer) = collect_trace(|| {
+            info_span!("first").in_scope(|| {
+                run_query(&first, || {
+                    collect_trace(|| {
+                        info_span!("second").in_scope(|| {
+                            run_query(&second, || {
+                                (0..8).into_par_iter().for_each(|i| {
+                                    first.in_scope(|| info_span!("search", i).in_scope(|| {}));
+                                    second.in_scope(|| info_span!("search", i).in_scope(|| {}));
+                                });
+                            })
+                        })
+                    })
+                })
+            })
+        });
+        assert_eq!(outer.len(), 1);
+        assert_eq!(outer[0].children.len(), 8);
+        assert_eq!(inner.len(), 1);
+        assert_eq!(inner[0].children.len(), 8);
+    }
+}
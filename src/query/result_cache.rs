@@ -0,0 +1,500 @@
+use crate::common::current_time_millis;
+use crate::common::types::Timestamp;
+use crate::config::get_global_settings;
+use ahash::AHashMap;
+use croaring::Bitmap64;
+use metricsql_parser::parser::parse;
+use metricsql_runtime::QueryResult;
+use std::mem::size_of;
+use std::sync::Mutex;
+
+/// Key for the range query result cache. Queries are keyed by their normalized (parsed and
+/// re-serialized) expression, so that whitespace and formatting differences map to the same entry.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct QueryCacheKey {
+    /// the db selected by the client. The same query selects different series in each db
+    pub db: u32,
+    pub query: String,
+    pub step: i64,
+    pub round_digits: u8,
+}
+
+impl QueryCacheKey {
+    /// Returns None if the query cannot be parsed. In that case the query should bypass the cache
+    /// and let the engine report the error.
+    pub fn new(db: u32, query: &str, step: i64, round_digits: u8) -> Option<Self> {
+        if step <= 0 {
+            return None;
+        }
+        let expr = parse(query).ok()?;
+        Some(Self {
+            db,
+            query: expr.to_string(),
+            step,
+            round_digits,
+        })
+    }
+}
+
+/// The portion of a cached result overlapping a requested range.
+pub struct CachedRange {
+    /// the last (step-aligned) timestamp covered by `results`
+    pub end: Timestamp,
+    pub results: Vec<QueryResult>,
+}
+
+struct CacheEntry {
+    start: Timestamp,
+    end: Timestamp,
+    results: Vec<QueryResult>,
+    /// ids of the series which contributed to the results. Used for invalidation.
+    series_ids: Bitmap64,
+    size: usize,
+    last_access: u64,
+}
+
+struct CacheInner {
+    entries: AHashMap<QueryCacheKey, CacheEntry>,
+    size: usize,
+    tick: u64,
+    /// Incremented by invalidations which may affect results being computed (see `generation`)
+    generation: u64,
+    /// The latest end of the entries since the cache was last cleared
+    max_end: Timestamp,
+}
+
+impl Default for CacheInner {
+    fn default() -> Self {
+        Self {
+            entries: AHashMap::new(),
+            size: 0,
+            tick: 0,
+            generation: 0,
+            max_end: Timestamp::MIN,
+        }
+    }
+}
+
+impl CacheInner {
+    /// Records an invalidation of results covering `timestamp`, returning whether any entry may
+    /// cover it. Results being computed are only cached up to now - query_cache_horizon, so a later
+    /// timestamp does not affect them.
+    fn begin_invalidation(&mut self, timestamp: Timestamp) -> bool {
+        let horizon = get_global_settings().query_cache_horizon.as_millis() as i64;
+        if timestamp <= current_time_millis().saturating_sub(horizon) {
+            self.generation += 1;
+        }
+        timestamp <= self.max_end && !self.entries.is_empty()
+    }
+
+    fn remove(&mut self, key: &QueryCacheKey) -> Option<CacheEntry> {
+        let entry = self.entries.remove(key)?;
+        self.size -= entry.size;
+        Some(entry)
+    }
+
+    fn retain<F>(&mut self, mut f: F)
+    where F: FnMut(&QueryCacheKey, &CacheEntry) -> bool
+    {
+        let mut removed = 0;
+        self.entries.retain(|key, entry| {
+            let keep = f(key, entry);
+            if !keep {
+                removed += entry.size;
+            }
+            keep
+        });
+        self.size -= removed;
+    }
+
+    fn evict(&mut self, max_size: usize) {
+        while self.size > max_size && !self.entries.is_empty() {
+            // O(n), but the number of entries is expected to be small relative to their size
+            let lru = self.entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_access)
+                .map(|(key, _)| key.clone());
+            if let Some(key) = lru {
+                self.remove(&key);
+            }
+        }
+    }
+}
+
+/// Caches the results of range queries. Entries cover a contiguous step-aligned range, so a
+/// subsequent query for an overlapping range only needs to compute the missing tail.
+#[derive(Default)]
+pub struct QueryResultCache {
+    inner: Mutex<CacheInner>,
+}
+
+impl QueryResultCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn max_size(&self) -> usize {
+        get_global_settings().max_query_cache_size
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_size() > 0
+    }
+
+    /// Returns the current generation of the cache, to be passed to `put` with results computed
+    /// after it is read. Results are not stored if an invalidation happened in the meantime, since
+    /// they may predate the write which caused it.
+    pub fn generation(&self) -> u64 {
+        self.inner.lock().unwrap().generation
+    }
+
+    /// Returns the cached results for `key` which overlap [`start`, `end`], provided the cached
+    /// range starts at or before `start`.
+    pub fn get(&self, key: &QueryCacheKey, start: Timestamp, end: Timestamp) -> Option<CachedRange> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let entry = inner.entries.get_mut(key)?;
+        if entry.start > start || entry.end < start {
+            return None;
+        }
+        entry.last_access = tick;
+        let cached_end = entry.end.min(end);
+        let results = entry.results
+            .iter()
+            .filter_map(|r| clip_result(r, start, cached_end))
+            .collect();
+        Some(CachedRange {
+            end: cached_end,
+            results,
+        })
+    }
+
+    /// Stores results covering [`start`, `end`], computed after `generation` was read (see
+    /// `generation`). If an existing entry ends within the new range, its earlier points are
+    /// retained so the entry keeps growing as a dashboard moves forward.
+    pub fn put(
+        &self,
+        key: QueryCacheKey,
+        start: Timestamp,
+        end: Timestamp,
+        results: &[QueryResult],
+        series_ids: Bitmap64,
+        generation: u64
+    ) {
+        let max_size = self.max_size();
+        if max_size == 0 || end < start {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.generation != generation {
+            return;
+        }
+        let mut start = start;
+        let mut series_ids = series_ids;
+        let mut results: Vec<QueryResult> = results
+            .iter()
+            .filter_map(|r| clip_result(r, start, end))
+            .collect();
+
+        if let Some(existing) = inner.remove(&key) {
+            if existing.start < start && existing.end + key.step >= start {
+                let head = existing.results
+                    .iter()
+                    .filter_map(|r| clip_result(r, existing.start, start - 1))
+                    .collect();
+                results = merge_query_results(head, results);
+                series_ids.or_inplace(&existing.series_ids);
+                start = existing.start;
+            }
+        }
+
+        let size = estimate_size(&results);
+        if size > max_size {
+            return;
+        }
+
+        inner.tick += 1;
+        let entry = CacheEntry {
+            start,
+            end,
+            results,
+            series_ids,
+            size,
+            last_access: inner.tick,
+        };
+        inner.size += size;
+        inner.max_end = inner.max_end.max(end);
+        inner.entries.insert(key, entry);
+        inner.evict(max_size);
+    }
+
+    /// Invalidate entries touching the series `id` which cover `timestamp`. Should be called on
+    /// writes and deletes to existing series.
+    pub fn invalidate_series(&self, id: u64, timestamp: Timestamp) {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.begin_invalidation(timestamp) {
+            return;
+        }
+        inner.retain(|_, entry| entry.end < timestamp || !entry.series_ids.contains(id));
+    }
+
+    /// Invalidate all entries of `db` covering `timestamp`. Used when a new series is created, since
+    /// we cannot cheaply tell which cached queries it would match.
+    pub fn invalidate_since(&self, db: u32, timestamp: Timestamp) {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.begin_invalidation(timestamp) {
+            return;
+        }
+        inner.retain(|key, entry| key.db != db || entry.end < timestamp);
+    }
+
+    /// Remove all entries of `db`, e.g. after it is flushed or swapped.
+    pub fn clear_db(&self, db: u32) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        if inner.entries.is_empty() {
+            return;
+        }
+        inner.retain(|key, _| key.db != db);
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.clear();
+        inner.size = 0;
+        inner.generation += 1;
+        inner.max_end = Timestamp::MIN;
+    }
+
+    pub fn len(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner.entries.len()
+    }
+
+    pub fn size(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner.size
+    }
+}
+
+/// Round `ts` down to a multiple of `step`.
+pub fn align_to_step(ts: Timestamp, step: i64) -> Timestamp {
+    if step <= 0 {
+        return ts;
+    }
+    ts - ts.rem_euclid(step)
+}
+
+/// Returns the portion of `result` in the range [`start`, `end`], or None if it is empty.
+fn clip_result(result: &QueryResult, start: Timestamp, end: Timestamp) -> Option<QueryResult> {
+    let timestamps = &result.timestamps;
+    let first = timestamps.partition_point(|&ts| ts < start);
+    let last = timestamps.partition_point(|&ts| ts <= end);
+    if first >= last {
+        return None;
+    }
+    Some(QueryResult::new(
+        result.metric.clone(),
+        timestamps[first..last].to_vec(),
+        result.values[first..last].to_vec(),
+    ))
+}
+
+/// Merge two result sets, where all points in `tail` are later than those in `head`.
+/// Series are matched by metric name.
+pub fn merge_query_results(head: Vec<QueryResult>, tail: Vec<QueryResult>) -> Vec<QueryResult> {
+    if head.is_empty() {
+        return tail;
+    }
+    if tail.is_empty() {
+        return head;
+    }
+    let mut positions: AHashMap<String, usize> = AHashMap::with_capacity(head.len());
+    let mut results = head;
+    for (i, r) in results.iter().enumerate() {
+        positions.insert(r.metric.to_string(), i);
+    }
+    for r in tail.into_iter() {
+        if let Some(&i) = positions.get(&r.metric.to_string()) {
+            let existing = &mut results[i];
+            existing.timestamps.extend_from_slice(&r.timestamps);
+            existing.values.extend_from_slice(&r.values);
+        } else {
+            results.push(r);
+        }
+    }
+    results
+}
+
+fn estimate_size(results: &[QueryResult]) -> usize {
+    results.iter().map(|r| {
+        let labels_size: usize = r.metric.labels
+            .iter()
+            .map(|l| l.name.len() + l.value.len())
+            .sum();
+        size_of::<QueryResult>() +
+            r.metric.measurement.len() +
+            labels_size +
+            r.timestamps.len() * (size_of::<i64>() + size_of::<f64>())
+    }).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metricsql_runtime::types::MetricName;
+
+    fn create_result(name: &str, timestamps: Vec<i64>) -> QueryResult {
+        let values = timestamps.iter().map(|&ts| ts as f64).collect();
+        QueryResult::new(MetricName::new(name), timestamps, values)
+    }
+
+    fn create_key() -> QueryCacheKey {
+        QueryCacheKey {
+            db: 0,
+            query: "up".to_string(),
+            step: 10,
+            round_digits: 100,
+        }
+    }
+
+    #[test]
+    fn test_align_to_step() {
+        assert_eq!(align_to_step(105, 10), 100);
+        assert_eq!(align_to_step(100, 10), 100);
+        assert_eq!(align_to_step(-5, 10), -10);
+        assert_eq!(align_to_step(105, 0), 105);
+    }
+
+    #[test]
+    fn test_clip_result() {
+        let r = create_result("up", vec![10, 20, 30, 40]);
+        let clipped = clip_result(&r, 15, 30).unwrap();
+        assert_eq!(clipped.timestamps, vec![20, 30]);
+        assert_eq!(clipped.values, vec![20.0, 30.0]);
+        assert!(clip_result(&r, 41, 50).is_none());
+    }
+
+    #[test]
+    fn test_merge_query_results() {
+        let head = vec![create_result("a", vec![10, 20]), create_result("b", vec![10])];
+        let tail = vec![create_result("a", vec![30]), create_result("c", vec![30])];
+        let merged = merge_query_results(head, tail);
+        assert_eq!(merged.len(), 3);
+        assert_eq!(merged[0].timestamps, vec![10, 20, 30]);
+        assert_eq!(merged[1].timestamps, vec![10]);
+        assert_eq!(merged[2].timestamps, vec![30]);
+    }
+
+    #[test]
+    fn test_get_returns_overlap() {
+        let cache = QueryResultCache::new();
+        let key = create_key();
+        let results = vec![create_result("a", vec![10, 20, 30, 40])];
+        cache.put(key.clone(), 10, 40, &results, Bitmap64::new(), cache.generation());
+
+        let cached = cache.get(&key, 20, 100).unwrap();
+        assert_eq!(cached.end, 40);
+        assert_eq!(cached.results[0].timestamps, vec![20, 30, 40]);
+
+        let cached = cache.get(&key, 20, 30).unwrap();
+        assert_eq!(cached.end, 30);
+        assert_eq!(cached.results[0].timestamps, vec![20, 30]);
+
+        // range starts before the cached range
+        assert!(cache.get(&key, 0, 40).is_none());
+        // range starts after the cached range
+        assert!(cache.get(&key, 50, 100).is_none());
+    }
+
+    #[test]
+    fn test_put_extends_existing_entry() {
+        let cache = QueryResultCache::new();
+        let key = create_key();
+        let results = [create_result("a", vec![10, 20, 30])];
+        cache.put(key.clone(), 10, 30, &results, Bitmap64::new(), cache.generation());
+        let results = [create_result("a", vec![20, 30, 40, 50])];
+        cache.put(key.clone(), 20, 50, &results, Bitmap64::new(), cache.generation());
+
+        let cached = cache.get(&key, 10, 50).unwrap();
+        assert_eq!(cached.end, 50);
+        assert_eq!(cached.results[0].timestamps, vec![10, 20, 30, 40, 50]);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_invalidate_series() {
+        let cache = QueryResultCache::new();
+        let key = create_key();
+        let mut ids = Bitmap64::new();
+        ids.add(7);
+        cache.put(key.clone(), 10, 40, &[create_result("a", vec![10, 40])], ids, cache.generation());
+
+        // write after the cached range
+        cache.invalidate_series(7, 50);
+        assert_eq!(cache.len(), 1);
+
+        // write to an unrelated series
+        cache.invalidate_series(8, 20);
+        assert_eq!(cache.len(), 1);
+
+        cache.invalidate_series(7, 20);
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn test_invalidate_since() {
+        let cache = QueryResultCache::new();
+        let key = create_key();
+        cache.put(key.clone(), 10, 40, &[create_result("a", vec![10, 40])], Bitmap64::new(), cache.generation());
+        // a series created in another db
+        cache.invalidate_since(1, 40);
+        assert_eq!(cache.len(), 1);
+        cache.invalidate_since(0, 41);
+        assert_eq!(cache.len(), 1);
+        cache.invalidate_since(0, 40);
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn test_key_includes_db() {
+        let cache = QueryResultCache::new();
+        let key = create_key();
+        cache.put(key.clone(), 10, 40, &[create_result("a", vec![10, 40])], Bitmap64::new(), cache.generation());
+
+        let other_db = QueryCacheKey { db: 1, ..key.clone() };
+        assert!(cache.get(&other_db, 10, 40).is_none());
+        assert!(cache.get(&key, 10, 40).is_some());
+
+        cache.clear_db(1);
+        assert_eq!(cache.len(), 1);
+        cache.clear_db(0);
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn test_put_after_invalidation_is_dropped() {
+        let cache = QueryResultCache::new();
+        let key = create_key();
+        let generation = cache.generation();
+        // a write to a series of the query while it is evaluated
+        cache.invalidate_series(7, 20);
+        cache.put(key.clone(), 10, 40, &[create_result("a", vec![10, 40])], Bitmap64::new(), generation);
+        assert_eq!(cache.len(), 0);
+
+        let generation = cache.generation();
+        cache.invalidate_since(1, 20);
+        cache.put(key.clone(), 10, 40, &[create_result("a", vec![10, 40])], Bitmap64::new(), generation);
+        assert_eq!(cache.len(), 0);
+
+        // writes of recent samples are not cached by any query
+        let generation = cache.generation();
+        cache.invalidate_series(7, current_time_millis() + 60_000);
+        cache.put(key.clone(), 10, 40, &[create_result("a", vec![10, 40])], Bitmap64::new(), generation);
+        assert_eq!(cache.len(), 1);
+    }
+}
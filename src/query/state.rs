@@ -0,0 +1,169 @@
+use super::{QueryLimits, QueryStats};
+use croaring::Bitmap64;
+use metricsql_runtime::RuntimeResult;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use tracing::dispatcher::{self, Dispatch};
+
+/// The state of a running query, shared by every thread evaluating it. The engine may call the
+/// data provider from any thread of the evaluation pool (see `run_query`), which finds the state
+/// by the deadline of the search (see `get_query_state`).
+pub struct QueryState {
+    /// The db selected by the client which issued the query
+    pub db: u32,
+    pub limits: QueryLimits,
+    series_matched: AtomicUsize,
+    samples_scanned: AtomicUsize,
+    cancelled: AtomicBool,
+    stats: Option<Mutex<QueryStats>>,
+    series_ids: Option<Mutex<Bitmap64>>,
+    dispatch: OnceLock<Dispatch>,
+}
+
+impl QueryState {
+    pub fn new(db: u32, limits: QueryLimits) -> Self {
+        Self {
+            db,
+            limits,
+            series_matched: AtomicUsize::new(0),
+            samples_scanned: AtomicUsize::new(0),
+            cancelled: AtomicBool::new(false),
+            stats: None,
+            series_ids: None,
+            dispatch: OnceLock::new(),
+        }
+    }
+
+    /// Collects the statistics of the query, returned by `stats`.
+    pub fn with_stats(mut self) -> Self {
+        self.stats = Some(Mutex::default());
+        self
+    }
+
+    /// Collects the ids of the series matched by the query, returned by `series_ids`.
+    pub fn with_series_ids(mut self) -> Self {
+        self.series_ids = Some(Mutex::default());
+        self
+    }
+
+    /// Adds `count` series matched by a selector to those matched by the query, failing if the
+    /// limit is exceeded.
+    pub fn add_matched_series(&self, count: usize) -> RuntimeResult<()> {
+        let total = self.series_matched.fetch_add(count, Ordering::Relaxed) + count;
+        self.limits.check_series(total, count)
+    }
+
+    /// Adds `count` to the samples scanned by the query, failing if the limit is exceeded.
+    pub fn add_scanned_samples(&self, count: usize) -> RuntimeResult<()> {
+        let total = self.samples_scanned.fetch_add(count, Ordering::Relaxed) + count;
+        self.limits.check_samples(total, count)
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Updates the statistics of the query, if they are being collected.
+    pub fn update_stats(&self, f: impl FnOnce(&mut QueryStats)) {
+        if let Some(stats) = self.stats.as_ref() {
+            f(&mut stats.lock().unwrap());
+        }
+    }
+
+    pub fn stats(&self) -> Option<QueryStats> {
+        self.stats.as_ref().map(|stats| stats.lock().unwrap().clone())
+    }
+
+    /// Called by the data provider with the series matched by a selector, including those without
+    /// samples in the range of the query, since samples may be added to them later.
+    pub fn record_series_ids(&self, matched: &Bitmap64) {
+        if let Some(ids) = self.series_ids.as_ref() {
+            ids.lock().unwrap().or_inplace(matched);
+        }
+    }
+
+    pub fn series_ids(&self) -> Bitmap64 {
+        self.series_ids
+            .as_ref()
+            .map(|ids| ids.lock().unwrap().clone())
+            .unwrap_or_default()
+    }
+
+    /// Sets the `tracing` dispatcher recording the spans of the query. Only the first one is kept.
+    pub(super) fn set_dispatch(&self, dispatch: Dispatch) {
+        let _ = self.dispatch.set(dispatch);
+    }
+
+    /// Runs `f` with the dispatcher of the query as the default of the current thread, so that
+    /// spans created on any thread of the evaluation pool are part of the trace of the query.
+    pub fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        match self.dispatch.get() {
+            Some(dispatch) => dispatcher::with_default(dispatch, f),
+            None => f(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(max_samples: usize) -> QueryLimits {
+        QueryLimits {
+            max_series: 0,
+            max_samples,
+            max_points_per_series: 0,
+        }
+    }
+
+    #[test]
+    fn test_series_limit_is_cumulative() {
+        let limits = QueryLimits {
+            max_series: 10,
+            ..limits(0)
+        };
+        let state = QueryState::new(0, limits);
+        assert!(state.add_matched_series(6).is_ok());
+        assert!(state.add_matched_series(4).is_ok());
+        assert!(state.add_matched_series(1).is_err());
+    }
+
+    #[test]
+    fn test_samples_limit_is_cumulative() {
+        let state = QueryState::new(0, limits(100));
+        assert!(state.add_scanned_samples(60).is_ok());
+        assert!(state.add_scanned_samples(40).is_ok());
+        assert!(state.add_scanned_samples(1).is_err());
+
+        let state = QueryState::new(0, limits(100));
+        assert!(state.add_scanned_samples(60).is_ok());
+    }
+
+    #[test]
+    fn test_stats_only_collected_if_enabled() {
+        let state = QueryState::new(0, limits(0));
+        state.update_stats(|stats| stats.series_matched = 10);
+        assert_eq!(state.stats(), None);
+
+        let state = QueryState::new(0, limits(0)).with_stats();
+        state.update_stats(|stats| stats.series_matched += 2);
+        state.update_stats(|stats| stats.series_matched += 3);
+        assert_eq!(state.stats().map(|stats| stats.series_matched), Some(5));
+    }
+
+    #[test]
+    fn test_series_ids() {
+        let state = QueryState::new(0, limits(0));
+        state.record_series_ids(&Bitmap64::from_iter([1u64]));
+        assert!(state.series_ids().is_empty());
+
+        let state = QueryState::new(0, limits(0)).with_series_ids();
+        state.record_series_ids(&Bitmap64::from_iter([1u64, 7]));
+        state.record_series_ids(&Bitmap64::from_iter([7u64, 9]));
+        assert_eq!(state.series_ids().iter().collect::<Vec<_>>(), vec![1, 7, 9]);
+    }
+}
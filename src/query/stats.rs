@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+/// Statistics of a query, returned with the STATS option.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct QueryStats {
+    /// Time spent resolving selectors to series in the index
+    pub index_lookup_time: Duration,
+    /// Time spent decoding the chunks of the selected series
+    pub fetch_time: Duration,
+    pub total_time: Duration,
+    /// Number of series matched by the selectors in the index
+    pub series_matched: usize,
+    /// Number of matched series with samples in the query range
+    pub series_fetched: usize,
+    pub chunks_decoded: usize,
+    pub samples_scanned: usize,
+}
+
+impl QueryStats {
+    /// Time spent evaluating the query, other than fetching series.
+    pub fn eval_time(&self) -> Duration {
+        let fetch = self.index_lookup_time + self.fetch_time;
+        self.total_time.saturating_sub(fetch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eval_time() {
+        let stats = QueryStats {
+            index_lookup_time: Duration::from_millis(2),
+            fetch_time: Duration::from_millis(3),
+            total_time: Duration::from_millis(10),
+            ..Default::default()
+        };
+        assert_eq!(stats.eval_time(), Duration::from_millis(5));
+
+        // timings are measured separately, so parts may add up to more than the total
+        let stats = QueryStats {
+            fetch_time: Duration::from_millis(11),
+            total_time: Duration::from_millis(10),
+            ..Default::default()
+        };
+        assert_eq!(stats.eval_time(), Duration::ZERO);
+    }
+}
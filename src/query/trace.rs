@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+use tracing::dispatcher::{self, Dispatch};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+/// A span of a query trace, returned with the TRACE option.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceNode {
+    pub name: String,
+    /// The fields recorded on the span, in order
+    pub fields: Vec<(String, String)>,
+    /// Time from the creation of the span to its close. Zero for events
+    pub duration: Duration,
+    pub children: Vec<TraceNode>,
+}
+
+/// Runs `f`, collecting the `tracing` spans and events created on the current thread into a tree,
+/// such as those of the query engine and of the data provider. Spans are also collected from
+/// other threads having the dispatcher as their default, as the threads evaluating the query do
+/// (see `run_query`). Those are children of the innermost span entered on the current thread.
+pub fn collect_trace<R>(f: impl FnOnce() -> R) -> (R, Vec<TraceNode>) {
+    let collector = TraceCollector::default();
+    collector.state.lock().unwrap().root_thread = Some(std::thread::current().id());
+    let dispatch = Dispatch::new(collector.clone());
+    let res = dispatcher::with_default(&dispatch, f);
+    (res, collector.into_tree())
+}
+
+struct SpanData {
+    name: String,
+    fields: Vec<(String, String)>,
+    parent: Option<u64>,
+    start: Instant,
+    duration: Option<Duration>,
+    ref_count: usize,
+}
+
+#[derive(Default)]
+struct TraceState {
+    next_id: u64,
+    spans: HashMap<u64, SpanData>,
+    /// Spans entered on each thread, innermost last
+    stacks: HashMap<ThreadId, Vec<u64>>,
+    /// The thread collecting the trace
+    root_thread: Option<ThreadId>,
+}
+
+impl TraceState {
+    fn current_span(&self) -> Option<u64> {
+        let innermost = |thread_id: &ThreadId| {
+            self.stacks.get(thread_id).and_then(|stack| stack.last().copied())
+        };
+        innermost(&std::thread::current().id())
+            .or_else(|| self.root_thread.as_ref().and_then(innermost))
+    }
+
+    fn add_span(&mut self, name: String, fields: Vec<(String, String)>, parent: Option<u64>) -> u64 {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.spans.insert(id, SpanData {
+            name,
+            fields,
+            parent,
+            start: Instant::now(),
+            duration: None,
+            ref_count: 1,
+        });
+        id
+    }
+}
+
+#[derive(Clone, Default)]
+struct TraceCollector {
+    state: Arc<Mutex<TraceState>>,
+}
+
+impl TraceCollector {
+    fn into_tree(self) -> Vec<TraceNode> {
+        let state = self.state.lock().unwrap();
+        let mut children: HashMap<Option<u64>, Vec<u64>> = HashMap::new();
+        for (id, span) in state.spans.iter() {
+            children.entry(span.parent).or_default().push(*id);
+        }
+        // ids are assigned in creation order
+        for ids in children.values_mut() {
+            ids.sort_unstable();
+        }
+        build_nodes(&state, &children, None)
+    }
+
+    fn parent_of(&self, state: &TraceState, explicit: Option<&Id>, is_contextual: bool) -> Option<u64> {
+        match explicit {
+            Some(id) => Some(id.into_u64()),
+            None if is_contextual => state.current_span(),
+            None => None,
+        }
+    }
+}
+
+fn build_nodes(
+    state: &TraceState,
+    children: &HashMap<Option<u64>, Vec<u64>>,
+    parent: Option<u64>
+) -> Vec<TraceNode> {
+    let Some(ids) = children.get(&parent) else {
+        return Vec::new();
+    };
+    ids.iter()
+        .map(|id| {
+            let span = &state.spans[id];
+            TraceNode {
+                name: span.name.clone(),
+                fields: span.fields.clone(),
+                // spans still open when the trace ends last until then
+                duration: span.duration.unwrap_or_else(|| span.start.elapsed()),
+                children: build_nodes(state, children, Some(*id)),
+            }
+        })
+        .collect()
+}
+
+impl Subscriber for TraceCollector {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        let mut fields = FieldCollector::default();
+        attrs.record(&mut fields);
+        let mut state = self.state.lock().unwrap();
+        let parent = self.parent_of(&state, attrs.parent(), attrs.is_contextual());
+        let id = state.add_span(attrs.metadata().name().to_string(), fields.0, parent);
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut fields = FieldCollector::default();
+        values.record(&mut fields);
+        let mut state = self.state.lock().unwrap();
+        if let Some(span) = state.spans.get_mut(&span.into_u64()) {
+            for (name, value) in fields.0 {
+                match span.fields.iter_mut().find(|(existing, _)| *existing == name) {
+                    Some(field) => field.1 = value,
+                    None => span.fields.push((name, value)),
+                }
+            }
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    /// Events are added as spans without duration, named by their message if they have one.
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = FieldCollector::default();
+        event.record(&mut fields);
+        let mut fields = fields.0;
+        let name = match fields.iter().position(|(name, _)| name == "message") {
+            Some(pos) => fields.remove(pos).1,
+            None => event.metadata().name().to_string(),
+        };
+        let mut state = self.state.lock().unwrap();
+        let parent = self.parent_of(&state, event.parent(), event.is_contextual());
+        let id = state.add_span(name, fields, parent);
+        if let Some(span) = state.spans.get_mut(&id) {
+            span.duration = Some(Duration::ZERO);
+        }
+    }
+
+    fn enter(&self, span: &Id) {
+        let thread_id = std::thread::current().id();
+        let mut state = self.state.lock().unwrap();
+        state.stacks.entry(thread_id).or_default().push(span.into_u64());
+    }
+
+    fn exit(&self, span: &Id) {
+        let thread_id = std::thread::current().id();
+        let mut state = self.state.lock().unwrap();
+        if let Some(stack) = state.stacks.get_mut(&thread_id) {
+            if let Some(pos) = stack.iter().rposition(|id| *id == span.into_u64()) {
+                stack.remove(pos);
+            }
+        }
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        let mut state = self.state.lock().unwrap();
+        if let Some(data) = state.spans.get_mut(&span.into_u64()) {
+            data.ref_count += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(data) = state.spans.get_mut(&span.into_u64()) else {
+            return false;
+        };
+        data.ref_count = data.ref_count.saturating_sub(1);
+        if data.ref_count > 0 {
+            return false;
+        }
+        data.duration = Some(data.start.elapsed());
+        true
+    }
+}
+
+#[derive(Default)]
+struct FieldCollector(Vec<(String, String)>);
+
+impl Visit for FieldCollector {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name().to_string(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.push((field.name().to_string(), format!("{value:?}")));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::field::Empty;
+    use tracing::{info, info_span};
+
+    #[test]
+    fn test_collect_trace() {
+        let (res, trace) = collect_trace(|| {
+            let root = info_span!("query", query = "up");
+            let _root = root.enter();
+            {
+                let search = info_span!("search", selector = "{__name__=\"up\"}", keys = Empty);
+                let _search = search.enter();
+                search.record("keys", 3);
+                info!(hit = true, "rollup cache");
+            }
+            info_span!("eval").in_scope(|| 42)
+        });
+        assert_eq!(res, 42);
+
+        assert_eq!(trace.len(), 1);
+        let root = &trace[0];
+        assert_eq!(root.name, "query");
+        assert_eq!(root.fields, vec![("query".to_string(), "up".to_string())]);
+
+        let children = root.children.iter().map(|node| node.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(children, vec!["search", "eval"]);
+
+        let search = &root.children[0];
+        assert_eq!(search.fields, vec![
+            ("selector".to_string(), "{__name__=\"up\"}".to_string()),
+            ("keys".to_string(), "3".to_string()),
+        ]);
+        assert_eq!(search.children.len(), 1);
+        let event = &search.children[0];
+        assert_eq!(event.name, "rollup cache");
+        assert_eq!(event.fields, vec![("hit".to_string(), "true".to_string())]);
+        assert_eq!(event.duration, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_trace_is_scoped() {
+        let (_, trace) = collect_trace(|| {});
+        assert!(trace.is_empty());
+
+        // spans outside of the scope are not collected
+        let _ = info_span!("outside").entered();
+        let (_, trace) = collect_trace(|| {
+            let _ = info_span!("inside").entered();
+        });
+        let names = trace.iter().map(|node| node.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["inside"]);
+    }
+}
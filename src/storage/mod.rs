@@ -198,6 +198,8 @@ pub struct TimeSeriesOptions {
     pub dedupe_interval: Option<Duration>,
     pub labels: Option<AHashMap<String, String>>,
     pub significant_digits: Option<u8>,
+    /// Create the series as a monotonic counter (see `TimeSeries::counter`)
+    pub counter: bool,
 }
 
 impl TimeSeriesOptions {
@@ -318,13 +320,35 @@ impl TryFrom<&ValkeyString> for BucketTimestamp {
     }
 }
 
+/// How empty aggregation buckets are filled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FillMode {
+    /// No value, reported as null
+    Null,
+    /// The value of the previous bucket
+    Previous,
+    /// Interpolated linearly between the surrounding buckets
+    Linear,
+    /// A fixed value
+    Value(f64),
+}
+
 #[derive(Debug, Clone)]
 pub struct AggregationOptions {
     pub aggregator: Aggregator,
     pub bucket_duration: Duration,
     pub timestamp_output: BucketTimestamp,
-    pub time_delta: i64,
-    pub empty: bool
+    pub empty: bool,
+    /// Fills empty buckets, which are then reported as with `empty`
+    pub fill: Option<FillMode>,
+}
+
+/// Options for resampling a range to one point per step, as PromQL does for instant vectors.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResampleOptions {
+    pub step: Duration,
+    /// How far back from a step to look for a sample
+    pub lookback: Duration,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -335,6 +359,7 @@ pub struct RangeOptions {
     pub aggregation: Option<AggregationOptions>,
     pub filter: Option<RangeFilter>,
     pub alignment: Option<RangeAlignment>,
+    pub resample: Option<ResampleOptions>,
     pub latest: bool
 }
 
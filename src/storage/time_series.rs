@@ -7,7 +7,8 @@ use super::{
     TimeSeriesChunk,
     TimeSeriesOptions
 };
-use crate::common::types::{Label, PooledTimestampVec, PooledValuesVec, Timestamp};
+use crate::common::types::{PooledTimestampVec, PooledValuesVec, Timestamp};
+use crate::common::InternedLabel;
 use crate::error::{TsdbError, TsdbResult};
 use crate::storage::constants::{DEFAULT_CHUNK_SIZE_BYTES, SPLIT_FACTOR};
 use crate::storage::timestamps_filter_iterator::TimestampsFilterIterator;
@@ -36,7 +37,7 @@ pub struct TimeSeries {
     /// the metric name is `http_requests_total`, and the labels are method="POST" and status="500"
     /// Metric names must match the regex [a-zA-Z_:][a-zA-Z0-9_:]*
     pub metric_name: String,
-    pub labels: Vec<Label>,
+    pub labels: Vec<InternedLabel>,
 
     pub retention: Duration,
     pub dedupe_interval: Option<Duration>,
@@ -46,6 +47,14 @@ pub struct TimeSeries {
     pub chunk_size_bytes: usize,
     pub chunks: Vec<TimeSeriesChunk>,
 
+    /// Whether the series is a monotonic counter. Counter resets are detected on ingest and
+    /// compensated by `counter_offset`, so that stored values never decrease
+    pub counter: bool,
+    /// Sum of the values before each reset, added to ingested values of a counter
+    pub counter_offset: f64,
+    /// Number of counter resets detected
+    pub counter_resets: usize,
+
     // meta
     pub total_samples: usize,
     pub first_timestamp: Timestamp,
@@ -71,7 +80,10 @@ impl TimeSeries {
             first_timestamp: 0,
             last_timestamp: 0,
             last_value: f64::NAN,
-            significant_digits: None
+            significant_digits: None,
+            counter: false,
+            counter_offset: 0.0,
+            counter_resets: 0,
         }
     }
 
@@ -82,6 +94,7 @@ impl TimeSeries {
             res.chunk_size_bytes = chunk_size;
         }
         res.duplicate_policy = options.duplicate_policy.unwrap_or(DuplicatePolicy::KeepLast);
+        res.counter = options.counter;
         // res.chunk_compression = options.encoding.unwrap_or(Encoding::Compressed);
         if let Some(metric_name) = options.metric_name {
             // todo: validate against regex
@@ -95,12 +108,9 @@ impl TimeSeries {
         }
         if let Some(labels) = options.labels {
             for (k, v) in labels.iter() {
-                res.labels.push(Label {
-                    name: k.to_string(),
-                    value: v.to_string(),
-                });
+                res.labels.push(InternedLabel::new(k, v));
             }
-            res.labels.sort_by(|a, b| a.name.cmp(&b.name));
+            res.labels.sort_by(|a, b| a.name().cmp(b.name()));
         }
         Ok(res)
     }
@@ -109,6 +119,11 @@ impl TimeSeries {
         self.total_samples == 0
     }
 
+    /// Returns the range [first_timestamp, last_timestamp] of the series, or None if it is empty.
+    pub fn time_range(&self) -> Option<(Timestamp, Timestamp)> {
+        (!self.is_empty()).then_some((self.first_timestamp, self.last_timestamp))
+    }
+
     /// Get the full metric name of the time series, including labels in Prometheus format.
     /// For example,
     ///
@@ -129,9 +144,9 @@ impl TimeSeries {
     pub fn create_key(&self) -> String {
         let mut hasher = xxhash_rust::xxh3::Xxh3::new();
         for label in self.labels.iter() {
-            hasher.write(label.name.as_bytes());
+            hasher.write(label.name().as_bytes());
             hasher.write_u8(b'=');
-            hasher.write(label.value.as_bytes());
+            hasher.write(label.value().as_bytes());
         }
         format!("{{{}}}:{}:{}", self.metric_name, hasher.digest(), self.id)
     }
@@ -146,11 +161,40 @@ impl TimeSeries {
         }
     }
 
+    /// Adds a sample. For counters, `value` is the raw counter value, and a value lower than the
+    /// last raw value is taken as a reset.
     pub fn add(
         &mut self,
         ts: Timestamp,
         value: f64,
         dp_override: Option<DuplicatePolicy>,
+    ) -> TsdbResult<()> {
+        if !self.counter {
+            return self.add_value(ts, value, dp_override);
+        }
+
+        // out of order samples are not checked for resets, and take the current offset
+        let last_raw_value = self.last_value - self.counter_offset;
+        let is_reset = !self.is_empty() && ts > self.last_timestamp && value < last_raw_value;
+        let offset = if is_reset {
+            self.counter_offset + last_raw_value
+        } else {
+            self.counter_offset
+        };
+        self.add_value(ts, value + offset, dp_override)?;
+        if is_reset {
+            self.counter_offset = offset;
+            self.counter_resets += 1;
+        }
+        Ok(())
+    }
+
+    /// Adds a sample with `value` stored as is.
+    fn add_value(
+        &mut self,
+        ts: Timestamp,
+        value: f64,
+        dp_override: Option<DuplicatePolicy>,
     ) -> TsdbResult<()> {
         if self.is_older_than_retention(ts) {
             return Err(TsdbError::SampleTooOld);
@@ -168,7 +212,7 @@ impl TimeSeries {
             }
 
             if ts <= last_ts {
-                let _ = self.upsert_sample(ts, value, dp_override);
+                self.upsert_sample(ts, value, dp_override)?;
                 return Ok(());
             }
         }
@@ -176,6 +220,43 @@ impl TimeSeries {
         self.add_sample(ts, value)
     }
 
+    /// Adds `delta` to the last value of the series, treating an empty series as 0. If `ts` falls
+    /// in the bucket of the last sample (buckets are `resolution` wide, or single timestamps if it
+    /// is zero), the last sample is updated in place, otherwise a sample is appended at `ts`.
+    /// Returns the timestamp of the updated or appended sample. Counters cannot be decremented.
+    pub fn increment(&mut self, ts: Timestamp, delta: f64, resolution: Duration) -> TsdbResult<Timestamp> {
+        if self.counter && delta < 0.0 {
+            let msg = format!("counters cannot be decremented, got a delta of {delta}");
+            return Err(TsdbError::InvalidNumber(msg));
+        }
+        // increments work on stored values, which for counters already include the offset
+        if self.is_empty() {
+            self.add_value(ts, delta, None)?;
+            return Ok(ts);
+        }
+
+        let last_ts = self.last_timestamp;
+        let value = self.last_value + delta;
+        let resolution = resolution.as_millis() as i64;
+        let same_bucket = if resolution > 0 {
+            ts.div_euclid(resolution) == last_ts.div_euclid(resolution)
+        } else {
+            ts == last_ts
+        };
+
+        if same_bucket {
+            self.upsert_sample(last_ts, value, Some(DuplicatePolicy::KeepLast))?;
+            return Ok(last_ts);
+        }
+        if ts < last_ts {
+            let msg = format!("timestamp {ts} is older than the last sample at {last_ts}");
+            return Err(TsdbError::InvalidTimestamp(msg));
+        }
+
+        self.add_value(ts, value, None)?;
+        Ok(ts)
+    }
+
     pub(super) fn add_sample(&mut self, time: Timestamp, value: f64) -> TsdbResult<()> {
         let value = self.adjust_value(value);
         let sample = Sample {
@@ -362,28 +443,29 @@ impl TimeSeries {
         Ok(result.into_sorted_vec())
     }
 
+    /// Appends the samples between `start_time` and `end_time` to `timestamps` and `values`.
+    /// Returns the number of chunks decoded.
     pub fn select_raw(
         &self,
         start_time: Timestamp,
         end_time: Timestamp,
         timestamps: &mut Vec<Timestamp>,
         values: &mut Vec<f64>,
-    ) -> TsdbResult<()> {
-        if self.is_empty() {
-            return Ok(());
+    ) -> TsdbResult<usize> {
+        let chunks = self.overlapping_chunks(start_time, end_time);
+        select_chunks_raw(chunks, end_time, timestamps, values)?;
+        Ok(chunks.len())
+    }
+
+    /// Returns the chunks holding samples between `start_time` and `end_time`.
+    pub fn overlapping_chunks(&self, start_time: Timestamp, end_time: Timestamp) -> &[TimeSeriesChunk] {
+        if self.is_empty() || self.chunks.is_empty() {
+            return &[];
         }
         let (index, _) = get_chunk_index(&self.chunks, start_time);
         let chunks = &self.chunks[index..];
-        // Get overlapping data points from the compressed blocks.
-        for chunk in chunks.iter() {
-            let first = chunk.first_timestamp();
-            if first > end_time {
-                break;
-            }
-            chunk.get_range(first, end_time, timestamps, values)?;
-        }
-
-        Ok(())
+        let count = chunks.partition_point(|chunk| chunk.first_timestamp() <= end_time);
+        &chunks[..count]
     }
 
     pub fn iter(&self) -> impl Iterator<Item = Sample> + '_ {
@@ -530,8 +612,8 @@ impl TimeSeries {
         raw::save_string(rdb, &self.metric_name);
         raw::save_unsigned(rdb, self.labels.len() as u64);
         for label in self.labels.iter() {
-            raw::save_string(rdb, &label.name);
-            raw::save_string(rdb, &label.value);
+            raw::save_string(rdb, label.name());
+            raw::save_string(rdb, label.value());
         }
         raw::save_unsigned(rdb, self.retention.as_secs());
         // todo: how to mark as optional ???
@@ -549,6 +631,9 @@ impl TimeSeries {
         for chunk in self.chunks.iter() {
             chunk.rdb_save(rdb);
         }
+        raw::save_unsigned(rdb, self.counter as u64);
+        raw::save_double(rdb, self.counter_offset);
+        raw::save_unsigned(rdb, self.counter_resets as u64);
     }
 
     pub fn rdb_load(rdb: *mut raw::RedisModuleIO, _encver: i32) -> *mut std::ffi::c_void {
@@ -559,15 +644,15 @@ impl TimeSeries {
         }
     }
 
-     fn load_internal(rdb: *mut raw::RedisModuleIO, _encver: i32) -> Result<Self, valkey_module::error::Error> {
+     fn load_internal(rdb: *mut raw::RedisModuleIO, encver: i32) -> Result<Self, valkey_module::error::Error> {
         let id = raw::load_unsigned(rdb)?;
         let metric_name = raw::load_string(rdb)?.into();
         let labels_len = raw::load_unsigned(rdb)? as usize;
         let mut labels = Vec::with_capacity(labels_len);
         for _ in 0..labels_len {
-            let name = raw::load_string(rdb)?;
-            let value = raw::load_string(rdb)?;
-            labels.push(Label { name: name.into(), value: value.into() });
+            let name: String = raw::load_string(rdb)?.into();
+            let value: String = raw::load_string(rdb)?.into();
+            labels.push(InternedLabel::new(&name, &value));
         }
         let retention = Duration::from_secs(raw::load_unsigned(rdb)?);
         let dedupe_interval = if let Ok(interval) = raw::load_unsigned(rdb) {
@@ -610,6 +695,16 @@ impl TimeSeries {
             chunks.push(chunk);
         }
 
+        // counter state was added in version 2
+        let (counter, counter_offset, counter_resets) = if encver >= 2 {
+            let counter = raw::load_unsigned(rdb)? != 0;
+            let offset = raw::load_double(rdb)?;
+            let resets = raw::load_unsigned(rdb)? as usize;
+            (counter, offset, resets)
+        } else {
+            (false, 0.0, 0)
+        };
+
         let ts = TimeSeries {
             id,
             metric_name,
@@ -625,6 +720,9 @@ impl TimeSeries {
             first_timestamp,
             last_timestamp,
             last_value,
+            counter,
+            counter_offset,
+            counter_resets,
         };
 
         // ts.update_meta();
@@ -649,12 +747,30 @@ impl Default for TimeSeries {
             first_timestamp: 0,
             last_timestamp: 0,
             last_value: f64::NAN,
-            significant_digits: None
+            significant_digits: None,
+            counter: false,
+            counter_offset: 0.0,
+            counter_resets: 0,
         }
     }
 }
 
 /// Return the index of the chunk in which the timestamp belongs. Assumes !chunks.is_empty()
+/// Appends the samples of `chunks`, as returned by `overlapping_chunks`, up to `end_time` to
+/// `timestamps` and `values`.
+pub fn select_chunks_raw(
+    chunks: &[TimeSeriesChunk],
+    end_time: Timestamp,
+    timestamps: &mut Vec<Timestamp>,
+    values: &mut Vec<f64>,
+) -> TsdbResult<()> {
+    // Get overlapping data points from the compressed blocks.
+    for chunk in chunks.iter() {
+        chunk.get_range(chunk.first_timestamp(), end_time, timestamps, values)?;
+    }
+    Ok(())
+}
+
 fn get_chunk_index(chunks: &[TimeSeriesChunk], timestamp: Timestamp) -> (usize, bool) {
     let len = chunks.len();
     let first = chunks[0].first_timestamp();
@@ -788,6 +904,86 @@ mod tests {
         assert_eq!(ts.last_timestamp, 100);
     }
 
+    #[test]
+    fn test_add_duplicate() {
+        let mut ts = TimeSeries::new();
+        ts.duplicate_policy = DuplicatePolicy::Block;
+        ts.add(100, 1.0, None).unwrap();
+        ts.add(200, 2.0, None).unwrap();
+
+        let res = ts.add(100, 3.0, None);
+        assert!(matches!(res, Err(TsdbError::DuplicateSample(_))));
+
+        // the override takes precedence over the policy of the series
+        ts.add(100, 3.0, Some(DuplicatePolicy::KeepLast)).unwrap();
+        let samples: Vec<_> = ts.iter().collect();
+        assert_eq!(samples[0].timestamp, 100);
+        assert_eq!(samples[0].value, 3.0);
+        assert_eq!(ts.total_samples, 2);
+    }
+
+    #[test]
+    fn test_counter_resets() {
+        let mut ts = TimeSeries::new();
+        ts.counter = true;
+        for (timestamp, value) in [(1000, 10.0), (2000, 15.0), (3000, 3.0), (4000, 8.0), (5000, 1.0)] {
+            ts.add(timestamp, value, None).unwrap();
+        }
+        assert_eq!(ts.counter_resets, 2);
+        assert_eq!(ts.counter_offset, 23.0);
+
+        let values: Vec<f64> = ts.iter().map(|sample| sample.value).collect();
+        assert_eq!(values, vec![10.0, 15.0, 18.0, 23.0, 24.0]);
+    }
+
+    #[test]
+    fn test_increment() {
+        let mut ts = TimeSeries::new();
+        assert_eq!(ts.increment(1000, 5.0, Duration::ZERO).unwrap(), 1000);
+        assert_eq!(ts.increment(2000, 2.0, Duration::ZERO).unwrap(), 2000);
+        assert_eq!(ts.increment(2000, -1.0, Duration::ZERO).unwrap(), 2000);
+        assert_eq!(ts.last_value, 6.0);
+        assert_eq!(ts.total_samples, 2);
+
+        let res = ts.increment(1500, 1.0, Duration::ZERO);
+        assert!(matches!(res, Err(TsdbError::InvalidTimestamp(_))));
+    }
+
+    #[test]
+    fn test_counter_increment_rejects_negative_delta() {
+        let mut ts = TimeSeries::new();
+        ts.counter = true;
+        ts.increment(1000, 5.0, Duration::ZERO).unwrap();
+        let res = ts.increment(2000, -1.0, Duration::ZERO);
+        assert!(matches!(res, Err(TsdbError::InvalidNumber(_))));
+        assert_eq!(ts.last_value, 5.0);
+        assert_eq!(ts.total_samples, 1);
+
+        // also for a new series
+        let mut ts = TimeSeries::new();
+        ts.counter = true;
+        assert!(ts.increment(1000, -1.0, Duration::ZERO).is_err());
+        assert!(ts.is_empty());
+    }
+
+    #[test]
+    fn test_increment_in_bucket() {
+        let mut ts = TimeSeries::new();
+        let resolution = Duration::from_secs(10);
+        ts.increment(10_000, 1.0, resolution).unwrap();
+        // same bucket as the last sample: updated in place
+        assert_eq!(ts.increment(15_000, 1.0, resolution).unwrap(), 10_000);
+        assert_eq!(ts.increment(19_999, 1.0, resolution).unwrap(), 10_000);
+        assert_eq!(ts.total_samples, 1);
+        assert_eq!(ts.last_value, 3.0);
+
+        assert_eq!(ts.increment(20_000, 1.0, resolution).unwrap(), 20_000);
+        assert_eq!(ts.total_samples, 2);
+        let samples: Vec<_> = ts.iter().collect();
+        assert_eq!(samples[0].value, 3.0);
+        assert_eq!(samples[1].value, 4.0);
+    }
+
     #[test]
     fn test_1000_entries() {
         let mut ts = TimeSeries::new();
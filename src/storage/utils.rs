@@ -1,6 +1,7 @@
 use enquote::enquote;
 use rand_distr::num_traits::Zero;
-use crate::common::types::{Label, Timestamp};
+use crate::common::types::Timestamp;
+use crate::common::InternedLabel;
 
 trait ModuloSignedExt {
     fn modulo(&self, n: Self) -> Self;
@@ -168,19 +169,19 @@ pub(crate) fn filter_samples_by_ts<'a>(
     (count, &by_ts_args[ts_filter_index..])
 }
 
-pub fn format_prometheus_metric_name_into(full_name: &mut String, name: &str, labels: &[Label]) {
+pub fn format_prometheus_metric_name_into(full_name: &mut String, name: &str, labels: &[InternedLabel]) {
     full_name.push_str(name);
     if !labels.is_empty() {
         full_name.push('{');
         for (i, label) in labels.iter().enumerate() {
-            full_name.push_str(&label.name);
+            full_name.push_str(label.name());
             full_name.push_str("=\"");
             // avoid allocation if possible
-            if label.value.contains('"') {
-                let quoted_value = enquote('\"', &label.value);
+            if label.value().contains('"') {
+                let quoted_value = enquote('\"', label.value());
                 full_name.push_str(&quoted_value);
             } else {
-                full_name.push_str(&label.value);
+                full_name.push_str(label.value());
             }
             full_name.push('"');
             if i < labels.len() - 1 {
@@ -191,9 +192,9 @@ pub fn format_prometheus_metric_name_into(full_name: &mut String, name: &str, la
     }
 }
 
-pub fn format_prometheus_metric_name(name: &str, labels: &[Label]) -> String {
+pub fn format_prometheus_metric_name(name: &str, labels: &[InternedLabel]) -> String {
     let size_hint = name.len() + labels.iter()
-        .map(|l| l.name.len() + l.value.len() + 3).sum::<usize>();
+        .map(|l| l.name().len() + l.value().len() + 3).sum::<usize>();
     let mut full_name: String = String::with_capacity(size_hint);
     format_prometheus_metric_name_into(&mut full_name, name, labels);
     full_name